@@ -193,7 +193,8 @@ impl Room {
 
         builder = builder
             .with_focus(configuration.focus.try_into()?)
-            .with_date_divider_mode(configuration.date_divider_mode.into());
+            .with_date_divider_mode(configuration.date_divider_mode.into())
+            .with_date_dividers(configuration.show_date_dividers);
 
         if configuration.track_read_receipts {
             builder = builder.track_read_marker_and_receipts();
@@ -904,6 +905,17 @@ impl Room {
         Ok(handle)
     }
 
+    /// Get the number of requests to join this room, e.g. to display a badge,
+    /// without subscribing to them.
+    pub async fn knock_requests_count(&self) -> Result<KnockRequestsCount, ClientError> {
+        Ok(self.inner.knock_requests_count().await?.into())
+    }
+
+    /// Mark all the current requests to join this room as seen at once.
+    pub async fn mark_all_knock_requests_as_seen(&self) -> Result<(), ClientError> {
+        Ok(self.inner.mark_all_knock_requests_as_seen().await?)
+    }
+
     /// Return a debug representation for the internal room events data
     /// structure, one line per entry in the resulting vector.
     pub async fn room_events_debug_string(&self) -> Result<Vec<String>, ClientError> {
@@ -1106,6 +1118,21 @@ impl From<matrix_sdk::room::knock_requests::KnockRequest> for KnockRequest {
     }
 }
 
+/// The number of requests to join a room.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct KnockRequestsCount {
+    /// The number of all the requests to join the room.
+    pub total: u64,
+    /// The number of the requests that haven't been marked as `seen`.
+    pub unseen: u64,
+}
+
+impl From<matrix_sdk::room::knock_requests::KnockRequestsCount> for KnockRequestsCount {
+    fn from(count: matrix_sdk::room::knock_requests::KnockRequestsCount) -> Self {
+        Self { total: count.total as u64, unseen: count.unseen as u64 }
+    }
+}
+
 /// A listener for receiving new requests to a join a room.
 #[matrix_sdk_ffi_macros::export(callback_interface)]
 pub trait KnockRequestsListener: Send + Sync {
@@ -1157,8 +1184,13 @@ impl KnockRequestActions {
 
     /// Declines the knock request by banning the user from the room with an
     /// optional reason.
-    pub async fn decline_and_ban(&self, reason: Option<String>) -> Result<(), ClientError> {
-        self.inner.decline_and_ban(reason.as_deref()).await.map_err(Into::into)
+    ///
+    /// Returns the membership of the user before the ban.
+    pub async fn decline_and_ban(
+        &self,
+        reason: Option<String>,
+    ) -> Result<PriorMembership, ClientError> {
+        Ok(self.inner.decline_and_ban(reason.as_deref()).await?.into())
     }
 
     /// Marks the knock request as 'seen'.
@@ -1170,6 +1202,29 @@ impl KnockRequestActions {
     }
 }
 
+/// The membership of a user before their knock request was declined with a
+/// ban.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct PriorMembership {
+    /// Whether the user was joined to the room.
+    pub was_joined: bool,
+    /// Whether the user was invited to the room.
+    pub was_invited: bool,
+    /// Whether the user only knocked on the room, without being joined or
+    /// invited to it before.
+    pub is_knock_only: bool,
+}
+
+impl From<matrix_sdk::room::knock_requests::PriorMembership> for PriorMembership {
+    fn from(value: matrix_sdk::room::knock_requests::PriorMembership) -> Self {
+        Self {
+            was_joined: value.was_joined(),
+            was_invited: value.was_invited(),
+            is_knock_only: value.is_knock_only(),
+        }
+    }
+}
+
 /// Generates a `matrix.to` permalink to the given room alias.
 #[matrix_sdk_ffi_macros::export]
 pub fn matrix_to_room_alias_permalink(
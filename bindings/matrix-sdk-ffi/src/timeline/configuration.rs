@@ -140,6 +140,13 @@ pub struct TimelineConfiguration {
     /// How often to insert date dividers
     pub date_divider_mode: DateDividerMode,
 
+    /// Should date dividers be inserted at all?
+    ///
+    /// If `false`, `date_divider_mode` is ignored and the timeline doesn't
+    /// contain any date divider.
+    #[uniffi(default = true)]
+    pub show_date_dividers: bool,
+
     /// Should the read receipts and read markers be tracked for the timeline
     /// items in this instance?
     ///
@@ -73,6 +73,9 @@ pub struct MessageContent {
     pub msg_type: MessageType,
     pub body: String,
     pub is_edited: bool,
+    /// Whether the latest edit couldn't be decrypted, in which case the content
+    /// is the one of the latest version that could be decrypted.
+    pub has_undecryptable_edit: bool,
     pub mentions: Option<Mentions>,
 }
 
@@ -114,6 +117,7 @@ impl TryFrom<matrix_sdk_ui::timeline::MsgLikeContent> for MsgLikeContent {
                             msg_type,
                             body: message.body().to_owned(),
                             is_edited: message.is_edited(),
+                            has_undecryptable_edit: message.has_undecryptable_edit(),
                             mentions: message.mentions().cloned().map(|m| m.into()),
                         },
                     },
@@ -1,4 +1,7 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use async_compat::get_runtime_handle;
 use language_tags::LanguageTag;
@@ -6,8 +9,8 @@ use matrix_sdk::{
     async_trait,
     widget::{MessageLikeEventFilter, StateEventFilter},
 };
-use ruma::events::MessageLikeEventType;
-use tracing::error;
+use ruma::{events::MessageLikeEventType, OwnedRoomId};
+use tracing::{error, info};
 
 use crate::room::Room;
 
@@ -26,6 +29,26 @@ pub fn make_widget_driver(settings: WidgetSettings) -> Result<WidgetDriverAndHan
     })
 }
 
+/// Create a widget driver which restores the state of a previous session from
+/// the given snapshot, without negotiating the capabilities again.
+#[matrix_sdk_ffi_macros::export]
+pub fn resume_widget_driver(
+    settings: WidgetSettings,
+    snapshot: Arc<WidgetSessionSnapshot>,
+) -> Result<WidgetDriverAndHandle, ParseError> {
+    let (driver, handle) =
+        matrix_sdk::widget::WidgetDriver::resume_from(settings.try_into()?, snapshot.0.clone());
+    Ok(WidgetDriverAndHandle {
+        driver: Arc::new(WidgetDriver(Mutex::new(Some(driver)))),
+        handle: Arc::new(WidgetDriverHandle(handle)),
+    })
+}
+
+/// The state of a widget session, to restore it later with
+/// `resume_widget_driver`.
+#[derive(uniffi::Object)]
+pub struct WidgetSessionSnapshot(matrix_sdk::widget::SessionSnapshot);
+
 /// An object that handles all interactions of a widget living inside a webview
 /// or IFrame with the Matrix world.
 #[derive(uniffi::Object)]
@@ -37,16 +60,29 @@ impl WidgetDriver {
         &self,
         room: Arc<Room>,
         capabilities_provider: Box<dyn WidgetCapabilitiesProvider>,
-    ) {
+    ) -> Result<(), WidgetError> {
         let Some(driver) = self.0.lock().unwrap().take() else {
             error!("Can't call run multiple times on a WidgetDriver");
-            return;
+            return Ok(());
         };
 
         let capabilities_provider = CapabilitiesProviderWrap(capabilities_provider.into());
-        if let Err(()) = driver.run(room.inner.clone(), capabilities_provider).await {
-            // TODO
-        }
+        let session_end = driver.run(room.inner.clone(), capabilities_provider).await;
+        info!(?session_end, "The widget session ended");
+        Ok(session_end.into_result()?)
+    }
+
+    /// Set the component that handles the requests of the widget to navigate
+    /// to another room, before calling `run`.
+    pub fn set_navigation_handler(&self, navigation_handler: Box<dyn WidgetNavigationHandler>) {
+        let mut driver = self.0.lock().unwrap();
+        let Some(inner) = driver.take() else {
+            error!("Can't set the navigation handler of a running WidgetDriver");
+            return;
+        };
+
+        *driver =
+            Some(inner.with_navigation_handler(NavigationHandlerWrap(navigation_handler.into())));
     }
 }
 
@@ -160,6 +196,82 @@ impl From<Intent> for matrix_sdk::widget::Intent {
     }
 }
 
+/// Defines which `parent_url` is used when none is provided.
+#[derive(uniffi::Enum, Clone)]
+pub enum ParentUrlDefault {
+    /// Use the `element_call_url`, for the non-iframe (dedicated webview)
+    /// usecase.
+    ElementCallUrl,
+    /// Don't set the `parentUrl` parameter at all.
+    Omit,
+}
+
+impl From<ParentUrlDefault> for matrix_sdk::widget::ParentUrlDefault {
+    fn from(value: ParentUrlDefault) -> Self {
+        match value {
+            ParentUrlDefault::ElementCallUrl => Self::ElementCallUrl,
+            ParentUrlDefault::Omit => Self::Omit,
+        }
+    }
+}
+
+/// Where the parameters of a virtual Element Call widget are put in its url.
+#[derive(uniffi::Enum, Clone)]
+pub enum ParamPlacement {
+    /// All the parameters are in the fragment, including the query of the
+    /// `element_call_url`.
+    FragmentOnly,
+    /// All the parameters are in the query.
+    QueryOnly,
+    /// The parameters of Element Call are in the fragment, so none of them is
+    /// sent to the server hosting Element Call, while the query of the
+    /// `element_call_url` stays in the query.
+    Mixed,
+}
+
+impl From<ParamPlacement> for matrix_sdk::widget::ParamPlacement {
+    fn from(value: ParamPlacement) -> Self {
+        match value {
+            ParamPlacement::FragmentOnly => Self::FragmentOnly,
+            ParamPlacement::QueryOnly => Self::QueryOnly,
+            ParamPlacement::Mixed => Self::Mixed,
+        }
+    }
+}
+
+/// The analytics backend of Element Call, and the id of the user for it.
+#[derive(uniffi::Record, Clone)]
+pub struct AnalyticsConfig {
+    /// The kind of analytics backend.
+    pub backend: AnalyticsBackend,
+    /// The id of the user for the analytics backend.
+    pub id: String,
+}
+
+impl From<AnalyticsConfig> for matrix_sdk::widget::AnalyticsConfig {
+    fn from(value: AnalyticsConfig) -> Self {
+        Self { backend: value.backend.into(), id: value.id }
+    }
+}
+
+/// The kind of analytics backend of Element Call.
+#[derive(uniffi::Enum, Clone)]
+pub enum AnalyticsBackend {
+    /// PostHog, the id is passed with the `posthogUserId` parameter.
+    PostHog,
+    /// Another backend, the id is passed with the given url parameter.
+    Custom { param_name: String },
+}
+
+impl From<AnalyticsBackend> for matrix_sdk::widget::AnalyticsBackend {
+    fn from(value: AnalyticsBackend) -> Self {
+        match value {
+            AnalyticsBackend::PostHog => Self::PostHog,
+            AnalyticsBackend::Custom { param_name } => Self::Custom { param_name },
+        }
+    }
+}
+
 /// Properties to create a new virtual Element Call widget.
 #[derive(uniffi::Record, Clone)]
 pub struct VirtualElementCallWidgetOptions {
@@ -171,6 +283,13 @@ pub struct VirtualElementCallWidgetOptions {
     /// The widget id.
     pub widget_id: String,
 
+    /// The hosts the `element_call_url` may point to, e.g. `call.element.io`.
+    ///
+    /// Creating the widget fails if the host of the `element_call_url` isn't
+    /// in this list. If the list is empty or unset, any host is allowed.
+    #[uniffi(default = None)]
+    pub allowed_hosts: Option<Vec<String>>,
+
     /// The url that is used as the target for the PostMessages sent
     /// by the widget (to the client).
     ///
@@ -182,10 +301,22 @@ pub struct VirtualElementCallWidgetOptions {
     /// messages. The `matrix-widget-api` (js) ignores those so this works but
     /// it might break custom implementations.
     ///
-    /// Defaults to `element_call_url` for the non-iframe (dedicated webview)
-    /// usecase.
+    /// Defaults to the value selected by `parent_url_default`.
     pub parent_url: Option<String>,
 
+    /// What to use for the `parent_url` if it is not set.
+    ///
+    /// Default: `ElementCallUrl`, for the non-iframe (dedicated webview)
+    /// usecase.
+    #[uniffi(default = None)]
+    pub parent_url_default: Option<ParentUrlDefault>,
+
+    /// Where the parameters are put in the url of the widget.
+    ///
+    /// Default: `Mixed`
+    #[uniffi(default = None)]
+    pub param_placement: Option<ParamPlacement>,
+
     /// Whether the branding header of Element call should be hidden.
     ///
     /// Default: `true`
@@ -229,6 +360,15 @@ pub struct VirtualElementCallWidgetOptions {
     /// Do not show the screenshare button.
     pub hide_screensharing: bool,
 
+    /// Disable analytics entirely, ignoring all the analytics options.
+    #[uniffi(default = false)]
+    pub analytics_disabled: bool,
+
+    /// The analytics backend to pass the id of the user to, taking precedence
+    /// over `posthog_user_id`.
+    #[uniffi(default = None)]
+    pub analytics: Option<AnalyticsConfig>,
+
     /// Can be used to pass a PostHog id to element call.
     pub posthog_user_id: Option<String>,
     /// The host of the posthog api.
@@ -255,13 +395,18 @@ impl From<VirtualElementCallWidgetOptions> for matrix_sdk::widget::VirtualElemen
         Self {
             element_call_url: value.element_call_url,
             widget_id: value.widget_id,
+            allowed_hosts: value.allowed_hosts.unwrap_or_default(),
             parent_url: value.parent_url,
+            parent_url_default: value.parent_url_default.map(Into::into).unwrap_or_default(),
+            param_placement: value.param_placement.map(Into::into).unwrap_or_default(),
             hide_header: value.hide_header,
             preload: value.preload,
             font_scale: value.font_scale,
             app_prompt: value.app_prompt,
             confine_to_room: value.confine_to_room,
             font: value.font,
+            analytics_disabled: value.analytics_disabled,
+            analytics: value.analytics.map(Into::into),
             posthog_user_id: value.posthog_user_id,
             encryption: value.encryption.into(),
             intent: value.intent.map(Into::into),
@@ -383,9 +528,17 @@ pub fn get_element_call_required_permissions(
         .into_iter()
         .chain(read_send)
         .collect(),
+        read_ephemeral: vec![],
         requires_client: true,
         update_delayed_event: true,
         send_delayed_event: true,
+        report_event: false,
+        get_user_profile: false,
+        always_on_screen: false,
+        navigate: false,
+        get_server_capabilities: false,
+        read_knock_requests: false,
+        upload_file: false,
     }
 }
 
@@ -400,13 +553,27 @@ pub struct ClientProperties {
     /// A string describing the theme (dark, light) or org.example.dark.
     /// (default: `light`)
     theme: Option<String>,
+    /// The client id passed to the widget, if it should differ from
+    /// `client_id`. (default: `client_id`)
+    #[uniffi(default = None)]
+    public_client_id: Option<String>,
+    /// Extra placeholders to replace in the widget url, e.g.
+    /// `$matrix_room_name`, mapped to their value.
+    #[uniffi(default = None)]
+    extra_props: Option<HashMap<String, String>>,
 }
 
 impl From<ClientProperties> for matrix_sdk::widget::ClientProperties {
     fn from(value: ClientProperties) -> Self {
-        let ClientProperties { client_id, language_tag, theme } = value;
+        let ClientProperties { client_id, language_tag, theme, public_client_id, extra_props } =
+            value;
         let language_tag = language_tag.and_then(|l| LanguageTag::parse(&l).ok());
-        Self::new(&client_id, language_tag, theme)
+        let props = Self::new(&client_id, language_tag, theme)
+            .with_extra_props(extra_props.unwrap_or_default());
+        match public_client_id {
+            Some(public_client_id) => props.with_public_client_id(&public_client_id),
+            None => props,
+        }
     }
 }
 
@@ -432,6 +599,82 @@ impl WidgetDriverHandle {
     pub async fn send(&self, msg: String) -> bool {
         self.0.send(msg).await
     }
+
+    /// Stop the widget session, e.g. because the widget was closed.
+    ///
+    /// The pending requests are abandoned, and the messages sent by the widget
+    /// afterwards are dropped.
+    pub fn stop(&self) {
+        self.0.stop();
+    }
+
+    /// Pause the delivery of the events of the room to the widget, without
+    /// ending the session.
+    ///
+    /// The latest events are delivered once the session is resumed, the older
+    /// ones and the ephemeral events are dropped.
+    ///
+    /// Returns `false` if the widget driver is no longer running.
+    pub async fn pause(&self) -> bool {
+        self.0.pause().await
+    }
+
+    /// Resume the delivery of the events of the room to the widget.
+    ///
+    /// Returns `false` if the widget driver is no longer running.
+    pub async fn resume(&self) -> bool {
+        self.0.resume().await
+    }
+
+    /// Send the latest critical messages to the widget again, e.g. the
+    /// notification of its capabilities, after it reconnected, e.g. because
+    /// its webview was reloaded.
+    ///
+    /// Returns `false` if the widget driver is no longer running.
+    pub async fn replay_on_reconnect(&self) -> bool {
+        self.0.replay_on_reconnect().await
+    }
+
+    /// Grant or revoke capabilities while the widget is running, by acquiring
+    /// the given capabilities instead of the negotiated ones.
+    ///
+    /// The revoked capabilities stop applying immediately, the new ones once
+    /// they are approved.
+    ///
+    /// Returns `false` if the capabilities weren't negotiated yet, or if the
+    /// widget driver is no longer running.
+    pub async fn update_capabilities(&self, desired_capabilities: WidgetCapabilities) -> bool {
+        self.0.update_capabilities(desired_capabilities.into()).await
+    }
+
+    /// Notify the widget that the theme of the client changed, e.g. from
+    /// `light` to `dark`.
+    ///
+    /// Returns `false` if the widget driver is no longer running.
+    pub async fn notify_theme_change(&self, theme: String) -> bool {
+        self.0.notify_theme_change(theme).await
+    }
+
+    /// Notify the widget that the properties of the client changed, e.g.
+    /// because the user changed the theme or the language of the system. Only
+    /// the properties that changed since the previous update are notified.
+    ///
+    /// Returns `false` if the widget driver is no longer running.
+    pub async fn update_client_properties(&self, props: ClientProperties) -> bool {
+        self.0.update_client_properties(props.into()).await
+    }
+
+    /// The capabilities requested by the widget, before they were approved, or
+    /// `None` if it didn't answer the `capabilities` request yet.
+    pub fn requested_capabilities(&self) -> Option<WidgetCapabilities> {
+        self.0.requested_capabilities().map(Into::into)
+    }
+
+    /// Take a snapshot of the session, e.g. when the app is backgrounded, to
+    /// resume it later with `resume_widget_driver`.
+    pub fn snapshot(&self) -> Arc<WidgetSessionSnapshot> {
+        Arc::new(WidgetSessionSnapshot(self.0.snapshot()))
+    }
 }
 
 /// Capabilities that a widget can request from a client.
@@ -441,6 +684,9 @@ pub struct WidgetCapabilities {
     pub read: Vec<WidgetEventFilter>,
     /// Types of the messages that a widget wants to be able to send.
     pub send: Vec<WidgetEventFilter>,
+    /// Types of the ephemeral events (e.g. `m.typing` or `m.receipt`) that a
+    /// widget wants to receive.
+    pub read_ephemeral: Vec<String>,
     /// If this capability is requested by the widget, it can not operate
     /// separately from the matrix client.
     ///
@@ -451,6 +697,24 @@ pub struct WidgetCapabilities {
     pub update_delayed_event: bool,
     /// This allows the widget to send events with a delay.
     pub send_delayed_event: bool,
+    /// This allows the widget to report events of the room to the homeserver
+    /// administrators.
+    pub report_event: bool,
+    /// This allows the widget to fetch the profile (display name and avatar)
+    /// of any user.
+    pub get_user_profile: bool,
+    /// This allows the widget to ask the client to keep it visible on the
+    /// screen, e.g. while a call is ongoing.
+    pub always_on_screen: bool,
+    /// This allows the widget to ask the client to navigate to a room.
+    pub navigate: bool,
+    /// This allows the widget to request the capabilities of the homeserver.
+    pub get_server_capabilities: bool,
+    /// This allows the widget to read the requests to join the room, e.g. for
+    /// moderation purposes.
+    pub read_knock_requests: bool,
+    /// Whether the widget can upload files, to send them in the room.
+    pub upload_file: bool,
 }
 
 impl From<WidgetCapabilities> for matrix_sdk::widget::Capabilities {
@@ -458,9 +722,17 @@ impl From<WidgetCapabilities> for matrix_sdk::widget::Capabilities {
         Self {
             read: value.read.into_iter().map(Into::into).collect(),
             send: value.send.into_iter().map(Into::into).collect(),
+            read_ephemeral: value.read_ephemeral,
             requires_client: value.requires_client,
             update_delayed_event: value.update_delayed_event,
             send_delayed_event: value.send_delayed_event,
+            report_event: value.report_event,
+            get_user_profile: value.get_user_profile,
+            always_on_screen: value.always_on_screen,
+            navigate: value.navigate,
+            get_server_capabilities: value.get_server_capabilities,
+            read_knock_requests: value.read_knock_requests,
+            upload_file: value.upload_file,
         }
     }
 }
@@ -470,9 +742,17 @@ impl From<matrix_sdk::widget::Capabilities> for WidgetCapabilities {
         Self {
             read: value.read.into_iter().map(Into::into).collect(),
             send: value.send.into_iter().map(Into::into).collect(),
+            read_ephemeral: value.read_ephemeral,
             requires_client: value.requires_client,
             update_delayed_event: value.update_delayed_event,
             send_delayed_event: value.send_delayed_event,
+            report_event: value.report_event,
+            get_user_profile: value.get_user_profile,
+            always_on_screen: value.always_on_screen,
+            navigate: value.navigate,
+            get_server_capabilities: value.get_server_capabilities,
+            read_knock_requests: value.read_knock_requests,
+            upload_file: value.upload_file,
         }
     }
 }
@@ -555,6 +835,52 @@ impl matrix_sdk::widget::CapabilitiesProvider for CapabilitiesProviderWrap {
     }
 }
 
+#[matrix_sdk_ffi_macros::export(callback_interface)]
+pub trait WidgetNavigationHandler: Send + Sync {
+    /// Navigate to the room with the given id, e.g. by focusing it, and return
+    /// whether the navigation was accepted.
+    fn navigate_to_room(&self, room_id: String) -> bool;
+}
+
+struct NavigationHandlerWrap(Arc<dyn WidgetNavigationHandler>);
+
+#[async_trait]
+impl matrix_sdk::widget::NavigationHandler for NavigationHandlerWrap {
+    async fn navigate_to_room(&self, room_id: OwnedRoomId) -> bool {
+        let this = self.0.clone();
+        // Like for the capabilities, this could require a prompt to the user.
+        get_runtime_handle()
+            .spawn_blocking(move || this.navigate_to_room(room_id.to_string()))
+            .await
+            // propagate panics from the blocking task
+            .unwrap()
+    }
+}
+
+#[derive(Debug, thiserror::Error, uniffi::Error)]
+#[uniffi(flat_error)]
+pub enum WidgetError {
+    #[error("the room of the widget isn't joined")]
+    RoomNotJoined,
+    #[error("the widget isn't allowed to run")]
+    PermissionsDenied,
+    #[error("the widget disconnected")]
+    ChannelClosed,
+    #[error("the client can't serve the widget anymore")]
+    ClientUnavailable,
+}
+
+impl From<matrix_sdk::widget::WidgetError> for WidgetError {
+    fn from(value: matrix_sdk::widget::WidgetError) -> Self {
+        match value {
+            matrix_sdk::widget::WidgetError::RoomNotJoined => Self::RoomNotJoined,
+            matrix_sdk::widget::WidgetError::PermissionsDenied => Self::PermissionsDenied,
+            matrix_sdk::widget::WidgetError::ChannelClosed => Self::ChannelClosed,
+            matrix_sdk::widget::WidgetError::ClientUnavailable => Self::ClientUnavailable,
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error, uniffi::Error)]
 #[uniffi(flat_error)]
 pub enum ParseError {
@@ -578,6 +904,20 @@ pub enum ParseError {
     SetHostOnCannotBeABaseUrl,
     #[error("URLs more than 4 GB are not supported")]
     Overflow,
+    #[error("the host of the widget URL changed while replacing its placeholders")]
+    HostChanged,
+    #[error("the scheme of the widget URL is not supported")]
+    UnsupportedScheme,
+    #[error("the host of the widget URL is not allowed")]
+    DisallowedHost,
+    #[error("the analytics parameter is reserved by Element Call")]
+    ReservedParam,
+    #[error("the widget URL and the homeserver URL use different schemes")]
+    MixedContent,
+    #[error("the profile of the user couldn't be fetched")]
+    ProfileUnavailable,
+    #[error("an extra placeholder overrides a built-in placeholder")]
+    PlaceholderCollision,
     #[error("unknown URL parsing error")]
     Other,
 }
@@ -602,6 +942,36 @@ impl From<url::ParseError> for ParseError {
     }
 }
 
+impl From<matrix_sdk::widget::WidgetSettingsError> for ParseError {
+    fn from(value: matrix_sdk::widget::WidgetSettingsError) -> Self {
+        match value {
+            matrix_sdk::widget::WidgetSettingsError::InvalidUrl(error) => error.into(),
+            matrix_sdk::widget::WidgetSettingsError::RelativeUrl => Self::RelativeUrlWithoutBase,
+            matrix_sdk::widget::WidgetSettingsError::UnsupportedScheme(_) => {
+                Self::UnsupportedScheme
+            }
+            matrix_sdk::widget::WidgetSettingsError::DisallowedHost(_) => Self::DisallowedHost,
+            matrix_sdk::widget::WidgetSettingsError::ReservedParam(_) => Self::ReservedParam,
+        }
+    }
+}
+
+impl From<matrix_sdk::widget::GenerateWebviewUrlError> for ParseError {
+    fn from(value: matrix_sdk::widget::GenerateWebviewUrlError) -> Self {
+        match value {
+            matrix_sdk::widget::GenerateWebviewUrlError::InvalidUrl(error) => error.into(),
+            matrix_sdk::widget::GenerateWebviewUrlError::HostChanged { .. } => Self::HostChanged,
+            matrix_sdk::widget::GenerateWebviewUrlError::MixedContent { .. } => Self::MixedContent,
+            matrix_sdk::widget::GenerateWebviewUrlError::ProfileUnavailable(_) => {
+                Self::ProfileUnavailable
+            }
+            matrix_sdk::widget::GenerateWebviewUrlError::PlaceholderCollision(_) => {
+                Self::PlaceholderCollision
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use matrix_sdk::widget::Capabilities;
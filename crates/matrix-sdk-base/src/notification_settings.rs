@@ -14,6 +14,10 @@
 
 //! Some shared types about notification settings.
 
+use ruma::{
+    push::{Action, PushConditionRoomCtx, Ruleset},
+    serde::Raw,
+};
 use serde::{Deserialize, Serialize};
 
 /// Enum representing the push notification modes for a room.
@@ -26,3 +30,114 @@ pub enum RoomNotificationMode {
     /// Do not receive any notifications.
     Mute,
 }
+
+/// Get the push actions of an event, according to the given push rules.
+///
+/// Edits never notify, even if they add mentions: they have no actions, like
+/// with the spec's `.m.rule.suppress_edits` rule. The mentions of an edit are
+/// still displayed by applying it to the original event.
+pub fn get_push_actions<'a, T>(
+    push_rules: &'a Ruleset,
+    event: &Raw<T>,
+    push_condition_room_ctx: &PushConditionRoomCtx,
+) -> &'a [Action] {
+    if is_edit(event) {
+        return &[];
+    }
+
+    push_rules.get_actions(event, push_condition_room_ctx)
+}
+
+/// Whether the given event replaces another event, i.e. is an edit.
+fn is_edit<T>(event: &Raw<T>) -> bool {
+    #[derive(Deserialize)]
+    struct Content {
+        #[serde(rename = "m.relates_to")]
+        relates_to: Option<RelatesTo>,
+    }
+
+    #[derive(Deserialize)]
+    struct RelatesTo {
+        rel_type: Option<String>,
+    }
+
+    matches!(
+        event.get_field::<Content>("content"),
+        Ok(Some(Content { relates_to: Some(RelatesTo { rel_type: Some(rel_type) }) }))
+            if rel_type == "m.replace"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{
+        push::{Action, PushConditionRoomCtx, Ruleset},
+        room_id,
+        serde::Raw,
+        uint, user_id,
+    };
+    use serde_json::json;
+
+    use super::get_push_actions;
+
+    fn push_context() -> PushConditionRoomCtx {
+        PushConditionRoomCtx {
+            user_id: user_id!("@alice:example.org").to_owned(),
+            room_id: room_id!("!room:example.org").to_owned(),
+            member_count: uint!(3),
+            user_display_name: "Alice".to_owned(),
+            power_levels: None,
+        }
+    }
+
+    #[test]
+    fn test_mention_notifies() {
+        let push_rules = Ruleset::server_default(user_id!("@alice:example.org"));
+        let event = Raw::new(&json!({
+            "type": "m.room.message",
+            "event_id": "$original",
+            "sender": "@bob:example.org",
+            "origin_server_ts": 1,
+            "content": {
+                "msgtype": "m.text",
+                "body": "hello Alice",
+                "m.mentions": { "user_ids": ["@alice:example.org"] },
+            },
+        }))
+        .unwrap();
+
+        let actions = get_push_actions(&push_rules, &event, &push_context());
+        assert!(actions.iter().any(Action::should_notify));
+        assert!(actions.iter().any(Action::is_highlight));
+    }
+
+    #[test]
+    fn test_edit_adding_mention_does_not_notify() {
+        let push_rules = Ruleset::server_default(user_id!("@alice:example.org"));
+        let event = Raw::new(&json!({
+            "type": "m.room.message",
+            "event_id": "$edit",
+            "sender": "@bob:example.org",
+            "origin_server_ts": 2,
+            "content": {
+                "msgtype": "m.text",
+                "body": "* hello Alice",
+                "m.mentions": { "user_ids": ["@alice:example.org"] },
+                "m.new_content": {
+                    "msgtype": "m.text",
+                    "body": "hello Alice",
+                    "m.mentions": { "user_ids": ["@alice:example.org"] },
+                },
+                "m.relates_to": {
+                    "rel_type": "m.replace",
+                    "event_id": "$original",
+                },
+            },
+        }))
+        .unwrap();
+
+        let actions = get_push_actions(&push_rules, &event, &push_context());
+        assert!(!actions.iter().any(Action::should_notify));
+        assert!(!actions.iter().any(Action::is_highlight));
+    }
+}
@@ -21,7 +21,8 @@ use ruma::{
 };
 
 use crate::{
-    deserialized_responses::RawAnySyncOrStrippedTimelineEvent, store::BaseStateStore, sync,
+    deserialized_responses::RawAnySyncOrStrippedTimelineEvent,
+    notification_settings::get_push_actions, store::BaseStateStore, sync,
 };
 
 /// A classical set of data used by some processors dealing with notifications
@@ -58,7 +59,7 @@ impl<'a> Notification<'a> {
     /// the [`Action`]s associated to this event and this
     /// `push_condition_room_ctx`. (based on `Self::push_rules`).
     ///
-    /// This method returns the fetched [`Action`]s.
+    /// This method returns the fetched [`Action`]s, see [`get_push_actions`].
     pub fn push_notification_from_event_if<E, P>(
         &mut self,
         room_id: &RoomId,
@@ -70,7 +71,7 @@ impl<'a> Notification<'a> {
         Raw<E>: Into<RawAnySyncOrStrippedTimelineEvent>,
         P: Fn(&Action) -> bool,
     {
-        let actions = self.push_rules.get_actions(event, push_condition_room_ctx);
+        let actions = get_push_actions(self.push_rules, event, push_condition_room_ctx);
 
         if actions.iter().any(predicate) {
             self.push_notification(room_id, actions.to_owned(), event.clone().into());
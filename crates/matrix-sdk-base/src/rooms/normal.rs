@@ -1241,7 +1241,12 @@ impl Room {
 
     /// Mark a list of requests to join the room as seen, given their state
     /// event ids.
-    pub async fn mark_knock_requests_as_seen(&self, user_ids: &[OwnedUserId]) -> StoreResult<()> {
+    ///
+    /// Returns the event ids of the requests that were newly marked as seen.
+    pub async fn mark_knock_requests_as_seen(
+        &self,
+        user_ids: &[OwnedUserId],
+    ) -> StoreResult<BTreeSet<OwnedEventId>> {
         let raw_user_ids: Vec<&str> = user_ids.iter().map(|id| id.as_str()).collect();
         let member_raw_events = self
             .store
@@ -1271,11 +1276,16 @@ impl Room {
         let current_seen_events_guard = self.get_write_guarded_current_knock_request_ids().await?;
         let mut current_seen_events = current_seen_events_guard.clone().unwrap_or_default();
 
-        current_seen_events.extend(event_to_user_ids);
+        let mut newly_seen_event_ids = BTreeSet::new();
+        for (event_id, user_id) in event_to_user_ids {
+            if current_seen_events.insert(event_id.clone(), user_id).is_none() {
+                newly_seen_event_ids.insert(event_id);
+            }
+        }
 
         self.update_seen_knock_request_ids(current_seen_events_guard, current_seen_events).await?;
 
-        Ok(())
+        Ok(newly_seen_event_ids)
     }
 
     /// Removes the seen knock request ids that are no longer valid given the
@@ -100,6 +100,16 @@ impl TimelineBuilder {
         self
     }
 
+    /// Choose whether to insert date separators between the timeline items at
+    /// all.
+    ///
+    /// Defaults to `true`. When `false`, no date divider virtual item is ever
+    /// added to the timeline.
+    pub fn with_date_dividers(mut self, show: bool) -> Self {
+        self.settings.show_date_dividers = show;
+        self
+    }
+
     /// Enable tracking of the fully-read marker and the read receipts on the
     /// timeline.
     pub fn track_read_marker_and_receipts(mut self) -> Self {
@@ -151,6 +151,9 @@ pub(super) struct TimelineSettings {
 
     /// Should the timeline items be grouped by day or month?
     pub(super) date_divider_mode: DateDividerMode,
+
+    /// Should date dividers be inserted between the timeline items at all?
+    pub(super) show_date_dividers: bool,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -159,6 +162,7 @@ impl fmt::Debug for TimelineSettings {
         f.debug_struct("TimelineSettings")
             .field("track_read_receipts", &self.track_read_receipts)
             .field("add_failed_to_parse", &self.add_failed_to_parse)
+            .field("show_date_dividers", &self.show_date_dividers)
             .finish_non_exhaustive()
     }
 }
@@ -170,6 +174,7 @@ impl Default for TimelineSettings {
             event_filter: Arc::new(default_event_filter),
             add_failed_to_parse: true,
             date_divider_mode: DateDividerMode::Daily,
+            show_date_dividers: true,
         }
     }
 }
@@ -790,15 +795,13 @@ impl<P: RoomDataProvider, D: Decryptor> TimelineController<P, D> {
         // Only add new items if the timeline is live.
         let should_add_new_items = self.is_live().await;
 
-        let date_divider_mode = self.settings.date_divider_mode.clone();
-
         let mut state = self.state.write().await;
         state
             .handle_local_event(
                 sender,
                 profile,
                 should_add_new_items,
-                date_divider_mode,
+                &self.settings,
                 txn_id,
                 send_handle,
                 content,
@@ -842,8 +845,7 @@ impl<P: RoomDataProvider, D: Decryptor> TimelineController<P, D> {
                 txn.items.remove(idx);
 
                 // Adjust the date dividers, if needs be.
-                let mut adjuster =
-                    DateDividerAdjuster::new(self.settings.date_divider_mode.clone());
+                let mut adjuster = DateDividerAdjuster::from_settings(&self.settings);
                 adjuster.run(&mut txn.items, &mut txn.meta);
             }
 
@@ -945,7 +947,7 @@ impl<P: RoomDataProvider, D: Decryptor> TimelineController<P, D> {
 
             // A read marker or a date divider may have been inserted before the local echo.
             // Ensure both are up to date.
-            let mut adjuster = DateDividerAdjuster::new(self.settings.date_divider_mode.clone());
+            let mut adjuster = DateDividerAdjuster::from_settings(&self.settings);
             adjuster.run(&mut txn.items, &mut txn.meta);
 
             txn.meta.update_read_marker(&mut txn.items);
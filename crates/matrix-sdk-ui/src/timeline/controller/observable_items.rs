@@ -404,6 +404,7 @@ mod observable_items_tests {
                         msgtype: MessageType::Text(TextMessageEventContent::plain("hello")),
                         edited: false,
                         mentions: None,
+                        has_undecryptable_edit: false,
                     }),
                     reactions: Default::default(),
                     thread_root: None,
@@ -43,8 +43,7 @@ use super::{
     },
     metadata::EventMeta,
     observable_items::ObservableItems,
-    DateDividerMode, TimelineFocusKind, TimelineMetadata, TimelineSettings,
-    TimelineStateTransaction,
+    TimelineFocusKind, TimelineMetadata, TimelineSettings, TimelineStateTransaction,
 };
 use crate::unable_to_decrypt_hook::UtdHookManager;
 
@@ -144,7 +143,7 @@ impl TimelineState {
         own_user_id: OwnedUserId,
         own_profile: Option<Profile>,
         should_add_new_items: bool,
-        date_divider_mode: DateDividerMode,
+        settings: &TimelineSettings,
         txn_id: OwnedTransactionId,
         send_handle: Option<SendHandle>,
         content: TimelineEventKind,
@@ -163,7 +162,7 @@ impl TimelineState {
 
         let mut txn = self.transaction();
 
-        let mut date_divider_adjuster = DateDividerAdjuster::new(date_divider_mode);
+        let mut date_divider_adjuster = DateDividerAdjuster::from_settings(settings);
 
         TimelineEventHandler::new(&mut txn, ctx)
             .handle_event(&mut date_divider_adjuster, content)
@@ -185,8 +184,7 @@ impl TimelineState {
     {
         let mut txn = self.transaction();
 
-        let mut date_divider_adjuster =
-            DateDividerAdjuster::new(settings.date_divider_mode.clone());
+        let mut date_divider_adjuster = DateDividerAdjuster::from_settings(settings);
 
         // Loop through all the indices, in order so we don't decrypt edits
         // before the event being edited, if both were UTD. Keep track of
@@ -86,8 +86,7 @@ impl<'a> TimelineStateTransaction<'a> {
     ) where
         RoomData: RoomDataProvider,
     {
-        let mut date_divider_adjuster =
-            DateDividerAdjuster::new(settings.date_divider_mode.clone());
+        let mut date_divider_adjuster = DateDividerAdjuster::from_settings(settings);
 
         for diff in diffs {
             match diff {
@@ -22,7 +22,7 @@ use ruma::MilliSecondsSinceUnixEpoch;
 use tracing::{error, event_enabled, instrument, trace, warn, Level};
 
 use super::{
-    controller::{ObservableItemsTransaction, TimelineMetadata},
+    controller::{ObservableItemsTransaction, TimelineMetadata, TimelineSettings},
     DateDividerMode, TimelineItem, TimelineItemKind, VirtualTimelineItem,
 };
 
@@ -64,6 +64,10 @@ pub(super) struct DateDividerAdjuster {
     consumed: bool,
 
     mode: DateDividerMode,
+
+    /// Whether date dividers are shown at all. If not, the adjuster only
+    /// removes the existing date dividers.
+    enabled: bool,
 }
 
 impl Drop for DateDividerAdjuster {
@@ -95,9 +99,18 @@ impl DateDividerAdjuster {
             // with `mark_used`.
             consumed: true,
             mode,
+            enabled: true,
         }
     }
 
+    /// Create a [`DateDividerAdjuster`] following the date dividers settings of
+    /// the timeline.
+    pub fn from_settings(settings: &TimelineSettings) -> Self {
+        let mut adjuster = Self::new(settings.date_divider_mode.clone());
+        adjuster.enabled = settings.show_date_dividers;
+        adjuster
+    }
+
     /// Marks this [`DateDividerAdjuster`] as used, which means it'll require a
     /// call to [`DateDividerAdjuster::run`] before getting dropped.
     pub fn mark_used(&mut self) {
@@ -109,6 +122,22 @@ impl DateDividerAdjuster {
     /// be.
     #[instrument(skip_all)]
     pub fn run(&mut self, items: &mut ObservableItemsTransaction<'_>, meta: &mut TimelineMetadata) {
+        if !self.enabled {
+            // Date dividers are hidden: there is nothing to insert, only remove the ones
+            // that could have been added before, in increasing order of the indices.
+            self.ops = items
+                .iter()
+                .enumerate()
+                .filter(|(_, item)| item.is_date_divider())
+                .map(|(i, _)| DateDividerOperation::Remove(i))
+                .collect();
+
+            self.process_ops(items, meta);
+            self.ops.clear();
+            self.consumed = true;
+            return;
+        }
+
         // We're going to record vector operations like inserting, replacing and
         // removing date dividers. Since we may remove or insert new items,
         // recorded offsets will change as we're iterating over the array. The
@@ -36,7 +36,7 @@ use ruma::{
         receipt::Receipt,
         relation::Replacement,
         room::{
-            encrypted::RoomEncryptedEventContent,
+            encrypted::{self, RoomEncryptedEventContent},
             member::RoomMemberEventContent,
             message::{Relation, RoomMessageEventContent, RoomMessageEventContentWithoutRelation},
         },
@@ -464,6 +464,10 @@ impl<'a, 'o> TimelineEventHandler<'a, 'o> {
 
             TimelineEventKind::UnableToDecrypt { content, utd_cause } => {
                 // TODO: Handle replacements if the replaced event is also UTD
+                if let Some(encrypted::Relation::Replacement(replacement)) = &content.relates_to {
+                    self.mark_undecryptable_msg_edit(&replacement.event_id);
+                }
+
                 if should_add {
                     self.add_item(
                         TimelineItemContent::MsgLike(MsgLikeContent::unable_to_decrypt(
@@ -665,6 +669,43 @@ impl<'a, 'o> TimelineEventHandler<'a, 'o> {
         }
     }
 
+    /// Remember that the latest edit of the message with the given event ID
+    /// couldn't be decrypted, while keeping its current content.
+    ///
+    /// The UTD edit is still added as an item of its own, so its decryption
+    /// can be retried; once decrypted, it's applied as a regular edit.
+    #[instrument(skip(self))]
+    fn mark_undecryptable_msg_edit(&mut self, edited_event_id: &EventId) {
+        let Some((item_pos, item)) = rfind_event_by_id(self.items, edited_event_id) else {
+            trace!("Edited item not found, ignoring undecryptable edit");
+            return;
+        };
+
+        if self.ctx.sender != item.sender() {
+            info!("Undecryptable edit applies to another user's timeline item, discarding");
+            return;
+        }
+
+        let TimelineItemContent::MsgLike(
+            content @ MsgLikeContent { kind: MsgLikeKind::Message(msg), .. },
+        ) = item.content()
+        else {
+            trace!("Undecryptable edit doesn't apply to a message, ignoring");
+            return;
+        };
+
+        let mut new_msg = msg.clone();
+        new_msg.has_undecryptable_edit = true;
+
+        let new_item = item.with_content(TimelineItemContent::MsgLike(
+            content.with_kind(MsgLikeKind::Message(new_msg)),
+        ));
+
+        let internal_id = item.internal_id.to_owned();
+        self.items.replace(item_pos, TimelineItem::new(new_item, internal_id));
+        self.result.items_updated += 1;
+    }
+
     /// Try to stash a pending edit, if it makes sense to do so.
     #[instrument(skip(self, replacement))]
     fn stash_pending_edit(
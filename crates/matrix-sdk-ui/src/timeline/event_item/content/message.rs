@@ -41,6 +41,7 @@ pub struct Message {
     pub(in crate::timeline) msgtype: MessageType,
     pub(in crate::timeline) edited: bool,
     pub(in crate::timeline) mentions: Option<Mentions>,
+    pub(in crate::timeline) has_undecryptable_edit: bool,
 }
 
 impl Message {
@@ -53,7 +54,8 @@ impl Message {
         let mut msgtype = c.msgtype;
         msgtype.sanitize(DEFAULT_SANITIZER_MODE, remove_reply_fallback);
 
-        let mut ret = Self { msgtype, edited: false, mentions: c.mentions };
+        let mut ret =
+            Self { msgtype, edited: false, mentions: c.mentions, has_undecryptable_edit: false };
 
         if let Some(edit) = edit {
             ret.apply_edit(edit);
@@ -70,6 +72,7 @@ impl Message {
         self.msgtype = new_content.msgtype;
         self.mentions = new_content.mentions;
         self.edited = true;
+        self.has_undecryptable_edit = false;
     }
 
     /// Get the `msgtype`-specific data of this message.
@@ -90,6 +93,15 @@ impl Message {
         self.edited
     }
 
+    /// Whether the latest edit of this message couldn't be decrypted.
+    ///
+    /// In this case, the message still has the content of the latest version
+    /// that could be decrypted (the original message or a previous edit),
+    /// which may be outdated.
+    pub fn has_undecryptable_edit(&self) -> bool {
+        self.has_undecryptable_edit
+    }
+
     /// Get the mentions of this message.
     pub fn mentions(&self) -> Option<&Mentions> {
         self.mentions.as_ref()
@@ -130,7 +142,12 @@ pub(crate) fn extract_room_msg_edit_content(
             }
         },
 
-        AnySyncMessageLikeEvent::RoomMessage(SyncRoomMessageEvent::Redacted(_)) => None,
+        AnySyncMessageLikeEvent::RoomMessage(SyncRoomMessageEvent::Redacted(_)) => {
+            // A redacted replacement has lost its `m.new_content`, so it can't be
+            // applied: keep the original content.
+            trace!("ignoring a redacted bundled edit event in a room message");
+            None
+        }
 
         _ => {
             error!("got m.room.message event with an edit of a different event type");
@@ -158,7 +175,10 @@ pub(crate) fn extract_poll_edit_content(
             }
         }
 
-        AnySyncMessageLikeEvent::UnstablePollStart(SyncUnstablePollStartEvent::Redacted(_)) => None,
+        AnySyncMessageLikeEvent::UnstablePollStart(SyncUnstablePollStartEvent::Redacted(_)) => {
+            trace!("ignoring a redacted bundled edit event in a poll");
+            None
+        }
 
         _ => {
             error!("got poll edit event with an edit of a different event type");
@@ -170,9 +190,12 @@ pub(crate) fn extract_poll_edit_content(
 #[cfg(not(tarpaulin_include))]
 impl fmt::Debug for Message {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let Self { msgtype: _, edited, mentions: _ } = self;
+        let Self { msgtype: _, edited, mentions: _, has_undecryptable_edit } = self;
         // since timeline items are logged, don't include all fields here so
         // people don't leak personal data in bug reports
-        f.debug_struct("Message").field("edited", edited).finish_non_exhaustive()
+        f.debug_struct("Message")
+            .field("edited", edited)
+            .field("has_undecryptable_edit", has_undecryptable_edit)
+            .finish_non_exhaustive()
     }
 }
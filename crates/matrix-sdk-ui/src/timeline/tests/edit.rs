@@ -24,14 +24,19 @@ use matrix_sdk_test::{async_test, ALICE, BOB};
 use ruma::{
     event_id,
     events::{
-        room::message::{MessageType, RedactedRoomMessageEventContent},
-        BundledMessageLikeRelations,
+        room::message::{
+            MessageType, RedactedRoomMessageEventContent, ReplacementMetadata,
+            RoomMessageEventContentWithoutRelation,
+        },
+        BundledMessageLikeRelations, Mentions,
     },
+    push::Action,
     room_id,
 };
 use stream_assert::{assert_next_matches, assert_pending};
 
 use super::TestTimeline;
+use crate::timeline::traits::RoomDataProvider;
 
 #[async_test]
 async fn test_live_redacted() {
@@ -152,6 +157,44 @@ async fn test_aggregated_sanitized() {
     assert!(date_divider.is_date_divider());
 }
 
+#[async_test]
+async fn test_aggregated_redacted_edit_is_ignored() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe().await;
+
+    let original_event_id = event_id!("$original");
+    let edit_event_id = event_id!("$edit");
+
+    let f = &timeline.factory;
+
+    let mut relations = BundledMessageLikeRelations::new();
+    relations.replace = Some(Box::new(
+        f.redacted(*ALICE, RedactedRoomMessageEventContent::new())
+            .event_id(edit_event_id)
+            .into_raw_sync(),
+    ));
+
+    let ev = f
+        .text_msg("original message")
+        .sender(*ALICE)
+        .event_id(original_event_id)
+        .bundled_relations(relations);
+
+    timeline.handle_live_event(ev).await;
+
+    // The redacted replacement isn't applied, the original content is kept.
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let first_event = item.as_event().unwrap();
+    assert_let!(Some(message) = first_event.content().as_message());
+    assert_let!(MessageType::Text(text) = message.msgtype());
+    assert_eq!(text.body, "original message");
+    assert!(!message.is_edited());
+    assert!(first_event.latest_edit_json().is_none());
+
+    let date_divider = assert_next_matches!(stream, VectorDiff::PushFront { value } => value);
+    assert!(date_divider.is_date_divider());
+}
+
 #[async_test]
 async fn test_edit_updates_encryption_info() {
     let timeline = TestTimeline::new();
@@ -441,3 +484,41 @@ async fn test_updated_reply_doesnt_lose_latest_edit() {
         assert_pending!(stream);
     }
 }
+
+#[async_test]
+async fn test_edit_adding_mention_updates_mentions_without_highlighting() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe_events().await;
+
+    let f = &timeline.factory;
+    let push_context = timeline.data().push_context().await.unwrap();
+
+    // Bob sends a message that doesn't mention Alice, the own user.
+    let original = event_id!("$original");
+    let mut event: TimelineEvent = f.text_msg("hello").sender(&BOB).event_id(original).into();
+    event.push_actions = Some(push_context.for_event(event.raw()));
+    timeline.handle_live_event(event).await;
+
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    assert!(item.content().as_message().unwrap().mentions().is_none());
+    assert!(!item.is_highlighted());
+
+    // Then Bob edits it to mention Alice, so the edit itself mentions her too.
+    let mut new_content =
+        RoomMessageEventContentWithoutRelation::new(MessageType::text_plain("hello Alice"));
+    new_content.mentions = Some(Mentions::with_user_ids([ALICE.to_owned()]));
+    let edit_content =
+        new_content.make_replacement(ReplacementMetadata::new(original.to_owned(), None), None);
+    assert!(edit_content.mentions.as_ref().unwrap().user_ids.contains(*ALICE));
+    let mut edit: TimelineEvent = f.event(edit_content).sender(&BOB).into();
+    edit.push_actions = Some(push_context.for_event(edit.raw()));
+    assert!(!edit.push_actions.as_ref().unwrap().iter().any(Action::is_highlight));
+    timeline.handle_live_event(edit).await;
+
+    // The mentions of the message are updated, but it isn't highlighted.
+    let item = assert_next_matches!(stream, VectorDiff::Set { index: 0, value } => value);
+    let mentions = item.content().as_message().unwrap().mentions().unwrap();
+    assert!(mentions.user_ids.contains(*ALICE));
+    assert!(!item.is_highlighted());
+    assert_pending!(stream);
+}
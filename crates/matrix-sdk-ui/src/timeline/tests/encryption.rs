@@ -385,6 +385,159 @@ async fn test_retry_edit_decryption() {
     assert_pending!(stream);
 }
 
+#[async_test]
+async fn test_utd_edit_of_readable_message() {
+    let timeline = TestTimeline::new();
+    let f = &timeline.factory;
+    let mut stream = timeline.subscribe_events().await;
+
+    // Given a readable message in the timeline,
+    let event_id = event_id!("$original");
+    timeline.handle_live_event(f.text_msg("Original").sender(&BOB).event_id(event_id)).await;
+    assert_next_matches_with_timeout!(stream, VectorDiff::PushBack { .. });
+
+    // When an edit of this message can't be decrypted,
+    let encrypted = EncryptedEventScheme::MegolmV1AesSha2(
+        MegolmV1AesSha2ContentInit {
+            ciphertext: "AwgAEtABWuWeRLintqVP5ez5kki8sDsX7zSq".to_owned(),
+            sender_key: "sKSGv2uD9zUncgL6GiLedvuky3fjVcEz9qVKZkpzN14".to_owned(),
+            device_id: "PNQBRWYIJL".into(),
+            session_id: "HSRlM67FgLYl0J0l1luflfGwpnFcLKHnNoRqUuIhQ5Q".into(),
+        }
+        .into(),
+    );
+    timeline
+        .handle_live_event(
+            f.event(assign!(RoomEncryptedEventContent::new(encrypted, None), {
+                relates_to: Some(Relation::Replacement(Replacement::new(event_id.to_owned()))),
+            }))
+            .sender(&BOB)
+            .into_utd_sync_timeline_event(),
+        )
+        .await;
+
+    // Then the original message keeps its content, but is marked as having an
+    // undecryptable edit,
+    let item =
+        assert_next_matches_with_timeout!(stream, VectorDiff::Set { index: 0, value } => value);
+    assert_let!(Some(msg) = item.content().as_message());
+    assert_eq!(msg.body(), "Original");
+    assert!(!msg.is_edited());
+    assert!(msg.has_undecryptable_edit());
+
+    // And the edit is kept as a UTD item, so its decryption can be retried.
+    let item = assert_next_matches_with_timeout!(stream, VectorDiff::PushBack { value } => value);
+    assert_matches!(
+        item.content(),
+        TimelineItemContent::MsgLike(MsgLikeContent { kind: MsgLikeKind::UnableToDecrypt(_), .. })
+    );
+
+    assert_pending!(stream);
+}
+
+#[async_test]
+async fn test_retry_decryption_of_edit_of_readable_message() {
+    const SESSION1_KEY: &[u8] = b"\
+        -----BEGIN MEGOLM SESSION DATA-----\n\
+        AXou7bY+PWm0GrxTioyoKTkxAgfrQ5lGIla62WoBMrqWAAAACgXidLIt0gaK5NT3mGigzFAPjh/M0ibXjSvo\
+        P9haNoJN2839XPCqHpErqje9x25Vy830vQXu9OpwT/QNgVXoffK6rXvIMvom6V2ElopBSVVHqgJdfqRrlGKH\
+        okfW6AE+ApVPk31BclxuUuxCy+Ph9sWBTW3MA64YGog5Ddp2PAz2Vk/iZ9Dcmtf5CDLbhIRsWiLuSEvO56ok\
+        8/ZxCsiuI4SXx+hikBs+krMTIHn74NL5ffpIlnPSOVtbiY49wE1SRwVgdeJUO9qjHpQX3fZKldBBC01l0BuB\
+        WK+W/f/LlOPgLr9Eac/u66fCK6Y81ziJOyn3l1wQuu3MQvuuJfwOqcljl47/yg6SaoTYhZ3ytHXkkBtYx0E6\
+        h+J3dgXvW6r0prqci/0gljDQR7KtWEUhXb0BwPK7ojRZWBIzF9T/5uKOio/hBZJ7MQHXt8S2HGOB+gKuzrG8\
+        azLt5EB48zgeciNlvQ5zh+AltVEErbyENhCAOxEMoO2sTjK1WZ58ZZmti8uaEZ2mJOCciAp6QiFFDnx2FiPv\
+        5DN4g22qr4A2Z4rFZNgum4VosoDA8hBvqr+G9TN5ZxVyi4IPOlqv7ycf6WGOLB6022HmZMX74KHlimDtiYlv\
+        G6q7EyfpmeT5rKs51f83rQNkRzcNXKlK83YwIBxCdv9EQXZ4WATAvRqeVF8/m2qpv58zIHjLmq7irckNDmPF\
+        W8aUFGxYXuU\n\
+        -----END MEGOLM SESSION DATA-----";
+
+    const SESSION2_KEY: &[u8] = b"\
+        -----BEGIN MEGOLM SESSION DATA-----\n\
+        AbMgil4w2zS9PcZ25f+vdcBdv0/YVaOg52K49DwCmMUkAAAAChEzP9tvnK3jd0NA+BjFfm0zzHYOiu5EyRK/\
+        F+2mFmC5vYzSiT6Zcx3dn23cU+BpmkCH/HxFli1TMZ29jLZt/ri6FgwRZtkNqmcRDnPi18xnY1GTDFYtdZEZ\
+        8Fv4L29JVOWLgEIGRdH1ct8HAqxxgSCAEcuVY7ns8xjGWKrX6gs2yanF9vUbdMyRHzBqgytzwnXl+sg5TvQS\
+        a5Hh8D0eGewv0gWzUVh4PIhpwTxbEJ97k6Dklq2UneJiBo4kmna4uCRz3khq69k0kajIEiqT6eZtwIz0lDDT\
+        V+MQz7YUKkFI6Th88VL9/eehcnuYQgefEEbHeb3zvoA6LSJGpvJEPcHaVNpFgnxNlQaDowtb5XMGZfI/YU4O\
+        exTiEdtbYSjGnwDEuVUXtFfHCElvrBhvO3MAiXrk1QbZRNzyNUvU+1+ZmPc0IBsDHJiCN/15MKuEWF9kKqt+\
+        9FsFoRnKbXwUfDk9azdOtzymiel6xiD7kr5RTEmyxBIbTQukqZSSyTzKcTxiWQyK7HL0vxztf7Vdy7o1qtKo\
+        9Q48eyIc4fc3HwcSLz6CqRlJENsuhqdPcovE4TeIrv72/WBFLot+gGFltrhdXeaNdzLo+xTSdIjXRpnPtNob\
+        dld8OyD3F7GpNdtMXoNhpQNfeOWca0eKUkL/gJw5T7kNkTwso2t1gfcIezEge1UpigAQxUgVDRLTdZZ+C1mM\
+        rHCyB4ElRjU\n\
+        -----END MEGOLM SESSION DATA-----";
+
+    let timeline = TestTimeline::new();
+    let f = &timeline.factory;
+    let mut stream = timeline.subscribe_events().await;
+
+    // Given a readable message in the timeline, and an edit of it that can't be
+    // decrypted yet,
+    let event_id = event_id!("$original");
+    timeline.handle_live_event(f.text_msg("Original").sender(&BOB).event_id(event_id)).await;
+    assert_next_matches_with_timeout!(stream, VectorDiff::PushBack { .. });
+
+    let encrypted = EncryptedEventScheme::MegolmV1AesSha2(
+        MegolmV1AesSha2ContentInit {
+            ciphertext: "\
+                AwgAEtABWuWeRLintqVP5ez5kki8sDsX7zSq++9AJo9lELGTDjNKzbF8sowUgg0DaGoP\
+                dgWyBmuUxT2bMggwM0fAevtu4XcFtWUx1c/sj1vhekrng9snmXpz4a30N8jhQ7N4WoIg\
+                /G5wsPKtOITjUHeon7EKjTPFU7xoYXmxbjDL/9R4hGQdRqogs1hj0ZnWRxNCvr3ahq24\
+                E0j8WyBrQXOb2PIHVNfV/9eW8AB744UQXn8FJpmQO8c0Us3YorXtIFrwAtvI3FknD7Lj\
+                eeYFpR9oeyZKuzo2Wzp7eiEZt0Lm+xb7Lfp9yY52RhAO7JLlCM4oPff2yXHpUmcjdGsi\
+                9Zc9Z92hiILkZoKOSGccYQoLjYlfL8rVsIVvl4tDDQ"
+                .to_owned(),
+            sender_key: "sKSGv2uD9zUncgL6GiLedvuky3fjVcEz9qVKZkpzN14".to_owned(),
+            device_id: "PNQBRWYIJL".into(),
+            session_id: "HSRlM67FgLYl0J0l1luflfGwpnFcLKHnNoRqUuIhQ5Q".into(),
+        }
+        .into(),
+    );
+    timeline
+        .handle_live_event(
+            f.event(assign!(RoomEncryptedEventContent::new(encrypted, None), {
+                relates_to: Some(Relation::Replacement(Replacement::new(event_id.to_owned()))),
+            }))
+            .sender(&BOB)
+            .into_utd_sync_timeline_event(),
+        )
+        .await;
+
+    let item =
+        assert_next_matches_with_timeout!(stream, VectorDiff::Set { index: 0, value } => value);
+    assert!(item.content().as_message().unwrap().has_undecryptable_edit());
+    assert_next_matches_with_timeout!(stream, VectorDiff::PushBack { .. });
+
+    // When the keys are received and the decryption is retried,
+    let mut keys = decrypt_room_key_export(Cursor::new(SESSION1_KEY), "1234").unwrap();
+    keys.extend(decrypt_room_key_export(Cursor::new(SESSION2_KEY), "1234").unwrap());
+    let own_user_id = user_id!("@example:morheus.localhost");
+    let olm_machine = OlmMachine::new(own_user_id, "SomeDeviceId".into()).await;
+    olm_machine.store().import_exported_room_keys(keys, |_, _| {}).await.unwrap();
+
+    timeline
+        .controller
+        .retry_event_decryption_test(
+            room_id!("!bdsREiCPHyZAPkpXer:morpheus.localhost"),
+            olm_machine,
+            None,
+        )
+        .await;
+
+    // Then the decrypted edit is applied to the original message,
+    let item =
+        assert_next_matches_with_timeout!(stream, VectorDiff::Set { index: 0, value } => value);
+    assert_eq!(item.event_id(), Some(event_id));
+    assert_matches!(item.encryption_info(), Some(_));
+    assert_matches!(item.latest_edit_json(), Some(_));
+    assert_let!(Some(msg) = item.content().as_message());
+    assert!(msg.is_edited());
+    assert!(!msg.has_undecryptable_edit());
+    assert_eq!(msg.body(), "This is Error");
+
+    // And the UTD placeholder of the edit is removed.
+    assert_next_matches_with_timeout!(stream, VectorDiff::Remove { index: 1 });
+    assert_pending!(stream);
+}
+
 #[async_test]
 async fn test_retry_edit_and_more() {
     const DEVICE_ID: &str = "MTEGRRVPEN";
@@ -24,8 +24,10 @@ use ruma::{
 };
 use stream_assert::assert_next_matches;
 
-use super::TestTimeline;
-use crate::timeline::{traits::RoomDataProvider as _, VirtualTimelineItem};
+use super::{TestTimeline, TestTimelineBuilder};
+use crate::timeline::{
+    controller::TimelineSettings, traits::RoomDataProvider as _, VirtualTimelineItem,
+};
 
 #[async_test]
 async fn test_date_divider() {
@@ -89,6 +91,49 @@ async fn test_date_divider() {
     assert!(date_divider.is_date_divider());
 }
 
+#[async_test]
+async fn test_no_date_divider_when_disabled() {
+    let timeline = TestTimelineBuilder::new()
+        .settings(TimelineSettings { show_date_dividers: false, ..Default::default() })
+        .build();
+    let mut stream = timeline.subscribe().await;
+
+    let f = &timeline.factory;
+
+    timeline
+        .handle_live_event(f.text_msg("This is a first message on the first day").sender(*ALICE))
+        .await;
+
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    item.as_event().unwrap();
+
+    // Timestamps start at unix epoch, advance to one day later
+    f.set_next_ts(24 * 60 * 60 * 1000);
+
+    timeline
+        .handle_live_event(f.text_msg("This is a first message on the next day").sender(*ALICE))
+        .await;
+
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    item.as_event().unwrap();
+
+    let _ = timeline
+        .handle_local_event(AnyMessageLikeEventContent::RoomMessage(
+            RoomMessageEventContent::text_plain("A message I'm sending just now"),
+        ))
+        .await;
+
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    item.as_event().unwrap();
+
+    // No date divider has been inserted, and the items are at the expected indices.
+    assert!(stream.next().now_or_never().is_none());
+
+    let items = timeline.controller.items().await;
+    assert_eq!(items.len(), 3);
+    assert!(items.iter().all(|item| item.is_event()));
+}
+
 #[async_test]
 async fn test_update_read_marker() {
     let timeline = TestTimeline::new();
@@ -26,7 +26,7 @@ use caches::ClientCaches;
 use eyeball::{SharedObservable, Subscriber};
 use eyeball_im::{Vector, VectorDiff};
 use futures_core::Stream;
-use futures_util::StreamExt;
+use futures_util::{stream, StreamExt, TryStreamExt};
 #[cfg(feature = "e2e-encryption")]
 use matrix_sdk_base::crypto::store::LockableCryptoStore;
 use matrix_sdk_base::{
@@ -90,6 +90,7 @@ use crate::{
     },
     http_client::HttpClient,
     notification_settings::NotificationSettings,
+    room::knock_requests::KnockRequest,
     room_preview::RoomPreview,
     send_queue::SendQueueData,
     sliding_sync::Version as SlidingSyncVersion,
@@ -1140,6 +1141,44 @@ impl Client {
             .collect()
     }
 
+    /// Returns the pending knock requests of all the joined rooms, grouped by
+    /// room.
+    ///
+    /// Only the rooms in which the current user can accept or decline the
+    /// knock requests, i.e. invite or kick the knocking users, are taken into
+    /// account. Rooms without any knock request are omitted.
+    ///
+    /// The rooms are loaded concurrently, since this may need to fetch their
+    /// members from the homeserver.
+    pub async fn all_knock_requests(&self) -> Result<Vec<(OwnedRoomId, Vec<KnockRequest>)>> {
+        const MAX_CONCURRENT_ROOMS: usize = 10;
+
+        let own_user_id = self.user_id().ok_or(Error::AuthenticationRequired)?;
+
+        let mut knock_requests: Vec<_> = stream::iter(self.joined_rooms())
+            .map(|room| async move {
+                // Rooms whose power levels are unknown can't be moderated either.
+                let can_invite = room.can_user_invite(own_user_id).await.unwrap_or(false);
+                let can_kick = room.can_user_kick(own_user_id).await.unwrap_or(false);
+                if !can_invite && !can_kick {
+                    return Ok::<_, Error>(None);
+                }
+
+                let seen_request_ids = room.get_seen_knock_request_ids().await?;
+                let requests = room.get_current_join_requests(&seen_request_ids).await?;
+
+                Ok((!requests.is_empty()).then(|| (room.room_id().to_owned(), requests)))
+            })
+            .buffer_unordered(MAX_CONCURRENT_ROOMS)
+            .try_filter_map(|requests| ready(Ok(requests)))
+            .try_collect()
+            .await?;
+
+        knock_requests.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        Ok(knock_requests)
+    }
+
     /// Returns the invited rooms this client knows about.
     pub fn invited_rooms(&self) -> Vec<Room> {
         self.base_client()
@@ -2567,17 +2606,17 @@ pub(crate) mod tests {
         RoomState,
     };
     use matrix_sdk_test::{
-        async_test, test_json, JoinedRoomBuilder, StateTestEvent, SyncResponseBuilder,
-        DEFAULT_TEST_ROOM_ID,
+        async_test, event_factory::EventFactory, test_json, JoinedRoomBuilder, StateTestEvent,
+        SyncResponseBuilder, DEFAULT_TEST_ROOM_ID,
     };
     #[cfg(target_arch = "wasm32")]
     wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
 
     use ruma::{
         api::{client::room::create_room::v3::Request as CreateRoomRequest, MatrixVersion},
-        assign,
-        events::ignored_user_list::IgnoredUserListEventContent,
-        owned_room_id, room_alias_id, room_id, RoomId, ServerName, UserId,
+        assign, event_id,
+        events::{ignored_user_list::IgnoredUserListEventContent, room::member::MembershipState},
+        owned_room_id, room_alias_id, room_id, user_id, RoomId, ServerName, UserId,
     };
     use serde_json::json;
     use tokio::{
@@ -2586,7 +2625,7 @@ pub(crate) mod tests {
     };
     use url::Url;
     use wiremock::{
-        matchers::{body_json, header, method, path, query_param_is_missing},
+        matchers::{body_json, header, method, path, path_regex, query_param_is_missing},
         Mock, MockServer, ResponseTemplate,
     };
 
@@ -3334,4 +3373,50 @@ pub(crate) mod tests {
 
         assert_eq!(banned_room.room_id().to_owned(), preview.room_id);
     }
+
+    #[async_test]
+    async fn test_all_knock_requests() {
+        let server = MatrixMockServer::new().await;
+        let client = server.client_builder().build().await;
+
+        let room_with_knocks_id = room_id!("!with-knocks:localhost");
+        let room_without_knocks_id = room_id!("!without-knocks:localhost");
+        let user_id = user_id!("@alice:localhost");
+
+        let f = EventFactory::new().room(room_with_knocks_id);
+        let knock_event = f
+            .member(user_id)
+            .membership(MembershipState::Knock)
+            .event_id(event_id!("$knock"))
+            .into_raw_timeline();
+
+        // Only the room with knocks has a knocking member.
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/_matrix/client/v3/rooms/.*with-knocks.*/members$"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "chunk": [knock_event] })),
+            )
+            .mount(server.server())
+            .await;
+        server.mock_get_members().ok(Vec::new()).mount().await;
+
+        // The current user is allowed to moderate both rooms.
+        for room_id in [room_with_knocks_id, room_without_knocks_id] {
+            server
+                .sync_room(
+                    &client,
+                    JoinedRoomBuilder::new(room_id).add_state_event(StateTestEvent::PowerLevels),
+                )
+                .await;
+        }
+
+        let knock_requests = client.all_knock_requests().await.unwrap();
+
+        assert_eq!(knock_requests.len(), 1);
+        let (room_id, requests) = &knock_requests[0];
+        assert_eq!(room_id, room_with_knocks_id);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].member_info.user_id, user_id);
+        assert!(!requests[0].is_seen);
+    }
 }
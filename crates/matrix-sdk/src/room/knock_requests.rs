@@ -12,8 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::BTreeSet;
+
 use js_int::UInt;
-use ruma::{EventId, OwnedEventId, OwnedMxcUri, OwnedUserId, RoomId};
+use matrix_sdk_base::deserialized_responses::MemberEvent;
+use ruma::{
+    events::{room::member::MembershipState, SyncStateEvent},
+    EventId, OwnedEventId, OwnedMxcUri, OwnedServerName, OwnedUserId, RoomId,
+};
+use tracing::warn;
 
 use crate::{room::RoomMember, Error, Room};
 
@@ -73,11 +80,115 @@ impl KnockRequest {
 
     /// Declines the knock request by banning the user from the room, with an
     /// optional reason.
-    pub async fn decline_and_ban(&self, reason: Option<&str>) -> Result<(), Error> {
-        self.room.ban_user(&self.member_info.user_id, reason).await
+    ///
+    /// Returns the membership of the user in the room state before the ban.
+    pub async fn decline_and_ban(&self, reason: Option<&str>) -> Result<PriorMembership, Error> {
+        let user_id = &self.member_info.user_id;
+
+        // This only reads the local room state, so the user is banned even if they
+        // left the room in the meantime, or if it fails.
+        let prior_membership = match self.room.get_member_no_sync(user_id).await {
+            Ok(member) => {
+                member.map(|member| PriorMembership::from_event(member.event())).unwrap_or_default()
+            }
+            Err(error) => {
+                warn!("Failed to load the membership of {user_id} before banning them: {error}");
+                PriorMembership::default()
+            }
+        };
+
+        self.room.ban_user(user_id, reason).await?;
+
+        Ok(prior_membership)
     }
 }
 
+/// Sorts the given knock requests by their timestamp, from the oldest to the
+/// newest. The requests without a timestamp are sorted last.
+pub(crate) fn sort_knock_requests_by_timestamp(requests: &mut [KnockRequest]) {
+    requests.sort_by_key(|request| (request.timestamp.is_none(), request.timestamp));
+}
+
+/// The membership of a user in a room before their knock request was declined
+/// with a ban, see [`KnockRequest::decline_and_ban`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PriorMembership {
+    /// The membership of the user in the room state, if the user was known.
+    pub membership: Option<MembershipState>,
+    /// The membership of the user before that one, if it is known.
+    pub previous_membership: Option<MembershipState>,
+}
+
+impl PriorMembership {
+    fn from_event(event: &MemberEvent) -> Self {
+        let previous_membership = match event {
+            MemberEvent::Sync(SyncStateEvent::Original(event)) => {
+                event.prev_content().map(|content| content.membership.clone())
+            }
+            _ => None,
+        };
+
+        Self { membership: Some(event.membership().clone()), previous_membership }
+    }
+
+    /// Whether the user was joined to the room.
+    pub fn was_joined(&self) -> bool {
+        self.was(&MembershipState::Join)
+    }
+
+    /// Whether the user was invited to the room.
+    pub fn was_invited(&self) -> bool {
+        self.was(&MembershipState::Invite)
+    }
+
+    /// Whether the user only knocked on the room, without being joined or
+    /// invited to it before.
+    pub fn is_knock_only(&self) -> bool {
+        self.membership == Some(MembershipState::Knock) && !self.was_joined() && !self.was_invited()
+    }
+
+    fn was(&self, membership: &MembershipState) -> bool {
+        self.membership.as_ref() == Some(membership)
+            || self.previous_membership.as_ref() == Some(membership)
+    }
+}
+
+/// A filter of knock requests by the server of the users who knocked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KnockRequestServerFilter {
+    /// Only the requests of users of these servers match.
+    Allow(BTreeSet<OwnedServerName>),
+    /// The requests of users of these servers don't match.
+    Block(BTreeSet<OwnedServerName>),
+}
+
+impl KnockRequestServerFilter {
+    /// Whether the given knock request matches this filter.
+    pub fn matches(&self, request: &KnockRequest) -> bool {
+        let server_name = request.member_info.user_id.server_name();
+        match self {
+            Self::Allow(server_names) => server_names.contains(server_name),
+            Self::Block(server_names) => !server_names.contains(server_name),
+        }
+    }
+
+    /// Splits the given knock requests into the ones matching this filter and
+    /// the others.
+    pub fn partition(&self, requests: Vec<KnockRequest>) -> (Vec<KnockRequest>, Vec<KnockRequest>) {
+        requests.into_iter().partition(|request| self.matches(request))
+    }
+}
+
+/// The number of knock requests in a room, see
+/// [`Room::knock_requests_count`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KnockRequestsCount {
+    /// The number of all the knock requests.
+    pub total: usize,
+    /// The number of the knock requests that haven't been marked as 'seen'.
+    pub unseen: usize,
+}
+
 /// General room member info to display along with the join request.
 #[derive(Debug, Clone)]
 pub struct KnockRequestMemberInfo {
@@ -105,13 +216,16 @@ impl KnockRequestMemberInfo {
 // The http mocking library is not supported for wasm32
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
+    use js_int::uint;
     use matrix_sdk_test::{async_test, event_factory::EventFactory, JoinedRoomBuilder};
     use ruma::{
         event_id, events::room::member::MembershipState, owned_user_id, room_id, user_id, EventId,
     };
 
     use crate::{
-        room::knock_requests::{KnockRequest, KnockRequestMemberInfo},
+        room::knock_requests::{
+            sort_knock_requests_by_timestamp, KnockRequest, KnockRequestMemberInfo,
+        },
         test_utils::mocks::MatrixMockServer,
         Room,
     };
@@ -202,6 +316,64 @@ mod tests {
             .expect("Failed to decline the request and ban the user");
     }
 
+    #[async_test]
+    async fn test_decline_and_ban_returns_the_prior_membership() {
+        let server = MatrixMockServer::new().await;
+        let client = server.client_builder().build().await;
+        let room_id = room_id!("!a:b.c");
+        let user_id = user_id!("@alice:b.c");
+
+        let f = EventFactory::new().room(room_id);
+        let joined_room_builder = JoinedRoomBuilder::new(room_id).add_state_bulk(vec![f
+            .member(user_id)
+            .membership(MembershipState::Knock)
+            .previous(MembershipState::Leave)
+            .into_raw_timeline()
+            .cast()]);
+        let room = server.sync_room(&client, joined_room_builder).await;
+
+        let knock_request = make_knock_request(&room, None);
+
+        server.mock_ban_user().ok().mock_once().mount().await;
+
+        // When we decline the knock request and ban the user from the room
+        let prior_membership = knock_request
+            .decline_and_ban(None)
+            .await
+            .expect("Failed to decline the request and ban the user");
+
+        // Then the membership of the user before the ban is returned
+        assert_eq!(prior_membership.membership, Some(MembershipState::Knock));
+        assert_eq!(prior_membership.previous_membership, Some(MembershipState::Leave));
+        assert!(prior_membership.is_knock_only());
+        assert!(!prior_membership.was_joined());
+        assert!(!prior_membership.was_invited());
+    }
+
+    #[async_test]
+    async fn test_sort_by_timestamp() {
+        let server = MatrixMockServer::new().await;
+        let client = server.client_builder().build().await;
+        let room = server.sync_joined_room(&client, room_id!("!a:b.c")).await;
+
+        let make_request = |event_id, timestamp| {
+            let mut request = make_knock_request(&room, Some(event_id));
+            request.timestamp = timestamp;
+            request
+        };
+        let mut requests = vec![
+            make_request(event_id!("$newest:b.c"), Some(uint!(2000))),
+            make_request(event_id!("$no-timestamp:b.c"), None),
+            make_request(event_id!("$oldest:b.c"), Some(uint!(1000))),
+        ];
+
+        sort_knock_requests_by_timestamp(&mut requests);
+
+        // The oldest request comes first, and the one without a timestamp last.
+        let event_ids: Vec<_> = requests.iter().map(|request| request.event_id.as_str()).collect();
+        assert_eq!(event_ids, ["$oldest:b.c", "$newest:b.c", "$no-timestamp:b.c"]);
+    }
+
     fn make_knock_request(room: &Room, event_id: Option<&EventId>) -> KnockRequest {
         KnockRequest::new(
             room,
@@ -16,7 +16,7 @@
 
 use std::{
     borrow::Borrow,
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     future::Future,
     ops::Deref,
     sync::Arc,
@@ -43,6 +43,7 @@ use matrix_sdk_base::{
     },
     event_cache::store::media::IgnoreMediaRetentionPolicy,
     media::MediaThumbnailSettings,
+    notification_settings::get_push_actions,
     store::StateStoreExt,
     ComposerDraft, EncryptionState, RoomInfoNotableUpdateReasons, RoomMemberships, SendOutsideWasm,
     StateChanges, StateStoreDataKey, StateStoreDataValue,
@@ -120,7 +121,7 @@ use ruma::{
     serde::Raw,
     time::Instant,
     EventId, Int, MatrixToUri, MatrixUri, MxcUri, OwnedEventId, OwnedRoomId, OwnedServerName,
-    OwnedTransactionId, OwnedUserId, RoomId, TransactionId, UInt, UserId,
+    OwnedTransactionId, OwnedUserId, RoomId, ServerName, TransactionId, UInt, UserId,
 };
 use serde::de::DeserializeOwned;
 use thiserror::Error;
@@ -149,7 +150,10 @@ use crate::{
     media::{MediaFormat, MediaRequestParameters},
     notification_settings::{IsEncrypted, IsOneToOne, RoomNotificationMode},
     room::{
-        knock_requests::{KnockRequest, KnockRequestMemberInfo},
+        knock_requests::{
+            sort_knock_requests_by_timestamp, KnockRequest, KnockRequestMemberInfo,
+            KnockRequestServerFilter, KnockRequestsCount,
+        },
         power_levels::{RoomPowerLevelChanges, RoomPowerLevelsExt},
         privacy_settings::RoomPrivacySettings,
     },
@@ -213,8 +217,10 @@ impl PushContext {
     }
 
     /// Compute the push rules for a given event.
+    ///
+    /// Edits never notify, see [`get_push_actions`].
     pub fn for_event<T>(&self, event: &Raw<T>) -> Vec<Action> {
-        self.push_rules.get_actions(event, &self.push_condition_room_ctx).to_owned()
+        get_push_actions(&self.push_rules, event, &self.push_condition_room_ctx).to_owned()
     }
 }
 
@@ -974,6 +980,63 @@ impl Room {
             .await?)
     }
 
+    /// Get the settings of the widgets of this room, from its `m.widget` and
+    /// `im.vector.modular.widgets` state events.
+    ///
+    /// Removed widgets, i.e. events with an empty content, and malformed
+    /// widgets are skipped. If a widget id is used by events of both types,
+    /// the `m.widget` one wins.
+    #[cfg(feature = "experimental-widgets")]
+    pub async fn widgets(&self) -> Result<Vec<crate::widget::WidgetSettings>> {
+        use ruma::serde::JsonObject;
+
+        use crate::widget::{WidgetSettings, WidgetStateEvent, WIDGET_STATE_EVENT_TYPES};
+
+        let mut widgets = Vec::<WidgetSettings>::new();
+
+        for event_type in WIDGET_STATE_EVENT_TYPES {
+            for event in self.get_state_events(event_type.into()).await? {
+                let (event, content, state_key) = match &event {
+                    RawAnySyncOrStrippedState::Sync(raw) => (
+                        raw.deserialize_as::<WidgetStateEvent>(),
+                        raw.get_field("content"),
+                        raw.get_field::<String>("state_key"),
+                    ),
+                    RawAnySyncOrStrippedState::Stripped(raw) => (
+                        raw.deserialize_as::<WidgetStateEvent>(),
+                        raw.get_field("content"),
+                        raw.get_field::<String>("state_key"),
+                    ),
+                };
+
+                if content.ok().flatten().is_some_and(|content: JsonObject| content.is_empty()) {
+                    // The widget was removed.
+                    continue;
+                }
+
+                let settings = match event {
+                    Ok(event) => WidgetSettings::try_from(event).map_err(|error| error.to_string()),
+                    Err(error) => Err(error.to_string()),
+                };
+
+                match settings {
+                    Ok(settings) => {
+                        if !widgets.iter().any(|widget| widget.widget_id() == settings.widget_id())
+                        {
+                            widgets.push(settings);
+                        }
+                    }
+                    Err(error) => {
+                        let state_key = state_key.ok().flatten();
+                        warn!(event_type, ?state_key, "Skipping a malformed widget: {error}");
+                    }
+                }
+            }
+        }
+
+        Ok(widgets)
+    }
+
     /// Returns the parents this room advertises as its parents.
     ///
     /// Results are in no particular order.
@@ -3403,7 +3466,8 @@ impl Room {
     /// Subscribe to knock requests in this `Room`.
     ///
     /// The current requests to join the room will be emitted immediately
-    /// when subscribing.
+    /// when subscribing. The requests are sorted by their timestamp, from the
+    /// oldest to the newest, and the ones without a timestamp come last.
     ///
     /// A new set of knock requests will be emitted whenever:
     /// - A new member event is received.
@@ -3522,11 +3586,149 @@ impl Room {
         Ok((combined_stream, clear_seen_ids_handle))
     }
 
-    async fn get_current_join_requests(
+    /// Get the number of knock requests in this `Room`, e.g. to display a
+    /// badge.
+    ///
+    /// This is cheaper than [`Room::subscribe_to_knock_requests`]: the cached
+    /// room members are used, and they are only fetched from the homeserver if
+    /// they aren't synced yet.
+    pub async fn knock_requests_count(&self) -> Result<KnockRequestsCount> {
+        let seen_request_ids = self.get_seen_knock_request_ids().await?;
+
+        let mut count = KnockRequestsCount::default();
+        for member in self.members(RoomMemberships::KNOCK).await? {
+            // Like in `get_current_join_requests`, a knock request needs an event id.
+            let Some(event_id) = member.event().event_id() else {
+                continue;
+            };
+
+            count.total += 1;
+            if !seen_request_ids.contains_key(event_id) {
+                count.unseen += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Mark all the current knock requests of this `Room` as seen, in a single
+    /// batch.
+    ///
+    /// The knock requests without an event id are ignored, since they can't be
+    /// marked as seen. The streams returned by
+    /// [`Room::subscribe_to_knock_requests`] will emit the updated requests.
+    pub async fn mark_all_knock_requests_as_seen(&self) -> Result<()> {
+        let user_ids: Vec<_> = self
+            .members(RoomMemberships::KNOCK)
+            .await?
+            .into_iter()
+            .filter(|member| member.event().event_id().is_some())
+            .map(|member| member.user_id().to_owned())
+            .collect();
+
+        if user_ids.is_empty() {
+            return Ok(());
+        }
+
+        self.mark_knock_requests_as_seen(&user_ids).await?;
+        Ok(())
+    }
+
+    /// Mark the given knock requests of this `Room` as seen, in a single
+    /// batch.
+    ///
+    /// The requests that are outdated, e.g. because the user joined or knocked
+    /// again since then, are skipped. Returns the event ids of the requests
+    /// that were newly marked as seen, i.e. without the ones that were already
+    /// seen.
+    pub async fn mark_knock_requests_as_seen_batch(
+        &self,
+        requests: &[KnockRequest],
+    ) -> Result<BTreeSet<OwnedEventId>> {
+        if requests.is_empty() {
+            return Ok(BTreeSet::new());
+        }
+
+        let user_ids: Vec<_> =
+            requests.iter().map(|request| request.member_info.user_id.clone()).collect();
+        let newly_seen_event_ids = self.mark_knock_requests_as_seen(&user_ids).await?;
+
+        // The current knock event of a user who knocked again isn't one of the given
+        // requests.
+        Ok(requests
+            .iter()
+            .filter(|request| newly_seen_event_ids.contains(&request.event_id))
+            .map(|request| request.event_id.clone())
+            .collect())
+    }
+
+    /// Get the current knock requests of this `Room`, split into the ones
+    /// matching the given filter and the others.
+    pub async fn partition_knock_requests(
+        &self,
+        filter: &KnockRequestServerFilter,
+    ) -> Result<(Vec<KnockRequest>, Vec<KnockRequest>)> {
+        let seen_request_ids = self.get_seen_knock_request_ids().await?;
+        let requests = self.get_current_join_requests(&seen_request_ids).await?;
+        Ok(filter.partition(requests))
+    }
+
+    /// Subscribe to the knock requests in this `Room` matching the given
+    /// filter.
+    ///
+    /// This behaves like [`Room::subscribe_to_knock_requests`], without the
+    /// requests that don't match the filter.
+    pub async fn subscribe_to_filtered_knock_requests(
+        &self,
+        filter: KnockRequestServerFilter,
+    ) -> Result<(impl Stream<Item = Vec<KnockRequest>>, JoinHandle<()>)> {
+        let (requests_stream, clear_seen_ids_handle) = self.subscribe_to_knock_requests().await?;
+        let filtered_stream = requests_stream.map(move |requests| filter.partition(requests).0);
+        Ok((filtered_stream, clear_seen_ids_handle))
+    }
+
+    /// Subscribe to the knock requests in this `Room` that haven't been seen
+    /// yet, e.g. to only display the requests that still need an action.
+    ///
+    /// This behaves like [`Room::subscribe_to_knock_requests`], without the
+    /// requests that were marked as seen.
+    pub async fn subscribe_to_unseen_knock_requests(
+        &self,
+    ) -> Result<(impl Stream<Item = Vec<KnockRequest>>, JoinHandle<()>)> {
+        let (requests_stream, clear_seen_ids_handle) = self.subscribe_to_knock_requests().await?;
+        let unseen_stream = requests_stream.map(|requests| {
+            requests.into_iter().filter(|request| !request.is_seen).collect::<Vec<_>>()
+        });
+        Ok((unseen_stream, clear_seen_ids_handle))
+    }
+
+    /// Decline all the current knock requests of users of the given server, by
+    /// kicking them from the room with an optional reason.
+    ///
+    /// Returns the ids of the users whose request was declined. Stops at the
+    /// first request that fails to be declined.
+    pub async fn decline_knock_requests_from_server(
+        &self,
+        server_name: &ServerName,
+        reason: Option<&str>,
+    ) -> Result<Vec<OwnedUserId>> {
+        let filter = KnockRequestServerFilter::Allow(BTreeSet::from([server_name.to_owned()]));
+        let (requests, _) = self.partition_knock_requests(&filter).await?;
+
+        let mut declined_user_ids = Vec::with_capacity(requests.len());
+        for request in requests {
+            request.decline(reason).await?;
+            declined_user_ids.push(request.member_info.user_id);
+        }
+
+        Ok(declined_user_ids)
+    }
+
+    pub(crate) async fn get_current_join_requests(
         &self,
         seen_request_ids: &BTreeMap<OwnedEventId, OwnedUserId>,
     ) -> Result<Vec<KnockRequest>> {
-        Ok(self
+        let mut requests: Vec<_> = self
             .members(RoomMemberships::KNOCK)
             .await?
             .into_iter()
@@ -3540,7 +3742,10 @@ impl Room {
                     seen_request_ids.contains_key(event_id),
                 ))
             })
-            .collect())
+            .collect();
+
+        sort_knock_requests_by_timestamp(&mut requests);
+        Ok(requests)
     }
 
     /// Access the room settings related to privacy and visibility.
@@ -15,7 +15,7 @@
 //! Types and traits related to the capabilities that a widget can request from
 //! a client.
 
-use std::fmt;
+use std::{collections::BTreeMap, fmt};
 
 use async_trait::async_trait;
 use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer};
@@ -23,7 +23,7 @@ use tracing::{debug, warn};
 
 use super::{
     filter::{Filter, FilterInput},
-    MessageLikeEventFilter, StateEventFilter,
+    MessageLikeEventFilter, StateEventFilter, WidgetSettings,
 };
 
 /// Must be implemented by a component that provides functionality of deciding
@@ -35,6 +35,18 @@ pub trait CapabilitiesProvider: Send + Sync + 'static {
     /// capabilities that the clients grants to a given widget (usually by
     /// prompting the user).
     async fn acquire_capabilities(&self, capabilities: Capabilities) -> Capabilities;
+
+    /// Whether the widget with the given settings may run at all.
+    ///
+    /// If this returns `false`, [`WidgetDriver::run`] ends the session with
+    /// [`SessionEnd::PermissionsDenied`] before any capability is requested.
+    /// All widgets are allowed by default.
+    ///
+    /// [`WidgetDriver::run`]: super::WidgetDriver::run
+    /// [`SessionEnd::PermissionsDenied`]: super::SessionEnd::PermissionsDenied
+    async fn allow_widget(&self, _settings: &WidgetSettings) -> bool {
+        true
+    }
 }
 
 /// Capabilities that a widget can request from a client.
@@ -45,6 +57,9 @@ pub struct Capabilities {
     pub read: Vec<Filter>,
     /// Types of the messages that a widget wants to be able to send.
     pub send: Vec<Filter>,
+    /// Types of the ephemeral events (e.g. `m.typing` or `m.receipt`) that a
+    /// widget wants to receive.
+    pub read_ephemeral: Vec<String>,
     /// If this capability is requested by the widget, it can not operate
     /// separately from the matrix client.
     ///
@@ -55,9 +70,54 @@ pub struct Capabilities {
     pub update_delayed_event: bool,
     /// This allows the widget to send events with a delay.
     pub send_delayed_event: bool,
+    /// This allows the widget to report events of the room to the homeserver
+    /// administrators.
+    pub report_event: bool,
+    /// This allows the widget to fetch the profile (display name and avatar)
+    /// of any user.
+    pub get_user_profile: bool,
+    /// This allows the widget to ask the client to keep it visible on the
+    /// screen, e.g. while a call is ongoing.
+    pub always_on_screen: bool,
+    /// This allows the widget to ask the client to navigate to a room.
+    pub navigate: bool,
+    /// This allows the widget to request the capabilities of the homeserver.
+    pub get_server_capabilities: bool,
+    /// This allows the widget to read the requests to join the room, e.g. for
+    /// moderation purposes.
+    pub read_knock_requests: bool,
+    /// Whether the widget can upload files, to send them in the room.
+    pub upload_file: bool,
 }
 
 impl Capabilities {
+    /// The capabilities that are granted both by `self` and by `other`.
+    ///
+    /// The filters are compared by the events they match, so a filter is kept
+    /// if a broader filter of the other capabilities covers it.
+    pub(super) fn intersection(&self, other: &Capabilities) -> Capabilities {
+        Capabilities {
+            read: intersect_filters(&self.read, &other.read),
+            send: intersect_filters(&self.send, &other.send),
+            read_ephemeral: self
+                .read_ephemeral
+                .iter()
+                .filter(|event_type| other.read_ephemeral.contains(event_type))
+                .cloned()
+                .collect(),
+            requires_client: self.requires_client && other.requires_client,
+            update_delayed_event: self.update_delayed_event && other.update_delayed_event,
+            send_delayed_event: self.send_delayed_event && other.send_delayed_event,
+            report_event: self.report_event && other.report_event,
+            get_user_profile: self.get_user_profile && other.get_user_profile,
+            always_on_screen: self.always_on_screen && other.always_on_screen,
+            navigate: self.navigate && other.navigate,
+            get_server_capabilities: self.get_server_capabilities && other.get_server_capabilities,
+            read_knock_requests: self.read_knock_requests && other.read_knock_requests,
+            upload_file: self.upload_file && other.upload_file,
+        }
+    }
+
     /// Checks if a given event is allowed to be forwarded to the widget.
     ///
     /// - `event_filter_input` is a minimized event respresntation that contains
@@ -100,15 +160,117 @@ impl Capabilities {
     pub(super) fn has_read_filter_for_type(&self, event_type: &str) -> bool {
         self.read.iter().any(|f| f.filter_event_type() == event_type)
     }
+
+    /// Checks if an ephemeral event of the given type is allowed to be
+    /// forwarded to the widget.
+    pub(super) fn allow_reading_ephemeral(&self, event_type: &str) -> bool {
+        self.read_ephemeral.iter().any(|t| t == event_type)
+    }
+
+    /// Whether the widget needs to be subscribed to the events of the room,
+    /// i.e. whether it may receive any of them.
+    pub(super) fn needs_subscription(&self) -> bool {
+        !self.read.is_empty() || !self.read_ephemeral.is_empty()
+    }
+
+    /// Get the capabilities among the given identifiers that aren't supported
+    /// by the widget driver, and would be silently ignored if they were
+    /// requested by a widget.
+    ///
+    /// This is useful to check a stored or approved set of capabilities before
+    /// starting a widget session.
+    pub fn unsupported_capabilities(capabilities: &[String]) -> Vec<String> {
+        capabilities
+            .iter()
+            .filter(|capability| {
+                serde_json::from_value::<Self>(serde_json::json!([capability]))
+                    .map_or(true, |parsed| parsed == Self::default())
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// The filters matching the events that are matched both by one of the
+/// `filters` and by one of the `other_filters`.
+fn intersect_filters(filters: &[Filter], other_filters: &[Filter]) -> Vec<Filter> {
+    let is_covered = |filter: &Filter, by: &[Filter]| by.iter().any(|f| filter.is_covered_by(f));
+
+    let mut intersection: Vec<_> =
+        filters.iter().filter(|filter| is_covered(filter, other_filters)).cloned().collect();
+    // The narrower filters of the other side are kept too.
+    for filter in other_filters {
+        if is_covered(filter, filters) && !is_covered(filter, &intersection) {
+            intersection.push(filter.clone());
+        }
+    }
+
+    intersection
+}
+
+/// Deprecated capability identifiers, e.g. from older versions of an MSC, with
+/// their current equivalents.
+#[derive(Clone, Debug, Default)]
+pub struct DeprecatedCapabilities {
+    /// The deprecated identifiers, mapped to their current equivalent.
+    ///
+    /// An identifier matches a capability either entirely, or up to the `:`
+    /// preceding its event type: `org.matrix.msc2762.send.event` matches
+    /// `org.matrix.msc2762.send.event:m.room.message`.
+    pub identifiers: BTreeMap<String, String>,
+    /// Whether the deprecated identifiers requested by a widget are replaced
+    /// by their current equivalent, instead of only logging a warning.
+    pub translate: bool,
+}
+
+impl DeprecatedCapabilities {
+    /// Warn about the deprecated identifiers among the given capabilities,
+    /// and translate them if enabled.
+    pub(super) fn process(&self, widget_id: &str, capabilities: Vec<String>) -> Vec<String> {
+        capabilities
+            .into_iter()
+            .map(|capability| {
+                let (identifier, filter) = match capability.split_once(':') {
+                    Some((identifier, filter)) => (identifier, Some(filter)),
+                    None => (capability.as_str(), None),
+                };
+                let Some(current) = self.identifiers.get(identifier) else {
+                    return capability;
+                };
+
+                warn!(
+                    widget_id,
+                    "The widget requested the deprecated capability `{capability}`, \
+                     `{current}` should be used instead"
+                );
+                if !self.translate {
+                    return capability;
+                }
+
+                match filter {
+                    Some(filter) => format!("{current}:{filter}"),
+                    None => current.clone(),
+                }
+            })
+            .collect()
+    }
 }
 
 const SEND_EVENT: &str = "org.matrix.msc2762.send.event";
 const READ_EVENT: &str = "org.matrix.msc2762.receive.event";
 const SEND_STATE: &str = "org.matrix.msc2762.send.state_event";
 const READ_STATE: &str = "org.matrix.msc2762.receive.state_event";
+const READ_EPHEMERAL: &str = "org.matrix.msc2762.receive.ephemeral_event";
 const REQUIRES_CLIENT: &str = "io.element.requires_client";
 pub(super) const SEND_DELAYED_EVENT: &str = "org.matrix.msc4157.send.delayed_event";
 pub(super) const UPDATE_DELAYED_EVENT: &str = "org.matrix.msc4157.update_delayed_event";
+pub(super) const REPORT_EVENT: &str = "io.element.report_event";
+pub(super) const GET_USER_PROFILE: &str = "io.element.get_user_profile";
+pub(super) const ALWAYS_ON_SCREEN: &str = "m.always_on_screen";
+pub(super) const NAVIGATE: &str = "org.matrix.msc2931.navigate";
+pub(super) const GET_SERVER_CAPABILITIES: &str = "io.element.get_server_capabilities";
+pub(super) const READ_KNOCK_REQUESTS: &str = "io.element.read_knock_requests";
+pub(super) const UPLOAD_FILE: &str = "org.matrix.msc4039.upload_file";
 
 impl Serialize for Capabilities {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -164,6 +326,27 @@ impl Serialize for Capabilities {
         if self.send_delayed_event {
             seq.serialize_element(SEND_DELAYED_EVENT)?;
         }
+        if self.report_event {
+            seq.serialize_element(REPORT_EVENT)?;
+        }
+        if self.get_user_profile {
+            seq.serialize_element(GET_USER_PROFILE)?;
+        }
+        if self.always_on_screen {
+            seq.serialize_element(ALWAYS_ON_SCREEN)?;
+        }
+        if self.navigate {
+            seq.serialize_element(NAVIGATE)?;
+        }
+        if self.get_server_capabilities {
+            seq.serialize_element(GET_SERVER_CAPABILITIES)?;
+        }
+        if self.read_knock_requests {
+            seq.serialize_element(READ_KNOCK_REQUESTS)?;
+        }
+        if self.upload_file {
+            seq.serialize_element(UPLOAD_FILE)?;
+        }
         for filter in &self.read {
             let name = match filter {
                 Filter::MessageLike(_) => READ_EVENT,
@@ -178,6 +361,9 @@ impl Serialize for Capabilities {
             };
             seq.serialize_element(&format!("{name}:{}", PrintEventFilter(filter)))?;
         }
+        for event_type in &self.read_ephemeral {
+            seq.serialize_element(&format!("{READ_EPHEMERAL}:{event_type}"))?;
+        }
 
         seq.end()
     }
@@ -192,8 +378,16 @@ impl<'de> Deserialize<'de> for Capabilities {
             RequiresClient,
             UpdateDelayedEvent,
             SendDelayedEvent,
+            ReportEvent,
+            GetUserProfile,
+            AlwaysOnScreen,
+            Navigate,
+            GetServerCapabilities,
+            ReadKnockRequests,
+            UploadFile,
             Read(Filter),
             Send(Filter),
+            ReadEphemeral(String),
             Unknown,
         }
 
@@ -212,6 +406,27 @@ impl<'de> Deserialize<'de> for Capabilities {
                 if s == SEND_DELAYED_EVENT {
                     return Ok(Self::SendDelayedEvent);
                 }
+                if s == REPORT_EVENT {
+                    return Ok(Self::ReportEvent);
+                }
+                if s == GET_USER_PROFILE {
+                    return Ok(Self::GetUserProfile);
+                }
+                if s == ALWAYS_ON_SCREEN {
+                    return Ok(Self::AlwaysOnScreen);
+                }
+                if s == NAVIGATE {
+                    return Ok(Self::Navigate);
+                }
+                if s == GET_SERVER_CAPABILITIES {
+                    return Ok(Self::GetServerCapabilities);
+                }
+                if s == READ_KNOCK_REQUESTS {
+                    return Ok(Self::ReadKnockRequests);
+                }
+                if s == UPLOAD_FILE {
+                    return Ok(Self::UploadFile);
+                }
 
                 match s.split_once(':') {
                     Some((READ_EVENT, filter_s)) => Ok(Permission::Read(Filter::MessageLike(
@@ -226,6 +441,9 @@ impl<'de> Deserialize<'de> for Capabilities {
                     Some((SEND_STATE, filter_s)) => {
                         Ok(Permission::Send(Filter::State(parse_state_event_filter(filter_s))))
                     }
+                    Some((READ_EPHEMERAL, event_type)) => {
+                        Ok(Permission::ReadEphemeral(event_type.to_owned()))
+                    }
                     _ => {
                         debug!("Unknown capability `{s}`");
                         Ok(Self::Unknown)
@@ -258,10 +476,20 @@ impl<'de> Deserialize<'de> for Capabilities {
                 Permission::RequiresClient => capabilities.requires_client = true,
                 Permission::Read(filter) => capabilities.read.push(filter),
                 Permission::Send(filter) => capabilities.send.push(filter),
+                Permission::ReadEphemeral(event_type) => {
+                    capabilities.read_ephemeral.push(event_type)
+                }
                 // ignore unknown capabilities
                 Permission::Unknown => {}
                 Permission::UpdateDelayedEvent => capabilities.update_delayed_event = true,
                 Permission::SendDelayedEvent => capabilities.send_delayed_event = true,
+                Permission::ReportEvent => capabilities.report_event = true,
+                Permission::GetUserProfile => capabilities.get_user_profile = true,
+                Permission::AlwaysOnScreen => capabilities.always_on_screen = true,
+                Permission::Navigate => capabilities.navigate = true,
+                Permission::GetServerCapabilities => capabilities.get_server_capabilities = true,
+                Permission::ReadKnockRequests => capabilities.read_knock_requests = true,
+                Permission::UploadFile => capabilities.upload_file = true,
             }
         }
 
@@ -275,6 +503,49 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn deprecated_capabilities_are_recognized() {
+        let capabilities = vec![
+            "org.example.msc1234.send.event:m.room.message#m.text".to_owned(),
+            "org.example.msc1234.requires_client".to_owned(),
+            "org.matrix.msc2762.receive.event:m.room.message".to_owned(),
+        ];
+        let mut deprecated = DeprecatedCapabilities {
+            identifiers: BTreeMap::from([
+                (
+                    "org.example.msc1234.send.event".to_owned(),
+                    "org.matrix.msc2762.send.event".to_owned(),
+                ),
+                ("org.example.msc1234.requires_client".to_owned(), REQUIRES_CLIENT.to_owned()),
+            ]),
+            translate: false,
+        };
+
+        // Without translation, the capabilities are left as they are.
+        assert_eq!(deprecated.process("widget", capabilities.clone()), capabilities);
+
+        deprecated.translate = true;
+        assert_eq!(
+            deprecated.process("widget", capabilities),
+            [
+                "org.matrix.msc2762.send.event:m.room.message#m.text",
+                "io.element.requires_client",
+                "org.matrix.msc2762.receive.event:m.room.message",
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_capabilities_are_reported() {
+        let capabilities =
+            vec!["io.element.get_user_profile".to_owned(), "org.example.unsupported".to_owned()];
+
+        assert_eq!(
+            Capabilities::unsupported_capabilities(&capabilities),
+            ["org.example.unsupported"]
+        );
+    }
+
     #[test]
     fn deserialization_of_no_capabilities() {
         let capabilities_str = r#"[]"#;
@@ -296,7 +567,14 @@ mod tests {
             "org.matrix.msc2762.send.event:org.matrix.rageshake_request",
             "org.matrix.msc2762.send.state_event:org.matrix.msc3401.call.member#@user:matrix.server",
             "org.matrix.msc4157.send.delayed_event",
-            "org.matrix.msc4157.update_delayed_event"
+            "org.matrix.msc4157.update_delayed_event",
+            "io.element.report_event",
+            "io.element.get_user_profile",
+            "org.matrix.msc2762.receive.ephemeral_event:m.typing",
+            "org.matrix.msc2931.navigate",
+            "io.element.get_server_capabilities",
+            "io.element.read_knock_requests",
+            "org.matrix.msc4039.upload_file"
         ]"#;
 
         let parsed = serde_json::from_str::<Capabilities>(capabilities_str).unwrap();
@@ -317,9 +595,17 @@ mod tests {
                     "@user:matrix.server".into(),
                 )),
             ],
+            read_ephemeral: vec!["m.typing".to_owned()],
             requires_client: true,
             update_delayed_event: true,
             send_delayed_event: true,
+            report_event: true,
+            get_user_profile: true,
+            always_on_screen: true,
+            navigate: true,
+            get_server_capabilities: true,
+            read_knock_requests: true,
+            upload_file: true,
         };
 
         assert_eq!(parsed, expected);
@@ -343,9 +629,17 @@ mod tests {
                     "@user:matrix.server".into(),
                 )),
             ],
+            read_ephemeral: vec!["m.receipt".to_owned()],
             requires_client: true,
             update_delayed_event: false,
             send_delayed_event: false,
+            report_event: false,
+            get_user_profile: false,
+            always_on_screen: false,
+            navigate: false,
+            get_server_capabilities: false,
+            read_knock_requests: false,
+            upload_file: false,
         };
 
         let capabilities_str = serde_json::to_string(&capabilities).unwrap();
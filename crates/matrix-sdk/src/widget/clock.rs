@@ -0,0 +1,94 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The source of time of the widget driver, so that its timeouts can be
+//! tested without waiting for them.
+
+use std::fmt;
+
+use matrix_sdk_common::{BoxFuture, SendOutsideWasm, SyncOutsideWasm};
+use ruma::time::{Duration, Instant};
+
+/// Tells the time to the widget driver, and lets it wait.
+pub(crate) trait Clock: fmt::Debug + SendOutsideWasm + SyncOutsideWasm {
+    /// The current instant.
+    fn now(&self) -> Instant;
+
+    /// Wait until the given duration elapsed.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The [`Clock`] of the system, used outside of tests.
+#[derive(Debug)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(matrix_sdk_common::sleep::sleep(duration))
+    }
+}
+
+/// A [`Clock`] that only moves forward when it is
+/// [advanced](TestClock::advance).
+#[cfg(test)]
+#[derive(Clone, Debug)]
+pub(crate) struct TestClock {
+    now: std::sync::Arc<tokio::sync::watch::Sender<Instant>>,
+}
+
+#[cfg(test)]
+impl TestClock {
+    pub(crate) fn new() -> Self {
+        let (now, _) = tokio::sync::watch::channel(Instant::now());
+        Self { now: now.into() }
+    }
+
+    /// Move the time forward, waking up the sleeps that are over.
+    pub(crate) fn advance(&self, duration: Duration) {
+        self.now.send_modify(|now| *now += duration);
+    }
+
+    /// Whether a task is sleeping on the clock.
+    pub(crate) fn is_sleeping(&self) -> bool {
+        self.now.receiver_count() > 0
+    }
+
+    /// Wait until a task is sleeping on the clock.
+    pub(crate) async fn until_sleeping(&self) {
+        while !self.is_sleeping() {
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+#[cfg(test)]
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let deadline = self.now() + duration;
+        let mut now = self.now.subscribe();
+
+        Box::pin(async move {
+            // The sender lives as long as the clock, which outlives the sleep in tests.
+            let _ = now.wait_for(|now| *now >= deadline).await;
+        })
+    }
+}
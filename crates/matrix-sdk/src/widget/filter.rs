@@ -24,8 +24,7 @@ use super::machine::SendEventRequest;
 /// A Filter for Matrix events. That is used to decide if a given event can be
 /// sent to the widget and if a widgets is allowed to send an event to to a
 /// Matrix room or not.
-#[derive(Clone, Debug)]
-#[cfg_attr(test, derive(PartialEq))]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Filter {
     /// Filter for message-like events.
     MessageLike(MessageLikeEventFilter),
@@ -43,6 +42,22 @@ impl Filter {
             Self::State(filter) => filter.matches(filter_input),
         }
     }
+
+    /// Whether an event with the given `type`, `state_key` and `msgtype` would
+    /// match this filter, with the same logic that is used to decide if an
+    /// event is forwarded to, or sent by, a widget.
+    ///
+    /// `state_key` is `None` for message-like events, and `msgtype` is the
+    /// `msgtype` of the content of the event, if any.
+    pub fn matches_event(
+        &self,
+        event_type: &str,
+        state_key: Option<&str>,
+        msgtype: Option<&str>,
+    ) -> bool {
+        self.matches(&FilterInput::new(event_type, state_key, msgtype))
+    }
+
     /// Returns the event type that this filter is configured to match.
     ///
     /// This method provides a string representation of the event type
@@ -53,11 +68,21 @@ impl Filter {
             Self::State(filter) => filter.filter_event_type(),
         }
     }
+
+    /// Whether all the events matched by this filter are matched by `other`
+    /// too, e.g. `m.room.member#@alice:example.org` is covered by
+    /// `m.room.member`.
+    pub(super) fn is_covered_by(&self, other: &Filter) -> bool {
+        match (self, other) {
+            (Self::MessageLike(filter), Self::MessageLike(other)) => filter.is_covered_by(other),
+            (Self::State(filter), Self::State(other)) => filter.is_covered_by(other),
+            _ => false,
+        }
+    }
 }
 
 /// Filter for message-like events.
-#[derive(Clone, Debug)]
-#[cfg_attr(test, derive(PartialEq))]
+#[derive(Clone, Debug, PartialEq)]
 pub enum MessageLikeEventFilter {
     /// Matches message-like events with the given `type`.
     WithType(MessageLikeEventType),
@@ -66,6 +91,19 @@ pub enum MessageLikeEventFilter {
 }
 
 impl<'a> MessageLikeEventFilter {
+    /// Whether an event with the given `type`, `state_key` and `msgtype` would
+    /// match this filter.
+    ///
+    /// See [`Filter::matches_event`] for more details.
+    pub fn matches_event(
+        &self,
+        event_type: &str,
+        state_key: Option<&str>,
+        msgtype: Option<&str>,
+    ) -> bool {
+        self.matches(&FilterInput::new(event_type, state_key, msgtype))
+    }
+
     fn matches(&self, filter_input: &FilterInput<'a>) -> bool {
         let FilterInput::MessageLike(message_like_filter_input) = filter_input else {
             return false;
@@ -87,11 +125,17 @@ impl<'a> MessageLikeEventFilter {
             Self::RoomMessageWithMsgtype(_) => MessageLikeEventType::RoomMessage.to_string(),
         }
     }
+
+    fn is_covered_by(&self, other: &Self) -> bool {
+        match other {
+            Self::WithType(event_type) => self.filter_event_type() == event_type.to_string(),
+            Self::RoomMessageWithMsgtype(_) => self == other,
+        }
+    }
 }
 
 /// Filter for state events.
-#[derive(Clone, Debug)]
-#[cfg_attr(test, derive(PartialEq))]
+#[derive(Clone, Debug, PartialEq)]
 pub enum StateEventFilter {
     /// Matches state events with the given `type`, regardless of `state_key`.
     WithType(StateEventType),
@@ -100,6 +144,19 @@ pub enum StateEventFilter {
 }
 
 impl<'a> StateEventFilter {
+    /// Whether an event with the given `type`, `state_key` and `msgtype` would
+    /// match this filter.
+    ///
+    /// See [`Filter::matches_event`] for more details.
+    pub fn matches_event(
+        &self,
+        event_type: &str,
+        state_key: Option<&str>,
+        msgtype: Option<&str>,
+    ) -> bool {
+        self.matches(&FilterInput::new(event_type, state_key, msgtype))
+    }
+
     fn matches(&self, filter_input: &FilterInput<'a>) -> bool {
         let FilterInput::State(state_filter_input) = filter_input else {
             return false;
@@ -115,12 +172,20 @@ impl<'a> StateEventFilter {
             }
         }
     }
+
     fn filter_event_type(&self) -> String {
         match self {
             Self::WithType(filter_event_type) => filter_event_type.to_string(),
             Self::WithTypeAndStateKey(event_type, _) => event_type.to_string(),
         }
     }
+
+    fn is_covered_by(&self, other: &Self) -> bool {
+        match other {
+            Self::WithType(event_type) => self.filter_event_type() == event_type.to_string(),
+            Self::WithTypeAndStateKey(..) => self == other,
+        }
+    }
 }
 
 // Filter input:
@@ -136,6 +201,18 @@ pub enum FilterInput<'a> {
 }
 
 impl<'a> FilterInput<'a> {
+    /// Create a filter input from the parts of an event that can be matched,
+    /// like it would be deserialized from a [`Raw<AnyTimelineEvent>`].
+    fn new(event_type: &'a str, state_key: Option<&'a str>, msgtype: Option<&'a str>) -> Self {
+        match state_key {
+            Some(state_key) => Self::state(event_type, state_key),
+            None => Self::MessageLike(FilterInputMessageLike {
+                event_type,
+                content: MessageLikeFilterEventContent { msgtype },
+            }),
+        }
+    }
+
     pub fn message_like(event_type: &'a str) -> Self {
         Self::MessageLike(FilterInputMessageLike {
             event_type,
@@ -415,6 +492,48 @@ mod tests {
         assert!(!room_message_filter()
             .matches(&FilterInput::message_like(&MessageLikeEventType::Reaction.to_string())));
     }
+
+    #[test]
+    fn test_state_filter_without_state_key_matches_any_state_key() {
+        let filter = StateEventFilter::WithType(StateEventType::RoomMember);
+
+        assert!(filter.matches_event("m.room.member", Some("@alice:example.org"), None));
+        assert!(filter.matches_event("m.room.member", Some(""), None));
+        assert!(!filter.matches_event("m.room.name", Some(""), None));
+        // A message-like event of the same type is not a state event.
+        assert!(!filter.matches_event("m.room.member", None, None));
+    }
+
+    #[test]
+    fn test_state_filter_with_state_key_only_matches_that_state_key() {
+        let filter = Filter::State(StateEventFilter::WithTypeAndStateKey(
+            StateEventType::RoomMember,
+            "@alice:example.org".to_owned(),
+        ));
+
+        assert!(filter.matches_event("m.room.member", Some("@alice:example.org"), None));
+        assert!(!filter.matches_event("m.room.member", Some("@bob:example.org"), None));
+        assert!(!filter.matches_event("m.room.member", Some(""), None));
+    }
+
+    #[test]
+    fn test_msgtype_filter_only_matches_room_messages_with_that_msgtype() {
+        let filter = MessageLikeEventFilter::RoomMessageWithMsgtype("m.text".to_owned());
+
+        assert!(filter.matches_event("m.room.message", None, Some("m.text")));
+        assert!(!filter.matches_event("m.room.message", None, Some("m.image")));
+        assert!(!filter.matches_event("m.room.message", None, None));
+        assert!(!filter.matches_event("io.element.message", None, Some("m.text")));
+        assert!(!filter.matches_event("m.room.message", Some(""), Some("m.text")));
+
+        // A filter on the event type matches any msgtype.
+        let filter = Filter::MessageLike(MessageLikeEventFilter::WithType(
+            MessageLikeEventType::RoomMessage,
+        ));
+        assert!(filter.matches_event("m.room.message", None, Some("m.image")));
+        assert!(filter.matches_event("m.room.message", None, None));
+    }
+
     #[test]
     fn test_convert_raw_event_into_message_like_filter_input() {
         let raw_event = &Raw::<AnyTimelineEvent>::from_json_string(
@@ -0,0 +1,162 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The delayed events sent by a widget that it can still update, shared by
+//! the successive sessions of the widget.
+
+use std::sync::{Arc, Mutex};
+
+use indexmap::IndexMap;
+use ruma::{
+    api::client::delayed_events::update_delayed_event::unstable::UpdateAction,
+    time::{Duration, Instant},
+};
+
+/// The maximum number of delayed events that a widget can update; the oldest
+/// ones are forgotten beyond it.
+const MAX_DELAYED_EVENTS: usize = 50;
+
+/// The minimum interval between two restarts of the same delayed event by the
+/// widget.
+///
+/// Sending or cancelling a delayed event can only succeed once, after which its
+/// delay id is forgotten, so only the restarts can be repeated and need to be
+/// limited. Cancelling a delayed event right after restarting it, e.g. when the
+/// user leaves a call, must not be delayed either.
+const MIN_DELAYED_EVENT_RESTART_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The delay ids of the delayed events sent by a widget that it can still
+/// update, in the order in which they were sent, with the time of their latest
+/// restart.
+///
+/// The clones share the same delay ids, so that they are kept when the widget
+/// reconnects.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DelayedEvents {
+    delay_ids: Arc<Mutex<IndexMap<String, Option<Instant>>>>,
+}
+
+impl DelayedEvents {
+    /// Add the delay id of a delayed event sent by the widget.
+    pub(crate) fn insert(&self, delay_id: String) {
+        let mut delay_ids = self.delay_ids.lock().unwrap();
+        delay_ids.insert(delay_id, None);
+
+        if delay_ids.len() > MAX_DELAYED_EVENTS {
+            delay_ids.shift_remove_index(0);
+        }
+    }
+
+    /// Add the delay ids of the delayed events sent by the widget in a previous
+    /// session.
+    pub(crate) fn extend(&self, delay_ids: impl IntoIterator<Item = String>) {
+        for delay_id in delay_ids {
+            self.insert(delay_id);
+        }
+    }
+
+    /// The delay ids of the delayed events that can still be updated, oldest
+    /// first.
+    pub(crate) fn delay_ids(&self) -> Vec<String> {
+        self.delay_ids.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Check that the widget can apply the given action to the delayed event
+    /// with the given delay id, before sending the update to the homeserver.
+    ///
+    /// A restart is recorded right away, so that the concurrent ones are
+    /// limited too. Returns the time of the previous restart, to restore it
+    /// with [`DelayedEvents::finish_update`] if the update fails.
+    pub(crate) fn start_update(
+        &self,
+        delay_id: &str,
+        action: &UpdateAction,
+        now: Instant,
+    ) -> Result<Option<Instant>, String> {
+        let mut delay_ids = self.delay_ids.lock().unwrap();
+        let Some(latest_restart) = delay_ids.get_mut(delay_id) else {
+            return Err("Unknown delay id: it wasn't returned for a delayed event sent by this \
+                        widget, or the delayed event was already sent or cancelled"
+                .to_owned());
+        };
+
+        let previous_restart = *latest_restart;
+        if !is_final(action) {
+            if previous_restart.is_some_and(|previous_restart| {
+                now.duration_since(previous_restart) < MIN_DELAYED_EVENT_RESTART_INTERVAL
+            }) {
+                return Err(format!(
+                    "Too many updates of the delayed event, the minimum interval between two \
+                     restarts is {MIN_DELAYED_EVENT_RESTART_INTERVAL:?}"
+                ));
+            }
+
+            *latest_restart = Some(now);
+        }
+
+        Ok(previous_restart)
+    }
+
+    /// Record the result of an update started with
+    /// [`DelayedEvents::start_update`].
+    ///
+    /// A delayed event that was sent or cancelled can't be updated anymore,
+    /// and a failed restart doesn't count against the next one.
+    pub(crate) fn finish_update(
+        &self,
+        delay_id: &str,
+        action: &UpdateAction,
+        succeeded: bool,
+        previous_restart: Option<Instant>,
+    ) {
+        let mut delay_ids = self.delay_ids.lock().unwrap();
+        if is_final(action) {
+            if succeeded {
+                delay_ids.shift_remove(delay_id);
+            }
+        } else if !succeeded {
+            if let Some(latest_restart) = delay_ids.get_mut(delay_id) {
+                *latest_restart = previous_restart;
+            }
+        }
+    }
+}
+
+/// Whether the given action ends the delayed event, i.e. sends or cancels it.
+fn is_final(action: &UpdateAction) -> bool {
+    matches!(action, UpdateAction::Send | UpdateAction::Cancel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DelayedEvents, MAX_DELAYED_EVENTS};
+
+    #[test]
+    fn oldest_delayed_events_are_forgotten_beyond_the_limit() {
+        let delayed_events = DelayedEvents::default();
+        delayed_events.extend((0..=MAX_DELAYED_EVENTS).map(|i| i.to_string()));
+
+        let delay_ids = delayed_events.delay_ids();
+        assert_eq!(delay_ids.len(), MAX_DELAYED_EVENTS);
+        assert_eq!(delay_ids.first().unwrap(), "1");
+        assert_eq!(delay_ids.last().unwrap(), &MAX_DELAYED_EVENTS.to_string());
+    }
+
+    #[test]
+    fn clones_share_the_delayed_events() {
+        let delayed_events = DelayedEvents::default();
+        delayed_events.clone().insert("1234".to_owned());
+        assert_eq!(delayed_events.delay_ids(), ["1234"]);
+    }
+}
@@ -16,20 +16,29 @@
 
 use std::marker::PhantomData;
 
+use mime::Mime;
 use ruma::{
-    api::client::{account::request_openid_token, delayed_events::update_delayed_event},
-    events::AnyTimelineEvent,
+    api::client::{
+        account::request_openid_token, delayed_events::update_delayed_event,
+        discovery::get_capabilities, profile::get_profile, room::report_content,
+    },
+    events::{room::server_acl::RoomServerAclEventContent, AnyTimelineEvent},
     serde::Raw,
+    OwnedEventId, OwnedRoomOrAliasId, OwnedUserId,
 };
 use serde::Deserialize;
 use serde_json::value::RawValue as RawJsonValue;
 use tracing::error;
 
 use super::{
-    from_widget::SendEventResponse, incoming::MatrixDriverResponse, Action,
-    MatrixDriverRequestMeta, WidgetMachine,
+    from_widget::{GetMembershipEventsResponse, SendEventResponse},
+    incoming::MatrixDriverResponse,
+    Action, MatrixDriverRequestMeta, WidgetMachine,
+};
+use crate::{
+    room::knock_requests::KnockRequest,
+    widget::{Capabilities, StateKeySelector},
 };
-use crate::widget::{Capabilities, StateKeySelector};
 
 #[derive(Clone, Debug)]
 pub(crate) enum MatrixDriverRequestData {
@@ -49,11 +58,69 @@ pub(crate) enum MatrixDriverRequestData {
     /// Read state event(s).
     ReadStateEvent(ReadStateEventRequest),
 
+    /// Read a page of the membership events of the room.
+    ReadMembershipEvents(ReadMembershipEventsRequest),
+
     /// Send matrix event that corresponds to the given description.
     SendMatrixEvent(SendEventRequest),
 
     /// Data for sending a UpdateDelayedEvent client server api request.
     UpdateDelayedEvent(UpdateDelayedEventRequest),
+
+    /// Report an event of the room to the homeserver administrators.
+    ReportEvent(ReportEventRequest),
+
+    /// Get the profile of a user.
+    GetUserProfile(GetUserProfileRequest),
+
+    /// Get the capabilities of the homeserver.
+    GetServerCapabilities(GetServerCapabilitiesRequest),
+
+    /// Get the current requests to join the room.
+    GetKnockRequests(GetKnockRequestsRequest),
+
+    /// Get the server access control list of the room.
+    GetServerAcl(GetServerAclRequest),
+
+    /// Get the power level required for an action in the room.
+    GetRequiredPowerLevel(GetRequiredPowerLevelRequest),
+
+    /// Send a reaction to an event of the room.
+    SendReaction(SendReactionRequest),
+
+    /// Redact an event of the room.
+    RedactEvent(RedactEventRequest),
+
+    /// Upload a file and send it in the room.
+    SendAttachment(SendAttachmentRequest),
+
+    /// Navigate to another room.
+    NavigateToRoom(NavigateToRoomRequest),
+}
+
+impl MatrixDriverRequestData {
+    /// The name of the kind of this request, e.g. `ReadStateEvent`.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Self::AcquireCapabilities(_) => "AcquireCapabilities",
+            Self::GetOpenId => "GetOpenId",
+            Self::ReadMessageLikeEvent(_) => "ReadMessageLikeEvent",
+            Self::ReadStateEvent(_) => "ReadStateEvent",
+            Self::ReadMembershipEvents(_) => "ReadMembershipEvents",
+            Self::SendMatrixEvent(_) => "SendMatrixEvent",
+            Self::UpdateDelayedEvent(_) => "UpdateDelayedEvent",
+            Self::ReportEvent(_) => "ReportEvent",
+            Self::GetUserProfile(_) => "GetUserProfile",
+            Self::GetServerCapabilities(_) => "GetServerCapabilities",
+            Self::GetKnockRequests(_) => "GetKnockRequests",
+            Self::GetServerAcl(_) => "GetServerAcl",
+            Self::GetRequiredPowerLevel(_) => "GetRequiredPowerLevel",
+            Self::SendReaction(_) => "SendReaction",
+            Self::RedactEvent(_) => "RedactEvent",
+            Self::SendAttachment(_) => "SendAttachment",
+            Self::NavigateToRoom(_) => "NavigateToRoom",
+        }
+    }
 }
 
 /// A handle to a pending `toWidget` request.
@@ -211,6 +278,42 @@ impl MatrixDriverRequest for ReadStateEventRequest {
     type Response = Vec<Raw<AnyTimelineEvent>>;
 }
 
+/// Ask the client to read a page of the membership events of the room, from
+/// the newest to the oldest one.
+#[derive(Clone, Debug)]
+pub(crate) struct ReadMembershipEventsRequest {
+    /// Only return the membership events targeting this user.
+    pub(crate) user_id: Option<OwnedUserId>,
+
+    /// The maximum number of events to fetch from the homeserver.
+    pub(crate) limit: u32,
+
+    /// The pagination token to start reading from.
+    pub(crate) from: Option<String>,
+}
+
+impl From<ReadMembershipEventsRequest> for MatrixDriverRequestData {
+    fn from(value: ReadMembershipEventsRequest) -> Self {
+        MatrixDriverRequestData::ReadMembershipEvents(value)
+    }
+}
+
+impl MatrixDriverRequest for ReadMembershipEventsRequest {
+    type Response = GetMembershipEventsResponse;
+}
+
+impl FromMatrixDriverResponse for GetMembershipEventsResponse {
+    fn from_response(ev: MatrixDriverResponse) -> Option<Self> {
+        match ev {
+            MatrixDriverResponse::MembershipEventsRead(response) => Some(response),
+            _ => {
+                error!("bug in MatrixDriver, received wrong event response");
+                None
+            }
+        }
+    }
+}
+
 /// Ask the client to send matrix event that corresponds to the given
 /// description and returns an event ID (or a delay ID,
 /// see [MSC4140](https://github.com/matrix-org/matrix-spec-proposals/pull/4140)) as a response.
@@ -229,6 +332,23 @@ pub(crate) struct SendEventRequest {
     /// If provided, the response will contain a delay_id instead of a event_id.
     /// Defined by [MSC4157](https://github.com/matrix-org/matrix-spec-proposals/pull/4157)
     pub(crate) delay: Option<u64>,
+    /// How the content is combined with the current content of the state
+    /// event, if it's a state event.
+    #[serde(default)]
+    pub(crate) update_mode: StateUpdateMode,
+}
+
+/// How the content of a state event sent by a widget is combined with the
+/// current content of this state event in the room.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum StateUpdateMode {
+    /// The new content replaces the current content entirely.
+    #[default]
+    Replace,
+    /// The top-level fields of the new content are merged into the current
+    /// content, overwriting the fields which are already set.
+    Merge,
 }
 
 impl From<SendEventRequest> for MatrixDriverRequestData {
@@ -282,3 +402,299 @@ impl FromMatrixDriverResponse for update_delayed_event::unstable::Response {
         }
     }
 }
+
+/// Ask the client to report the event with the given `event_id` to the
+/// homeserver administrators.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct ReportEventRequest {
+    /// The ID of the event to report, it must be part of the widget's room.
+    pub(crate) event_id: OwnedEventId,
+    /// The score to rate the content, between -100 (most offensive) and 0
+    /// (inoffensive).
+    pub(crate) score: Option<i8>,
+    /// The reason the content is being reported.
+    pub(crate) reason: Option<String>,
+}
+
+impl From<ReportEventRequest> for MatrixDriverRequestData {
+    fn from(value: ReportEventRequest) -> Self {
+        MatrixDriverRequestData::ReportEvent(value)
+    }
+}
+
+impl MatrixDriverRequest for ReportEventRequest {
+    type Response = report_content::v3::Response;
+}
+
+impl FromMatrixDriverResponse for report_content::v3::Response {
+    fn from_response(ev: MatrixDriverResponse) -> Option<Self> {
+        match ev {
+            MatrixDriverResponse::EventReported(response) => Some(response),
+            _ => {
+                error!("bug in MatrixDriver, received wrong event response");
+                None
+            }
+        }
+    }
+}
+
+/// Ask the client to fetch the profile of the user with the given `user_id`.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct GetUserProfileRequest {
+    /// The ID of the user whose profile should be fetched.
+    pub(crate) user_id: OwnedUserId,
+}
+
+impl From<GetUserProfileRequest> for MatrixDriverRequestData {
+    fn from(value: GetUserProfileRequest) -> Self {
+        MatrixDriverRequestData::GetUserProfile(value)
+    }
+}
+
+impl MatrixDriverRequest for GetUserProfileRequest {
+    type Response = get_profile::v3::Response;
+}
+
+impl FromMatrixDriverResponse for get_profile::v3::Response {
+    fn from_response(ev: MatrixDriverResponse) -> Option<Self> {
+        match ev {
+            MatrixDriverResponse::UserProfileReceived(response) => Some(response),
+            _ => {
+                error!("bug in MatrixDriver, received wrong event response");
+                None
+            }
+        }
+    }
+}
+
+/// Ask the client to fetch the capabilities of the homeserver.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct GetServerCapabilitiesRequest {}
+
+impl From<GetServerCapabilitiesRequest> for MatrixDriverRequestData {
+    fn from(value: GetServerCapabilitiesRequest) -> Self {
+        MatrixDriverRequestData::GetServerCapabilities(value)
+    }
+}
+
+impl MatrixDriverRequest for GetServerCapabilitiesRequest {
+    type Response = get_capabilities::v3::Capabilities;
+}
+
+impl FromMatrixDriverResponse for get_capabilities::v3::Capabilities {
+    fn from_response(ev: MatrixDriverResponse) -> Option<Self> {
+        match ev {
+            MatrixDriverResponse::ServerCapabilitiesReceived(capabilities) => Some(capabilities),
+            _ => {
+                error!("bug in MatrixDriver, received wrong event response");
+                None
+            }
+        }
+    }
+}
+
+/// Ask the client to get the current requests to join the room.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct GetKnockRequestsRequest {}
+
+impl From<GetKnockRequestsRequest> for MatrixDriverRequestData {
+    fn from(value: GetKnockRequestsRequest) -> Self {
+        MatrixDriverRequestData::GetKnockRequests(value)
+    }
+}
+
+impl MatrixDriverRequest for GetKnockRequestsRequest {
+    type Response = Vec<KnockRequest>;
+}
+
+impl FromMatrixDriverResponse for Vec<KnockRequest> {
+    fn from_response(ev: MatrixDriverResponse) -> Option<Self> {
+        match ev {
+            MatrixDriverResponse::KnockRequestsReceived(requests) => Some(requests),
+            _ => {
+                error!("bug in MatrixDriver, received wrong event response");
+                None
+            }
+        }
+    }
+}
+
+/// Ask the client to get the server access control list of the room.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct GetServerAclRequest {}
+
+impl From<GetServerAclRequest> for MatrixDriverRequestData {
+    fn from(value: GetServerAclRequest) -> Self {
+        MatrixDriverRequestData::GetServerAcl(value)
+    }
+}
+
+impl MatrixDriverRequest for GetServerAclRequest {
+    type Response = Option<RoomServerAclEventContent>;
+}
+
+impl FromMatrixDriverResponse for Option<RoomServerAclEventContent> {
+    fn from_response(ev: MatrixDriverResponse) -> Option<Self> {
+        match ev {
+            MatrixDriverResponse::ServerAclReceived(acl) => Some(acl),
+            _ => {
+                error!("bug in MatrixDriver, received wrong event response");
+                None
+            }
+        }
+    }
+}
+
+/// Ask the client for the power level required for an action in the room,
+/// according to its `m.room.power_levels` event.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub(crate) enum GetRequiredPowerLevelRequest {
+    /// Send an event of the given type.
+    SendEvent {
+        /// The type of the event, e.g. `m.room.name`.
+        event_type: String,
+        /// Whether it is a state event.
+        #[serde(default)]
+        state: bool,
+    },
+    /// Moderate the room.
+    Moderate {
+        /// The moderation action.
+        action: ModerationAction,
+    },
+}
+
+/// An action of a moderator, whose required power level is defined by the
+/// `m.room.power_levels` event of the room.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ModerationAction {
+    Ban,
+    Kick,
+    Invite,
+    Redact,
+}
+
+/// The power level required for an action, along with the one of the user.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RequiredPowerLevel {
+    /// The power level required for the action.
+    pub(crate) required: i64,
+    /// The power level of the user.
+    pub(crate) own: i64,
+}
+
+impl From<GetRequiredPowerLevelRequest> for MatrixDriverRequestData {
+    fn from(value: GetRequiredPowerLevelRequest) -> Self {
+        MatrixDriverRequestData::GetRequiredPowerLevel(value)
+    }
+}
+
+impl MatrixDriverRequest for GetRequiredPowerLevelRequest {
+    type Response = RequiredPowerLevel;
+}
+
+impl FromMatrixDriverResponse for RequiredPowerLevel {
+    fn from_response(ev: MatrixDriverResponse) -> Option<Self> {
+        match ev {
+            MatrixDriverResponse::RequiredPowerLevelReceived(level) => Some(level),
+            _ => {
+                error!("bug in MatrixDriver, received wrong event response");
+                None
+            }
+        }
+    }
+}
+
+/// Ask the client to react to the event with the given `event_id` with the
+/// given `key`.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct SendReactionRequest {
+    /// The ID of the event to react to, it must be part of the widget's room.
+    pub(crate) event_id: OwnedEventId,
+    /// The reaction key, usually an emoji.
+    pub(crate) key: String,
+}
+
+impl From<SendReactionRequest> for MatrixDriverRequestData {
+    fn from(value: SendReactionRequest) -> Self {
+        MatrixDriverRequestData::SendReaction(value)
+    }
+}
+
+impl MatrixDriverRequest for SendReactionRequest {
+    type Response = SendEventResponse;
+}
+
+/// Ask the client to redact the event with the given `event_id`.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct RedactEventRequest {
+    /// The ID of the event to redact, it must be part of the widget's room.
+    pub(crate) event_id: OwnedEventId,
+    /// The reason of the redaction.
+    pub(crate) reason: Option<String>,
+}
+
+impl From<RedactEventRequest> for MatrixDriverRequestData {
+    fn from(value: RedactEventRequest) -> Self {
+        MatrixDriverRequestData::RedactEvent(value)
+    }
+}
+
+impl MatrixDriverRequest for RedactEventRequest {
+    type Response = SendEventResponse;
+}
+
+/// Ask the client to upload a file and to send it in the room.
+///
+/// In an encrypted room, the file is encrypted before being uploaded.
+#[derive(Debug, Clone)]
+pub(crate) struct SendAttachmentRequest {
+    /// The name of the file.
+    pub(crate) filename: String,
+    /// The MIME type of the file.
+    pub(crate) content_type: Mime,
+    /// The content of the file.
+    pub(crate) data: Vec<u8>,
+}
+
+impl From<SendAttachmentRequest> for MatrixDriverRequestData {
+    fn from(value: SendAttachmentRequest) -> Self {
+        MatrixDriverRequestData::SendAttachment(value)
+    }
+}
+
+impl MatrixDriverRequest for SendAttachmentRequest {
+    type Response = SendEventResponse;
+}
+
+/// Ask the client to navigate to the room with the given ID or alias, e.g. to
+/// focus it.
+#[derive(Debug, Clone)]
+pub(crate) struct NavigateToRoomRequest {
+    /// The ID or alias of the room to navigate to.
+    pub(crate) room: OwnedRoomOrAliasId,
+}
+
+impl From<NavigateToRoomRequest> for MatrixDriverRequestData {
+    fn from(value: NavigateToRoomRequest) -> Self {
+        MatrixDriverRequestData::NavigateToRoom(value)
+    }
+}
+
+impl MatrixDriverRequest for NavigateToRoomRequest {
+    type Response = ();
+}
+
+impl FromMatrixDriverResponse for () {
+    fn from_response(ev: MatrixDriverResponse) -> Option<Self> {
+        match ev {
+            MatrixDriverResponse::NavigatedToRoom => Some(()),
+            _ => {
+                error!("bug in MatrixDriver, received wrong event response");
+                None
+            }
+        }
+    }
+}
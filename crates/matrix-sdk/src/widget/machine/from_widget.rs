@@ -16,16 +16,32 @@ use as_variant::as_variant;
 use ruma::{
     api::client::{
         delayed_events::{delayed_message_event, delayed_state_event, update_delayed_event},
+        discovery::get_capabilities,
         error::{ErrorBody, StandardErrorBody},
+        profile::get_profile,
+        room::report_content,
     },
-    events::AnyTimelineEvent,
-    serde::Raw,
-    OwnedEventId, OwnedRoomId,
+    events::{room::server_acl::RoomServerAclEventContent, AnyTimelineEvent},
+    matrix_uri::MatrixId,
+    serde::{Base64, Raw},
+    MatrixToUri, MatrixUri, OwnedEventId, OwnedMxcUri, OwnedRoomId, OwnedRoomOrAliasId,
+    OwnedUserId, UInt,
 };
 use serde::{Deserialize, Serialize};
 
-use super::{SendEventRequest, UpdateDelayedEventRequest};
-use crate::{widget::StateKeySelector, Error, HttpError, RumaApiError};
+use super::{
+    driver_req::{
+        GetKnockRequestsRequest, GetRequiredPowerLevelRequest, GetServerAclRequest,
+        GetServerCapabilitiesRequest, GetUserProfileRequest, RedactEventRequest,
+        ReportEventRequest, RequiredPowerLevel, SendReactionRequest,
+    },
+    SendEventRequest, UpdateDelayedEventRequest,
+};
+use crate::{
+    room::knock_requests::{KnockRequest, KnockRequestMemberInfo},
+    widget::StateKeySelector,
+    Error, HttpError, RumaApiError,
+};
 
 #[derive(Deserialize, Debug)]
 #[serde(tag = "action", rename_all = "snake_case", content = "data")]
@@ -34,11 +50,28 @@ pub(super) enum FromWidgetRequest {
     ContentLoaded {},
     #[serde(rename = "get_openid")]
     GetOpenId {},
+    #[serde(rename = "io.element.join")]
+    Join {},
+    SetAlwaysOnScreen(SetAlwaysOnScreenRequest),
     #[serde(rename = "org.matrix.msc2876.read_events")]
     ReadEvent(ReadEventRequest),
     SendEvent(SendEventRequest),
     #[serde(rename = "org.matrix.msc4157.update_delayed_event")]
     DelayedEventUpdate(UpdateDelayedEventRequest),
+    ReportEvent(ReportEventRequest),
+    GetUserProfile(GetUserProfileRequest),
+    GetServerCapabilities(GetServerCapabilitiesRequest),
+    GetKnockRequests(GetKnockRequestsRequest),
+    GetMembershipEvents(GetMembershipEventsRequest),
+    GetServerAcl(GetServerAclRequest),
+    GetRequiredPowerLevel(GetRequiredPowerLevelRequest),
+    SendReaction(SendReactionRequest),
+    RedactEvent(RedactEventRequest),
+    SendFile(SendFileRequest),
+    #[serde(rename = "org.matrix.msc2931.navigate")]
+    Navigate(NavigateRequest),
+    WatchEvent(WatchEventRequest),
+    UnwatchEvent(WatchEventRequest),
 }
 
 /// The full response a client sends to a [`FromWidgetRequest`] in case of an
@@ -188,6 +221,40 @@ pub(super) enum ReadEventRequest {
     },
 }
 
+/// A request to read the history of the memberships of the room, e.g. to audit
+/// the knocks and joins.
+#[derive(Deserialize, Debug)]
+pub(super) struct GetMembershipEventsRequest {
+    /// Only return the membership events targeting this user.
+    pub(super) user_id: Option<OwnedUserId>,
+    /// The maximum number of events to fetch.
+    pub(super) limit: Option<u32>,
+    /// The `next_token` of a previous response, to read older events.
+    pub(super) from: Option<String>,
+}
+
+/// A request to upload a file and to send it in the room, encrypted if the
+/// room is encrypted.
+#[derive(Deserialize, Debug)]
+pub(super) struct SendFileRequest {
+    /// The name of the file.
+    pub(super) filename: String,
+    /// The MIME type of the file, `application/octet-stream` by default.
+    pub(super) mimetype: Option<String>,
+    /// The content of the file.
+    pub(super) data: Base64,
+}
+
+/// A page of the membership events of the room, as returned to the widget for
+/// a [`FromWidgetRequest::GetMembershipEvents`] request.
+#[derive(Serialize, Debug)]
+pub(crate) struct GetMembershipEventsResponse {
+    pub(crate) events: Vec<Raw<AnyTimelineEvent>>,
+    /// The token to read the next (older) page of events, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) next_token: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 pub(super) struct ReadEventResponse {
     pub(super) events: Vec<Raw<AnyTimelineEvent>>,
@@ -237,3 +304,163 @@ impl From<update_delayed_event::unstable::Response> for UpdateDelayedEventRespon
         Self {}
     }
 }
+
+/// A wrapper type for the empty okay response from
+/// [`report_content`](report_content::v3::Response) which derives Serialize.
+#[derive(Serialize, Debug)]
+pub(crate) struct ReportEventResponse {}
+impl From<report_content::v3::Response> for ReportEventResponse {
+    fn from(_: report_content::v3::Response) -> Self {
+        Self {}
+    }
+}
+
+/// The profile of a user, as returned to the widget for a
+/// [`FromWidgetRequest::GetUserProfile`] request.
+#[derive(Serialize, Debug)]
+pub(crate) struct GetUserProfileResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    displayname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<OwnedMxcUri>,
+}
+
+impl From<get_profile::v3::Response> for GetUserProfileResponse {
+    fn from(value: get_profile::v3::Response) -> Self {
+        Self { displayname: value.displayname, avatar_url: value.avatar_url }
+    }
+}
+
+/// The capabilities of the homeserver, as returned to the widget for a
+/// [`FromWidgetRequest::GetServerCapabilities`] request.
+#[derive(Serialize, Debug)]
+pub(crate) struct GetServerCapabilitiesResponse {
+    capabilities: get_capabilities::v3::Capabilities,
+}
+
+impl From<get_capabilities::v3::Capabilities> for GetServerCapabilitiesResponse {
+    fn from(capabilities: get_capabilities::v3::Capabilities) -> Self {
+        Self { capabilities }
+    }
+}
+
+/// The server access control list of the room, as returned to the widget for
+/// a [`FromWidgetRequest::GetServerAcl`] request.
+///
+/// The `acl` is absent if the room doesn't have one.
+#[derive(Serialize, Debug)]
+pub(crate) struct GetServerAclResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acl: Option<RoomServerAclEventContent>,
+}
+
+impl From<Option<RoomServerAclEventContent>> for GetServerAclResponse {
+    fn from(acl: Option<RoomServerAclEventContent>) -> Self {
+        Self { acl }
+    }
+}
+
+/// The power level required for an action, as returned to the widget for a
+/// [`FromWidgetRequest::GetRequiredPowerLevel`] request.
+#[derive(Serialize, Debug)]
+pub(crate) struct GetRequiredPowerLevelResponse {
+    required_level: i64,
+    user_level: i64,
+    /// Whether the power level of the user is high enough for the action.
+    allowed: bool,
+}
+
+impl From<RequiredPowerLevel> for GetRequiredPowerLevelResponse {
+    fn from(level: RequiredPowerLevel) -> Self {
+        Self {
+            required_level: level.required,
+            user_level: level.own,
+            allowed: level.own >= level.required,
+        }
+    }
+}
+
+/// The requests to join the room, as returned to the widget for a
+/// [`FromWidgetRequest::GetKnockRequests`] request.
+#[derive(Serialize, Debug)]
+pub(crate) struct GetKnockRequestsResponse {
+    requests: Vec<KnockRequestResponse>,
+}
+
+impl From<Vec<KnockRequest>> for GetKnockRequestsResponse {
+    fn from(requests: Vec<KnockRequest>) -> Self {
+        Self { requests: requests.into_iter().map(Into::into).collect() }
+    }
+}
+
+/// A request to join the room, in a [`GetKnockRequestsResponse`].
+#[derive(Serialize, Debug)]
+struct KnockRequestResponse {
+    event_id: OwnedEventId,
+    user_id: OwnedUserId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    displayname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<OwnedMxcUri>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<UInt>,
+    is_seen: bool,
+}
+
+impl From<KnockRequest> for KnockRequestResponse {
+    fn from(request: KnockRequest) -> Self {
+        let KnockRequestMemberInfo { user_id, display_name, avatar_url, reason } =
+            request.member_info;
+
+        Self {
+            event_id: request.event_id,
+            user_id,
+            displayname: display_name,
+            avatar_url,
+            reason,
+            timestamp: request.timestamp,
+            is_seen: request.is_seen,
+        }
+    }
+}
+
+/// The request of a widget to be kept visible on the screen, or not.
+#[derive(Deserialize, Debug)]
+pub(super) struct SetAlwaysOnScreenRequest {
+    pub(super) value: bool,
+}
+
+/// A request of the widget to start or stop being notified of the edits,
+/// reactions and redaction of an event.
+#[derive(Deserialize, Debug)]
+pub(super) struct WatchEventRequest {
+    /// The id of the watched event.
+    pub(super) event_id: OwnedEventId,
+}
+
+/// A request of the widget to navigate to a room.
+#[derive(Deserialize, Debug)]
+pub(super) struct NavigateRequest {
+    /// A `matrix.to` or `matrix:` URI of the room, or of an event of the room.
+    pub(super) uri: String,
+}
+
+impl NavigateRequest {
+    /// The ID or alias of the room to navigate to, or `None` if the URI isn't
+    /// a valid room or event URI.
+    pub(super) fn room(&self) -> Option<OwnedRoomOrAliasId> {
+        let id = match MatrixToUri::parse(&self.uri) {
+            Ok(uri) => uri.id().clone(),
+            Err(_) => MatrixUri::parse(&self.uri).ok()?.id().clone(),
+        };
+
+        match id {
+            MatrixId::Room(room_id) => Some(room_id.into()),
+            MatrixId::RoomAlias(alias) => Some(alias.into()),
+            MatrixId::Event(room, _) => Some(room),
+            _ => None,
+        }
+    }
+}
@@ -12,9 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::{collections::BTreeSet, fmt};
+
 use ruma::{
-    api::client::{account::request_openid_token, delayed_events},
-    events::AnyTimelineEvent,
+    api::client::{
+        account::request_openid_token, delayed_events, discovery::get_capabilities,
+        profile::get_profile, room::report_content,
+    },
+    events::{
+        room::server_acl::RoomServerAclEventContent, AnyEphemeralRoomEvent, AnyTimelineEvent,
+    },
     serde::Raw,
 };
 use serde::{de, Deserialize, Deserializer};
@@ -22,10 +29,11 @@ use serde_json::value::RawValue as RawJsonValue;
 use uuid::Uuid;
 
 use super::{
-    from_widget::{FromWidgetRequest, SendEventResponse},
+    driver_req::RequiredPowerLevel,
+    from_widget::{FromWidgetRequest, GetMembershipEventsResponse, SendEventResponse},
     to_widget::ToWidgetResponse,
 };
-use crate::widget::Capabilities;
+use crate::{room::knock_requests::KnockRequest, widget::Capabilities};
 
 /// Incoming event that the client API must process.
 pub(crate) enum IncomingMessage {
@@ -49,6 +57,60 @@ pub(crate) enum IncomingMessage {
     /// This means that the machine previously subscribed to some events
     /// ([`crate::widget::Action::Subscribe`] request).
     MatrixEventReceived(Raw<AnyTimelineEvent>),
+
+    /// The `MatrixDriver` notified the `WidgetMachine` of a new ephemeral
+    /// event, e.g. a typing notification or a read receipt.
+    ///
+    /// Like [`IncomingMessage::MatrixEventReceived`], this is only sent after
+    /// the machine subscribed to the events of the room.
+    MatrixEphemeralEventReceived(Raw<AnyEphemeralRoomEvent>),
+
+    /// The theme of the client changed, e.g. from `light` to `dark`.
+    ThemeChanged(String),
+
+    /// The properties of the client changed, e.g. because the user changed the
+    /// theme or the language of the system while the widget is running.
+    ClientPropertiesChanged {
+        /// The theme of the client, e.g. `light` or `dark`.
+        theme: String,
+        /// The language tag of the client, e.g. `en-US`.
+        language: String,
+    },
+
+    /// The client wants to grant or revoke capabilities while the widget is
+    /// running, by acquiring the given capabilities instead of the negotiated
+    /// ones.
+    UpdateCapabilities(Capabilities),
+
+    /// The client wants to stop delivering the events of the room to the
+    /// widget, until [`IncomingMessage::Resume`].
+    Pause,
+
+    /// The client wants to deliver the events of the room to the widget
+    /// again, after [`IncomingMessage::Pause`].
+    Resume,
+
+    /// The widget reconnected, e.g. because its webview was reloaded, and may
+    /// have missed the latest critical messages sent to it.
+    WidgetReconnected,
+
+    /// The client backing the `MatrixDriver` can't be used anymore for the
+    /// room of the widget, e.g. because it was logged out.
+    ///
+    /// This is sent right after the response to the request that failed
+    /// because of this, so the widget is told about the failure first.
+    ClientUnavailable,
+
+    /// The room of the widget isn't joined anymore.
+    ///
+    /// Like [`IncomingMessage::ClientUnavailable`], this is sent right after
+    /// the response to the request that failed because of this.
+    RoomUnavailable,
+
+    /// The timeout of a request sent to the widget elapsed, so the requests
+    /// that the widget didn't answer in time must fail, even if nothing else
+    /// happens in the session.
+    ToWidgetTimeoutElapsed,
 }
 
 pub(crate) enum MatrixDriverResponse {
@@ -62,10 +124,34 @@ pub(crate) enum MatrixDriverResponse {
     /// Client read some matrix event(s).
     /// A response to an `Action::ReadMatrixEvent` commands.
     MatrixEventRead(Vec<Raw<AnyTimelineEvent>>),
+    /// Client read a page of the membership events of the room.
+    /// A response to an `Action::ReadMembershipEvents` command.
+    MembershipEventsRead(GetMembershipEventsResponse),
     /// Client sent some matrix event. The response contains the event ID.
     /// A response to an `Action::SendMatrixEvent` command.
     MatrixEventSent(SendEventResponse),
     MatrixDelayedEventUpdate(delayed_events::update_delayed_event::unstable::Response),
+    /// Client reported an event to the homeserver administrators.
+    /// A response to an `Action::ReportEvent` command.
+    EventReported(report_content::v3::Response),
+    /// Client fetched the profile of a user.
+    /// A response to an `Action::GetUserProfile` command.
+    UserProfileReceived(get_profile::v3::Response),
+    /// Client fetched the capabilities of the homeserver.
+    /// A response to an `Action::GetServerCapabilities` command.
+    ServerCapabilitiesReceived(get_capabilities::v3::Capabilities),
+    /// Client got the current requests to join the room.
+    /// A response to an `Action::GetKnockRequests` command.
+    KnockRequestsReceived(Vec<KnockRequest>),
+    /// Client got the server access control list of the room, if it has one.
+    /// A response to an `Action::GetServerAcl` command.
+    ServerAclReceived(Option<RoomServerAclEventContent>),
+    /// Client computed the power level required for an action.
+    /// A response to an `Action::GetRequiredPowerLevel` command.
+    RequiredPowerLevelReceived(RequiredPowerLevel),
+    /// Client navigated to another room.
+    /// A response to an `Action::NavigateToRoom` command.
+    NavigatedToRoom,
 }
 
 pub(super) struct IncomingWidgetMessage {
@@ -115,3 +201,306 @@ impl<'de> Deserialize<'de> for IncomingWidgetMessage {
         Ok(Self { widget_id, request_id, kind })
     }
 }
+
+/// How a message of the widget that isn't a valid [`IncomingWidgetMessage`]
+/// is handled.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum MalformedMessage {
+    /// A `fromWidget` request with a request id, which is answered with the
+    /// given error.
+    ReplyWithError(String),
+
+    /// A message with a request id that can't be answered, e.g. a `toWidget`
+    /// response, which is logged with the given error.
+    Log {
+        /// The id of the request that the message is about.
+        request_id: String,
+        /// The reason why the message is invalid.
+        error: String,
+    },
+
+    /// A message that can't be related to any request, e.g. invalid JSON or
+    /// an array, which is dropped with the given error.
+    Drop(String),
+}
+
+/// Deserializes a message of the widget, or decides how to handle it if it
+/// isn't valid.
+pub(super) fn parse_widget_message(raw: &str) -> Result<IncomingWidgetMessage, MalformedMessage> {
+    let error = match serde_json::from_str::<IncomingWidgetMessage>(raw) {
+        Ok(message) => return Ok(message),
+        Err(error) => error.to_string(),
+    };
+
+    let Ok(object) = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(raw) else {
+        return Err(MalformedMessage::Drop(error));
+    };
+
+    let api = object.get("api").and_then(serde_json::Value::as_str);
+    let request_id = object.get("requestId").and_then(serde_json::Value::as_str);
+
+    Err(match (api, request_id) {
+        (Some("fromWidget"), Some(_)) => MalformedMessage::ReplyWithError(error),
+        (_, Some(request_id)) => MalformedMessage::Log { request_id: request_id.to_owned(), error },
+        (_, None) => MalformedMessage::Drop(error),
+    })
+}
+
+/// Checks whether the given JSON is nested deeper than `max_depth`, without
+/// recursing into it nor allocating.
+pub(super) fn exceeds_json_depth(raw: &str, max_depth: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    // The structural characters of JSON are all ASCII, so they can't be part of
+    // a multi-byte UTF-8 sequence.
+    for b in raw.bytes() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if b == b'\\' {
+                escaped = true;
+            } else if b == b'"' {
+                in_string = false;
+            }
+        } else {
+            match b {
+                b'"' => in_string = true,
+                b'{' | b'[' if depth == max_depth => return true,
+                b'{' | b'[' => depth += 1,
+                b'}' | b']' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+    }
+
+    false
+}
+
+/// Returns a copy of the given JSON in which the values nested deeper than
+/// `max_depth` are replaced by `null`, so that the header of a message that
+/// [exceeds the depth](exceeds_json_depth) can still be deserialized to answer
+/// it.
+pub(super) fn prune_too_deep_json(raw: &str, max_depth: usize) -> String {
+    let mut pruned = String::with_capacity(raw.len());
+    let mut depth = 0usize;
+    // The depth inside of the value which is being replaced, if any.
+    let mut pruned_depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in raw.chars() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else {
+            match c {
+                '"' => in_string = true,
+                '{' | '[' if pruned_depth > 0 => pruned_depth += 1,
+                '{' | '[' if depth == max_depth => {
+                    pruned_depth = 1;
+                    pruned.push_str("null");
+                    continue;
+                }
+                '{' | '[' => depth += 1,
+                '}' | ']' if pruned_depth > 0 => {
+                    pruned_depth -= 1;
+                    continue;
+                }
+                '}' | ']' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+
+        if pruned_depth == 0 {
+            pruned.push(c);
+        }
+    }
+
+    pruned
+}
+
+/// Check that the objects of the given JSON, at any depth, don't have
+/// duplicate keys.
+///
+/// `serde_json` accepts duplicate keys and keeps the last value, which could
+/// hide a spoofed field, e.g. a second `widgetId`. Returns the first
+/// duplicate key found, if any. Invalid JSON is left to the deserialization of
+/// the message to report.
+pub(super) fn find_duplicate_key(raw: &str) -> Option<String> {
+    struct NoDuplicateKeys;
+
+    impl<'de> Deserialize<'de> for NoDuplicateKeys {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(NoDuplicateKeysVisitor)
+        }
+    }
+
+    struct NoDuplicateKeysVisitor;
+
+    impl<'de> de::Visitor<'de> for NoDuplicateKeysVisitor {
+        type Value = NoDuplicateKeys;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+            formatter.write_str("any JSON value")
+        }
+
+        fn visit_bool<E: de::Error>(self, _: bool) -> Result<Self::Value, E> {
+            Ok(NoDuplicateKeys)
+        }
+
+        fn visit_i64<E: de::Error>(self, _: i64) -> Result<Self::Value, E> {
+            Ok(NoDuplicateKeys)
+        }
+
+        fn visit_u64<E: de::Error>(self, _: u64) -> Result<Self::Value, E> {
+            Ok(NoDuplicateKeys)
+        }
+
+        fn visit_f64<E: de::Error>(self, _: f64) -> Result<Self::Value, E> {
+            Ok(NoDuplicateKeys)
+        }
+
+        fn visit_str<E: de::Error>(self, _: &str) -> Result<Self::Value, E> {
+            Ok(NoDuplicateKeys)
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(NoDuplicateKeys)
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            while seq.next_element::<NoDuplicateKeys>()?.is_some() {}
+            Ok(NoDuplicateKeys)
+        }
+
+        fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut keys = BTreeSet::new();
+            while let Some(key) = map.next_key::<String>()? {
+                if keys.contains(&key) {
+                    return Err(de::Error::custom(DuplicateKey(key)));
+                }
+                map.next_value::<NoDuplicateKeys>()?;
+                keys.insert(key);
+            }
+            Ok(NoDuplicateKeys)
+        }
+    }
+
+    /// The error of a duplicate key, whose message is the key itself.
+    struct DuplicateKey(String);
+
+    impl fmt::Display for DuplicateKey {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+
+    match serde_json::from_str::<NoDuplicateKeys>(raw) {
+        Ok(_) => None,
+        Err(error) if error.is_data() => {
+            // Strip the position that `serde_json` adds to the message.
+            let message = error.to_string();
+            let key = message.rsplit_once(" at line ").map_or(message.as_str(), |(key, _)| key);
+            Some(key.to_owned())
+        }
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches2::{assert_let, assert_matches};
+    use serde_json::json;
+
+    use super::{
+        exceeds_json_depth, parse_widget_message, prune_too_deep_json, IncomingWidgetMessageKind,
+        MalformedMessage,
+    };
+
+    #[test]
+    fn valid_request_is_parsed() {
+        let raw = json!({
+            "api": "fromWidget",
+            "widgetId": "w",
+            "requestId": "1",
+            "action": "content_loaded",
+            "data": {},
+        });
+        let message = parse_widget_message(&raw.to_string()).unwrap();
+
+        assert_eq!(message.widget_id, "w");
+        assert_eq!(message.request_id, "1");
+        assert_matches!(message.kind, IncomingWidgetMessageKind::Request(_));
+    }
+
+    #[test]
+    fn valid_response_is_parsed() {
+        let raw = json!({
+            "api": "toWidget",
+            "widgetId": "w",
+            "requestId": "1",
+            "action": "send_event",
+            "data": {},
+            "response": {},
+        });
+        let message = parse_widget_message(&raw.to_string()).unwrap();
+
+        assert_eq!(message.request_id, "1");
+        assert_matches!(message.kind, IncomingWidgetMessageKind::Response(_));
+    }
+
+    #[test]
+    fn request_without_widget_id_is_answered_with_an_error() {
+        let malformed = parse_widget_message(
+            r#"{"api":"fromWidget","requestId":"1","action":"content_loaded","data":{}}"#,
+        )
+        .unwrap_err();
+
+        assert_matches!(malformed, MalformedMessage::ReplyWithError(_));
+    }
+
+    #[test]
+    fn object_with_a_response_field_is_logged() {
+        let malformed =
+            parse_widget_message(r#"{"api":"toWidget","requestId":"1","response":null}"#)
+                .unwrap_err();
+
+        assert_let!(MalformedMessage::Log { request_id, .. } = malformed);
+        assert_eq!(request_id, "1");
+    }
+
+    #[test]
+    fn array_is_dropped() {
+        let malformed =
+            parse_widget_message(r#"[{"api":"fromWidget","requestId":"1"}]"#).unwrap_err();
+        assert_matches!(malformed, MalformedMessage::Drop(_));
+    }
+
+    #[test]
+    fn unparseable_bytes_are_dropped() {
+        let malformed = parse_widget_message("\u{0}{not json").unwrap_err();
+        assert_matches!(malformed, MalformedMessage::Drop(_));
+    }
+
+    #[test]
+    fn depth_is_scanned_outside_of_strings() {
+        let raw = r#"{"a":[{"b":"[[[{{{\"]]"}]}"#;
+
+        assert!(!exceeds_json_depth(raw, 3));
+        assert!(exceeds_json_depth(raw, 2));
+    }
+
+    #[test]
+    fn too_deep_values_are_pruned() {
+        let raw = r#"{"requestId":"1","data":{"a":[[1]],"b":"{["}}"#;
+
+        assert_eq!(prune_too_deep_json(raw, 2), r#"{"requestId":"1","data":{"a":null,"b":"{["}}"#);
+    }
+}
@@ -14,46 +14,70 @@
 
 //! No I/O logic of the [`WidgetDriver`].
 
-use std::time::Duration;
+use std::{
+    collections::{BTreeSet, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
 
 use driver_req::UpdateDelayedEventRequest;
-use from_widget::UpdateDelayedEventResponse;
+use from_widget::{
+    GetKnockRequestsResponse, GetRequiredPowerLevelResponse, GetServerAclResponse,
+    GetServerCapabilitiesResponse, GetUserProfileResponse, ReportEventResponse,
+    UpdateDelayedEventResponse,
+};
 use indexmap::IndexMap;
+use mime::Mime;
 use ruma::{
+    events::AnyTimelineEvent,
     serde::{JsonObject, Raw},
-    OwnedRoomId,
+    OwnedEventId, OwnedRoomId,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue as RawJsonValue;
 use tracing::{error, info, instrument, warn};
 use uuid::Uuid;
 
 use self::{
     driver_req::{
-        AcquireCapabilities, MatrixDriverRequest, MatrixDriverRequestHandle,
-        ReadMessageLikeEventRequest, RequestOpenId,
+        AcquireCapabilities, MatrixDriverRequest, MatrixDriverRequestHandle, NavigateToRoomRequest,
+        ReadMembershipEventsRequest, ReadMessageLikeEventRequest, RequestOpenId,
+        SendAttachmentRequest,
     },
     from_widget::{
         FromWidgetErrorResponse, FromWidgetRequest, ReadEventRequest, ReadEventResponse,
         SupportedApiVersionsResponse,
     },
-    incoming::{IncomingWidgetMessage, IncomingWidgetMessageKind},
+    incoming::{
+        exceeds_json_depth, find_duplicate_key, parse_widget_message, prune_too_deep_json,
+        IncomingWidgetMessageKind, MalformedMessage,
+    },
     openid::{OpenIdResponse, OpenIdState},
     pending::{PendingRequests, RequestLimits},
     to_widget::{
-        NotifyCapabilitiesChanged, NotifyNewMatrixEvent, NotifyOpenIdChanged, RequestCapabilities,
-        ToWidgetRequest, ToWidgetRequestHandle, ToWidgetResponse,
+        NotifyCapabilitiesChanged, NotifyLanguageChanged, NotifyNewEphemeralEvent,
+        NotifyNewMatrixEvent, NotifyOpenIdChanged, NotifyThemeChanged, NotifyWatchedEventUpdate,
+        RequestCapabilities, ToWidgetRequest, ToWidgetRequestHandle, ToWidgetResponse,
+        ToWidgetTimeout,
     },
 };
+#[cfg(test)]
+use super::clock::SystemClock;
 #[cfg(doc)]
 use super::WidgetDriver;
 use super::{
-    capabilities::{SEND_DELAYED_EVENT, UPDATE_DELAYED_EVENT},
+    capabilities::{
+        DeprecatedCapabilities, ALWAYS_ON_SCREEN, GET_SERVER_CAPABILITIES, GET_USER_PROFILE,
+        NAVIGATE, READ_KNOCK_REQUESTS, REPORT_EVENT, SEND_DELAYED_EVENT, UPDATE_DELAYED_EVENT,
+        UPLOAD_FILE,
+    },
+    clock::Clock,
     filter::FilterInput,
-    Capabilities, StateKeySelector,
+    Capabilities, SessionEnd, SessionTrace, StateKeySelector,
 };
 use crate::Result;
 
+mod delayed_events;
 mod driver_req;
 mod from_widget;
 mod incoming;
@@ -64,11 +88,35 @@ mod tests;
 mod to_widget;
 
 pub(crate) use self::{
-    driver_req::{MatrixDriverRequestData, ReadStateEventRequest, SendEventRequest},
-    from_widget::SendEventResponse,
+    delayed_events::DelayedEvents,
+    driver_req::{
+        GetRequiredPowerLevelRequest, MatrixDriverRequestData, ModerationAction,
+        ReadStateEventRequest, RequiredPowerLevel, SendEventRequest, StateUpdateMode,
+    },
+    from_widget::{GetMembershipEventsResponse, SendEventResponse},
     incoming::{IncomingMessage, MatrixDriverResponse},
 };
 
+/// The default maximum nesting depth of the messages received from the widget.
+pub(crate) const DEFAULT_MAX_MESSAGE_DEPTH: usize = 64;
+
+/// The default time that the widget has to answer the requests sent to it.
+pub(crate) const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The time after which an unanswered request to the `MatrixDriver` is
+/// considered failed.
+const MATRIX_DRIVER_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum number of events held back while the session is paused; the
+/// oldest ones are dropped beyond it.
+///
+/// This is below the limit of pending requests to the widget, so that all of
+/// them can be delivered at once when the session is resumed.
+const MAX_PAUSED_EVENTS: usize = 10;
+
+/// The maximum number of events that a widget can watch at the same time.
+const MAX_WATCHED_EVENTS: usize = 50;
+
 /// A command to perform in reaction to an [`IncomingMessage`].
 ///
 /// There are also initial actions that may be performed at the creation of a
@@ -100,6 +148,17 @@ pub(crate) enum Action {
     /// Unsuscribe from the events in the *current* room. Symmetrical to
     /// `Subscribe`.
     Unsubscribe,
+
+    /// Let the client know that the widget wants to join, e.g. a preloaded
+    /// Element Call widget which joins the call.
+    Join,
+
+    /// Let the client know whether the widget wants to be kept visible on the
+    /// screen.
+    SetAlwaysOnScreen(bool),
+
+    /// End the session, because the client can't serve the widget anymore.
+    EndSession(SessionEnd),
 }
 
 /// No I/O state machine.
@@ -123,26 +182,115 @@ pub(crate) struct WidgetMachine {
 
     /// Current negotiation state for capabilities.
     capabilities: CapabilitiesState,
+
+    /// Whether the widget already sent a `content_loaded` request.
+    content_loaded: bool,
+
+    /// The event types that are never forwarded to the widget, regardless of
+    /// its capabilities.
+    denied_event_types: Vec<String>,
+
+    /// The deprecated capability identifiers that the widget may request.
+    deprecated_capabilities: DeprecatedCapabilities,
+
+    /// The events held back while the session is paused, or `None` if it
+    /// isn't paused.
+    paused_events: Option<VecDeque<Raw<AnyTimelineEvent>>>,
+
+    /// The events whose edits, reactions and redaction are notified to the
+    /// widget.
+    watched_events: BTreeSet<OwnedEventId>,
+
+    /// The delayed events sent by the widget that can still be updated.
+    delayed_events: DelayedEvents,
+
+    /// The source of time of the machine.
+    clock: Arc<dyn Clock>,
+
+    /// The maximum nesting depth of the messages received from the widget.
+    max_message_depth: usize,
+
+    /// Whether the messages received from the widget with duplicate keys are
+    /// rejected.
+    strict_json: bool,
+
+    /// Whether the user already consented to share their OpenID identity with
+    /// the widget.
+    openid_pre_consented: bool,
+
+    /// The data of the latest critical `toWidget` requests, by action, to send
+    /// them again when the widget reconnects.
+    critical_to_widget_requests: IndexMap<&'static str, Box<RawJsonValue>>,
+
+    /// The trace recording the messages and actions of the session, if
+    /// enabled.
+    session_trace: Option<SessionTrace>,
+
+    /// The latest theme of the client notified to the widget, if any.
+    client_theme: Option<String>,
+
+    /// The latest language of the client notified to the widget, if any.
+    client_language: Option<String>,
 }
 
 impl WidgetMachine {
-    /// Creates a new instance of a client widget API state machine.
+    /// Creates a new instance of a client widget API state machine, which uses
+    /// the time of the system.
+    #[cfg(test)]
+    pub(crate) fn new(
+        widget_id: String,
+        room_id: OwnedRoomId,
+        init_on_content_load: bool,
+    ) -> (Self, Vec<Action>) {
+        Self::with_clock(
+            widget_id,
+            room_id,
+            init_on_content_load,
+            DEFAULT_RESPONSE_TIMEOUT,
+            Arc::new(SystemClock),
+        )
+    }
+
+    /// Creates a new instance of a client widget API state machine, whose
+    /// pending requests expire according to the given clock.
     /// Returns the client api handler as well as the channel to receive
     /// actions (commands) from the client.
-    pub(crate) fn new(
+    ///
+    /// The widget must answer the requests sent to it within
+    /// `to_widget_timeout`.
+    pub(crate) fn with_clock(
         widget_id: String,
         room_id: OwnedRoomId,
         init_on_content_load: bool,
+        to_widget_timeout: Duration,
+        clock: Arc<dyn Clock>,
     ) -> (Self, Vec<Action>) {
-        let limits =
-            RequestLimits { max_pending_requests: 15, response_timeout: Duration::from_secs(10) };
+        let limits = RequestLimits {
+            max_pending_requests: 15,
+            response_timeout: MATRIX_DRIVER_RESPONSE_TIMEOUT,
+        };
+        let to_widget_limits = RequestLimits { response_timeout: to_widget_timeout, ..limits };
 
         let mut machine = Self {
             widget_id,
             room_id,
-            pending_to_widget_requests: PendingRequests::new(limits.clone()),
-            pending_matrix_driver_requests: PendingRequests::new(limits),
+            pending_to_widget_requests: PendingRequests::new(to_widget_limits, clock.clone()),
+            pending_matrix_driver_requests: PendingRequests::new(limits, clock.clone()),
             capabilities: CapabilitiesState::Unset,
+            content_loaded: false,
+            denied_event_types: Vec::new(),
+            deprecated_capabilities: DeprecatedCapabilities::default(),
+            paused_events: None,
+            watched_events: BTreeSet::new(),
+            delayed_events: DelayedEvents::default(),
+            clock,
+            max_message_depth: DEFAULT_MAX_MESSAGE_DEPTH,
+            strict_json: false,
+            openid_pre_consented: false,
+            critical_to_widget_requests: IndexMap::new(),
+            session_trace: None,
+            client_theme: None,
+            client_language: None,
         };
 
         let initial_actions =
@@ -151,12 +299,130 @@ impl WidgetMachine {
         (machine, initial_actions)
     }
 
+    /// Set the maximum nesting depth of the messages received from the widget.
+    pub(crate) fn set_max_message_depth(&mut self, max_message_depth: usize) {
+        self.max_message_depth = max_message_depth;
+    }
+
+    /// Set whether the messages received from the widget with duplicate keys
+    /// are rejected, instead of keeping the last value of each key.
+    pub(crate) fn set_strict_json(&mut self, strict_json: bool) {
+        self.strict_json = strict_json;
+    }
+
+    /// Set whether the user already consented to share their OpenID identity
+    /// with the widget, to answer its `get_openid` requests without a pending
+    /// state.
+    pub(crate) fn set_openid_pre_consented(&mut self, pre_consented: bool) {
+        self.openid_pre_consented = pre_consented;
+    }
+
+    /// Set the trace recording the messages that the machine processes and the
+    /// actions that it emits from now on.
+    pub(crate) fn set_session_trace(&mut self, session_trace: SessionTrace) {
+        self.session_trace = Some(session_trace);
+    }
+
+    /// Set the delayed events that the widget can update, e.g. the ones sent in
+    /// a previous session.
+    pub(crate) fn set_delayed_events(&mut self, delayed_events: DelayedEvents) {
+        self.delayed_events = delayed_events;
+    }
+
+    /// Set the event types that are never forwarded to the widget, regardless
+    /// of its capabilities.
+    pub(crate) fn set_denied_event_types(&mut self, denied_event_types: Vec<String>) {
+        self.denied_event_types = denied_event_types;
+    }
+
+    /// Set the deprecated capability identifiers that the widget may request,
+    /// to warn about them during the capabilities negotiation.
+    pub(crate) fn set_deprecated_capabilities(
+        &mut self,
+        deprecated_capabilities: DeprecatedCapabilities,
+    ) {
+        self.deprecated_capabilities = deprecated_capabilities;
+    }
+
+    /// The id of the watched event that the given event edits, reacts to or
+    /// redacts, if any.
+    fn watched_event_updated_by(&self, event: &Raw<AnyTimelineEvent>) -> Option<OwnedEventId> {
+        #[derive(Deserialize)]
+        struct RelatesTo {
+            rel_type: Option<String>,
+            event_id: Option<OwnedEventId>,
+        }
+
+        #[derive(Deserialize)]
+        struct UpdateContent {
+            #[serde(rename = "m.relates_to")]
+            relates_to: Option<RelatesTo>,
+            redacts: Option<OwnedEventId>,
+        }
+
+        if self.watched_events.is_empty() {
+            return None;
+        }
+
+        // The redacted event is at the top level before room version 11, and in the
+        // content since then.
+        let redacts = event.get_field::<OwnedEventId>("redacts").ok().flatten();
+        let content = event.get_field::<UpdateContent>("content").ok().flatten();
+
+        let target = redacts.or_else(|| {
+            let content = content?;
+            content.redacts.or_else(|| {
+                let relates_to = content.relates_to?;
+                matches!(relates_to.rel_type.as_deref(), Some("m.replace" | "m.annotation"))
+                    .then_some(relates_to.event_id)
+                    .flatten()
+            })
+        })?;
+
+        self.watched_events.contains(&target).then_some(target)
+    }
+
+    /// Whether the type of the given event is denied to the widget.
+    fn is_event_type_denied<T>(&self, event: &Raw<T>) -> bool {
+        if self.denied_event_types.is_empty() {
+            return false;
+        }
+
+        match event.get_field::<String>("type") {
+            Ok(Some(event_type)) => self.denied_event_types.contains(&event_type),
+            // Don't let an event through if we can't tell whether it is denied.
+            _ => true,
+        }
+    }
+
     /// Main entry point to drive the state machine.
     pub(crate) fn process(&mut self, event: IncomingMessage) -> Vec<Action> {
-        // Clean up stale requests.
-        self.pending_to_widget_requests.remove_expired();
+        if let Some(session_trace) = &self.session_trace {
+            session_trace.record_incoming(&event);
+        }
+
+        // Clean up stale requests, and let the handlers of the requests that the
+        // widget didn't answer know that they timed out.
+        let mut actions = Vec::new();
+        for request in self.pending_to_widget_requests.remove_expired() {
+            let timeout = ToWidgetTimeout { action: request.action };
+            warn!("{timeout}");
+            if let Some(response_fn) = request.response_fn {
+                actions.extend(response_fn(Err(timeout), self));
+            }
+        }
         self.pending_matrix_driver_requests.remove_expired();
 
+        actions.extend(self.process_incoming_message(event));
+
+        if let Some(session_trace) = &self.session_trace {
+            session_trace.record_actions(&actions);
+        }
+
+        actions
+    }
+
+    fn process_incoming_message(&mut self, event: IncomingMessage) -> Vec<Action> {
         match event {
             IncomingMessage::WidgetMessage(widget_message_raw) => {
                 self.process_widget_message(&widget_message_raw)
@@ -170,25 +436,176 @@ impl WidgetMachine {
                     return Vec::new();
                 };
 
-                capabilities
-                    .allow_reading(&event)
+                if !capabilities.allow_reading(&event) || self.is_event_type_denied(&event) {
+                    return Vec::new();
+                }
+
+                if let Some(paused_events) = &mut self.paused_events {
+                    if paused_events.len() >= MAX_PAUSED_EVENTS {
+                        warn!("Too many events received while paused, dropping the oldest one");
+                        paused_events.pop_front();
+                    }
+                    paused_events.push_back(event);
+                    return Vec::new();
+                }
+
+                let watched_event_id = self.watched_event_updated_by(&event);
+
+                let mut actions = self
+                    .send_to_widget_request(NotifyNewMatrixEvent(event.clone()))
+                    .map(|(_request, action)| vec![action])
+                    .unwrap_or_default();
+
+                if let Some(event_id) = watched_event_id {
+                    actions.extend(
+                        self.send_to_widget_request(NotifyWatchedEventUpdate { event_id, event })
+                            .map(|(_request, action)| action),
+                    );
+                }
+
+                actions
+            }
+            IncomingMessage::MatrixEphemeralEventReceived(event) => {
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    error!("Received ephemeral event before capabilities negotiation");
+                    return Vec::new();
+                };
+
+                if self.paused_events.is_some() {
+                    // Ephemeral events are outdated by the time the session is resumed.
+                    return Vec::new();
+                }
+
+                let allowed = match event.get_field::<String>("type") {
+                    Ok(Some(event_type)) => capabilities.allow_reading_ephemeral(&event_type),
+                    _ => {
+                        warn!("Failed to read the type of an ephemeral event");
+                        false
+                    }
+                };
+
+                (allowed && !self.is_event_type_denied(&event))
                     .then(|| {
-                        self.send_to_widget_request(NotifyNewMatrixEvent(event))
+                        self.send_to_widget_request(NotifyNewEphemeralEvent(event))
                             .map(|(_request, action)| vec![action])
                             .unwrap_or_default()
                     })
                     .unwrap_or_default()
             }
+            IncomingMessage::ThemeChanged(name) => {
+                self.client_theme = Some(name.clone());
+                self.send_to_widget_request(NotifyThemeChanged { name })
+                    .map(|(_request, action)| vec![action])
+                    .unwrap_or_default()
+            }
+            IncomingMessage::ClientPropertiesChanged { theme, language } => {
+                // Only notify the widget about the properties that changed.
+                let theme_action = if self.client_theme.as_ref() != Some(&theme) {
+                    self.client_theme = Some(theme.clone());
+                    self.send_to_widget_request(NotifyThemeChanged { name: theme })
+                        .map(|(_request, action)| action)
+                } else {
+                    None
+                };
+                let language_action = if self.client_language.as_ref() != Some(&language) {
+                    self.client_language = Some(language.clone());
+                    self.send_to_widget_request(NotifyLanguageChanged { lang: language })
+                        .map(|(_request, action)| action)
+                } else {
+                    None
+                };
+                theme_action.into_iter().chain(language_action).collect()
+            }
+            IncomingMessage::UpdateCapabilities(desired_capabilities) => {
+                self.update_capabilities(desired_capabilities)
+            }
+            IncomingMessage::Pause => {
+                if self.paused_events.is_none() {
+                    self.paused_events = Some(VecDeque::new());
+                }
+                Vec::new()
+            }
+            IncomingMessage::Resume => {
+                let paused_events = self.paused_events.take().unwrap_or_default();
+
+                // The capabilities may have been revoked while the session was paused, so
+                // the held back events are checked again like the live ones.
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    return Vec::new();
+                };
+                let allowed_events: Vec<_> = paused_events
+                    .into_iter()
+                    .filter(|event| {
+                        capabilities.allow_reading(event) && !self.is_event_type_denied(event)
+                    })
+                    .collect();
+
+                allowed_events
+                    .into_iter()
+                    .filter_map(|event| {
+                        self.send_to_widget_request(NotifyNewMatrixEvent(event))
+                            .map(|(_request, action)| action)
+                    })
+                    .collect()
+            }
+            IncomingMessage::WidgetReconnected => {
+                // The reloaded widget will load its content again.
+                self.content_loaded = false;
+
+                self.critical_to_widget_requests
+                    .clone()
+                    .into_iter()
+                    .filter_map(|(action, data)| {
+                        self.send_raw_to_widget_request(action, data).map(|(_meta, action)| action)
+                    })
+                    .collect()
+            }
+            IncomingMessage::ClientUnavailable => {
+                vec![Action::EndSession(SessionEnd::ClientUnavailable)]
+            }
+            IncomingMessage::RoomUnavailable => {
+                vec![Action::EndSession(SessionEnd::RoomUnavailable)]
+            }
+            // The expired requests were already removed before processing the
+            // message.
+            IncomingMessage::ToWidgetTimeoutElapsed => Vec::new(),
         }
     }
 
     fn process_widget_message(&mut self, raw: &str) -> Vec<Action> {
-        let message = match serde_json::from_str::<IncomingWidgetMessage>(raw) {
-            Ok(msg) => msg,
-            Err(error) => {
-                error!("couldn't deserialize incoming widget message: {error}");
-                return Vec::new();
+        // Don't report a misleading JSON error for messages without any content.
+        if raw.trim().is_empty() {
+            warn!("Received an empty message from the widget, ignoring");
+            return Vec::new();
+        }
+
+        // Check the depth of the message before deserializing it, so that a
+        // malicious widget can't exhaust the stack. The message is only copied
+        // when it needs to be pruned to be answered.
+        if exceeds_json_depth(raw, self.max_message_depth) {
+            let pruned = prune_too_deep_json(raw, self.max_message_depth);
+            let error = format!(
+                "The message exceeds the maximum nesting depth of {}",
+                self.max_message_depth
+            );
+            return self.reject_message(&pruned, error);
+        }
+
+        if self.strict_json {
+            if let Some(key) = find_duplicate_key(raw) {
+                // Keep the last value of each key to answer the message.
+                let Ok(deduplicated) = serde_json::from_str::<serde_json::Value>(raw) else {
+                    error!("couldn't deserialize incoming widget message with duplicate keys");
+                    return Vec::new();
+                };
+                let error = format!("The message has a duplicate `{key}` key");
+                return self.reject_message(&deduplicated.to_string(), error);
             }
+        }
+
+        let message = match parse_widget_message(raw) {
+            Ok(msg) => msg,
+            Err(malformed) => return self.process_malformed_message(raw, malformed),
         };
 
         if message.widget_id != self.widget_id {
@@ -206,6 +623,73 @@ impl WidgetMachine {
         }
     }
 
+    /// Handles a message of the widget that can't be deserialized, as decided
+    /// by [`parse_widget_message`].
+    fn process_malformed_message(&self, raw: &str, malformed: MalformedMessage) -> Vec<Action> {
+        match malformed {
+            MalformedMessage::ReplyWithError(error) => {
+                // The message is a JSON object, so it can be answered like a valid request.
+                let Ok(raw_request) = Raw::<FromWidgetRequest>::from_json_string(raw.to_owned())
+                else {
+                    error!("couldn't deserialize incoming widget message: {error}");
+                    return Vec::new();
+                };
+
+                // Only a message without any widget id can't be told apart from the ones of
+                // this widget.
+                match raw_request.get_field::<serde_json::Value>("widgetId") {
+                    Ok(None) => {}
+                    Ok(Some(serde_json::Value::String(widget_id)))
+                        if widget_id == self.widget_id => {}
+                    _ => {
+                        error!("Received a message from a wrong widget, ignoring");
+                        return Vec::new();
+                    }
+                }
+
+                vec![Self::send_from_widget_error_string_response(
+                    raw_request,
+                    format!("Invalid request: {error}"),
+                )]
+            }
+            MalformedMessage::Log { request_id, error } => {
+                error!(request_id, "couldn't deserialize incoming widget message: {error}");
+                Vec::new()
+            }
+            MalformedMessage::Drop(error) => {
+                warn!("Received a message that isn't a widget API message, ignoring: {error}");
+                Vec::new()
+            }
+        }
+    }
+
+    /// Answers a message that can't be processed with the given error, if it's
+    /// a request.
+    ///
+    /// `raw` must be safe to deserialize, e.g. a message nested too deeply
+    /// with its values nested too deeply replaced by `null`.
+    fn reject_message(&self, raw: &str, error: String) -> Vec<Action> {
+        let message = match parse_widget_message(raw) {
+            Ok(msg) => msg,
+            Err(malformed) => return self.process_malformed_message(raw, malformed),
+        };
+
+        if message.widget_id != self.widget_id {
+            error!("Received a message from a wrong widget, ignoring");
+            return Vec::new();
+        }
+
+        match message.kind {
+            IncomingWidgetMessageKind::Request(raw_request) => {
+                vec![Self::send_from_widget_error_string_response(raw_request, error)]
+            }
+            IncomingWidgetMessageKind::Response(_) => {
+                error!("{error}, ignoring the response");
+                Vec::new()
+            }
+        }
+    }
+
     #[instrument(skip_all, fields(?request_id))]
     fn process_from_widget_request(
         &mut self,
@@ -229,6 +713,16 @@ impl WidgetMachine {
             }
 
             FromWidgetRequest::ContentLoaded {} => {
+                // The content can only be loaded again to restart a capabilities negotiation
+                // that timed out.
+                if self.content_loaded && !matches!(self.capabilities, CapabilitiesState::Unset) {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "The content of the widget was already loaded",
+                    )];
+                }
+                self.content_loaded = true;
+
                 let mut response =
                     vec![Self::send_from_widget_response(raw_request, Ok(JsonObject::new()))];
                 if matches!(self.capabilities, CapabilitiesState::Unset) {
@@ -237,6 +731,67 @@ impl WidgetMachine {
                 response
             }
 
+            FromWidgetRequest::Join {} => {
+                vec![
+                    Self::send_from_widget_response(raw_request, Ok(JsonObject::new())),
+                    Action::Join,
+                ]
+            }
+
+            FromWidgetRequest::SetAlwaysOnScreen(req) => {
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Received always on screen request before capabilities were negotiated",
+                    )];
+                };
+
+                if !capabilities.always_on_screen {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        format!("Not allowed: missing the {ALWAYS_ON_SCREEN} capability."),
+                    )];
+                }
+
+                vec![
+                    Self::send_from_widget_response(raw_request, Ok(JsonObject::new())),
+                    Action::SetAlwaysOnScreen(req.value),
+                ]
+            }
+
+            FromWidgetRequest::WatchEvent(req) => {
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Received watch event request before capabilities were negotiated",
+                    )];
+                };
+
+                if capabilities.read.is_empty() {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Not allowed: missing the capability to read events.",
+                    )];
+                }
+
+                if !self.watched_events.contains(&req.event_id)
+                    && self.watched_events.len() >= MAX_WATCHED_EVENTS
+                {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        format!("Too many watched events, the limit is {MAX_WATCHED_EVENTS}"),
+                    )];
+                }
+
+                self.watched_events.insert(req.event_id);
+                vec![Self::send_from_widget_response(raw_request, Ok(JsonObject::new()))]
+            }
+
+            FromWidgetRequest::UnwatchEvent(req) => {
+                self.watched_events.remove(&req.event_id);
+                vec![Self::send_from_widget_response(raw_request, Ok(JsonObject::new()))]
+            }
+
             FromWidgetRequest::ReadEvent(req) => self
                 .process_read_event_request(req, raw_request)
                 .map(|a| vec![a])
@@ -247,6 +802,30 @@ impl WidgetMachine {
                 .map(|a| vec![a])
                 .unwrap_or_default(),
 
+            FromWidgetRequest::GetOpenId {} if self.openid_pre_consented => {
+                // Answer the request directly once we got the token, the user doesn't need to
+                // be asked.
+                self.send_matrix_driver_request(RequestOpenId)
+                    .map(|(request, request_action)| {
+                        request.then(|res, _machine| {
+                            let response = match res {
+                                Ok(res) => {
+                                    OpenIdResponse::Allowed(OpenIdState::new(request_id, res))
+                                }
+                                Err(msg) => {
+                                    info!("OpenID request failed: {msg}");
+                                    OpenIdResponse::Blocked { original_request_id: request_id }
+                                }
+                            };
+
+                            vec![Self::send_from_widget_response(raw_request, Ok(response))]
+                        });
+
+                        vec![request_action]
+                    })
+                    .unwrap_or_default()
+            }
+
             FromWidgetRequest::GetOpenId {} => {
                 let mut actions =
                     vec![Self::send_from_widget_response(raw_request, Ok(OpenIdResponse::Pending))];
@@ -292,26 +871,451 @@ impl WidgetMachine {
                     )];
                 }
 
-                self.send_matrix_driver_request(UpdateDelayedEventRequest {
-                    action: req.action,
-                    delay_id: req.delay_id,
-                })
-                .map(|(request, request_action)| {
-                    request.then(|result, _machine| {
-                        vec![Self::send_from_widget_response(
+                let previous_restart = match self.delayed_events.start_update(
+                    &req.delay_id,
+                    &req.action,
+                    self.clock.now(),
+                ) {
+                    Ok(previous_restart) => previous_restart,
+                    Err(error) => {
+                        return vec![Self::send_from_widget_error_string_response(
                             raw_request,
-                            // This is mapped to another type because the
-                            // update_delay_event::Response
-                            // does not impl Serialize
-                            result
-                                .map(Into::<UpdateDelayedEventResponse>::into)
-                                .map_err(FromWidgetErrorResponse::from_error),
-                        )]
-                    });
+                            error,
+                        )];
+                    }
+                };
 
-                    vec![request_action]
-                })
-                .unwrap_or_default()
+                let delay_id = req.delay_id.clone();
+                let action = req.action.clone();
+                let Some((request, request_action)) =
+                    self.send_matrix_driver_request(UpdateDelayedEventRequest {
+                        action: req.action,
+                        delay_id: req.delay_id,
+                    })
+                else {
+                    self.delayed_events.finish_update(&delay_id, &action, false, previous_restart);
+                    return Vec::new();
+                };
+
+                request.then(move |result, machine| {
+                    machine.delayed_events.finish_update(
+                        &delay_id,
+                        &action,
+                        result.is_ok(),
+                        previous_restart,
+                    );
+
+                    vec![Self::send_from_widget_response(
+                        raw_request,
+                        // This is mapped to another type because the
+                        // update_delay_event::Response
+                        // does not impl Serialize
+                        result
+                            .map(Into::<UpdateDelayedEventResponse>::into)
+                            .map_err(FromWidgetErrorResponse::from_error),
+                    )]
+                });
+
+                vec![request_action]
+            }
+
+            FromWidgetRequest::ReportEvent(req) => {
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Received report event request before capabilities were negotiated",
+                    )];
+                };
+
+                if !capabilities.report_event {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        format!("Not allowed: missing the {REPORT_EVENT} capability."),
+                    )];
+                }
+
+                self.send_matrix_driver_request(req)
+                    .map(|(request, request_action)| {
+                        request.then(|result, _machine| {
+                            vec![Self::send_from_widget_response(
+                                raw_request,
+                                result
+                                    .map(Into::<ReportEventResponse>::into)
+                                    .map_err(FromWidgetErrorResponse::from_error),
+                            )]
+                        });
+
+                        vec![request_action]
+                    })
+                    .unwrap_or_default()
+            }
+
+            FromWidgetRequest::GetUserProfile(req) => {
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Received get user profile request before capabilities were negotiated",
+                    )];
+                };
+
+                if !capabilities.get_user_profile {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        format!("Not allowed: missing the {GET_USER_PROFILE} capability."),
+                    )];
+                }
+
+                self.send_matrix_driver_request(req)
+                    .map(|(request, request_action)| {
+                        request.then(|result, _machine| {
+                            vec![Self::send_from_widget_response(
+                                raw_request,
+                                result
+                                    .map(Into::<GetUserProfileResponse>::into)
+                                    .map_err(FromWidgetErrorResponse::from_error),
+                            )]
+                        });
+
+                        vec![request_action]
+                    })
+                    .unwrap_or_default()
+            }
+
+            FromWidgetRequest::GetServerCapabilities(req) => {
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Received get server capabilities request before capabilities were negotiated",
+                    )];
+                };
+
+                if !capabilities.get_server_capabilities {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        format!("Not allowed: missing the {GET_SERVER_CAPABILITIES} capability."),
+                    )];
+                }
+
+                self.send_matrix_driver_request(req)
+                    .map(|(request, request_action)| {
+                        request.then(|result, _machine| {
+                            vec![Self::send_from_widget_response(
+                                raw_request,
+                                result
+                                    .map(Into::<GetServerCapabilitiesResponse>::into)
+                                    .map_err(FromWidgetErrorResponse::from_error),
+                            )]
+                        });
+
+                        vec![request_action]
+                    })
+                    .unwrap_or_default()
+            }
+
+            FromWidgetRequest::GetKnockRequests(req) => {
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Received get knock requests request before capabilities were negotiated",
+                    )];
+                };
+
+                if !capabilities.read_knock_requests {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        format!("Not allowed: missing the {READ_KNOCK_REQUESTS} capability."),
+                    )];
+                }
+
+                self.send_matrix_driver_request(req)
+                    .map(|(request, request_action)| {
+                        request.then(|result, _machine| {
+                            vec![Self::send_from_widget_response(
+                                raw_request,
+                                result
+                                    .map(Into::<GetKnockRequestsResponse>::into)
+                                    .map_err(FromWidgetErrorResponse::from_error),
+                            )]
+                        });
+
+                        vec![request_action]
+                    })
+                    .unwrap_or_default()
+            }
+
+            FromWidgetRequest::GetMembershipEvents(req) => {
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Received get membership events request before capabilities were negotiated",
+                    )];
+                };
+
+                if !capabilities.has_read_filter_for_type("m.room.member") {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Not allowed to read m.room.member events",
+                    )];
+                }
+
+                const DEFAULT_EVENT_LIMIT: u32 = 50;
+                const MAX_EVENT_LIMIT: u32 = 100;
+                let request = ReadMembershipEventsRequest {
+                    user_id: req.user_id,
+                    limit: req.limit.unwrap_or(DEFAULT_EVENT_LIMIT).min(MAX_EVENT_LIMIT),
+                    from: req.from,
+                };
+
+                self.send_matrix_driver_request(request)
+                    .map(|(request, request_action)| {
+                        request.then(|result, machine| {
+                            let capabilities = match &machine.capabilities {
+                                CapabilitiesState::Negotiated(capabilities) => Some(capabilities),
+                                _ => None,
+                            };
+                            let response = result
+                                .map(|mut response| {
+                                    response.events.retain(|e| {
+                                        capabilities.is_some_and(|c| c.allow_reading(e))
+                                            && !machine.is_event_type_denied(e)
+                                    });
+                                    response
+                                })
+                                .map_err(FromWidgetErrorResponse::from_error);
+                            vec![Self::send_from_widget_response(raw_request, response)]
+                        });
+
+                        vec![request_action]
+                    })
+                    .unwrap_or_default()
+            }
+
+            FromWidgetRequest::GetServerAcl(req) => {
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Received get server ACL request before capabilities were negotiated",
+                    )];
+                };
+
+                if !capabilities.allow_reading(FilterInput::state("m.room.server_acl", "")) {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Not allowed to read the m.room.server_acl state event",
+                    )];
+                }
+
+                self.send_matrix_driver_request(req)
+                    .map(|(request, request_action)| {
+                        request.then(|result, _machine| {
+                            vec![Self::send_from_widget_response(
+                                raw_request,
+                                result
+                                    .map(Into::<GetServerAclResponse>::into)
+                                    .map_err(FromWidgetErrorResponse::from_error),
+                            )]
+                        });
+
+                        vec![request_action]
+                    })
+                    .unwrap_or_default()
+            }
+
+            FromWidgetRequest::GetRequiredPowerLevel(req) => {
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Received get required power level request before capabilities were \
+                         negotiated",
+                    )];
+                };
+
+                if !capabilities.allow_reading(FilterInput::state("m.room.power_levels", "")) {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Not allowed to read the m.room.power_levels state event",
+                    )];
+                }
+
+                self.send_matrix_driver_request(req)
+                    .map(|(request, request_action)| {
+                        request.then(|result, _machine| {
+                            vec![Self::send_from_widget_response(
+                                raw_request,
+                                result
+                                    .map(Into::<GetRequiredPowerLevelResponse>::into)
+                                    .map_err(FromWidgetErrorResponse::from_error),
+                            )]
+                        });
+
+                        vec![request_action]
+                    })
+                    .unwrap_or_default()
+            }
+
+            FromWidgetRequest::SendReaction(req) => {
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Received send reaction request before capabilities were negotiated",
+                    )];
+                };
+
+                if !capabilities.allow_sending(FilterInput::message_like("m.reaction")) {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Not allowed to send m.reaction events",
+                    )];
+                }
+
+                self.send_matrix_driver_request(req)
+                    .map(|(request, request_action)| {
+                        request.then(|mut result, machine| {
+                            if let Ok(r) = result.as_mut() {
+                                r.set_room_id(machine.room_id.clone());
+                            }
+                            vec![Self::send_from_widget_response(
+                                raw_request,
+                                result.map_err(FromWidgetErrorResponse::from_error),
+                            )]
+                        });
+
+                        vec![request_action]
+                    })
+                    .unwrap_or_default()
+            }
+
+            FromWidgetRequest::RedactEvent(req) => {
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Received redact event request before capabilities were negotiated",
+                    )];
+                };
+
+                if !capabilities.allow_sending(FilterInput::message_like("m.room.redaction")) {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Not allowed to send m.room.redaction events",
+                    )];
+                }
+
+                self.send_matrix_driver_request(req)
+                    .map(|(request, request_action)| {
+                        request.then(|mut result, machine| {
+                            if let Ok(r) = result.as_mut() {
+                                r.set_room_id(machine.room_id.clone());
+                            }
+                            vec![Self::send_from_widget_response(
+                                raw_request,
+                                result.map_err(FromWidgetErrorResponse::from_error),
+                            )]
+                        });
+
+                        vec![request_action]
+                    })
+                    .unwrap_or_default()
+            }
+
+            FromWidgetRequest::SendFile(req) => {
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Received send file request before capabilities were negotiated",
+                    )];
+                };
+
+                if !capabilities.upload_file {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        format!("Not allowed: missing the {UPLOAD_FILE} capability."),
+                    )];
+                }
+
+                let content_type = match req.mimetype.as_deref().map(str::parse::<Mime>) {
+                    None => mime::APPLICATION_OCTET_STREAM,
+                    Some(Ok(content_type)) => content_type,
+                    Some(Err(error)) => {
+                        return vec![Self::send_from_widget_error_string_response(
+                            raw_request,
+                            format!("Invalid MIME type: {error}"),
+                        )];
+                    }
+                };
+
+                // The attachment is sent with the message type matching its MIME type.
+                let msgtype = match content_type.type_() {
+                    mime::IMAGE => "m.image",
+                    mime::AUDIO => "m.audio",
+                    mime::VIDEO => "m.video",
+                    _ => "m.file",
+                };
+                if !capabilities.allow_sending(FilterInput::message_with_msgtype(msgtype)) {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        format!("Not allowed to send {msgtype} messages"),
+                    )];
+                }
+
+                let request = SendAttachmentRequest {
+                    filename: req.filename,
+                    content_type,
+                    data: req.data.into_inner(),
+                };
+
+                self.send_matrix_driver_request(request)
+                    .map(|(request, request_action)| {
+                        request.then(|mut result, machine| {
+                            if let Ok(r) = result.as_mut() {
+                                r.set_room_id(machine.room_id.clone());
+                            }
+                            vec![Self::send_from_widget_response(
+                                raw_request,
+                                result.map_err(FromWidgetErrorResponse::from_error),
+                            )]
+                        });
+
+                        vec![request_action]
+                    })
+                    .unwrap_or_default()
+            }
+
+            FromWidgetRequest::Navigate(req) => {
+                let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        "Received navigate request before capabilities were negotiated",
+                    )];
+                };
+
+                if !capabilities.navigate {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        format!("Not allowed: missing the {NAVIGATE} capability."),
+                    )];
+                }
+
+                let Some(room) = req.room() else {
+                    return vec![Self::send_from_widget_error_string_response(
+                        raw_request,
+                        format!("Invalid room URI: {}", req.uri),
+                    )];
+                };
+
+                self.send_matrix_driver_request(NavigateToRoomRequest { room })
+                    .map(|(request, request_action)| {
+                        request.then(|result, _machine| {
+                            vec![Self::send_from_widget_response(
+                                raw_request,
+                                result
+                                    .map(|()| JsonObject::new())
+                                    .map_err(FromWidgetErrorResponse::from_error),
+                            )]
+                        });
+
+                        vec![request_action]
+                    })
+                    .unwrap_or_default()
             }
         }
     }
@@ -354,7 +1358,10 @@ impl WidgetMachine {
                             }
                             CapabilitiesState::Negotiated(capabilities) => result
                             .map(|mut events| {
-                                events.retain(|e| capabilities.allow_reading(e));
+                                events.retain(|e| {
+                                    capabilities.allow_reading(e)
+                                        && !machine.is_event_type_denied(e)
+                                });
                                 ReadEventResponse { events }
                             })
                             .map_err(FromWidgetErrorResponse::from_error),
@@ -383,9 +1390,24 @@ impl WidgetMachine {
                 if allowed {
                     self.send_matrix_driver_request(ReadStateEventRequest { event_type, state_key })
                         .map(|(request, action)| {
-                            request.then(|result, _machine| {
+                            request.then(|result, machine| {
+                                let capabilities = match &machine.capabilities {
+                                    CapabilitiesState::Negotiated(capabilities) => {
+                                        Some(capabilities)
+                                    }
+                                    _ => None,
+                                };
                                 let response = result
-                                    .map(|events| ReadEventResponse { events })
+                                    .map(|mut events| {
+                                        // Reading any state key is allowed as soon as the widget
+                                        // can read one of them, so only keep the events it has
+                                        // the capability to read.
+                                        events.retain(|e| {
+                                            capabilities.is_some_and(|c| c.allow_reading(e))
+                                                && !machine.is_event_type_denied(e)
+                                        });
+                                        ReadEventResponse { events }
+                                    })
                                     .map_err(FromWidgetErrorResponse::from_error);
                                 vec![Self::send_from_widget_response(raw_request, response)]
                             });
@@ -425,11 +1447,22 @@ impl WidgetMachine {
             ));
         }
 
+        if request.update_mode == StateUpdateMode::Merge && request.state_key.is_none() {
+            return Some(Self::send_from_widget_error_string_response(
+                raw_request,
+                "Only the content of state events can be merged",
+            ));
+        }
+
         let (request, action) = self.send_matrix_driver_request(request)?;
 
         request.then(|mut result, machine| {
             if let Ok(r) = result.as_mut() {
                 r.set_room_id(machine.room_id.clone());
+
+                if let Some(delay_id) = &r.delay_id {
+                    machine.delayed_events.insert(delay_id.clone());
+                }
             }
             vec![Self::send_from_widget_response(
                 raw_request,
@@ -469,7 +1502,7 @@ impl WidgetMachine {
 
         request
             .response_fn
-            .map(|response_fn| response_fn(response.response_data, self))
+            .map(|response_fn| response_fn(Ok(response.response_data), self))
             .unwrap_or_default()
     }
 
@@ -548,6 +1581,23 @@ impl WidgetMachine {
         &mut self,
         to_widget_request: T,
     ) -> Option<(ToWidgetRequestHandle<'_, T::ResponseData>, Action)> {
+        if T::CRITICAL {
+            let data = serde_json::value::to_raw_value(&to_widget_request)
+                .expect("Failed to serialize request");
+            // Keep the requests in the order in which they were last sent.
+            self.critical_to_widget_requests.shift_remove(T::ACTION);
+            self.critical_to_widget_requests.insert(T::ACTION, data);
+        }
+
+        let (meta, action) = self.send_raw_to_widget_request(T::ACTION, to_widget_request)?;
+        Some((ToWidgetRequestHandle::new(meta), action))
+    }
+
+    fn send_raw_to_widget_request(
+        &mut self,
+        action: &'static str,
+        data: impl Serialize,
+    ) -> Option<(&mut ToWidgetRequestMeta, Action)> {
         #[derive(Serialize)]
         #[serde(tag = "api", rename = "toWidget", rename_all = "camelCase")]
         struct ToWidgetRequestSerdeHelper<'a, T> {
@@ -558,21 +1608,17 @@ impl WidgetMachine {
         }
 
         let request_id = Uuid::new_v4();
-        let full_request = ToWidgetRequestSerdeHelper {
-            widget_id: &self.widget_id,
-            request_id,
-            action: T::ACTION,
-            data: to_widget_request,
-        };
+        let full_request =
+            ToWidgetRequestSerdeHelper { widget_id: &self.widget_id, request_id, action, data };
+        let serialized = serde_json::to_string(&full_request).expect("Failed to serialize request");
 
-        let request_meta = ToWidgetRequestMeta::new(T::ACTION);
+        let request_meta = ToWidgetRequestMeta::new(action);
         let Some(meta) = self.pending_to_widget_requests.insert(request_id, request_meta) else {
             warn!("Reached limits of pending requests for toWidget requests");
             return None;
         };
 
-        let serialized = serde_json::to_string(&full_request).expect("Failed to serialize request");
-        Some((ToWidgetRequestHandle::new(meta), Action::SendToWidget(serialized)))
+        Some((meta, Action::SendToWidget(serialized)))
     }
 
     #[instrument(skip_all)]
@@ -598,7 +1644,8 @@ impl WidgetMachine {
     fn negotiate_capabilities(&mut self) -> Vec<Action> {
         let mut actions = Vec::new();
 
-        if matches!(&self.capabilities, CapabilitiesState::Negotiated(c) if !c.read.is_empty()) {
+        if matches!(&self.capabilities, CapabilitiesState::Negotiated(c) if c.needs_subscription())
+        {
             actions.push(Action::Unsubscribe);
         }
 
@@ -610,7 +1657,23 @@ impl WidgetMachine {
         };
 
         request.then(|response, machine| {
-            let requested_capabilities = response.capabilities;
+            let response = match response {
+                Ok(response) => response,
+                Err(timeout) => {
+                    // Let the widget start the negotiation again.
+                    error!("Negotiating capabilities failed: {timeout}");
+                    machine.capabilities = CapabilitiesState::Unset;
+                    return Vec::new();
+                }
+            };
+
+            let capabilities =
+                machine.deprecated_capabilities.process(&machine.widget_id, response.capabilities);
+            let requested_capabilities =
+                serde_json::from_value::<Capabilities>(capabilities.into()).unwrap_or_else(|e| {
+                    error!("Failed to parse the requested capabilities: {e}");
+                    Capabilities::default()
+                });
 
             let Some((request, action)) = machine.send_matrix_driver_request(AcquireCapabilities {
                 desired_capabilities: requested_capabilities.clone(),
@@ -625,38 +1688,90 @@ impl WidgetMachine {
                     Capabilities::default()
                 });
 
-                let mut actions = Vec::new();
-                if !approved_capabilities.read.is_empty() {
-                    actions.push(Action::Subscribe);
-                }
+                machine.set_negotiated_capabilities(requested_capabilities, approved_capabilities)
+            });
 
-                machine.capabilities = CapabilitiesState::Negotiated(approved_capabilities.clone());
+            vec![action]
+        });
 
-                let notify_caps_changed = NotifyCapabilitiesChanged {
-                    approved: approved_capabilities,
-                    requested: requested_capabilities,
-                };
+        actions.push(action);
+        actions
+    }
 
-                if let Some(action) = machine
-                    .send_to_widget_request(notify_caps_changed)
-                    .map(|(_request, action)| action)
-                {
-                    actions.push(action);
-                }
+    /// Acquire the given capabilities instead of the negotiated ones, and
+    /// notify the widget about the approved ones.
+    ///
+    /// The negotiated capabilities that aren't desired anymore are revoked
+    /// immediately, while the new ones are only granted once approved.
+    fn update_capabilities(&mut self, desired_capabilities: Capabilities) -> Vec<Action> {
+        let CapabilitiesState::Negotiated(capabilities) = &self.capabilities else {
+            warn!("Can't update the capabilities before they were negotiated");
+            return Vec::new();
+        };
 
-                actions
+        self.capabilities =
+            CapabilitiesState::Negotiated(capabilities.intersection(&desired_capabilities));
+
+        let Some((request, action)) = self.send_matrix_driver_request(AcquireCapabilities {
+            desired_capabilities: desired_capabilities.clone(),
+        }) else {
+            return Vec::new();
+        };
+
+        request.then(|result, machine| {
+            let approved_capabilities = result.unwrap_or_else(|e| {
+                error!("Acquiring capabilities failed: {e}");
+                Capabilities::default()
             });
 
-            vec![action]
+            machine.set_negotiated_capabilities(desired_capabilities, approved_capabilities)
         });
 
-        actions.push(action);
+        vec![action]
+    }
+
+    /// Use the given approved capabilities, and notify the widget about them.
+    ///
+    /// This ends a negotiation, or restores the capabilities of a previous
+    /// session without negotiating them again.
+    pub(crate) fn set_negotiated_capabilities(
+        &mut self,
+        requested_capabilities: Capabilities,
+        approved_capabilities: Capabilities,
+    ) -> Vec<Action> {
+        let was_subscribed = match &self.capabilities {
+            CapabilitiesState::Negotiated(capabilities) => capabilities.needs_subscription(),
+            _ => false,
+        };
+
+        let mut actions = Vec::new();
+        if approved_capabilities.needs_subscription() {
+            actions.push(Action::Subscribe);
+        } else if was_subscribed {
+            actions.push(Action::Unsubscribe);
+        }
+
+        self.capabilities = CapabilitiesState::Negotiated(approved_capabilities.clone());
+
+        let notify_caps_changed = NotifyCapabilitiesChanged {
+            approved: approved_capabilities,
+            requested: requested_capabilities,
+        };
+
+        if let Some(action) =
+            self.send_to_widget_request(notify_caps_changed).map(|(_request, action)| action)
+        {
+            actions.push(action);
+        }
+
         actions
     }
 }
 
-type ToWidgetResponseFn =
-    Box<dyn FnOnce(Box<RawJsonValue>, &mut WidgetMachine) -> Vec<Action> + Send>;
+type ToWidgetResponseFn = Box<
+    dyn FnOnce(Result<Box<RawJsonValue>, ToWidgetTimeout>, &mut WidgetMachine) -> Vec<Action>
+        + Send,
+>;
 
 pub(crate) struct ToWidgetRequestMeta {
     action: &'static str,
@@ -15,11 +15,15 @@
 //! A wrapper around a hash map that tracks pending requests and makes sure
 //! that expired requests are removed.
 
+use std::sync::Arc;
+
 use indexmap::{map::Entry, IndexMap};
 use ruma::time::{Duration, Instant};
 use tracing::warn;
 use uuid::Uuid;
 
+use crate::widget::clock::Clock;
+
 /// Configuration of limits for the outgoing request handling.
 #[derive(Clone, Debug)]
 pub(crate) struct RequestLimits {
@@ -42,11 +46,12 @@ pub(crate) struct RequestLimits {
 pub(super) struct PendingRequests<T> {
     requests: IndexMap<Uuid, Expirable<T>>,
     limits: RequestLimits,
+    clock: Arc<dyn Clock>,
 }
 
 impl<T> PendingRequests<T> {
-    pub(super) fn new(limits: RequestLimits) -> Self {
-        Self { requests: IndexMap::with_capacity(limits.max_pending_requests), limits }
+    pub(super) fn new(limits: RequestLimits, clock: Arc<dyn Clock>) -> Self {
+        Self { requests: IndexMap::with_capacity(limits.max_pending_requests), limits, clock }
     }
 
     /// Inserts a new request into the map.
@@ -61,7 +66,7 @@ impl<T> PendingRequests<T> {
             panic!("uuid collision");
         };
 
-        let expirable = Expirable::new(value, Instant::now() + self.limits.response_timeout);
+        let expirable = Expirable::new(value, self.clock.now() + self.limits.response_timeout);
         let inserted = entry.insert(expirable);
         Some(&mut inserted.value)
     }
@@ -72,18 +77,28 @@ impl<T> PendingRequests<T> {
     pub(super) fn extract(&mut self, key: &Uuid) -> Result<T, &'static str> {
         let value =
             self.requests.swap_remove(key).ok_or("Received response for an unknown request")?;
-        value.value().ok_or("Dropping response for an expired request")
+        value.value(self.clock.now()).ok_or("Dropping response for an expired request")
     }
 
-    /// Removes all expired requests from the map.
-    pub(super) fn remove_expired(&mut self) {
-        self.requests.retain(|id, req| {
-            let expired = req.expired();
-            if expired {
+    /// Removes all expired requests from the map, and returns them.
+    pub(super) fn remove_expired(&mut self) -> Vec<T> {
+        let now = self.clock.now();
+        let mut expired = Vec::new();
+        for (id, req) in std::mem::take(&mut self.requests) {
+            if req.expired(now) {
                 warn!(?id, "Dropping response for an expired request");
+                expired.push(req.value);
+            } else {
+                self.requests.insert(id, req);
             }
-            !expired
-        });
+        }
+        expired
+    }
+
+    /// Whether no request is pending anymore.
+    #[cfg(test)]
+    pub(super) fn is_empty(&self) -> bool {
+        self.requests.is_empty()
     }
 }
 
@@ -97,31 +112,32 @@ impl<T> Expirable<T> {
         Self { value, expires_at }
     }
 
-    fn value(self) -> Option<T> {
-        (!self.expired()).then_some(self.value)
+    fn value(self, now: Instant) -> Option<T> {
+        (!self.expired(now)).then_some(self.value)
     }
 
-    fn expired(&self) -> bool {
-        Instant::now() >= self.expires_at
+    fn expired(&self, now: Instant) -> bool {
+        now >= self.expires_at
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{sync::Arc, time::Duration};
 
     use uuid::Uuid;
 
     use super::{PendingRequests, RequestLimits};
+    use crate::widget::clock::{SystemClock, TestClock};
 
     struct Dummy;
 
     #[test]
     fn insertion_limits_for_pending_requests_work() {
-        let mut pending: PendingRequests<Dummy> = PendingRequests::new(RequestLimits {
-            max_pending_requests: 1,
-            response_timeout: Duration::from_secs(10),
-        });
+        let mut pending: PendingRequests<Dummy> = PendingRequests::new(
+            RequestLimits { max_pending_requests: 1, response_timeout: Duration::from_secs(10) },
+            Arc::new(SystemClock),
+        );
 
         // First insert is ok.
         let first = Uuid::new_v4();
@@ -147,10 +163,10 @@ mod tests {
 
     #[test]
     fn time_limits_for_pending_requests_work() {
-        let mut pending: PendingRequests<Dummy> = PendingRequests::new(RequestLimits {
-            max_pending_requests: 10,
-            response_timeout: Duration::from_secs(1),
-        });
+        let mut pending: PendingRequests<Dummy> = PendingRequests::new(
+            RequestLimits { max_pending_requests: 10, response_timeout: Duration::from_secs(1) },
+            Arc::new(SystemClock),
+        );
 
         // Insert a request, it's fine, limits are high.
         let key = Uuid::new_v4();
@@ -181,4 +197,45 @@ mod tests {
         assert!(pending.extract(&key).is_ok());
         assert!(pending.requests.is_empty());
     }
+
+    #[test]
+    fn time_limits_follow_the_clock() {
+        let clock = TestClock::new();
+        let mut pending: PendingRequests<Dummy> = PendingRequests::new(
+            RequestLimits { max_pending_requests: 10, response_timeout: Duration::from_secs(1) },
+            Arc::new(clock.clone()),
+        );
+
+        let key = Uuid::new_v4();
+        assert!(pending.insert(key, Dummy).is_some());
+
+        // The request doesn't lapse until the clock moves past its timeout.
+        clock.advance(Duration::from_millis(999));
+        pending.remove_expired();
+        assert_eq!(pending.requests.len(), 1);
+
+        clock.advance(Duration::from_millis(1));
+        assert!(pending.extract(&key).is_err());
+    }
+
+    #[test]
+    fn expired_requests_are_returned() {
+        let clock = TestClock::new();
+        let mut pending: PendingRequests<u8> = PendingRequests::new(
+            RequestLimits { max_pending_requests: 10, response_timeout: Duration::from_secs(1) },
+            Arc::new(clock.clone()),
+        );
+
+        assert!(pending.insert(Uuid::new_v4(), 1).is_some());
+        clock.advance(Duration::from_millis(500));
+        assert!(pending.insert(Uuid::new_v4(), 2).is_some());
+
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(pending.remove_expired(), [1]);
+        assert_eq!(pending.requests.len(), 1);
+
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(pending.remove_expired(), [2]);
+        assert!(pending.requests.is_empty());
+    }
 }
@@ -12,14 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
 use assert_matches::assert_matches;
 use assert_matches2::assert_let;
-use ruma::owned_room_id;
+use ruma::{owned_room_id, serde::Raw};
 use serde_json::{from_value, json};
 
 use super::{parse_msg, WIDGET_ID};
-use crate::widget::machine::{
-    incoming::MatrixDriverResponse, Action, IncomingMessage, MatrixDriverRequestData, WidgetMachine,
+use crate::widget::{
+    clock::TestClock,
+    machine::{
+        incoming::MatrixDriverResponse, Action, IncomingMessage, MatrixDriverRequestData,
+        WidgetMachine, DEFAULT_RESPONSE_TIMEOUT,
+    },
+    Capabilities, DeprecatedCapabilities, SessionTrace, SessionTraceStep,
 };
 
 #[test]
@@ -29,6 +36,89 @@ fn test_machine_can_negotiate_capabilities_immediately() {
     assert_capabilities_dance(&mut machine, actions, None);
 }
 
+#[test]
+fn test_session_trace_records_the_capabilities_negotiation() {
+    let room_id = owned_room_id!("!a98sd12bjh:example.org");
+    let (mut machine, actions) = WidgetMachine::new(WIDGET_ID.to_owned(), room_id, false);
+
+    let session_trace = SessionTrace::default();
+    session_trace.record_actions(&actions);
+    machine.set_session_trace(session_trace.clone());
+
+    assert_capabilities_dance(&mut machine, actions, None);
+
+    let action_of = |message: &str| {
+        let message = serde_json::from_str::<serde_json::Value>(message).unwrap();
+        message["action"].as_str().unwrap().to_owned()
+    };
+    let steps: Vec<_> = session_trace
+        .steps()
+        .into_iter()
+        .map(|step| match step {
+            SessionTraceStep::ToWidget { message } => format!("to_widget {}", action_of(&message)),
+            SessionTraceStep::FromWidget { message } => {
+                format!("from_widget {}", action_of(&message))
+            }
+            SessionTraceStep::DriverRequest { kind, .. } => format!("driver_request {kind}"),
+            SessionTraceStep::DriverResponse { is_error, .. } => {
+                format!("driver_response is_error={is_error}")
+            }
+            SessionTraceStep::ClientAction { action } => format!("client_action {action}"),
+            step => panic!("unexpected step: {step:?}"),
+        })
+        .collect();
+
+    assert_eq!(
+        steps,
+        [
+            "to_widget capabilities",
+            "from_widget capabilities",
+            "driver_request AcquireCapabilities",
+            "driver_response is_error=false",
+            "client_action Subscribe",
+            "to_widget notify_capabilities",
+            "from_widget notify_capabilities",
+        ]
+    );
+}
+
+#[test]
+fn test_session_trace_redacts_the_incoming_contents() {
+    let session_trace = SessionTrace::default();
+    session_trace.record_incoming(&IncomingMessage::WidgetMessage(json_string!({
+        "api": "fromWidget",
+        "widgetId": WIDGET_ID,
+        "requestId": "send-event-request-id",
+        "action": "send_event",
+        "data": {
+            "type": "m.room.message",
+            "content": { "msgtype": "m.text", "body": "Hello" },
+        },
+    })));
+    session_trace.record_incoming(&text_message_event());
+
+    let steps = session_trace.steps();
+    assert_let!(
+        [SessionTraceStep::FromWidget { message }, SessionTraceStep::MatrixEvent { event }] =
+            steps.as_slice()
+    );
+
+    let message = serde_json::from_str::<serde_json::Value>(message).unwrap();
+    assert_eq!(
+        message,
+        json!({
+            "api": "fromWidget",
+            "widgetId": WIDGET_ID,
+            "requestId": "send-event-request-id",
+            "action": "send_event",
+            "data": "[REDACTED]",
+        })
+    );
+
+    let event = serde_json::from_str::<serde_json::Value>(event).unwrap();
+    assert_eq!(event, json!({ "type": "m.room.message", "event_id": "$message" }));
+}
+
 #[test]
 fn test_machine_can_request_capabilities_on_content_load() {
     let room_id = owned_room_id!("!a98sd12bjh:example.org");
@@ -66,6 +156,275 @@ fn test_machine_can_request_capabilities_on_content_load() {
     assert_capabilities_dance(&mut machine, actions, None);
 }
 
+#[test]
+fn test_machine_rejects_content_loaded_twice() {
+    let room_id = owned_room_id!("!a98sd12bjh:example.org");
+    let (mut machine, _) = WidgetMachine::new(WIDGET_ID.to_owned(), room_id, true);
+
+    let content_loaded = json_string!({
+        "api": "fromWidget",
+        "widgetId": WIDGET_ID,
+        "requestId": "content-loaded-request-id",
+        "action": "content_loaded",
+        "data": {},
+    });
+
+    let mut actions = machine.process(IncomingMessage::WidgetMessage(content_loaded.clone()));
+    // Skip the response to the `content_loaded` request.
+    actions.remove(0);
+    assert_capabilities_dance(&mut machine, actions, None);
+
+    // The second `content_loaded` request is rejected, without negotiating the
+    // capabilities again.
+    let actions = machine.process(IncomingMessage::WidgetMessage(content_loaded));
+    let [action]: [Action; 1] = actions.try_into().unwrap();
+    assert_let!(Action::SendToWidget(msg) = action);
+    let (msg, request_id) = parse_msg(&msg);
+    assert_eq!(request_id, "content-loaded-request-id");
+    assert_eq!(
+        msg["response"]["error"]["message"].as_str().unwrap(),
+        "The content of the widget was already loaded"
+    );
+}
+
+fn room_event(event: serde_json::Value) -> IncomingMessage {
+    IncomingMessage::MatrixEventReceived(Raw::new(&event).unwrap().cast())
+}
+
+fn alice_member_event() -> IncomingMessage {
+    room_event(json!({
+        "type": "m.room.member",
+        "event_id": "$member",
+        "room_id": "!a98sd12bjh:example.org",
+        "sender": "@alice:example.org",
+        "state_key": "@alice:example.org",
+        "origin_server_ts": 0,
+        "content": { "membership": "join" },
+    }))
+}
+
+fn text_message_event() -> IncomingMessage {
+    room_event(json!({
+        "type": "m.room.message",
+        "event_id": "$message",
+        "room_id": "!a98sd12bjh:example.org",
+        "sender": "@alice:example.org",
+        "origin_server_ts": 0,
+        "content": { "msgtype": "m.text", "body": "Hello" },
+    }))
+}
+
+fn assert_event_is_forwarded(machine: &mut WidgetMachine, event: IncomingMessage) {
+    let [action]: [Action; 1] = machine.process(event).try_into().unwrap();
+    assert_let!(Action::SendToWidget(msg) = action);
+    let (msg, _) = parse_msg(&msg);
+    assert_eq!(msg["action"], "send_event");
+}
+
+#[test]
+fn test_revoked_capabilities_stop_applying_immediately() {
+    let room_id = owned_room_id!("!a98sd12bjh:example.org");
+    let (mut machine, _) = WidgetMachine::new(WIDGET_ID.to_owned(), room_id, true);
+
+    let capabilities: Capabilities = from_value(json!([
+        "org.matrix.msc2762.receive.state_event:m.room.member",
+        "org.matrix.msc2762.receive.event:m.room.message",
+    ]))
+    .unwrap();
+    machine.set_negotiated_capabilities(capabilities.clone(), capabilities);
+
+    // The member and message events are forwarded to the widget.
+    assert_event_is_forwarded(&mut machine, alice_member_event());
+    assert_event_is_forwarded(&mut machine, text_message_event());
+
+    // The client revokes the capability to read the member events only.
+    let desired_capabilities: Capabilities =
+        from_value(json!(["org.matrix.msc2762.receive.event:m.room.message"])).unwrap();
+    let actions =
+        machine.process(IncomingMessage::UpdateCapabilities(desired_capabilities.clone()));
+    let [action]: [Action; 1] = actions.try_into().unwrap();
+    assert_let!(
+        Action::MatrixDriverRequest {
+            request_id,
+            data: MatrixDriverRequestData::AcquireCapabilities(data)
+        } = action
+    );
+    assert_eq!(data.desired_capabilities, desired_capabilities);
+
+    // The member events aren't forwarded anymore, even before the new
+    // capabilities are acquired, but the message events still are.
+    assert!(machine.process(alice_member_event()).is_empty());
+    assert_event_is_forwarded(&mut machine, text_message_event());
+
+    // Once acquired, the widget is notified of the new capabilities.
+    let response = Ok(MatrixDriverResponse::CapabilitiesAcquired(desired_capabilities));
+    let actions = machine.process(IncomingMessage::MatrixDriverResponse { request_id, response });
+    let [action]: [Action; 1] = actions.try_into().unwrap();
+    assert_let!(Action::SendToWidget(msg) = action);
+    let (msg, _) = parse_msg(&msg);
+    assert_eq!(msg["action"], "notify_capabilities");
+    let capability = json!(["org.matrix.msc2762.receive.event:m.room.message"]);
+    assert_eq!(msg["data"], json!({ "requested": capability, "approved": capability }));
+
+    assert!(machine.process(alice_member_event()).is_empty());
+    assert_event_is_forwarded(&mut machine, text_message_event());
+}
+
+#[test]
+fn test_events_held_back_while_paused_are_checked_again_on_resume() {
+    let room_id = owned_room_id!("!a98sd12bjh:example.org");
+    let (mut machine, _) = WidgetMachine::new(WIDGET_ID.to_owned(), room_id, true);
+
+    let capabilities: Capabilities = from_value(json!([
+        "org.matrix.msc2762.receive.state_event:m.room.member",
+        "org.matrix.msc2762.receive.event:m.room.message",
+    ]))
+    .unwrap();
+    machine.set_negotiated_capabilities(capabilities.clone(), capabilities);
+
+    // The events received while paused are held back.
+    assert!(machine.process(IncomingMessage::Pause).is_empty());
+    assert!(machine.process(alice_member_event()).is_empty());
+    assert!(machine.process(text_message_event()).is_empty());
+
+    // The client revokes the capability to read the member events meanwhile.
+    let desired_capabilities: Capabilities =
+        from_value(json!(["org.matrix.msc2762.receive.event:m.room.message"])).unwrap();
+    let actions = machine.process(IncomingMessage::UpdateCapabilities(desired_capabilities));
+    assert_eq!(actions.len(), 1);
+
+    // Only the message event is forwarded once the session is resumed.
+    let [action]: [Action; 1] = machine.process(IncomingMessage::Resume).try_into().unwrap();
+    assert_let!(Action::SendToWidget(msg) = action);
+    let (msg, _) = parse_msg(&msg);
+    assert_eq!(msg["action"], "send_event");
+    assert_eq!(msg["data"]["type"], "m.room.message");
+}
+
+#[test]
+fn test_broader_desired_capabilities_keep_the_narrower_negotiated_ones() {
+    let room_id = owned_room_id!("!a98sd12bjh:example.org");
+    let (mut machine, _) = WidgetMachine::new(WIDGET_ID.to_owned(), room_id, true);
+
+    let capabilities: Capabilities = from_value(json!([
+        "org.matrix.msc2762.receive.state_event:m.room.member#@alice:example.org",
+    ]))
+    .unwrap();
+    machine.set_negotiated_capabilities(capabilities.clone(), capabilities);
+    assert_event_is_forwarded(&mut machine, alice_member_event());
+
+    // The client asks for all the member events.
+    let desired_capabilities: Capabilities =
+        from_value(json!(["org.matrix.msc2762.receive.state_event:m.room.member"])).unwrap();
+    let actions = machine.process(IncomingMessage::UpdateCapabilities(desired_capabilities));
+    assert_eq!(actions.len(), 1);
+
+    // The member event of Alice is still forwarded while they are acquired.
+    assert_event_is_forwarded(&mut machine, alice_member_event());
+}
+
+#[test]
+fn test_capabilities_response_after_the_timeout_is_dropped() {
+    let room_id = owned_room_id!("!a98sd12bjh:example.org");
+    let clock = TestClock::new();
+    let (mut machine, actions) = WidgetMachine::with_clock(
+        WIDGET_ID.to_owned(),
+        room_id,
+        false,
+        DEFAULT_RESPONSE_TIMEOUT,
+        Arc::new(clock.clone()),
+    );
+
+    let [action]: [Action; 1] = actions.try_into().unwrap();
+    assert_let!(Action::SendToWidget(msg) = action);
+    let (msg, request_id) = parse_msg(&msg);
+    assert_eq!(msg["action"], "capabilities");
+
+    // The widget answers too late.
+    clock.advance(DEFAULT_RESPONSE_TIMEOUT);
+    let actions = machine.process(IncomingMessage::WidgetMessage(json_string!({
+        "api": "toWidget",
+        "widgetId": WIDGET_ID,
+        "requestId": request_id,
+        "action": "capabilities",
+        "data": {},
+        "response": {
+            "capabilities": ["org.matrix.msc2762.receive.state_event:m.room.member"],
+        },
+    })));
+
+    // So the capabilities are never acquired.
+    assert!(actions.is_empty());
+}
+
+#[test]
+fn test_expired_requests_are_removed_when_the_timeout_elapsed() {
+    let room_id = owned_room_id!("!a98sd12bjh:example.org");
+    let clock = TestClock::new();
+    let (mut machine, actions) = WidgetMachine::with_clock(
+        WIDGET_ID.to_owned(),
+        room_id,
+        false,
+        DEFAULT_RESPONSE_TIMEOUT,
+        Arc::new(clock.clone()),
+    );
+
+    let [action]: [Action; 1] = actions.try_into().unwrap();
+    assert_let!(Action::SendToWidget(msg) = action);
+    let (msg, _) = parse_msg(&msg);
+    assert_eq!(msg["action"], "capabilities");
+    assert!(!machine.pending_to_widget_requests.is_empty());
+
+    // The widget never answers, and nothing else happens until the timer of the
+    // request fires.
+    clock.advance(DEFAULT_RESPONSE_TIMEOUT);
+    let actions = machine.process(IncomingMessage::ToWidgetTimeoutElapsed);
+    assert!(actions.is_empty());
+    assert!(machine.pending_to_widget_requests.is_empty());
+}
+
+#[test]
+fn test_capabilities_are_negotiated_again_after_a_timeout() {
+    let room_id = owned_room_id!("!a98sd12bjh:example.org");
+    let clock = TestClock::new();
+    let (mut machine, actions) = WidgetMachine::with_clock(
+        WIDGET_ID.to_owned(),
+        room_id,
+        true,
+        Duration::from_secs(30),
+        Arc::new(clock.clone()),
+    );
+    assert!(actions.is_empty());
+
+    let content_loaded = json_string!({
+        "api": "fromWidget",
+        "widgetId": WIDGET_ID,
+        "requestId": "content-loaded-request-id",
+        "action": "content_loaded",
+        "data": {},
+    });
+
+    let actions = machine.process(IncomingMessage::WidgetMessage(content_loaded.clone()));
+    let [_response, action]: [Action; 2] = actions.try_into().unwrap();
+    assert_let!(Action::SendToWidget(msg) = action);
+    let (msg, _) = parse_msg(&msg);
+    assert_eq!(msg["action"], "capabilities");
+
+    // The widget doesn't answer before the configured timeout, the negotiation
+    // is still pending.
+    clock.advance(Duration::from_secs(29));
+    let actions = machine.process(IncomingMessage::WidgetMessage(content_loaded.clone()));
+    assert_eq!(actions.len(), 1);
+
+    // Once the request timed out, the widget can start the negotiation again.
+    clock.advance(Duration::from_secs(1));
+    let actions = machine.process(IncomingMessage::WidgetMessage(content_loaded));
+    let [_response, action]: [Action; 2] = actions.try_into().unwrap();
+    assert_let!(Action::SendToWidget(msg) = action);
+    let (msg, _) = parse_msg(&msg);
+    assert_eq!(msg["action"], "capabilities");
+}
+
 #[test]
 fn test_capabilities_failure_results_into_empty_capabilities() {
     let room_id = owned_room_id!("!a98sd12bjh:example.org");
@@ -136,6 +495,57 @@ fn test_capabilities_failure_results_into_empty_capabilities() {
     );
 }
 
+#[test]
+fn test_deprecated_capabilities_are_translated() {
+    let room_id = owned_room_id!("!a98sd12bjh:example.org");
+    let (mut machine, actions) = WidgetMachine::new(WIDGET_ID.to_owned(), room_id, false);
+    machine.set_deprecated_capabilities(DeprecatedCapabilities {
+        identifiers: BTreeMap::from([(
+            "org.example.msc1234.receive.state_event".to_owned(),
+            "org.matrix.msc2762.receive.state_event".to_owned(),
+        )]),
+        translate: true,
+    });
+
+    // Ask widget to provide desired capabilities.
+    let actions = {
+        let [action]: [Action; 1] = actions.try_into().unwrap();
+        assert_let!(Action::SendToWidget(msg) = action);
+        let (_msg, request_id) = parse_msg(&msg);
+
+        machine.process(IncomingMessage::WidgetMessage(json_string!({
+            "api": "toWidget",
+            "widgetId": WIDGET_ID,
+            "requestId": request_id,
+            "action": "capabilities",
+            "data": {},
+            "response": {
+                "capabilities": [
+                    "org.example.msc1234.receive.state_event:m.room.member",
+                    "io.element.requires_client",
+                ],
+            },
+        })))
+    };
+
+    // The driver is asked for the capabilities with their current identifiers.
+    let [action]: [Action; 1] = actions.try_into().unwrap();
+    assert_let!(
+        Action::MatrixDriverRequest {
+            data: MatrixDriverRequestData::AcquireCapabilities(data),
+            ..
+        } = action
+    );
+    assert_eq!(
+        data.desired_capabilities,
+        from_value(json!([
+            "org.matrix.msc2762.receive.state_event:m.room.member",
+            "io.element.requires_client",
+        ]))
+        .unwrap()
+    );
+}
+
 /// Performs a capability "dance", if no capability is specified, we assume that
 /// it's: `org.matrix.msc2762.receive.state_event:m.room.member`.
 pub(super) fn assert_capabilities_dance(
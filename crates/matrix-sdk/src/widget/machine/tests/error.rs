@@ -45,6 +45,115 @@ fn test_machine_sends_error_for_unknown_request() {
     assert!(msg["response"]["error"]["message"].is_string());
 }
 
+#[test]
+fn test_machine_sends_error_for_too_deeply_nested_request() {
+    let room_id = owned_room_id!("!a98sd12bjh:example.org");
+    let (mut machine, _) = WidgetMachine::new(WIDGET_ID.to_owned(), room_id, true);
+
+    let nested_content = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+    let message = format!(
+        r#"{{"api":"fromWidget","widgetId":"{WIDGET_ID}","requestId":"deep-req","action":"send_event","data":{{"type":"m.room.message","content":{{"body":"[{{\"","nested":{nested_content}}}}}}}"#
+    );
+
+    let actions = machine.process(IncomingMessage::WidgetMessage(message));
+
+    let [action]: [Action; 1] = actions.try_into().unwrap();
+    assert_let!(Action::SendToWidget(msg) = action);
+    let (msg, request_id) = parse_msg(&msg);
+    assert_eq!(request_id, "deep-req");
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "send_event");
+    assert_eq!(msg["data"]["content"]["body"], "[{\"");
+    assert_eq!(
+        msg["response"]["error"]["message"].as_str().unwrap(),
+        "The message exceeds the maximum nesting depth of 64"
+    );
+}
+
+#[test]
+fn test_machine_checks_the_widget_id_of_malformed_requests() {
+    let room_id = owned_room_id!("!a98sd12bjh:example.org");
+    let (mut machine, _) = WidgetMachine::new(WIDGET_ID.to_owned(), room_id, true);
+
+    // A request with an invalid widget id isn't answered.
+    let actions = machine.process(IncomingMessage::WidgetMessage(json_string!({
+        "api": "fromWidget",
+        "widgetId": 42,
+        "requestId": "wrong-widget-req",
+        "action": "content_loaded",
+        "data": {},
+    })));
+    assert!(actions.is_empty());
+
+    // A request without a widget id is answered with an error.
+    let actions = machine.process(IncomingMessage::WidgetMessage(json_string!({
+        "api": "fromWidget",
+        "requestId": "no-widget-req",
+        "action": "content_loaded",
+        "data": {},
+    })));
+
+    let [action]: [Action; 1] = actions.try_into().unwrap();
+    assert_let!(Action::SendToWidget(msg) = action);
+    let (msg, request_id) = parse_msg(&msg);
+    assert_eq!(request_id, "no-widget-req");
+    assert!(msg["response"]["error"]["message"].is_string());
+}
+
+#[test]
+fn test_machine_rejects_duplicate_keys_in_strict_mode() {
+    let room_id = owned_room_id!("!a98sd12bjh:example.org");
+    let (mut machine, _) = WidgetMachine::new(WIDGET_ID.to_owned(), room_id, true);
+    machine.set_strict_json(true);
+
+    let message = format!(
+        r#"{{"api":"fromWidget","widgetId":"other-widget","requestId":"dup-req","action":"content_loaded","data":{{}},"widgetId":"{WIDGET_ID}"}}"#
+    );
+    let actions = machine.process(IncomingMessage::WidgetMessage(message));
+
+    let [action]: [Action; 1] = actions.try_into().unwrap();
+    assert_let!(Action::SendToWidget(msg) = action);
+    let (msg, request_id) = parse_msg(&msg);
+    assert_eq!(request_id, "dup-req");
+    assert_eq!(msg["action"], "content_loaded");
+    assert_eq!(
+        msg["response"]["error"]["message"].as_str().unwrap(),
+        "The message has a duplicate `widgetId` key"
+    );
+
+    // Nested objects are checked too.
+    let message = format!(
+        r#"{{"api":"fromWidget","widgetId":"{WIDGET_ID}","requestId":"nested-dup-req","action":"content_loaded","data":{{"a":1,"a":2}}}}"#
+    );
+    let actions = machine.process(IncomingMessage::WidgetMessage(message));
+
+    let [action]: [Action; 1] = actions.try_into().unwrap();
+    assert_let!(Action::SendToWidget(msg) = action);
+    let (msg, _) = parse_msg(&msg);
+    assert_eq!(
+        msg["response"]["error"]["message"].as_str().unwrap(),
+        "The message has a duplicate `a` key"
+    );
+}
+
+#[test]
+fn test_machine_accepts_duplicate_keys_by_default() {
+    let room_id = owned_room_id!("!a98sd12bjh:example.org");
+    let (mut machine, _) = WidgetMachine::new(WIDGET_ID.to_owned(), room_id, true);
+
+    let message = format!(
+        r#"{{"api":"fromWidget","widgetId":"{WIDGET_ID}","requestId":"dup-req","action":"content_loaded","data":{{"a":1,"a":2}}}}"#
+    );
+    let actions = machine.process(IncomingMessage::WidgetMessage(message));
+
+    // The content is loaded and the capabilities are requested.
+    let [response, _capabilities]: [Action; 2] = actions.try_into().unwrap();
+    assert_let!(Action::SendToWidget(msg) = response);
+    let (msg, request_id) = parse_msg(&msg);
+    assert_eq!(request_id, "dup-req");
+    assert!(msg["response"].as_object().unwrap().is_empty());
+}
+
 #[test]
 fn test_read_messages_without_capabilities() {
     let (mut machine, _) =
@@ -225,3 +334,31 @@ fn test_read_request_for_message_like_with_disallowed_msg_type_fails() {
         "Not allowed to read message like event"
     );
 }
+
+#[test]
+fn test_empty_message_is_ignored() {
+    let (mut machine, _) =
+        WidgetMachine::new(WIDGET_ID.to_owned(), owned_room_id!("!a98sd12bjh:example.org"), true);
+
+    let actions = machine.process(IncomingMessage::WidgetMessage(String::new()));
+    assert!(actions.is_empty());
+
+    // The machine keeps processing the following messages.
+    let actions = machine.process(IncomingMessage::WidgetMessage(json_string!({
+        "api": "fromWidget",
+        "widgetId": WIDGET_ID,
+        "requestId": "invalid-req",
+        "action": "I AM ERROR",
+        "data": {},
+    })));
+    assert_eq!(actions.len(), 1);
+}
+
+#[test]
+fn test_whitespace_message_is_ignored() {
+    let (mut machine, _) =
+        WidgetMachine::new(WIDGET_ID.to_owned(), owned_room_id!("!a98sd12bjh:example.org"), true);
+
+    let actions = machine.process(IncomingMessage::WidgetMessage(" \n\t ".to_owned()));
+    assert!(actions.is_empty());
+}
@@ -12,9 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::BTreeMap;
+
 use assert_matches2::assert_let;
 use ruma::serde::JsonObject;
 use serde_json::Value as JsonValue;
+use uuid::Uuid;
+
+use super::{Action, MatrixDriverRequestData};
 
 /// Create a JSON string from a [`json!`][serde_json::json] "literal".
 #[macro_export]
@@ -26,6 +31,7 @@ mod api_versions;
 mod capabilities;
 mod error;
 mod openid;
+mod read_events;
 mod send_event;
 
 const WIDGET_ID: &str = "test-widget";
@@ -35,3 +41,24 @@ fn parse_msg(msg: &str) -> (JsonValue, String) {
     assert_let!(JsonValue::String(request_id) = deserialized.remove("requestId").unwrap());
     (JsonValue::Object(deserialized), request_id)
 }
+
+/// Take the matrix driver requests out of the given actions, grouped by their
+/// [`kind`](MatrixDriverRequestData::kind).
+///
+/// The other actions are left in `actions`, in the same order.
+fn drain_matrix_driver_requests(
+    actions: &mut Vec<Action>,
+) -> BTreeMap<&'static str, Vec<(Uuid, MatrixDriverRequestData)>> {
+    let mut requests = BTreeMap::<_, Vec<_>>::new();
+
+    for action in std::mem::take(actions) {
+        match action {
+            Action::MatrixDriverRequest { request_id, data } => {
+                requests.entry(data.kind()).or_default().push((request_id, data));
+            }
+            action => actions.push(action),
+        }
+    }
+
+    requests
+}
@@ -107,6 +107,66 @@ fn test_openid_request_handling_works() {
     }
 }
 
+#[test]
+fn test_openid_request_of_pre_consented_widget_is_answered_directly() {
+    let (mut machine, _) =
+        WidgetMachine::new(WIDGET_ID.to_owned(), owned_room_id!("!a98sd12bjh:example.org"), true);
+    machine.set_openid_pre_consented(true);
+
+    // Widget requests an open ID token, which is requested from the driver
+    // right away, without a pending response.
+    let actions = machine.process(IncomingMessage::WidgetMessage(json_string!({
+        "api": "fromWidget",
+        "widgetId": WIDGET_ID,
+        "requestId": "openid-request-id",
+        "action": "get_openid",
+        "data": {},
+    })));
+
+    let actions = {
+        let [action]: [Action; 1] = actions.try_into().unwrap();
+        assert_let!(
+            Action::MatrixDriverRequest { request_id, data: MatrixDriverRequestData::GetOpenId } =
+                action
+        );
+
+        machine.process(IncomingMessage::MatrixDriverResponse {
+            request_id,
+            response: Ok(MatrixDriverResponse::OpenIdReceived(
+                request_openid_token::v3::Response::new(
+                    "access_token".to_owned(),
+                    TokenType::Bearer,
+                    ServerName::parse("example.org").unwrap(),
+                    Duration::from_secs(3600),
+                ),
+            )),
+        })
+    };
+
+    // The widget gets the token in the response to its request.
+    let [action]: [Action; 1] = actions.try_into().unwrap();
+    assert_let!(Action::SendToWidget(msg) = action);
+    let (msg, request_id) = parse_msg(&msg);
+    assert_eq!(request_id, "openid-request-id");
+    assert_eq!(
+        msg,
+        json!({
+            "api": "fromWidget",
+            "widgetId": WIDGET_ID,
+            "action": "get_openid",
+            "data": {},
+            "response": {
+                "state": "allowed",
+                "original_request_id": "openid-request-id",
+                "access_token": "access_token",
+                "token_type": "Bearer",
+                "matrix_server_name": "example.org",
+                "expires_in": 3600,
+            },
+        }),
+    );
+}
+
 #[test]
 fn test_openid_fail_results_in_response_blocked() {
     let (mut machine, _) =
@@ -0,0 +1,65 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use assert_matches2::assert_let;
+use ruma::owned_room_id;
+use serde_json::json;
+
+use super::{capabilities::assert_capabilities_dance, drain_matrix_driver_requests, WIDGET_ID};
+use crate::widget::machine::{IncomingMessage, MatrixDriverRequestData, WidgetMachine};
+
+#[test]
+fn test_read_requests_are_forwarded_to_the_driver() {
+    let room_id = owned_room_id!("!a98sd12bjh:example.org");
+    let (mut machine, actions) = WidgetMachine::new(WIDGET_ID.to_owned(), room_id, false);
+    assert_capabilities_dance(&mut machine, actions, None);
+
+    // The widget sends several read requests without waiting for the responses.
+    let mut actions = Vec::new();
+    for (request_id, action, data) in [
+        (
+            "read-members",
+            "org.matrix.msc2876.read_events",
+            json!({ "type": "m.room.member", "state_key": true }),
+        ),
+        (
+            "read-alice",
+            "org.matrix.msc2876.read_events",
+            json!({ "type": "m.room.member", "state_key": "@alice:example.org" }),
+        ),
+        ("read-history", "get_membership_events", json!({ "limit": 20 })),
+    ] {
+        actions.extend(machine.process(IncomingMessage::WidgetMessage(json_string!({
+            "api": "fromWidget",
+            "widgetId": WIDGET_ID,
+            "requestId": request_id,
+            "action": action,
+            "data": data,
+        }))));
+    }
+
+    // Each of them is forwarded to the driver.
+    let requests = drain_matrix_driver_requests(&mut actions);
+    assert!(actions.is_empty());
+    assert_eq!(
+        requests.keys().copied().collect::<Vec<_>>(),
+        ["ReadMembershipEvents", "ReadStateEvent"]
+    );
+    assert_eq!(requests["ReadStateEvent"].len(), 2);
+    assert_let!(
+        [(_, MatrixDriverRequestData::ReadMembershipEvents(request))] =
+            requests["ReadMembershipEvents"].as_slice()
+    );
+    assert_eq!(request.limit, 20);
+}
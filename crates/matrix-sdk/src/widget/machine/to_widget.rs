@@ -14,7 +14,11 @@
 
 use std::marker::PhantomData;
 
-use ruma::{events::AnyTimelineEvent, serde::Raw};
+use ruma::{
+    events::{AnyEphemeralRoomEvent, AnyTimelineEvent},
+    serde::Raw,
+    OwnedEventId,
+};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::value::RawValue as RawJsonValue;
 use tracing::error;
@@ -36,13 +40,22 @@ where
         Self { request_meta, _phantom: PhantomData }
     }
 
+    /// Set the handler of the response of the widget, which is called with an
+    /// error if the widget doesn't answer in time.
     pub(crate) fn then(
         self,
-        response_handler: impl FnOnce(T, &mut WidgetMachine) -> Vec<Action> + Send + 'static,
+        response_handler: impl FnOnce(Result<T, ToWidgetTimeout>, &mut WidgetMachine) -> Vec<Action>
+            + Send
+            + 'static,
     ) {
         self.request_meta.response_fn = Some(Box::new(move |raw_response_data, machine| {
+            let raw_response_data = match raw_response_data {
+                Ok(raw_response_data) => raw_response_data,
+                Err(timeout) => return response_handler(Err(timeout), machine),
+            };
+
             match serde_json::from_str(raw_response_data.get()) {
-                Ok(response_data) => response_handler(response_data, machine),
+                Ok(response_data) => response_handler(Ok(response_data), machine),
                 Err(e) => {
                     error!("Failed to deserialize toWidget response: {e}");
                     Vec::new()
@@ -52,6 +65,14 @@ where
     }
 }
 
+/// The widget didn't answer a `toWidget` request in time.
+#[derive(Debug, thiserror::Error)]
+#[error("the widget didn't answer the `{action}` request in time")]
+pub(crate) struct ToWidgetTimeout {
+    /// The action of the request.
+    pub(crate) action: &'static str,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub(super) struct ToWidgetResponse {
@@ -74,6 +95,10 @@ pub(super) struct ToWidgetResponse {
 pub(crate) trait ToWidgetRequest: Serialize {
     const ACTION: &'static str;
     type ResponseData: DeserializeOwned;
+
+    /// Whether the latest request of this kind is sent again when the widget
+    /// reconnects, because the widget can't work properly without it.
+    const CRITICAL: bool = false;
 }
 
 /// Request the widget to send the list of capabilities that it wants to have.
@@ -87,7 +112,8 @@ impl ToWidgetRequest for RequestCapabilities {
 
 #[derive(Deserialize)]
 pub(super) struct RequestCapabilitiesResponse {
-    pub(super) capabilities: Capabilities,
+    /// The raw capabilities, which may use deprecated identifiers.
+    pub(super) capabilities: Vec<String>,
 }
 
 /// Notify the widget that the list of the granted capabilities has changed.
@@ -100,6 +126,7 @@ pub(super) struct NotifyCapabilitiesChanged {
 impl ToWidgetRequest for NotifyCapabilitiesChanged {
     const ACTION: &'static str = "notify_capabilities";
     type ResponseData = Empty;
+    const CRITICAL: bool = true;
 }
 
 /// Notify the widget that the OpenID credentials changed.
@@ -122,5 +149,58 @@ impl ToWidgetRequest for NotifyNewMatrixEvent {
     type ResponseData = Empty;
 }
 
+/// Notify the widget that an event it watches was edited, reacted to or
+/// redacted.
+#[derive(Serialize)]
+pub(crate) struct NotifyWatchedEventUpdate {
+    /// The id of the watched event.
+    pub(crate) event_id: OwnedEventId,
+
+    /// The edit, reaction or redaction of the watched event.
+    pub(crate) event: Raw<AnyTimelineEvent>,
+}
+
+impl ToWidgetRequest for NotifyWatchedEventUpdate {
+    const ACTION: &'static str = "watched_event_update";
+    type ResponseData = Empty;
+}
+
+/// Notify the widget that we received a new ephemeral event, e.g. a typing
+/// notification or a read receipt.
+#[derive(Serialize)]
+#[serde(transparent)]
+pub(crate) struct NotifyNewEphemeralEvent(pub(crate) Raw<AnyEphemeralRoomEvent>);
+
+impl ToWidgetRequest for NotifyNewEphemeralEvent {
+    const ACTION: &'static str = "send_ephemeral_event";
+    type ResponseData = Empty;
+}
+
+/// Notify the widget that the theme of the client changed.
+#[derive(Serialize)]
+pub(crate) struct NotifyThemeChanged {
+    /// The new theme, e.g. `light` or `dark`.
+    pub(crate) name: String,
+}
+
+impl ToWidgetRequest for NotifyThemeChanged {
+    const ACTION: &'static str = "theme_change";
+    type ResponseData = Empty;
+    const CRITICAL: bool = true;
+}
+
+/// Notify the widget that the language of the client changed.
+#[derive(Serialize)]
+pub(crate) struct NotifyLanguageChanged {
+    /// The new language tag, e.g. `en-US` or `de-DE`.
+    pub(crate) lang: String,
+}
+
+impl ToWidgetRequest for NotifyLanguageChanged {
+    const ACTION: &'static str = "language_change";
+    type ResponseData = Empty;
+    const CRITICAL: bool = true;
+}
+
 #[derive(Deserialize)]
 pub(crate) struct Empty {}
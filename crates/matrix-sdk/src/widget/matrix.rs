@@ -15,41 +15,76 @@
 //! Matrix driver implementation that exposes Matrix functionality
 //! that is relevant for the widget API.
 
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
-use matrix_sdk_base::deserialized_responses::RawAnySyncOrStrippedState;
+use matrix_sdk_base::deserialized_responses::{RawAnySyncOrStrippedState, SyncOrStrippedState};
+use mime::Mime;
+use once_cell::sync::OnceCell;
 use ruma::{
     api::client::{
         account::request_openid_token::v3::{Request as OpenIdRequest, Response as OpenIdResponse},
         delayed_events::{self, update_delayed_event::unstable::UpdateAction},
+        discovery::get_capabilities,
+        error::ErrorKind,
         filter::RoomEventFilter,
+        profile::get_profile,
+        room::report_content,
     },
     assign,
     events::{
-        AnyMessageLikeEventContent, AnyStateEventContent, AnySyncMessageLikeEvent,
-        AnySyncStateEvent, AnySyncTimelineEvent, AnyTimelineEvent, MessageLikeEventType,
-        StateEventType, TimelineEventType,
+        reaction::ReactionEventContent, relation::Annotation,
+        room::server_acl::RoomServerAclEventContent, AnyEphemeralRoomEvent,
+        AnyMessageLikeEventContent, AnyStateEventContent, AnySyncEphemeralRoomEvent,
+        AnySyncMessageLikeEvent, AnySyncStateEvent, AnySyncTimelineEvent, AnyTimelineEvent,
+        MessageLikeEventType, StateEventType, SyncStateEvent, TimelineEventType,
     },
-    serde::{from_raw_json_value, Raw},
-    EventId, RoomId, TransactionId,
+    serde::{from_raw_json_value, JsonObject, Raw},
+    time::Instant,
+    EventId, OwnedEventId, OwnedRoomId, OwnedTransactionId, OwnedUserId, RoomId, RoomOrAliasId,
+    TransactionId, UserId,
 };
 use serde_json::{value::RawValue as RawJsonValue, Value};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 use tracing::error;
 
-use super::{machine::SendEventResponse, StateKeySelector};
-use crate::{event_handler::EventHandlerDropGuard, room::MessagesOptions, Error, Result, Room};
+use super::{
+    clock::Clock,
+    machine::{
+        GetMembershipEventsResponse, GetRequiredPowerLevelRequest, ModerationAction,
+        RequiredPowerLevel, SendEventResponse, StateUpdateMode,
+    },
+    StateKeySelector,
+};
+use crate::{
+    attachment::AttachmentConfig,
+    event_handler::EventHandlerDropGuard,
+    room::{knock_requests::KnockRequest, MessagesOptions, ReportedContentScore},
+    Error, Result, Room, RoomState,
+};
 
 /// Thin wrapper around a [`Room`] that provides functionality relevant for
 /// widgets.
 pub(crate) struct MatrixDriver {
     room: Room,
+    /// The capabilities of the homeserver, fetched once per widget session.
+    server_capabilities: OnceCell<get_capabilities::v3::Capabilities>,
+    /// The prefix of the transaction ids of the events sent by the widget.
+    transaction_id_prefix: Option<String>,
 }
 
 impl MatrixDriver {
     /// Creates a new `MatrixDriver` for a given `room`.
-    pub(crate) fn new(room: Room) -> Self {
-        Self { room }
+    pub(crate) fn new(room: Room, transaction_id_prefix: Option<String>) -> Self {
+        Self { room, server_capabilities: OnceCell::new(), transaction_id_prefix }
+    }
+
+    /// Generates a new unique transaction id for an event sent by the widget,
+    /// with the configured prefix.
+    fn new_transaction_id(&self) -> OwnedTransactionId {
+        match &self.transaction_id_prefix {
+            Some(prefix) => format!("{prefix}{}", TransactionId::new()).into(),
+            None => TransactionId::new(),
+        }
     }
 
     /// Requests an OpenID token for the current user.
@@ -79,6 +114,41 @@ impl MatrixDriver {
         Ok(messages.chunk.into_iter().map(|ev| ev.into_raw().cast()).collect())
     }
 
+    /// Reads a page of at most `limit` membership events of the room, from the
+    /// newest to the oldest one, starting at the `from` pagination token.
+    ///
+    /// If a `user_id` is given, only the membership events targeting this user
+    /// are returned, so the page can contain less than `limit` events.
+    pub(crate) async fn read_membership_events(
+        &self,
+        user_id: Option<&UserId>,
+        limit: u32,
+        from: Option<String>,
+    ) -> Result<GetMembershipEventsResponse> {
+        let options = assign!(MessagesOptions::backward(), {
+            from,
+            limit: limit.into(),
+            filter: assign!(RoomEventFilter::default(), {
+                types: Some(vec![StateEventType::RoomMember.to_string()])
+            }),
+        });
+
+        let messages = self.room.messages(options).await?;
+        let events = messages
+            .chunk
+            .into_iter()
+            .map(|ev| ev.into_raw().cast::<AnyTimelineEvent>())
+            .filter(|ev| {
+                user_id.is_none_or(|user_id| {
+                    ev.get_field::<String>("state_key").ok().flatten().as_deref()
+                        == Some(user_id.as_str())
+                })
+            })
+            .collect();
+
+        Ok(GetMembershipEventsResponse { events, next_token: messages.end })
+    }
+
     pub(crate) async fn read_state_events(
         &self,
         event_type: StateEventType,
@@ -121,9 +191,17 @@ impl MatrixDriver {
         state_key: Option<String>,
         content: Box<RawJsonValue>,
         delayed_event_parameters: Option<delayed_events::DelayParameters>,
+        update_mode: StateUpdateMode,
     ) -> Result<SendEventResponse> {
         let type_str = event_type.to_string();
 
+        let content = match (&state_key, update_mode) {
+            (Some(key), StateUpdateMode::Merge) => {
+                self.merge_state_event_content(&type_str, key, &content).await?
+            }
+            _ => content,
+        };
+
         if let Some(redacts) = from_raw_json_value::<Value, serde_json::Error>(&content)
             .ok()
             .and_then(|b| b["redacts"].as_str().and_then(|s| EventId::parse(s).ok()))
@@ -135,7 +213,11 @@ impl MatrixDriver {
 
         Ok(match (state_key, delayed_event_parameters) {
             (None, None) => SendEventResponse::from_event_id(
-                self.room.send_raw(&type_str, content).await?.event_id,
+                self.room
+                    .send_raw(&type_str, content)
+                    .with_transaction_id(&self.new_transaction_id())
+                    .await?
+                    .event_id,
             ),
 
             (Some(key), None) => SendEventResponse::from_event_id(
@@ -145,7 +227,7 @@ impl MatrixDriver {
             (None, Some(delayed_event_parameters)) => {
                 let r = delayed_events::delayed_message_event::unstable::Request::new_raw(
                     self.room.room_id().to_owned(),
-                    TransactionId::new(),
+                    self.new_transaction_id(),
                     MessageLikeEventType::from(type_str),
                     delayed_event_parameters,
                     Raw::<AnyMessageLikeEventContent>::from_json(content),
@@ -166,6 +248,37 @@ impl MatrixDriver {
         })
     }
 
+    /// Merges the top-level fields of the given `content` into the current
+    /// content of the state event with the given type and state key.
+    ///
+    /// If there's no such state event in the room yet, the given `content` is
+    /// used as is.
+    async fn merge_state_event_content(
+        &self,
+        event_type: &str,
+        state_key: &str,
+        content: &RawJsonValue,
+    ) -> Result<Box<RawJsonValue>> {
+        let mut new_content: JsonObject = serde_json::from_str(content.get())?;
+
+        let current_content =
+            match self.room.get_state_event(StateEventType::from(event_type), state_key).await? {
+                Some(RawAnySyncOrStrippedState::Sync(event)) => {
+                    event.get_field::<JsonObject>("content")?.unwrap_or_default()
+                }
+                Some(RawAnySyncOrStrippedState::Stripped(_)) => {
+                    error!("MatrixDriver can't operate in invited rooms");
+                    JsonObject::new()
+                }
+                None => JsonObject::new(),
+            };
+
+        let mut merged_content = current_content;
+        merged_content.append(&mut new_content);
+
+        Ok(serde_json::value::to_raw_value(&merged_content)?)
+    }
+
     /// Send a request to the `/delayed_events`` endpoint ([MSC4140](https://github.com/matrix-org/matrix-spec-proposals/pull/4140))
     /// This can be used to refresh cancel or send a Delayed Event (An Event
     /// that is send ahead of time to the homeserver and gets distributed
@@ -179,6 +292,201 @@ impl MatrixDriver {
         self.room.client.send(r).await.map_err(|error| Error::Http(Box::new(error)))
     }
 
+    /// Whether the given error means that the client can't be used anymore,
+    /// i.e. that it was logged out.
+    pub(crate) fn is_client_unavailable(&self, error: &Error) -> bool {
+        matches!(error.client_api_error_kind(), Some(ErrorKind::UnknownToken { .. }))
+    }
+
+    /// Whether the room can't be used anymore, i.e. that it was left.
+    pub(crate) fn is_room_unavailable(&self) -> bool {
+        self.room.state() != RoomState::Joined
+    }
+
+    /// Reports the event with the given `event_id` to the homeserver
+    /// administrators.
+    ///
+    /// Fails if the event can't be found in the room, so that a widget can't
+    /// report events from rooms it doesn't live in.
+    pub(crate) async fn report_event(
+        &self,
+        event_id: OwnedEventId,
+        score: Option<i8>,
+        reason: Option<String>,
+    ) -> Result<report_content::v3::Response> {
+        self.room.load_or_fetch_event(&event_id, None).await?;
+
+        let score = score.map(ReportedContentScore::new_saturating);
+        self.room.report_content(event_id, score, reason).await
+    }
+
+    /// Fetches the profile of the user with the given `user_id`.
+    ///
+    /// Returns an empty profile if the homeserver doesn't know about this
+    /// user. Requests to the matrix driver are processed one after the other,
+    /// so a widget asking for many profiles at once won't flood the
+    /// homeserver.
+    pub(crate) async fn get_user_profile(
+        &self,
+        user_id: &UserId,
+    ) -> Result<get_profile::v3::Response> {
+        match self.room.client.account().fetch_user_profile_of(user_id).await {
+            Err(error) if error.client_api_error_kind() == Some(&ErrorKind::NotFound) => {
+                Ok(get_profile::v3::Response::default())
+            }
+            result => result,
+        }
+    }
+
+    /// Fetches the capabilities of the homeserver.
+    ///
+    /// The capabilities are only requested once per widget session, and
+    /// cached for the subsequent requests.
+    pub(crate) async fn get_server_capabilities(
+        &self,
+    ) -> Result<get_capabilities::v3::Capabilities> {
+        if let Some(capabilities) = self.server_capabilities.get() {
+            return Ok(capabilities.clone());
+        }
+
+        let capabilities = self
+            .room
+            .client
+            .get_capabilities()
+            .await
+            .map_err(|error| Error::Http(Box::new(error)))?;
+        Ok(self.server_capabilities.get_or_init(|| capabilities).clone())
+    }
+
+    /// Gets the current requests to join the room, along with whether they
+    /// have been marked as seen.
+    pub(crate) async fn get_knock_requests(&self) -> Result<Vec<KnockRequest>> {
+        let seen_request_ids = self.room.get_seen_knock_request_ids().await?;
+        self.room.get_current_join_requests(&seen_request_ids).await
+    }
+
+    /// Gets the server access control list of the room, or `None` if it
+    /// doesn't have one.
+    pub(crate) async fn get_server_acl(&self) -> Result<Option<RoomServerAclEventContent>> {
+        let acl = self
+            .room
+            .get_state_event_static::<RoomServerAclEventContent>()
+            .await?
+            .and_then(|ev| ev.deserialize().ok());
+
+        Ok(acl.and_then(|ev| match ev {
+            SyncOrStrippedState::Sync(SyncStateEvent::Original(ev)) => Some(ev.content),
+            SyncOrStrippedState::Sync(SyncStateEvent::Redacted(_)) => None,
+            SyncOrStrippedState::Stripped(ev) => Some(ev.content),
+        }))
+    }
+
+    /// Gets the power level required for the given action, according to the
+    /// `m.room.power_levels` event of the room, along with the one of the
+    /// user.
+    pub(crate) async fn get_required_power_level(
+        &self,
+        request: GetRequiredPowerLevelRequest,
+    ) -> Result<RequiredPowerLevel> {
+        let power_levels = self.room.power_levels().await?;
+
+        let required = match request {
+            GetRequiredPowerLevelRequest::SendEvent { event_type, state } => {
+                let default =
+                    if state { power_levels.state_default } else { power_levels.events_default };
+                power_levels
+                    .events
+                    .get(&TimelineEventType::from(event_type.as_str()))
+                    .copied()
+                    .unwrap_or(default)
+            }
+            GetRequiredPowerLevelRequest::Moderate { action } => match action {
+                ModerationAction::Ban => power_levels.ban,
+                ModerationAction::Kick => power_levels.kick,
+                ModerationAction::Invite => power_levels.invite,
+                ModerationAction::Redact => power_levels.redact,
+            },
+        };
+        let own = power_levels.for_user(self.room.own_user_id());
+
+        Ok(RequiredPowerLevel { required: required.into(), own: own.into() })
+    }
+
+    /// Reacts to the event with the given `event_id` with the given `key`.
+    ///
+    /// Fails if the event can't be found in the room, so that a widget can't
+    /// annotate events from rooms it doesn't live in.
+    pub(crate) async fn send_reaction(
+        &self,
+        event_id: OwnedEventId,
+        key: String,
+    ) -> Result<SendEventResponse> {
+        self.room.load_or_fetch_event(&event_id, None).await?;
+
+        let content = ReactionEventContent::new(Annotation::new(event_id, key));
+        let response =
+            self.room.send(content).with_transaction_id(self.new_transaction_id()).await?;
+        Ok(SendEventResponse::from_event_id(response.event_id))
+    }
+
+    /// Redacts the event with the given `event_id`.
+    ///
+    /// Fails if the event can't be found in the room, or if the user isn't
+    /// allowed to redact it according to the power levels of the room.
+    pub(crate) async fn redact_event(
+        &self,
+        event_id: OwnedEventId,
+        reason: Option<String>,
+    ) -> Result<SendEventResponse> {
+        let event = self.room.load_or_fetch_event(&event_id, None).await?;
+
+        let own_user_id = self.room.own_user_id();
+        let sender = event.raw().get_field::<OwnedUserId>("sender").ok().flatten();
+        let allowed = if sender.as_deref() == Some(own_user_id) {
+            self.room.can_user_redact_own(own_user_id).await?
+        } else {
+            self.room.can_user_redact_other(own_user_id).await?
+        };
+        if !allowed {
+            return Err(Error::UnknownError(
+                "The user doesn't have the power level to redact this event".into(),
+            ));
+        }
+
+        let response =
+            self.room.redact(&event_id, reason.as_deref(), Some(self.new_transaction_id())).await?;
+        Ok(SendEventResponse::from_event_id(response.event_id))
+    }
+
+    /// Uploads the given file and sends it in the room.
+    ///
+    /// In an encrypted room, the file is encrypted before being uploaded, and
+    /// the keys to decrypt it are part of the encrypted event.
+    pub(crate) async fn send_attachment(
+        &self,
+        filename: String,
+        content_type: Mime,
+        data: Vec<u8>,
+    ) -> Result<SendEventResponse> {
+        let config = AttachmentConfig::new().txn_id(&self.new_transaction_id());
+        let response = self.room.send_attachment(filename, &content_type, data, config).await?;
+        Ok(SendEventResponse::from_event_id(response.event_id))
+    }
+
+    /// Resolves the given room ID or alias, and tells whether the user is a
+    /// member of the room.
+    pub(crate) async fn resolve_room(&self, room: &RoomOrAliasId) -> Result<(OwnedRoomId, bool)> {
+        let client = &self.room.client;
+        let room_id = match <&RoomId>::try_from(room) {
+            Ok(room_id) => room_id.to_owned(),
+            Err(alias) => client.resolve_room_alias(alias).await?.room_id,
+        };
+
+        let is_joined =
+            client.get_room(&room_id).is_some_and(|room| room.state() == RoomState::Joined);
+        Ok((room_id, is_joined))
+    }
+
     /// Starts forwarding new room events. Once the returned `EventReceiver`
     /// is dropped, forwarding will be stopped.
     pub(crate) fn events(&self) -> EventReceiver {
@@ -210,6 +518,30 @@ impl MatrixDriver {
         // events.
         EventReceiver { rx, _drop_guards: [drop_guard_msg_like, drop_guard_state] }
     }
+
+    /// Starts forwarding new ephemeral events of the room, e.g. typing
+    /// notifications and read receipts, debounced according to the given
+    /// clock. Once the returned `EphemeralEventReceiver` is dropped,
+    /// forwarding will be stopped.
+    pub(crate) fn ephemeral_events(&self, clock: Arc<dyn Clock>) -> EphemeralEventReceiver {
+        let (tx, rx) = unbounded_channel();
+        let room_id = self.room.room_id().to_owned();
+
+        let handle = self.room.add_event_handler(move |raw: Raw<AnySyncEphemeralRoomEvent>| {
+            let _ = tx.send(attach_room_id(raw.cast_ref(), &room_id).cast());
+            async {}
+        });
+        let drop_guard = self.room.client().event_handler_drop_guard(handle);
+
+        EphemeralEventReceiver {
+            rx,
+            pending_typing: None,
+            pending_typing_since: clock.now(),
+            next: None,
+            clock,
+            _drop_guard: drop_guard,
+        }
+    }
 }
 
 /// A simple entity that wraps an `UnboundedReceiver`
@@ -225,8 +557,134 @@ impl EventReceiver {
     }
 }
 
+/// How long a typing notification is held back, waiting for a newer one to
+/// replace it.
+const TYPING_DEBOUNCE_DELAY: Duration = Duration::from_millis(300);
+
+/// The longest time a typing notification is held back, even if newer ones
+/// keep replacing it, so that the widget isn't starved while a user keeps
+/// typing.
+const TYPING_DEBOUNCE_MAX_WAIT: Duration = Duration::from_secs(1);
+
+/// A simple entity that wraps an `UnboundedReceiver` of ephemeral events
+/// along with the drop guard for the room event handler.
+///
+/// Typing notifications are debounced: when several of them are received in
+/// a quick succession, only the latest one is returned, at the latest
+/// [`TYPING_DEBOUNCE_MAX_WAIT`] after the first one was received.
+pub(crate) struct EphemeralEventReceiver {
+    rx: UnboundedReceiver<Raw<AnyEphemeralRoomEvent>>,
+    /// The latest typing notification, while waiting for a newer one.
+    pending_typing: Option<Raw<AnyEphemeralRoomEvent>>,
+    /// When the first of the typing notifications replaced by
+    /// `pending_typing` was received.
+    pending_typing_since: Instant,
+    /// An event received while waiting for a newer typing notification, to
+    /// be returned right after it.
+    next: Option<Raw<AnyEphemeralRoomEvent>>,
+    /// The clock of the driver, to wait for newer typing notifications.
+    clock: Arc<dyn Clock>,
+    _drop_guard: EventHandlerDropGuard,
+}
+
+impl EphemeralEventReceiver {
+    /// Receive the next ephemeral event.
+    ///
+    /// This is cancel-safe: a pending typing notification is kept until the
+    /// next call.
+    pub(crate) async fn recv(&mut self) -> Option<Raw<AnyEphemeralRoomEvent>> {
+        if let Some(event) = self.next.take() {
+            return Some(event);
+        }
+
+        loop {
+            if self.pending_typing.is_none() {
+                let event = self.rx.recv().await?;
+                if !is_typing_event(&event) {
+                    return Some(event);
+                }
+                self.pending_typing = Some(event);
+                self.pending_typing_since = self.clock.now();
+            }
+
+            let elapsed = self.clock.now().saturating_duration_since(self.pending_typing_since);
+            let remaining = TYPING_DEBOUNCE_MAX_WAIT.saturating_sub(elapsed);
+            if remaining.is_zero() {
+                return self.pending_typing.take();
+            }
+
+            let delay = self.clock.sleep(TYPING_DEBOUNCE_DELAY.min(remaining));
+            tokio::select! {
+                biased;
+
+                event = self.rx.recv() => match event {
+                    Some(event) if is_typing_event(&event) => self.pending_typing = Some(event),
+                    Some(event) => {
+                        self.next = Some(event);
+                        return self.pending_typing.take();
+                    }
+                    None => return self.pending_typing.take(),
+                },
+                _ = delay => return self.pending_typing.take(),
+            }
+        }
+    }
+}
+
+fn is_typing_event(event: &Raw<AnyEphemeralRoomEvent>) -> bool {
+    matches!(event.get_field::<&str>("type"), Ok(Some("m.typing")))
+}
+
 fn attach_room_id(raw_ev: &Raw<AnySyncTimelineEvent>, room_id: &RoomId) -> Raw<AnyTimelineEvent> {
     let mut ev_obj = raw_ev.deserialize_as::<BTreeMap<String, Box<RawJsonValue>>>().unwrap();
     ev_obj.insert("room_id".to_owned(), serde_json::value::to_raw_value(room_id).unwrap());
     Raw::new(&ev_obj).unwrap().cast()
 }
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use matrix_sdk_test::{event_factory::EventFactory, JoinedRoomBuilder};
+    use ruma::{room_id, user_id};
+
+    use super::MatrixDriver;
+    use crate::{test_utils::mocks::MatrixMockServer, widget::clock::TestClock};
+
+    #[matrix_sdk_test::async_test]
+    async fn test_typing_notifications_are_debounced_according_to_the_clock() {
+        let server = MatrixMockServer::new().await;
+        let client = server.client_builder().build().await;
+        let room_id = room_id!("!a:b.c");
+        let room = server.sync_joined_room(&client, room_id).await;
+
+        let clock = TestClock::new();
+        let mut ephemeral = MatrixDriver::new(room, None).ephemeral_events(Arc::new(clock.clone()));
+
+        // Two typing notifications are received in a quick succession.
+        let f = EventFactory::new();
+        for user_id in [user_id!("@alice:b.c"), user_id!("@bob:b.c")] {
+            server
+                .sync_room(
+                    &client,
+                    JoinedRoomBuilder::new(room_id).add_typing(f.typing(vec![user_id])),
+                )
+                .await;
+        }
+
+        let recv = tokio::spawn(async move { ephemeral.recv().await });
+
+        // The latest one is held back while waiting for a newer one.
+        clock.until_sleeping().await;
+        clock.advance(Duration::from_millis(299));
+        tokio::task::yield_now().await;
+        assert!(!recv.is_finished());
+
+        // It is returned once the debounce delay is over.
+        clock.advance(Duration::from_millis(1));
+        let event = recv.await.unwrap().unwrap();
+        assert_eq!(event.get_field::<String>("type").unwrap().as_deref(), Some("m.typing"));
+        let content = event.get_field::<serde_json::Value>("content").unwrap().unwrap();
+        assert_eq!(content["user_ids"], serde_json::json!(["@bob:b.c"]));
+    }
+}
@@ -14,38 +14,52 @@
 
 //! Widget API implementation.
 
-use std::{fmt, time::Duration};
+use std::{fmt, mem, sync::Arc, time::Duration};
 
 use async_channel::{Receiver, Sender};
-use futures_util::StreamExt;
+use async_trait::async_trait;
+use eyeball::{SharedObservable, Subscriber};
+use futures_util::{stream::FuturesUnordered, StreamExt};
 use matrix_sdk_common::executor::spawn;
-use ruma::api::client::delayed_events::DelayParameters;
+use ruma::{api::client::delayed_events::DelayParameters, OwnedRoomId, RoomOrAliasId};
 use serde::de::{self, Deserialize, Deserializer, Visitor};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::{
+    broadcast,
+    mpsc::{unbounded_channel, UnboundedSender},
+};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{debug, info, warn, Level};
 
 use self::{
+    clock::{Clock, SystemClock},
     machine::{
-        Action, IncomingMessage, MatrixDriverRequestData, MatrixDriverResponse, SendEventRequest,
-        WidgetMachine,
+        Action, DelayedEvents, IncomingMessage, MatrixDriverRequestData, MatrixDriverResponse,
+        SendEventRequest, WidgetMachine,
     },
     matrix::MatrixDriver,
 };
-use crate::{room::Room, Result};
+use crate::{room::Room, Error, Result, RoomState};
 
 mod capabilities;
+mod clock;
 mod filter;
 mod machine;
 mod matrix;
 mod settings;
+mod trace;
 
+pub(crate) use self::settings::WIDGET_STATE_EVENT_TYPES;
 pub use self::{
-    capabilities::{Capabilities, CapabilitiesProvider},
+    capabilities::{Capabilities, CapabilitiesProvider, DeprecatedCapabilities},
     filter::{Filter, MessageLikeEventFilter, StateEventFilter},
     settings::{
-        ClientProperties, EncryptionSystem, Intent, VirtualElementCallWidgetOptions, WidgetSettings,
+        AnalyticsBackend, AnalyticsConfig, ClientProperties, ClientPropertiesBuilder,
+        ClientPropertiesOverrides, ElementCallParams, EncryptionSystem, GenerateWebviewUrlError,
+        Intent, ParamPlacement, ParentUrlDefault, VirtualElementCallWidgetOptions, WidgetSettings,
+        WidgetSettingsError, WidgetStateEvent,
     },
+    trace::{SessionTrace, SessionTraceStep},
 };
 
 /// An object that handles all interactions of a widget living inside a webview
@@ -65,15 +79,221 @@ pub struct WidgetDriver {
     /// These can be both requests and responses.
     to_widget_tx: Sender<String>,
 
+    /// Notifications from the client about changes that are relevant to the
+    /// widget, e.g. a new theme.
+    from_client_rx: Receiver<IncomingMessage>,
+
+    /// A copy of all the outgoing messages, with their secrets redacted, for
+    /// the subscribers of [`WidgetDriverHandle::subscribe_to_outgoing_messages`].
+    audit_tx: broadcast::Sender<String>,
+
+    /// Notifies the subscribers of [`WidgetDriverHandle::subscribe_to_joins`]
+    /// when the widget wants to join.
+    join_tx: broadcast::Sender<()>,
+
+    /// Whether the widget asked to be kept visible on the screen.
+    always_on_screen: SharedObservable<bool>,
+
+    /// The capabilities requested by the widget, before the
+    /// [`CapabilitiesProvider`] approved them.
+    requested_capabilities: SharedObservable<Option<Capabilities>>,
+
+    /// The capabilities approved by the [`CapabilitiesProvider`].
+    approved_capabilities: SharedObservable<Option<Capabilities>>,
+
+    /// The trace recording the steps of the session, if enabled with
+    /// [`WidgetSettings::with_session_trace`].
+    session_trace: Option<SessionTrace>,
+
+    /// The delayed events sent by the widget that it can still update,
+    /// including the ones sent in a previous session.
+    delayed_events: DelayedEvents,
+
+    /// The requested and approved capabilities of a previous session, to
+    /// restore instead of negotiating them again.
+    ///
+    /// Only set if the driver was created with [`WidgetDriver::resume_from`].
+    resumed_capabilities: Option<(Capabilities, Capabilities)>,
+
     /// Drop guard for an event handler forwarding all events from the Matrix
     /// room to the widget.
     ///
     /// Only set if a subscription happened ([`Action::Subscribe`]).
     event_forwarding_guard: Option<DropGuard>,
+
+    /// Handles the requests of the widget to navigate to another room.
+    navigation_handler: Option<Arc<dyn NavigationHandler>>,
+
+    /// Cancelled when the session is stopped with
+    /// [`WidgetDriverHandle::stop`].
+    stop_token: CancellationToken,
+
+    /// The source of time of the request timeouts and the reconnection delays.
+    clock: Arc<dyn Clock>,
+}
+
+/// The reason why a widget session, i.e. [`WidgetDriver::run`], ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// The widget is gone, i.e. the [`WidgetDriverHandle`] was dropped.
+    WidgetDisconnected,
+
+    /// The client can't serve the widget anymore, because it was logged out.
+    ///
+    /// The request of the widget that ran into this condition was answered
+    /// with an error beforehand.
+    ClientUnavailable,
+
+    /// The room of the widget isn't joined, either because it was left during
+    /// the session, or because it wasn't joined when the session started.
+    ///
+    /// If the room was left during the session, the request of the widget that
+    /// ran into this condition was answered with an error beforehand.
+    RoomUnavailable,
+
+    /// The [`CapabilitiesProvider`] refused to run the widget at all, see
+    /// [`CapabilitiesProvider::allow_widget`].
+    PermissionsDenied,
+
+    /// The session was stopped with [`WidgetDriverHandle::stop`].
+    Stopped,
+}
+
+impl SessionEnd {
+    /// Whether a new session can't be started for the same widget after this
+    /// one, e.g. because the client was logged out.
+    ///
+    /// A [`SessionEnd::RoomUnavailable`] is fatal too: a new session would end
+    /// right away until the room is joined again, which the client should
+    /// wait for before starting a new session.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Self::WidgetDisconnected => false,
+            Self::ClientUnavailable
+            | Self::RoomUnavailable
+            | Self::PermissionsDenied
+            | Self::Stopped => true,
+        }
+    }
+
+    /// Convert this into a [`Result`], which is only `Ok` if the session was
+    /// stopped by the client.
+    pub fn into_result(self) -> Result<(), WidgetError> {
+        match self {
+            Self::Stopped => Ok(()),
+            Self::WidgetDisconnected => Err(WidgetError::ChannelClosed),
+            Self::ClientUnavailable => Err(WidgetError::ClientUnavailable),
+            Self::RoomUnavailable => Err(WidgetError::RoomNotJoined),
+            Self::PermissionsDenied => Err(WidgetError::PermissionsDenied),
+        }
+    }
+}
+
+/// An error describing why a widget session failed, see
+/// [`SessionEnd::into_result`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum WidgetError {
+    /// The room of the widget isn't joined.
+    #[error("the room of the widget isn't joined")]
+    RoomNotJoined,
+
+    /// The [`CapabilitiesProvider`] refused to run the widget.
+    #[error("the widget isn't allowed to run")]
+    PermissionsDenied,
+
+    /// The widget disconnected.
+    #[error("the widget disconnected")]
+    ChannelClosed,
+
+    /// The client can't serve the widget anymore, because it was logged out.
+    #[error("the client can't serve the widget anymore")]
+    ClientUnavailable,
+}
+
+/// How [`WidgetDriver::run_with_reconnect`] restarts a session after a
+/// non-fatal [`SessionEnd`].
+///
+/// The delay before a restart starts at `initial_delay` and doubles after each
+/// attempt, up to `max_delay`. A session that ran for at least `reset_after`
+/// is considered healthy: the delay goes back to `initial_delay` and the
+/// attempts are counted from zero again.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// The delay before the first restart.
+    pub initial_delay: Duration,
+    /// The maximum delay between two restarts.
+    pub max_delay: Duration,
+    /// The maximum number of consecutive restarts, or `None` to restart the
+    /// session until it ends for a fatal reason.
+    pub max_attempts: Option<u32>,
+    /// The duration after which a session resets the delay and the number of
+    /// attempts.
+    pub reset_after: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_attempts: Some(10),
+            reset_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The state of a widget session that can be restored in a new session with
+/// [`WidgetDriver::resume_from`], e.g. when the app comes back to the
+/// foreground shortly after being backgrounded.
+///
+/// Only the negotiated capabilities and the delayed events that the widget can
+/// still update are part of it. The requests that were pending in the previous
+/// session are considered failed: they don't get a response in the new
+/// session.
+#[derive(Clone, Debug, Default)]
+pub struct SessionSnapshot {
+    requested_capabilities: Option<Capabilities>,
+    approved_capabilities: Option<Capabilities>,
+    delay_ids: Vec<String>,
+}
+
+impl SessionSnapshot {
+    /// The capabilities approved in the session, or `None` if they weren't
+    /// negotiated yet.
+    pub fn approved_capabilities(&self) -> Option<&Capabilities> {
+        self.approved_capabilities.as_ref()
+    }
+}
+
+/// Must be implemented by a component that lets a widget navigate to another
+/// room, e.g. a room directory widget which opens the room selected by the
+/// user.
+#[async_trait]
+pub trait NavigationHandler: Send + Sync + 'static {
+    /// Receives a request of the widget to navigate to the room with the given
+    /// id, and returns whether the client accepted it, e.g. by focusing the
+    /// room.
+    ///
+    /// The room is either joined by the user, or the navigation to rooms that
+    /// aren't was allowed with
+    /// [`WidgetSettings::with_allow_navigation_to_unjoined_rooms`].
+    async fn navigate_to_room(&self, room_id: OwnedRoomId) -> bool;
+}
+
+impl fmt::Debug for dyn NavigationHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NavigationHandler").finish_non_exhaustive()
+    }
 }
 
 /// A handle that encapsulates the communication between a widget driver and the
 /// corresponding widget (inside a webview or iframe).
+///
+/// The channels of the handle stay open as long as the session, i.e.
+/// [`WidgetDriver::run`], is running. Once it ends, for whatever reason, they
+/// are closed: [`WidgetDriverHandle::recv`] returns `None` once the last
+/// messages to the widget have been received, and [`WidgetDriverHandle::send`]
+/// returns `false`. Dropping all the clones of the handle ends the session.
 #[derive(Clone, Debug)]
 pub struct WidgetDriverHandle {
     /// Raw incoming messages from the widget driver to the widget (normally
@@ -91,6 +311,34 @@ pub struct WidgetDriverHandle {
     /// care what's what though because they are only supposed to forward
     /// messages between the webview / iframe, and the SDK's widget driver.
     from_widget_tx: Sender<String>,
+
+    /// Notifications from the client about changes that are relevant to the
+    /// widget, e.g. a new theme.
+    from_client_tx: Sender<IncomingMessage>,
+
+    /// A copy of all the outgoing messages, with their secrets redacted.
+    audit_tx: broadcast::Sender<String>,
+
+    /// Notified when the widget wants to join.
+    join_tx: broadcast::Sender<()>,
+
+    /// Whether the widget asked to be kept visible on the screen.
+    always_on_screen: SharedObservable<bool>,
+
+    /// The capabilities requested by the widget.
+    requested_capabilities: SharedObservable<Option<Capabilities>>,
+
+    /// The capabilities approved for the widget.
+    approved_capabilities: SharedObservable<Option<Capabilities>>,
+
+    /// The trace recording the steps of the session, if enabled.
+    session_trace: Option<SessionTrace>,
+
+    /// The delayed events sent by the widget that it can still update.
+    delayed_events: DelayedEvents,
+
+    /// Cancelled to stop the session.
+    stop_token: CancellationToken,
 }
 
 impl WidgetDriverHandle {
@@ -109,6 +357,165 @@ impl WidgetDriverHandle {
     pub async fn send(&self, message: String) -> bool {
         self.from_widget_tx.send(message).await.is_ok()
     }
+
+    /// Stop the widget session, e.g. because the widget was closed.
+    ///
+    /// The pending requests of the widget and of the widget driver are
+    /// abandoned. The messages sent by the widget afterwards, e.g. late
+    /// responses to the requests of the widget driver, are dropped, and
+    /// [`Self::send`] returns `false` for them.
+    ///
+    /// [`WidgetDriver::run`] then returns [`SessionEnd::Stopped`].
+    pub fn stop(&self) {
+        self.from_widget_tx.close();
+        self.stop_token.cancel();
+    }
+
+    /// Pause the delivery of the events of the room to the widget, e.g. while
+    /// the widget isn't visible, without ending the session.
+    ///
+    /// While paused, the latest events that the widget may read are held back
+    /// and delivered once the session is resumed with [`Self::resume`]; the
+    /// older ones, as well as all the ephemeral events (e.g. typing
+    /// notifications), are dropped. The requests of the widget are still
+    /// answered.
+    ///
+    /// Returns `false` if the widget driver is no longer running.
+    pub async fn pause(&self) -> bool {
+        self.from_client_tx.send(IncomingMessage::Pause).await.is_ok()
+    }
+
+    /// Resume the delivery of the events of the room to the widget, after
+    /// [`Self::pause`].
+    ///
+    /// Returns `false` if the widget driver is no longer running.
+    pub async fn resume(&self) -> bool {
+        self.from_client_tx.send(IncomingMessage::Resume).await.is_ok()
+    }
+
+    /// Grant or revoke capabilities while the widget is running, by acquiring
+    /// the given capabilities from the [`CapabilitiesProvider`] instead of the
+    /// negotiated ones.
+    ///
+    /// The negotiated capabilities that aren't desired anymore are revoked
+    /// immediately, while the new ones are only granted once approved. The
+    /// widget is then notified of the approved capabilities.
+    ///
+    /// Returns `false` if the capabilities weren't negotiated yet, in which
+    /// case there is nothing to update, or if the widget driver is no longer
+    /// running.
+    pub async fn update_capabilities(&self, desired_capabilities: Capabilities) -> bool {
+        if self.approved_capabilities.get().is_none() {
+            warn!("Can't update the capabilities before they were negotiated");
+            return false;
+        }
+
+        self.from_client_tx
+            .send(IncomingMessage::UpdateCapabilities(desired_capabilities))
+            .await
+            .is_ok()
+    }
+
+    /// Send the latest critical messages to the widget again, e.g. the
+    /// notification of its approved capabilities, because it reconnected and
+    /// may have missed them, e.g. after its webview was reloaded.
+    ///
+    /// The messages are sent as new requests, which the widget must answer
+    /// again.
+    ///
+    /// Returns `false` if the widget driver is no longer running.
+    pub async fn replay_on_reconnect(&self) -> bool {
+        self.from_client_tx.send(IncomingMessage::WidgetReconnected).await.is_ok()
+    }
+
+    /// Notify the widget that the theme of the client changed, e.g. from
+    /// `light` to `dark`, with a `theme_change` action.
+    ///
+    /// Returns `false` if the widget driver is no longer running.
+    pub async fn notify_theme_change(&self, theme: String) -> bool {
+        self.from_client_tx.send(IncomingMessage::ThemeChanged(theme)).await.is_ok()
+    }
+
+    /// Notify the widget that the properties of the client changed, e.g.
+    /// because the user changed the theme or the language of the system while
+    /// the widget is running.
+    ///
+    /// The widget receives the new theme with a `theme_change` action and the
+    /// new language with a `language_change` action. Only the properties that
+    /// differ from the ones notified earlier in the session are sent, so the
+    /// first update notifies both of them.
+    ///
+    /// Returns `false` if the widget driver is no longer running.
+    pub async fn update_client_properties(&self, props: ClientProperties) -> bool {
+        let message = IncomingMessage::ClientPropertiesChanged {
+            theme: props.theme().to_owned(),
+            language: props.language().to_string(),
+        };
+        self.from_client_tx.send(message).await.is_ok()
+    }
+
+    /// Subscribe to a read-only copy of all the messages sent by the widget
+    /// driver to the widget, e.g. to keep an audit log of them.
+    ///
+    /// Secrets like OpenID access tokens are redacted from the messages. A
+    /// subscriber that doesn't keep up misses the oldest messages, instead of
+    /// slowing down the widget driver.
+    pub fn subscribe_to_outgoing_messages(&self) -> broadcast::Receiver<String> {
+        self.audit_tx.subscribe()
+    }
+
+    /// Subscribe to the `io.element.join` requests of the widget.
+    ///
+    /// A preloaded Element Call widget sends this request once the user wants
+    /// to join the call, the client can then e.g. show the widget. The widget
+    /// driver acknowledges the request on its own.
+    pub fn subscribe_to_joins(&self) -> broadcast::Receiver<()> {
+        self.join_tx.subscribe()
+    }
+
+    /// Whether the widget asked to be kept visible on the screen (with the
+    /// `set_always_on_screen` action), e.g. while a call is ongoing.
+    pub fn is_always_on_screen(&self) -> bool {
+        self.always_on_screen.get()
+    }
+
+    /// Get a subscriber to observe whether the widget asked to be kept visible
+    /// on the screen.
+    pub fn subscribe_to_always_on_screen(&self) -> Subscriber<bool> {
+        self.always_on_screen.subscribe()
+    }
+
+    /// The capabilities requested by the widget, or `None` if it didn't answer
+    /// the `capabilities` request yet.
+    ///
+    /// They are set as soon as the widget answers, before the
+    /// [`CapabilitiesProvider`] approved them, e.g. to show a consent prompt
+    /// to the user right away. Some of them may not be approved in the end.
+    pub fn requested_capabilities(&self) -> Option<Capabilities> {
+        self.requested_capabilities.get()
+    }
+
+    /// Get a subscriber to observe the capabilities requested by the widget.
+    pub fn subscribe_to_requested_capabilities(&self) -> Subscriber<Option<Capabilities>> {
+        self.requested_capabilities.subscribe()
+    }
+
+    /// The trace recording the steps of the session, if it was enabled with
+    /// [`WidgetSettings::with_session_trace`].
+    pub fn session_trace(&self) -> Option<SessionTrace> {
+        self.session_trace.clone()
+    }
+
+    /// Take a snapshot of the session, to restore it later in a new session
+    /// with [`WidgetDriver::resume_from`] without negotiating the
+    /// capabilities again.
+    pub fn snapshot(&self) -> SessionSnapshot {
+        SessionSnapshot {
+            requested_capabilities: self.requested_capabilities.get(),
+            approved_capabilities: self.approved_capabilities.get(),
+            delay_ids: self.delayed_events.delay_ids(),
+        }
+    }
 }
 
 impl WidgetDriver {
@@ -117,26 +524,129 @@ impl WidgetDriver {
     pub fn new(settings: WidgetSettings) -> (Self, WidgetDriverHandle) {
         let (from_widget_tx, from_widget_rx) = async_channel::unbounded();
         let (to_widget_tx, to_widget_rx) = async_channel::unbounded();
+        let (from_client_tx, from_client_rx) = async_channel::unbounded();
+        let (audit_tx, _) = broadcast::channel(32);
+        let (join_tx, _) = broadcast::channel(1);
+        let always_on_screen = SharedObservable::new(false);
+        let requested_capabilities = SharedObservable::new(None);
+        let approved_capabilities = SharedObservable::new(None);
+        let session_trace = settings.session_trace().then(SessionTrace::default);
+        let delayed_events = DelayedEvents::default();
+        let stop_token = CancellationToken::new();
 
-        let driver = Self { settings, from_widget_rx, to_widget_tx, event_forwarding_guard: None };
-        let channels = WidgetDriverHandle { from_widget_tx, to_widget_rx };
+        let driver = Self {
+            settings,
+            from_widget_rx,
+            to_widget_tx,
+            from_client_rx,
+            audit_tx: audit_tx.clone(),
+            join_tx: join_tx.clone(),
+            always_on_screen: always_on_screen.clone(),
+            requested_capabilities: requested_capabilities.clone(),
+            approved_capabilities: approved_capabilities.clone(),
+            session_trace: session_trace.clone(),
+            delayed_events: delayed_events.clone(),
+            resumed_capabilities: None,
+            event_forwarding_guard: None,
+            navigation_handler: None,
+            stop_token: stop_token.clone(),
+            clock: Arc::new(SystemClock),
+        };
+        let channels = WidgetDriverHandle {
+            from_widget_tx,
+            to_widget_rx,
+            from_client_tx,
+            audit_tx,
+            join_tx,
+            always_on_screen,
+            requested_capabilities,
+            approved_capabilities,
+            session_trace,
+            delayed_events,
+            stop_token,
+        };
 
         (driver, channels)
     }
 
+    /// Creates a new `WidgetDriver` which restores the state of a previous
+    /// session from the given snapshot, taken with
+    /// [`WidgetDriverHandle::snapshot`].
+    ///
+    /// The capabilities approved in the previous session are used right away,
+    /// without asking the widget and the [`CapabilitiesProvider`] again, and
+    /// the widget is notified about them. If they weren't negotiated yet, the
+    /// session starts like a new one. The widget can still update the delayed
+    /// events that it sent in the previous session.
+    pub fn resume_from(
+        settings: WidgetSettings,
+        snapshot: SessionSnapshot,
+    ) -> (Self, WidgetDriverHandle) {
+        let (mut driver, handle) = Self::new(settings);
+
+        let SessionSnapshot { requested_capabilities, approved_capabilities, delay_ids } = snapshot;
+        driver.delayed_events.extend(delay_ids);
+        if let Some(approved_capabilities) = approved_capabilities {
+            let requested_capabilities = requested_capabilities.unwrap_or_default();
+            driver.requested_capabilities.set(Some(requested_capabilities.clone()));
+            driver.approved_capabilities.set(Some(approved_capabilities.clone()));
+            driver.resumed_capabilities = Some((requested_capabilities, approved_capabilities));
+        }
+
+        (driver, handle)
+    }
+
+    /// Set the component that handles the requests of the widget to navigate
+    /// to another room.
+    ///
+    /// Without it, these requests are rejected.
+    pub fn with_navigation_handler(mut self, navigation_handler: impl NavigationHandler) -> Self {
+        self.navigation_handler = Some(Arc::new(navigation_handler));
+        self
+    }
+
+    /// Replace the clock of the driver, to control its timeouts in tests.
+    #[cfg(test)]
+    pub(crate) fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
     /// Run client widget API state machine in a given joined `room` forever.
     ///
-    /// The function returns once the widget is disconnected or any terminal
-    /// error occurs.
+    /// The function returns once the widget is disconnected, once the session
+    /// is stopped, or once the client can't be used for the `room` anymore,
+    /// e.g. because it was logged out. The returned [`SessionEnd`] tells which
+    /// one happened.
+    ///
+    /// If the `room` isn't joined, the session ends immediately with
+    /// [`SessionEnd::RoomUnavailable`]. If the `capabilities_provider` doesn't
+    /// allow the widget, it ends immediately with
+    /// [`SessionEnd::PermissionsDenied`].
     pub async fn run(
         mut self,
         room: Room,
         capabilities_provider: impl CapabilitiesProvider,
-    ) -> Result<(), ()> {
+    ) -> SessionEnd {
+        if room.state() != RoomState::Joined {
+            info!(room_id = ?room.room_id(), "Not running the widget in a room that isn't joined");
+            self.to_widget_tx.close();
+            self.from_widget_rx.close();
+            return SessionEnd::RoomUnavailable;
+        }
+
+        if !capabilities_provider.allow_widget(&self.settings).await {
+            info!(widget_id = self.settings.widget_id(), "The widget isn't allowed to run");
+            self.to_widget_tx.close();
+            self.from_widget_rx.close();
+            return SessionEnd::PermissionsDenied;
+        }
+
         // Create a channel so that we can conveniently send all messages to it.
         //
         // It will receive:
         // - all incoming messages from the widget
+        // - all notifications from the client
         // - all responses from the Matrix driver
         // - all events from the Matrix driver, if subscribed
         let (incoming_msg_tx, incoming_msg_rx) = unbounded_channel();
@@ -150,24 +660,63 @@ impl WidgetDriver {
         spawn({
             let incoming_msg_tx = incoming_msg_tx.clone();
             let from_widget_rx = self.from_widget_rx.clone();
+            let widget_id = self.settings.widget_id().to_owned();
+            let debug_enabled = self.debug_enabled();
 
             async move {
                 while let Ok(msg) = from_widget_rx.recv().await {
+                    if debug_enabled {
+                        debug!(widget_id, "Received a message from the widget");
+                    }
                     let _ = incoming_msg_tx.send(IncomingMessage::WidgetMessage(msg));
                 }
             }
         });
 
+        // Forward all of the notifications from the client.
+        spawn({
+            let incoming_msg_tx = incoming_msg_tx.clone();
+            let from_client_rx = self.from_client_rx.clone();
+
+            async move {
+                while let Ok(msg) = from_client_rx.recv().await {
+                    let _ = incoming_msg_tx.send(msg);
+                }
+            }
+        });
+
         // Create the widget API machine. The widget machine will process messages it
         // receives from the widget and convert it into actions the `MatrixDriver` will
         // then execute on.
-        let (mut widget_machine, initial_actions) = WidgetMachine::new(
+        //
+        // The capabilities of a resumed session aren't negotiated again.
+        let (mut widget_machine, mut initial_actions) = WidgetMachine::with_clock(
             self.settings.widget_id().to_owned(),
             room.room_id().to_owned(),
-            self.settings.init_on_content_load(),
+            self.settings.should_init_after_content_load() || self.resumed_capabilities.is_some(),
+            self.settings.to_widget_timeout(),
+            self.clock.clone(),
         );
+        widget_machine.set_denied_event_types(self.settings.denied_event_types().to_vec());
+        widget_machine.set_deprecated_capabilities(self.settings.deprecated_capabilities().clone());
+        widget_machine.set_max_message_depth(self.settings.max_message_depth());
+        widget_machine.set_strict_json(self.settings.strict_json());
+        widget_machine.set_openid_pre_consented(self.settings.openid_pre_consented());
+        widget_machine.set_delayed_events(self.delayed_events.clone());
 
-        let matrix_driver = MatrixDriver::new(room.clone());
+        if let Some((requested, approved)) = self.resumed_capabilities.take() {
+            initial_actions = widget_machine.set_negotiated_capabilities(requested, approved);
+        }
+
+        if let Some(session_trace) = &self.session_trace {
+            session_trace.record_actions(&initial_actions);
+            widget_machine.set_session_trace(session_trace.clone());
+        }
+
+        let matrix_driver = MatrixDriver::new(
+            room.clone(),
+            self.settings.transaction_id_prefix().map(ToOwned::to_owned),
+        );
 
         // Convert the incoming message receiver into a stream of actions.
         let stream = UnboundedReceiverStream::new(incoming_msg_rx)
@@ -176,15 +725,148 @@ impl WidgetDriver {
         // Let's combine our set of initial actions with the stream of received actions.
         let mut combined = tokio_stream::iter(initial_actions).chain(stream);
 
-        // Let's now process all actions we receive forever.
-        while let Some(action) = combined.next().await {
-            self.process_action(&matrix_driver, &incoming_msg_tx, &capabilities_provider, action)
-                .await?;
+        // Let's now process all actions we receive forever, or until the session is
+        // stopped.
+        let stop_token = self.stop_token.clone();
+        let clock = self.clock.clone();
+        let to_widget_timeout = self.settings.to_widget_timeout();
+        let process_actions = async {
+            // Each message sent to the widget arms a timer, so that the requests among
+            // them fail once they timed out, even if nothing else happens in the session.
+            let mut to_widget_timeouts = FuturesUnordered::new();
+
+            loop {
+                let action = tokio::select! {
+                    action = combined.next() => action,
+                    Some(()) = to_widget_timeouts.next() => {
+                        let _ = incoming_msg_tx.send(IncomingMessage::ToWidgetTimeoutElapsed);
+                        continue;
+                    }
+                };
+                let Some(action) = action else {
+                    break;
+                };
+
+                let sent_to_widget = matches!(action, Action::SendToWidget(_));
+                if let Err(session_end) = self
+                    .process_action(
+                        &matrix_driver,
+                        &incoming_msg_tx,
+                        &capabilities_provider,
+                        action,
+                    )
+                    .await
+                {
+                    return session_end;
+                }
+
+                if sent_to_widget {
+                    to_widget_timeouts.push(clock.sleep(to_widget_timeout));
+                }
+            }
+
+            SessionEnd::WidgetDisconnected
+        };
+
+        let session_end = tokio::select! {
+            biased;
+
+            // The pending requests are abandoned along with the widget machine.
+            _ = stop_token.cancelled() => SessionEnd::Stopped,
+            session_end = process_actions => session_end,
+        };
+
+        // Close the channels with the widget, so that the other side observes that the
+        // session ended, even if it still holds a `WidgetDriverHandle`.
+        self.to_widget_tx.close();
+        self.from_widget_rx.close();
+
+        session_end
+    }
+
+    /// Run widget sessions in the given joined `room`, restarting them when
+    /// they end for a non-fatal reason, see [`SessionEnd::is_fatal`].
+    ///
+    /// `new_driver` is called to create the driver of each session, so that
+    /// the client can connect the new [`WidgetDriverHandle`] to the widget.
+    /// The restarts are delayed according to the given `policy`. The widget can
+    /// still update the delayed events that it sent in the previous sessions.
+    ///
+    /// Returns the [`SessionEnd`] of the last session, once it ended for a
+    /// fatal reason or once the maximum number of consecutive restarts was
+    /// reached.
+    pub async fn run_with_reconnect(
+        room: Room,
+        capabilities_provider: impl CapabilitiesProvider + Clone,
+        policy: ReconnectPolicy,
+        mut new_driver: impl FnMut() -> WidgetDriver,
+    ) -> SessionEnd {
+        let mut attempts = 0;
+        let mut delay = policy.initial_delay;
+        let mut delay_ids = Vec::new();
+
+        loop {
+            let driver = new_driver();
+            driver.delayed_events.extend(mem::take(&mut delay_ids));
+            let delayed_events = driver.delayed_events.clone();
+            let clock = driver.clock.clone();
+            let started = clock.now();
+            let session_end = driver.run(room.clone(), capabilities_provider.clone()).await;
+            delay_ids = delayed_events.delay_ids();
+
+            // Don't let the failures of a session that recovered count against the
+            // following ones.
+            if clock.now().saturating_duration_since(started) >= policy.reset_after {
+                attempts = 0;
+                delay = policy.initial_delay;
+            }
+
+            if session_end.is_fatal()
+                || policy.max_attempts.is_some_and(|max_attempts| attempts >= max_attempts)
+            {
+                return session_end;
+            }
+
+            attempts += 1;
+            info!(?session_end, attempts, ?delay, "Restarting the widget session");
+            clock.sleep(delay).await;
+            delay = delay.saturating_mul(2).min(policy.max_delay);
+        }
+    }
+
+    /// Let the navigation handler navigate to the given room, if allowed.
+    async fn navigate_to_room(
+        &self,
+        matrix_driver: &MatrixDriver,
+        room: &RoomOrAliasId,
+    ) -> Result<()> {
+        let Some(navigation_handler) = &self.navigation_handler else {
+            return Err(Error::UnknownError(
+                "The client doesn't support navigating to rooms".into(),
+            ));
+        };
+
+        let (room_id, is_joined) = matrix_driver.resolve_room(room).await?;
+        if !is_joined && !self.settings.allow_navigation_to_unjoined_rooms() {
+            return Err(Error::UnknownError(
+                format!("Not allowed to navigate to {room_id}: the user isn't a member of it")
+                    .into(),
+            ));
+        }
+
+        if !navigation_handler.navigate_to_room(room_id).await {
+            return Err(Error::UnknownError("The client declined the navigation".into()));
         }
 
         Ok(())
     }
 
+    /// Whether the `DEBUG` logs of this driver should be emitted, according to
+    /// the log level of the widget.
+    fn debug_enabled(&self) -> bool {
+        Level::DEBUG <= self.settings.log_level()
+    }
+
     /// Process a single [`Action`].
     async fn process_action(
         &mut self,
@@ -192,18 +874,38 @@ impl WidgetDriver {
         incoming_msg_tx: &UnboundedSender<IncomingMessage>,
         capabilities_provider: &impl CapabilitiesProvider,
         action: Action,
-    ) -> Result<(), ()> {
+    ) -> Result<(), SessionEnd> {
         match action {
             Action::SendToWidget(msg) => {
-                self.to_widget_tx.send(msg).await.map_err(|_| ())?;
+                if self.debug_enabled() {
+                    debug!(
+                        widget_id = self.settings.widget_id(),
+                        "Sending a message to the widget"
+                    );
+                }
+                if self.audit_tx.receiver_count() > 0 {
+                    // Nobody may be listening anymore, that's fine.
+                    let _ = self.audit_tx.send(redact_outgoing_message(&msg));
+                }
+                self.to_widget_tx.send(msg).await.map_err(|_| SessionEnd::WidgetDisconnected)?;
             }
 
             Action::MatrixDriverRequest { request_id, data } => {
+                if self.debug_enabled() {
+                    debug!(
+                        widget_id = self.settings.widget_id(),
+                        %request_id,
+                        "Processing a request to the matrix driver"
+                    );
+                }
+
                 let response = match data {
                     MatrixDriverRequestData::AcquireCapabilities(cmd) => {
+                        self.requested_capabilities.set(Some(cmd.desired_capabilities.clone()));
                         let obtained = capabilities_provider
                             .acquire_capabilities(cmd.desired_capabilities)
                             .await;
+                        self.approved_capabilities.set(Some(obtained.clone()));
                         Ok(MatrixDriverResponse::CapabilitiesAcquired(obtained))
                     }
 
@@ -222,7 +924,8 @@ impl WidgetDriver {
                         .map(MatrixDriverResponse::MatrixEventRead),
 
                     MatrixDriverRequestData::SendMatrixEvent(req) => {
-                        let SendEventRequest { event_type, state_key, content, delay } = req;
+                        let SendEventRequest { event_type, state_key, content, delay, update_mode } =
+                            req;
                         // The widget api action does not use the unstable prefix:
                         // `org.matrix.msc4140.delay` so we
                         // cannot use the `DelayParameters` here and need to convert
@@ -231,7 +934,13 @@ impl WidgetDriver {
                             timeout: Duration::from_millis(d),
                         });
                         matrix_driver
-                            .send(event_type.into(), state_key, content, delay_event_parameter)
+                            .send(
+                                event_type.into(),
+                                state_key,
+                                content,
+                                delay_event_parameter,
+                                update_mode,
+                            )
                             .await
                             .map(MatrixDriverResponse::MatrixEventSent)
                     }
@@ -240,12 +949,85 @@ impl WidgetDriver {
                         .update_delayed_event(req.delay_id, req.action)
                         .await
                         .map(MatrixDriverResponse::MatrixDelayedEventUpdate),
+
+                    MatrixDriverRequestData::ReportEvent(req) => matrix_driver
+                        .report_event(req.event_id, req.score, req.reason)
+                        .await
+                        .map(MatrixDriverResponse::EventReported),
+
+                    MatrixDriverRequestData::GetUserProfile(req) => matrix_driver
+                        .get_user_profile(&req.user_id)
+                        .await
+                        .map(MatrixDriverResponse::UserProfileReceived),
+
+                    MatrixDriverRequestData::GetServerCapabilities(_) => matrix_driver
+                        .get_server_capabilities()
+                        .await
+                        .map(MatrixDriverResponse::ServerCapabilitiesReceived),
+
+                    MatrixDriverRequestData::ReadMembershipEvents(req) => matrix_driver
+                        .read_membership_events(req.user_id.as_deref(), req.limit, req.from)
+                        .await
+                        .map(MatrixDriverResponse::MembershipEventsRead),
+
+                    MatrixDriverRequestData::GetKnockRequests(_) => matrix_driver
+                        .get_knock_requests()
+                        .await
+                        .map(MatrixDriverResponse::KnockRequestsReceived),
+
+                    MatrixDriverRequestData::GetServerAcl(_) => matrix_driver
+                        .get_server_acl()
+                        .await
+                        .map(MatrixDriverResponse::ServerAclReceived),
+
+                    MatrixDriverRequestData::GetRequiredPowerLevel(req) => matrix_driver
+                        .get_required_power_level(req)
+                        .await
+                        .map(MatrixDriverResponse::RequiredPowerLevelReceived),
+
+                    MatrixDriverRequestData::SendReaction(req) => matrix_driver
+                        .send_reaction(req.event_id, req.key)
+                        .await
+                        .map(MatrixDriverResponse::MatrixEventSent),
+
+                    MatrixDriverRequestData::RedactEvent(req) => matrix_driver
+                        .redact_event(req.event_id, req.reason)
+                        .await
+                        .map(MatrixDriverResponse::MatrixEventSent),
+
+                    MatrixDriverRequestData::SendAttachment(req) => matrix_driver
+                        .send_attachment(req.filename, req.content_type, req.data)
+                        .await
+                        .map(MatrixDriverResponse::MatrixEventSent),
+
+                    MatrixDriverRequestData::NavigateToRoom(req) => self
+                        .navigate_to_room(matrix_driver, &req.room)
+                        .await
+                        .map(|()| MatrixDriverResponse::NavigatedToRoom),
+                };
+
+                let unavailable = match &response {
+                    Err(error) if matrix_driver.is_client_unavailable(error) => {
+                        Some(IncomingMessage::ClientUnavailable)
+                    }
+                    Err(_) if matrix_driver.is_room_unavailable() => {
+                        Some(IncomingMessage::RoomUnavailable)
+                    }
+                    _ => None,
                 };
 
                 // Forward the matrix driver response to the incoming message stream.
                 incoming_msg_tx
                     .send(IncomingMessage::MatrixDriverResponse { request_id, response })
-                    .map_err(|_| ())?;
+                    .map_err(|_| SessionEnd::WidgetDisconnected)?;
+
+                if let Some(unavailable) = unavailable {
+                    // Let the widget machine answer the widget with the error above, before
+                    // ending the session.
+                    incoming_msg_tx
+                        .send(unavailable)
+                        .map_err(|_| SessionEnd::WidgetDisconnected)?;
+                }
             }
 
             Action::Subscribe => {
@@ -262,6 +1044,7 @@ impl WidgetDriver {
                 self.event_forwarding_guard = Some(guard);
 
                 let mut matrix = matrix_driver.events();
+                let mut ephemeral = matrix_driver.ephemeral_events(self.clock.clone());
                 let incoming_msg_tx = incoming_msg_tx.clone();
 
                 spawn(async move {
@@ -276,6 +1059,10 @@ impl WidgetDriver {
                                 // Forward all events to the incoming messages stream.
                                 let _ = incoming_msg_tx.send(IncomingMessage::MatrixEventReceived(event));
                             }
+
+                            Some(event) = ephemeral.recv() => {
+                                let _ = incoming_msg_tx.send(IncomingMessage::MatrixEphemeralEventReceived(event));
+                            }
                         }
                     }
                 });
@@ -284,12 +1071,50 @@ impl WidgetDriver {
             Action::Unsubscribe => {
                 self.event_forwarding_guard = None;
             }
+
+            Action::Join => {
+                // Nobody may be listening, that's fine.
+                let _ = self.join_tx.send(());
+            }
+
+            Action::SetAlwaysOnScreen(value) => {
+                self.always_on_screen.set_if_not_eq(value);
+            }
+
+            Action::EndSession(session_end) => {
+                if self.debug_enabled() {
+                    debug!(
+                        widget_id = self.settings.widget_id(),
+                        ?session_end,
+                        "Ending the widget session"
+                    );
+                }
+                return Err(session_end);
+            }
         }
 
         Ok(())
     }
 }
 
+/// Redact the secrets of a message sent to the widget, so that it can be safely
+/// logged.
+fn redact_outgoing_message(msg: &str) -> String {
+    let Ok(mut message) = serde_json::from_str::<serde_json::Value>(msg) else {
+        return msg.to_owned();
+    };
+
+    for field in ["data", "response"] {
+        if let Some(access_token) =
+            message.get_mut(field).and_then(|value| value.get_mut("access_token"))
+        {
+            *access_token = "[REDACTED]".into();
+        }
+    }
+
+    message.to_string()
+}
+
 // TODO: Decide which module this type should live in
 #[derive(Clone, Debug)]
 pub(crate) enum StateKeySelector {
@@ -347,6 +1172,11 @@ mod tests {
     use serde_json::json;
 
     use super::StateKeySelector;
+    #[cfg(not(target_arch = "wasm32"))]
+    use super::{
+        clock::TestClock, Capabilities, CapabilitiesProvider, ReconnectPolicy, SessionEnd,
+        WidgetDriver, WidgetSettings,
+    };
 
     #[test]
     fn state_key_selector_from_true() {
@@ -371,4 +1201,200 @@ mod tests {
         let result = serde_json::from_value::<StateKeySelector>(json!(5));
         assert_matches!(result, Err(e) if e.is_data());
     }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[derive(Clone)]
+    struct GrantAllCapabilities;
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[async_trait::async_trait]
+    impl CapabilitiesProvider for GrantAllCapabilities {
+        async fn acquire_capabilities(&self, capabilities: Capabilities) -> Capabilities {
+            capabilities
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[matrix_sdk_test::async_test]
+    async fn test_reconnection_delay_follows_the_clock() {
+        use std::{
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+            time::Duration,
+        };
+
+        use ruma::room_id;
+
+        use crate::test_utils::mocks::MatrixMockServer;
+
+        let server = MatrixMockServer::new().await;
+        let client = server.client_builder().build().await;
+        let room = server.sync_joined_room(&client, room_id!("!a:b.c")).await;
+
+        let settings =
+            WidgetSettings::new("test-widget".to_owned(), false, "https://foo.bar/widget").unwrap();
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(1),
+            max_attempts: Some(1),
+            reset_after: Duration::from_secs(60),
+        };
+        let clock = TestClock::new();
+
+        // The widget is gone before each session could send it anything.
+        let sessions = Arc::new(AtomicUsize::new(0));
+        let new_driver = {
+            let clock = clock.clone();
+            let sessions = sessions.clone();
+            move || {
+                sessions.fetch_add(1, Ordering::SeqCst);
+                WidgetDriver::new(settings.clone()).0.with_clock(clock.clone())
+            }
+        };
+        let reconnection = tokio::spawn(WidgetDriver::run_with_reconnect(
+            room,
+            GrantAllCapabilities,
+            policy,
+            new_driver,
+        ));
+
+        // The first session ended, but the restart waits for the clock.
+        clock.until_sleeping().await;
+        clock.advance(Duration::from_millis(999));
+        tokio::task::yield_now().await;
+        assert_eq!(sessions.load(Ordering::SeqCst), 1);
+        assert!(clock.is_sleeping());
+        assert!(!reconnection.is_finished());
+
+        // Once the delay is over, the session is restarted.
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(reconnection.await.unwrap(), SessionEnd::WidgetDisconnected);
+        assert_eq!(sessions.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[matrix_sdk_test::async_test]
+    async fn test_long_session_resets_the_reconnection_attempts() {
+        use std::{
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+            time::Duration,
+        };
+
+        use matrix_sdk_common::timeout::timeout;
+        use ruma::room_id;
+
+        use crate::test_utils::mocks::MatrixMockServer;
+
+        let server = MatrixMockServer::new().await;
+        let client = server.client_builder().build().await;
+        let room = server.sync_joined_room(&client, room_id!("!a:b.c")).await;
+
+        let settings =
+            WidgetSettings::new("test-widget".to_owned(), false, "https://foo.bar/widget").unwrap();
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(1),
+            max_attempts: Some(1),
+            reset_after: Duration::from_secs(10),
+        };
+        let clock = TestClock::new();
+
+        // The widget is gone before the first and the third sessions could send it
+        // anything, but it stays connected to the second one for a while.
+        let (handle_tx, mut handle_rx) = tokio::sync::mpsc::unbounded_channel();
+        let sessions = Arc::new(AtomicUsize::new(0));
+        let new_driver = {
+            let clock = clock.clone();
+            let sessions = sessions.clone();
+            move || {
+                let (driver, handle) = WidgetDriver::new(settings.clone());
+                if sessions.fetch_add(1, Ordering::SeqCst) == 1 {
+                    handle_tx.send(handle).unwrap();
+                }
+                driver.with_clock(clock.clone())
+            }
+        };
+        let reconnection = tokio::spawn(WidgetDriver::run_with_reconnect(
+            room,
+            GrantAllCapabilities,
+            policy,
+            new_driver,
+        ));
+
+        clock.until_sleeping().await;
+        clock.advance(Duration::from_secs(1));
+
+        // The second session runs long enough to reset the attempts, before the
+        // widget disconnects. The timeout of its `capabilities` request doesn't
+        // elapse meanwhile.
+        let handle = handle_rx.recv().await.unwrap();
+        handle.recv().await.unwrap();
+        clock.advance(Duration::from_secs(10));
+        handle.to_widget_rx.close();
+        assert!(handle.notify_theme_change("dark".to_owned()).await);
+
+        // Which allows one more restart, instead of giving up, once the second
+        // session ended along with its timers.
+        while !handle.from_widget_tx.is_closed() {
+            tokio::task::yield_now().await;
+        }
+        timeout(clock.until_sleeping(), Duration::from_secs(1)).await.unwrap();
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(reconnection.await.unwrap(), SessionEnd::WidgetDisconnected);
+        assert_eq!(sessions.load(Ordering::SeqCst), 3);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[matrix_sdk_test::async_test]
+    async fn test_to_widget_request_times_out_without_other_messages() {
+        use std::time::Duration;
+
+        use matrix_sdk_common::timeout::timeout;
+        use ruma::room_id;
+
+        use super::SessionTraceStep;
+        use crate::test_utils::mocks::MatrixMockServer;
+
+        let server = MatrixMockServer::new().await;
+        let client = server.client_builder().build().await;
+        let room = server.sync_joined_room(&client, room_id!("!a:b.c")).await;
+
+        let settings =
+            WidgetSettings::new("test-widget".to_owned(), false, "https://foo.bar/widget")
+                .unwrap()
+                .with_session_trace(true)
+                .with_to_widget_timeout(Duration::from_secs(5));
+        let clock = TestClock::new();
+        let (driver, handle) = WidgetDriver::new(settings);
+        let session =
+            tokio::spawn(driver.with_clock(clock.clone()).run(room, GrantAllCapabilities));
+
+        // The widget never answers the `capabilities` request.
+        handle.recv().await.unwrap();
+        clock.until_sleeping().await;
+
+        // Once the timeout elapsed, the machine is told to expire the request.
+        clock.advance(Duration::from_secs(5));
+        let session_trace = handle.session_trace().unwrap();
+        let timed_out =
+            SessionTraceStep::ClientNotification { kind: "ToWidgetTimeoutElapsed".to_owned() };
+        timeout(
+            async {
+                while !session_trace.steps().contains(&timed_out) {
+                    tokio::task::yield_now().await;
+                }
+            },
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+
+        handle.stop();
+        session.await.unwrap();
+    }
 }
@@ -19,16 +19,20 @@
 // TODO: The goal is to have not any Element Call specific code
 // in the rust sdk. Find a better solution for this.
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use tracing::level_filters::LevelFilter;
 use url::Url;
 
-use super::{url_params, WidgetSettings};
+use super::{
+    url_params, WidgetKind, WidgetSettings, WidgetSettingsError, DEFAULT_MAX_MESSAGE_DEPTH,
+    DEFAULT_RESPONSE_TIMEOUT,
+};
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 /// Parameters for the Element Call widget.
 /// These are documented at https://github.com/element-hq/element-call/blob/livekit/docs/url-params.md
-struct ElementCallParams {
+struct ElementCallUrlParams {
     user_id: String,
     room_id: String,
     widget_id: String,
@@ -39,7 +43,7 @@ struct ElementCallParams {
     device_id: String,
     base_url: String,
     // Non template parameters
-    parent_url: String,
+    parent_url: Option<String>,
     /// Deprecated since Element Call v0.8.0. Included for backwards
     /// compatibility. Set to `true` if intent is `Intent::StartCall`.
     skip_lobby: Option<bool>,
@@ -72,6 +76,60 @@ struct ElementCallParams {
     hide_screensharing: bool,
 }
 
+/// The names of the parameters of [`ElementCallUrlParams`], which can't be used
+/// by a custom analytics backend.
+const BUILT_IN_PARAMS: [&str; 28] = [
+    "userId",
+    "roomId",
+    "widgetId",
+    "displayName",
+    "lang",
+    "theme",
+    "clientId",
+    "deviceId",
+    "baseUrl",
+    "parentUrl",
+    "skipLobby",
+    "confineToRoom",
+    "appPrompt",
+    "hideHeader",
+    "preload",
+    "analyticsId",
+    "posthogUserId",
+    "fontScale",
+    "font",
+    "perParticipantE2EE",
+    "password",
+    "intent",
+    "posthogApiHost",
+    "posthogApiKey",
+    "rageshakeSubmitUrl",
+    "sentryDsn",
+    "sentryEnvironment",
+    "hideScreensharing",
+];
+
+/// The Element Call specific parameters of a virtual Element Call widget, see
+/// [`WidgetSettings::element_call_params`].
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ElementCallParams {
+    /// Whether the branding header of Element Call is hidden.
+    pub hide_header: bool,
+    /// Whether the widget waits for the `io.element.join` action to join the
+    /// call.
+    pub preload: bool,
+    /// Whether the lobby is skipped, only set if the intent is
+    /// [`Intent::StartCall`].
+    pub skip_lobby: Option<bool>,
+    /// Whether the calls list can't be reached from the widget.
+    pub confine_to_room: bool,
+    /// The font scale used inside Element Call.
+    pub font_scale: Option<f64>,
+    /// The analytics id passed to Element Call.
+    pub analytics_id: Option<String>,
+}
+
 /// Defines if a call is encrypted and which encryption system should be used.
 ///
 /// This controls the url parameters: `perParticipantE2EE`, `password`.
@@ -105,6 +163,77 @@ pub enum Intent {
     JoinExisting,
 }
 
+/// Defines which `parentUrl` is used when
+/// [`VirtualElementCallWidgetOptions::parent_url`] is not set.
+///
+/// The `parentUrl` is the target for the PostMessages sent by the widget (to
+/// the client). For a web app client this is the client url. In case of using
+/// other platforms the client most likely is setup up to listen to
+/// postmessages in the same webview the widget is hosted (dedicated webview).
+/// In this case the `parentUrl` must be the url of the webview with the
+/// widget, which the SDK can't know. Be aware that this means that the widget
+/// will receive its own postmessage messages. The `matrix-widget-api` (js)
+/// ignores those so this works but it might break custom implementations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParentUrlDefault {
+    /// Use the `element_call_url`, which is the url of the webview for
+    /// clients hosting Element Call in a dedicated webview.
+    #[default]
+    ElementCallUrl,
+    /// Don't set the `parentUrl` parameter at all.
+    Omit,
+}
+
+/// Where the parameters of a virtual Element Call widget are put in its url.
+///
+/// Parameters in the fragment of the url are not sent to the server hosting
+/// Element Call, unlike the ones in the query. In both cases the placeholders
+/// are kept, so they can be replaced when the url is generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParamPlacement {
+    /// All the parameters are in the fragment, after a `?`, including the
+    /// query of the `element_call_url`.
+    FragmentOnly,
+    /// All the parameters are in the query.
+    QueryOnly,
+    /// The parameters of Element Call are in the fragment, after a `?`, while
+    /// the query of the `element_call_url` stays in the query.
+    #[default]
+    Mixed,
+}
+
+/// The analytics backend of Element Call, and the id of the user for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnalyticsConfig {
+    /// The kind of analytics backend, which defines the url parameter of the
+    /// id.
+    pub backend: AnalyticsBackend,
+    /// The id of the user for the analytics backend.
+    pub id: String,
+}
+
+impl AnalyticsConfig {
+    /// Use PostHog with the given user id.
+    pub fn posthog(id: impl Into<String>) -> Self {
+        Self { backend: AnalyticsBackend::PostHog, id: id.into() }
+    }
+}
+
+/// The kind of analytics backend of Element Call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnalyticsBackend {
+    /// PostHog, the id is passed with the `posthogUserId` parameter, and the
+    /// deprecated `analyticsId` one.
+    PostHog,
+    /// Another backend, the id is passed with the given url parameter, e.g.
+    /// `matomoVisitorId`. It can't be one of the parameters of Element Call,
+    /// like `roomId`.
+    Custom {
+        /// The name of the url parameter of the id.
+        param_name: String,
+    },
+}
+
 /// Properties to create a new virtual Element Call widget.
 #[derive(Debug, Default)]
 pub struct VirtualElementCallWidgetOptions {
@@ -116,21 +245,33 @@ pub struct VirtualElementCallWidgetOptions {
     /// The widget id.
     pub widget_id: String,
 
+    /// The hosts the `element_call_url` may point to, e.g. `call.element.io`.
+    ///
+    /// Creating the widget fails if the host of the `element_call_url` isn't
+    /// in this list. If the list is empty, any host is allowed.
+    ///
+    /// Default: empty
+    pub allowed_hosts: Vec<String>,
+
     /// The url that is used as the target for the PostMessages sent
     /// by the widget (to the client).
     ///
-    /// For a web app client this is the client url. In case of using other
-    /// platforms the client most likely is setup up to listen to
-    /// postmessages in the same webview the widget is hosted. In this case
-    /// the `parent_url` is set to the url of the webview with the widget. Be
-    /// aware that this means that the widget will receive its own postmessage
-    /// messages. The `matrix-widget-api` (js) ignores those so this works but
-    /// it might break custom implementations.
+    /// See [`ParentUrlDefault`] for the url to use depending on the platform.
     ///
-    /// Defaults to `element_call_url` for the non-iframe (dedicated webview)
-    /// usecase.
+    /// Defaults to the value selected by `parent_url_default`.
     pub parent_url: Option<String>,
 
+    /// What to use for the `parentUrl` if `parent_url` is not set.
+    ///
+    /// Default: [`ParentUrlDefault::ElementCallUrl`], for the non-iframe
+    /// (dedicated webview) usecase.
+    pub parent_url_default: ParentUrlDefault,
+
+    /// Where the parameters are put in the url of the widget.
+    ///
+    /// Default: [`ParamPlacement::Mixed`]
+    pub param_placement: ParamPlacement,
+
     /// Whether the branding header of Element call should be hidden.
     ///
     /// Default: `true`
@@ -159,6 +300,9 @@ pub struct VirtualElementCallWidgetOptions {
     pub confine_to_room: Option<bool>,
 
     /// The font to use, to adapt to the system font.
+    ///
+    /// The value is percent-encoded in the url, so it can contain any
+    /// character, including the commas of a font family list.
     pub font: Option<String>,
 
     /// The encryption system to use.
@@ -174,7 +318,26 @@ pub struct VirtualElementCallWidgetOptions {
     /// Do not show the screenshare button.
     pub hide_screensharing: bool,
 
+    /// Disable analytics entirely.
+    ///
+    /// When `true`, none of the analytics parameters (`analyticsId`,
+    /// `posthogUserId`, `posthogApiHost`, `posthogApiKey`, or the one of a
+    /// custom backend) are added to the url, even if `analytics`,
+    /// `posthog_user_id`, `posthog_api_host` or `posthog_api_key` are set.
+    ///
+    /// Default: `false`
+    pub analytics_disabled: bool,
+
+    /// The analytics backend to pass the id of the user to.
+    ///
+    /// Takes precedence over `posthog_user_id`, which is the same as
+    /// [`AnalyticsConfig::posthog`]. Ignored if `analytics_disabled` is
+    /// `true`.
+    pub analytics: Option<AnalyticsConfig>,
+
     /// Can be used to pass a PostHog id to element call.
+    ///
+    /// Ignored if `analytics` is set, or if `analytics_disabled` is `true`.
     pub posthog_user_id: Option<String>,
     /// The host of the posthog api.
     /// This is only used by the embedded package of Element Call.
@@ -209,18 +372,54 @@ impl WidgetSettings {
     ///
     /// * `props` - A struct containing the configuration parameters for a
     ///   element call widget.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the `element_call_url` can't be parsed, if its host isn't one
+    /// of the `allowed_hosts`, or if a custom analytics backend uses the name
+    /// of a parameter of Element Call.
     pub fn new_virtual_element_call_widget(
-        props: VirtualElementCallWidgetOptions,
-    ) -> Result<Self, url::ParseError> {
+        mut props: VirtualElementCallWidgetOptions,
+    ) -> Result<Self, WidgetSettingsError> {
         let mut raw_url: Url = Url::parse(&props.element_call_url)?;
 
+        if !props.allowed_hosts.is_empty() {
+            let host = raw_url.host_str().unwrap_or_default();
+            if !props.allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)) {
+                return Err(WidgetSettingsError::DisallowedHost(host.to_owned()));
+            }
+        }
+
         let skip_lobby = if props.intent.as_ref().is_some_and(|x| x == &Intent::StartCall) {
             Some(true)
         } else {
             None
         };
 
-        let query_params = ElementCallParams {
+        let mut custom_analytics = None;
+        match props.analytics.take() {
+            Some(AnalyticsConfig { backend: AnalyticsBackend::PostHog, id }) => {
+                props.posthog_user_id = Some(id);
+            }
+            Some(AnalyticsConfig { backend: AnalyticsBackend::Custom { param_name }, id }) => {
+                // The id must not override, or be overridden by, a parameter of Element Call.
+                if BUILT_IN_PARAMS.contains(&param_name.as_str()) {
+                    return Err(WidgetSettingsError::ReservedParam(param_name));
+                }
+                props.posthog_user_id = None;
+                custom_analytics = Some((param_name, id));
+            }
+            None => {}
+        }
+
+        if props.analytics_disabled {
+            props.posthog_user_id = None;
+            props.posthog_api_host = None;
+            props.posthog_api_key = None;
+            custom_analytics = None;
+        }
+
+        let query_params = ElementCallUrlParams {
             user_id: url_params::USER_ID.to_owned(),
             room_id: url_params::ROOM_ID.to_owned(),
             widget_id: url_params::WIDGET_ID.to_owned(),
@@ -231,7 +430,10 @@ impl WidgetSettings {
             device_id: url_params::DEVICE_ID.to_owned(),
             base_url: url_params::HOMESERVER_URL.to_owned(),
 
-            parent_url: props.parent_url.unwrap_or(props.element_call_url.clone()),
+            parent_url: props.parent_url.or_else(|| match props.parent_url_default {
+                ParentUrlDefault::ElementCallUrl => Some(props.element_call_url.clone()),
+                ParentUrlDefault::Omit => None,
+            }),
             confine_to_room: props.confine_to_room.unwrap_or(true),
             app_prompt: props.app_prompt.unwrap_or(false),
             hide_header: props.hide_header.unwrap_or(true),
@@ -255,19 +457,117 @@ impl WidgetSettings {
             hide_screensharing: props.hide_screensharing,
         };
 
-        let query =
+        let mut query =
             serde_html_form::to_string(query_params).map_err(|_| url::ParseError::Overflow)?;
+        if let Some(custom_analytics) = custom_analytics {
+            let param = serde_html_form::to_string([custom_analytics])
+                .map_err(|_| url::ParseError::Overflow)?;
+            query = format!("{query}&{param}");
+        }
 
         // Revert the encoding for the template parameters. So we can have a unified
         // replace logic.
         let query = query.replace("%24", "$");
 
-        // All the params will be set inside the fragment (to keep the traffic to the
-        // server minimal and most importantly don't send the passwords).
-        raw_url.set_fragment(Some(&format!("?{}", query)));
+        // By default, all the params are set inside the fragment (to keep the traffic
+        // to the server minimal and most importantly don't send the passwords).
+        let (query, fragment_query) = match props.param_placement {
+            ParamPlacement::Mixed => (None, Some(query)),
+            ParamPlacement::QueryOnly => (Some(query), None),
+            ParamPlacement::FragmentOnly => {
+                // Move the query of the `element_call_url` to the fragment too.
+                let query = match raw_url.query() {
+                    Some(existing) if !existing.is_empty() => format!("{existing}&{query}"),
+                    _ => query,
+                };
+                raw_url.set_query(None);
+                (None, Some(query))
+            }
+        };
+
+        if let Some(query) = query {
+            // Keep the query of the `element_call_url`, if any.
+            let query = match raw_url.query() {
+                Some(existing) if !existing.is_empty() => format!("{existing}&{query}"),
+                _ => query,
+            };
+            raw_url.set_query(Some(&query));
+        }
+        if let Some(fragment_query) = fragment_query {
+            raw_url.set_fragment(Some(&format!("?{fragment_query}")));
+        }
 
         // for EC we always want init on content load to be true.
-        Ok(Self { widget_id: props.widget_id, init_on_content_load: true, raw_url })
+        Ok(Self {
+            widget_id: props.widget_id,
+            init_on_content_load: true,
+            raw_url,
+            kind: WidgetKind::ElementCall,
+            allow_host_change: false,
+            deny_mixed_content: false,
+            log_level: LevelFilter::WARN,
+            denied_event_types: Vec::new(),
+            deprecated_capabilities: Default::default(),
+            allow_navigation_to_unjoined_rooms: false,
+            max_message_depth: DEFAULT_MAX_MESSAGE_DEPTH,
+            strict_json: false,
+            transaction_id_prefix: None,
+            openid_pre_consented: false,
+            to_widget_timeout: DEFAULT_RESPONSE_TIMEOUT,
+            session_trace: false,
+        })
+    }
+}
+
+impl WidgetSettings {
+    /// Read back the Element Call specific parameters of these settings.
+    ///
+    /// Returns `None` if these settings weren't created with
+    /// [`WidgetSettings::new_virtual_element_call_widget`].
+    pub fn element_call_params(&self) -> Option<ElementCallParams> {
+        if self.kind != WidgetKind::ElementCall {
+            return None;
+        }
+
+        // The params are in the query, in the fragment, or in both depending on the
+        // `ParamPlacement`.
+        let query = self.raw_url.query().unwrap_or_default();
+        let fragment_query =
+            self.raw_url.fragment().and_then(|fragment| fragment.split_once('?')).map(|(_, q)| q);
+        let params = [Some(query), fragment_query]
+            .into_iter()
+            .flatten()
+            .filter(|params| !params.is_empty())
+            .collect::<Vec<_>>()
+            .join("&");
+
+        serde_html_form::from_str(&params).ok()
+    }
+
+    /// Get the raw url of the widget with the parameters of its fragment moved
+    /// to its query, e.g. for debugging or for widgets which only read the
+    /// query.
+    ///
+    /// This is the inverse of [`ParamPlacement::FragmentOnly`]: the
+    /// parameters after the `?` of the fragment are appended to the query, and
+    /// the fragment is removed if nothing else is left in it. The placeholders
+    /// are kept.
+    pub fn to_query_based_url(&self) -> Url {
+        let mut url = self.raw_url.clone();
+        let Some((fragment, fragment_query)) =
+            self.raw_url.fragment().and_then(|fragment| fragment.split_once('?'))
+        else {
+            return url;
+        };
+
+        let query = match url.query() {
+            Some(existing) if !existing.is_empty() => format!("{existing}&{fragment_query}"),
+            _ => fragment_query.to_owned(),
+        };
+        url.set_query(Some(&query));
+        url.set_fragment((!fragment.is_empty()).then_some(fragment));
+
+        url
     }
 }
 
@@ -275,10 +575,11 @@ impl WidgetSettings {
 mod tests {
     use std::collections::BTreeSet;
 
+    use assert_matches2::assert_matches;
     use ruma::api::client::profile::get_profile;
     use url::Url;
 
-    use crate::widget::{ClientProperties, Intent, WidgetSettings};
+    use crate::widget::{ClientProperties, Intent, WidgetSettings, WidgetSettingsError};
 
     const WIDGET_ID: &str = "1/@#w23";
 
@@ -336,7 +637,10 @@ mod tests {
 
     use serde_html_form::from_str;
 
-    use super::{EncryptionSystem, VirtualElementCallWidgetOptions};
+    use super::{
+        AnalyticsBackend, AnalyticsConfig, ElementCallParams, EncryptionSystem, ParamPlacement,
+        ParentUrlDefault, VirtualElementCallWidgetOptions,
+    };
 
     fn get_query_sets(url: &Url) -> Option<(QuerySet, QuerySet)> {
         let fq = from_str::<QuerySet>(url.fragment_query().unwrap_or_default()).ok()?;
@@ -382,6 +686,12 @@ mod tests {
         assert_eq!(url, gen);
     }
 
+    #[test]
+    fn new_virtual_element_call_widget_inits_after_content_load() {
+        let widget_settings = get_widget_settings(None, false, false, false, None);
+        assert!(widget_settings.should_init_after_content_load());
+    }
+
     #[test]
     fn new_virtual_element_call_widget_id() {
         assert_eq!(get_widget_settings(None, false, false, false, None).widget_id(), WIDGET_ID);
@@ -389,7 +699,7 @@ mod tests {
 
     fn build_url_from_widget_settings(settings: WidgetSettings) -> String {
         settings
-            ._generate_webview_url(
+            .generate_webview_url_sync(
                 get_profile::v3::Response::new(Some("some-url".into()), Some("hello".into())),
                 "@test:user.org".try_into().unwrap(),
                 "!room_id:room.org".try_into().unwrap(),
@@ -612,4 +922,283 @@ mod tests {
             }
         }
     }
+
+    fn get_parent_url(options: VirtualElementCallWidgetOptions) -> Option<String> {
+        let settings = WidgetSettings::new_virtual_element_call_widget(options).unwrap();
+        let (_, fragment_query) = get_query_sets(settings.raw_url()).unwrap();
+        fragment_query.into_iter().find(|(key, _)| key == "parentUrl").map(|(_, value)| value)
+    }
+
+    #[test]
+    fn parent_url_defaults_to_element_call_url() {
+        let parent_url = get_parent_url(VirtualElementCallWidgetOptions {
+            element_call_url: "https://call.element.io".to_owned(),
+            ..VirtualElementCallWidgetOptions::default()
+        });
+        assert_eq!(parent_url.as_deref(), Some("https://call.element.io"));
+    }
+
+    #[test]
+    fn parent_url_can_be_omitted_by_default() {
+        let parent_url = get_parent_url(VirtualElementCallWidgetOptions {
+            element_call_url: "https://call.element.io".to_owned(),
+            parent_url_default: ParentUrlDefault::Omit,
+            ..VirtualElementCallWidgetOptions::default()
+        });
+        assert_eq!(parent_url, None);
+    }
+
+    #[test]
+    fn explicit_parent_url_overrides_the_default() {
+        for parent_url_default in [ParentUrlDefault::ElementCallUrl, ParentUrlDefault::Omit] {
+            let parent_url = get_parent_url(VirtualElementCallWidgetOptions {
+                element_call_url: "https://call.element.io".to_owned(),
+                parent_url: Some("https://my.client.org".to_owned()),
+                parent_url_default,
+                ..VirtualElementCallWidgetOptions::default()
+            });
+            assert_eq!(parent_url.as_deref(), Some("https://my.client.org"));
+        }
+    }
+
+    #[test]
+    fn font_with_commas_round_trips() {
+        let font = "Inter, \"Noto Sans\", sans-serif";
+        let settings =
+            WidgetSettings::new_virtual_element_call_widget(VirtualElementCallWidgetOptions {
+                element_call_url: "https://call.element.io".to_owned(),
+                font: Some(font.to_owned()),
+                ..VirtualElementCallWidgetOptions::default()
+            })
+            .unwrap();
+
+        let url = Url::parse(&build_url_from_widget_settings(settings)).unwrap();
+        let (_, fragment_query) = get_query_sets(&url).unwrap();
+
+        // The font is a single parameter, whose value is the whole font list.
+        let fonts: Vec<_> = fragment_query.iter().filter(|(key, _)| key == "font").collect();
+        assert_eq!(fonts, [&("font".to_owned(), font.to_owned())]);
+        assert!(!url.fragment().unwrap().contains(", "));
+    }
+
+    #[test]
+    fn disabled_analytics_override_posthog_options() {
+        let settings =
+            WidgetSettings::new_virtual_element_call_widget(VirtualElementCallWidgetOptions {
+                element_call_url: "https://call.element.io".to_owned(),
+                analytics_disabled: true,
+                posthog_user_id: Some("POSTHOG_USER_ID".to_owned()),
+                posthog_api_host: Some("posthog.element.io".to_owned()),
+                posthog_api_key: Some("POSTHOG_KEY".to_owned()),
+                ..VirtualElementCallWidgetOptions::default()
+            })
+            .unwrap();
+
+        let url = build_url_from_widget_settings(settings);
+        assert!(!url.contains("POSTHOG_USER_ID"));
+        assert!(!url.contains("POSTHOG_KEY"));
+
+        let (query, fragment_query) = get_query_sets(&Url::parse(&url).unwrap()).unwrap();
+        for key in ["analyticsId", "posthogUserId", "posthogApiHost", "posthogApiKey"] {
+            assert!(
+                !query.iter().chain(&fragment_query).any(|(k, _)| k == key),
+                "The url should not contain the `{key}` analytics parameter: {url}"
+            );
+        }
+    }
+
+    #[test]
+    fn custom_analytics_backend_uses_its_own_param() {
+        let settings =
+            WidgetSettings::new_virtual_element_call_widget(VirtualElementCallWidgetOptions {
+                element_call_url: "https://call.element.io".to_owned(),
+                analytics: Some(AnalyticsConfig {
+                    backend: AnalyticsBackend::Custom { param_name: "matomoVisitorId".to_owned() },
+                    id: "VISITOR ID".to_owned(),
+                }),
+                posthog_user_id: Some("POSTHOG_USER_ID".to_owned()),
+                ..VirtualElementCallWidgetOptions::default()
+            })
+            .unwrap();
+
+        let (_, fragment_query) = get_query_sets(settings.raw_url()).unwrap();
+        assert!(fragment_query.contains(&("matomoVisitorId".to_owned(), "VISITOR ID".to_owned())));
+        for key in ["analyticsId", "posthogUserId"] {
+            assert!(!fragment_query.iter().any(|(k, _)| k == key), "Unexpected `{key}` parameter");
+        }
+    }
+
+    #[test]
+    fn custom_analytics_backend_cannot_use_a_built_in_param() {
+        for param_name in ["roomId", "password", "posthogUserId"] {
+            let error =
+                WidgetSettings::new_virtual_element_call_widget(VirtualElementCallWidgetOptions {
+                    element_call_url: "https://call.element.io".to_owned(),
+                    analytics: Some(AnalyticsConfig {
+                        backend: AnalyticsBackend::Custom { param_name: param_name.to_owned() },
+                        id: "VISITOR ID".to_owned(),
+                    }),
+                    ..VirtualElementCallWidgetOptions::default()
+                })
+                .unwrap_err();
+            assert_matches!(error, WidgetSettingsError::ReservedParam(name));
+            assert_eq!(name, param_name);
+        }
+    }
+
+    #[test]
+    fn posthog_analytics_config_sets_the_posthog_params() {
+        let settings =
+            WidgetSettings::new_virtual_element_call_widget(VirtualElementCallWidgetOptions {
+                element_call_url: "https://call.element.io".to_owned(),
+                analytics: Some(AnalyticsConfig::posthog("POSTHOG_USER_ID")),
+                ..VirtualElementCallWidgetOptions::default()
+            })
+            .unwrap();
+
+        let (_, fragment_query) = get_query_sets(settings.raw_url()).unwrap();
+        for key in ["analyticsId", "posthogUserId"] {
+            assert!(fragment_query.contains(&(key.to_owned(), "POSTHOG_USER_ID".to_owned())));
+        }
+    }
+
+    #[test]
+    fn element_call_params_match_the_options() {
+        let settings =
+            WidgetSettings::new_virtual_element_call_widget(VirtualElementCallWidgetOptions {
+                element_call_url: "https://call.element.io".to_owned(),
+                widget_id: WIDGET_ID.to_owned(),
+                hide_header: Some(false),
+                preload: Some(true),
+                confine_to_room: Some(false),
+                font_scale: Some(1.5),
+                intent: Some(Intent::StartCall),
+                posthog_user_id: Some("POSTHOG_USER_ID".to_owned()),
+                ..VirtualElementCallWidgetOptions::default()
+            })
+            .unwrap();
+
+        assert_eq!(
+            settings.element_call_params(),
+            Some(ElementCallParams {
+                hide_header: false,
+                preload: true,
+                skip_lobby: Some(true),
+                confine_to_room: false,
+                font_scale: Some(1.5),
+                analytics_id: Some("POSTHOG_USER_ID".to_owned()),
+            })
+        );
+    }
+
+    fn settings_with_placement(placement: ParamPlacement) -> WidgetSettings {
+        WidgetSettings::new_virtual_element_call_widget(VirtualElementCallWidgetOptions {
+            element_call_url: "https://call.element.io/room?via=element.io".to_owned(),
+            widget_id: WIDGET_ID.to_owned(),
+            preload: Some(true),
+            encryption: EncryptionSystem::SharedSecret { secret: "secret".to_owned() },
+            param_placement: placement,
+            ..VirtualElementCallWidgetOptions::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn params_are_in_the_fragment_by_default() {
+        assert_eq!(ParamPlacement::default(), ParamPlacement::Mixed);
+
+        let settings = settings_with_placement(ParamPlacement::default());
+        let url = settings.raw_url();
+        assert_eq!(url.query(), Some("via=element.io"));
+
+        let (query, fragment_query) = get_query_sets(url).unwrap();
+        assert_eq!(query.len(), 1);
+        assert!(fragment_query.contains(&("widgetId".to_owned(), "$matrix_widget_id".to_owned())));
+        assert!(fragment_query.contains(&("password".to_owned(), "secret".to_owned())));
+        assert!(settings.element_call_params().unwrap().preload);
+    }
+
+    #[test]
+    fn params_are_in_the_query_with_query_only_placement() {
+        let settings = settings_with_placement(ParamPlacement::QueryOnly);
+        let url = settings.raw_url();
+        assert_eq!(url.fragment(), None);
+
+        let (query, _) = get_query_sets(url).unwrap();
+        assert!(query.contains(&("via".to_owned(), "element.io".to_owned())));
+        assert!(query.contains(&("widgetId".to_owned(), "$matrix_widget_id".to_owned())));
+        assert!(query.contains(&("password".to_owned(), "secret".to_owned())));
+        assert!(settings.element_call_params().unwrap().preload);
+
+        // The placeholders of the query are replaced.
+        let url = Url::parse(&build_url_from_widget_settings(settings)).unwrap();
+        assert!(url.query_pairs().any(|(key, value)| key == "widgetId" && value == WIDGET_ID));
+    }
+
+    #[test]
+    fn all_params_are_in_the_fragment_with_fragment_only_placement() {
+        let settings = settings_with_placement(ParamPlacement::FragmentOnly);
+        let url = settings.raw_url();
+        assert_eq!(url.query(), None);
+
+        let (_, fragment_query) = get_query_sets(url).unwrap();
+        assert!(fragment_query.contains(&("via".to_owned(), "element.io".to_owned())));
+        assert!(fragment_query.contains(&("widgetId".to_owned(), "$matrix_widget_id".to_owned())));
+        assert!(fragment_query.contains(&("password".to_owned(), "secret".to_owned())));
+        assert!(settings.element_call_params().unwrap().preload);
+    }
+
+    #[test]
+    fn fragment_params_are_moved_to_the_query() {
+        let settings = settings_with_placement(ParamPlacement::FragmentOnly);
+        let url = settings.to_query_based_url();
+        assert_eq!(url.fragment(), None);
+
+        // All the params are in the query, like with the query only placement.
+        let (query, fragment_query) = get_query_sets(&url).unwrap();
+        assert!(fragment_query.is_empty());
+        let (expected, _) =
+            get_query_sets(settings_with_placement(ParamPlacement::QueryOnly).raw_url()).unwrap();
+        assert_eq!(query, expected);
+        assert!(query.contains(&("widgetId".to_owned(), "$matrix_widget_id".to_owned())));
+        assert!(query.contains(&("via".to_owned(), "element.io".to_owned())));
+
+        // A url without params in the fragment is left untouched.
+        let settings =
+            WidgetSettings::new(WIDGET_ID.to_owned(), true, "https://foo.bar/widget?a=b#section")
+                .unwrap();
+        assert_eq!(settings.to_query_based_url(), *settings.raw_url());
+    }
+
+    #[test]
+    fn custom_widgets_have_no_element_call_params() {
+        let settings = WidgetSettings::new(
+            WIDGET_ID.to_owned(),
+            true,
+            "https://call.element.io#?preload=true",
+        )
+        .unwrap();
+        assert_eq!(settings.element_call_params(), None);
+    }
+
+    #[test]
+    fn element_call_url_must_have_an_allowed_host() {
+        let options = |element_call_url: &str| VirtualElementCallWidgetOptions {
+            element_call_url: element_call_url.to_owned(),
+            allowed_hosts: vec!["call.element.io".to_owned()],
+            ..VirtualElementCallWidgetOptions::default()
+        };
+
+        let error =
+            WidgetSettings::new_virtual_element_call_widget(options("https://call.evil.org/room"))
+                .unwrap_err();
+        assert_matches!(error, WidgetSettingsError::DisallowedHost(host));
+        assert_eq!(host, "call.evil.org");
+
+        let settings = WidgetSettings::new_virtual_element_call_widget(options(
+            "https://call.element.io/room",
+        ))
+        .unwrap();
+        assert_eq!(settings.base_url().unwrap().as_str(), "https://call.element.io/");
+    }
 }
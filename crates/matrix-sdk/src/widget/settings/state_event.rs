@@ -0,0 +1,192 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ruma::serde::JsonObject;
+use serde::Deserialize;
+
+use super::{WidgetSettings, WidgetSettingsError};
+
+/// The event types of the room state events which describe widgets, the
+/// stable one first.
+pub(crate) const WIDGET_STATE_EVENT_TYPES: [&str; 2] = ["m.widget", "im.vector.modular.widgets"];
+
+/// A widget described by an `m.widget` or `im.vector.modular.widgets` event.
+///
+/// It can be deserialized from a whole room state event, whose state key is
+/// the widget id, or from an entry of the legacy `m.widgets` account data,
+/// which has the same layout. Events with an empty content, i.e. removed
+/// widgets, fail to deserialize.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "WidgetEventRepr")]
+pub struct WidgetStateEvent {
+    /// The id of the widget.
+    pub id: String,
+    /// The url template of the widget, which may contain placeholders.
+    pub url: String,
+    /// The custom data of the widget.
+    pub data: JsonObject,
+    /// Whether the client should negotiate the capabilities as soon as the
+    /// widget is loaded, instead of waiting for its `content_loaded` request.
+    pub wait_for_iframe_load: bool,
+}
+
+impl WidgetStateEvent {
+    /// Whether the client should wait for the `content_loaded` request of the
+    /// widget before negotiating the capabilities.
+    ///
+    /// An `init_after_content_load` flag in the data of the widget takes
+    /// precedence over [`WidgetStateEvent::wait_for_iframe_load`].
+    pub fn init_after_content_load(&self) -> bool {
+        self.data
+            .get("init_after_content_load")
+            .and_then(|flag| flag.as_bool())
+            .unwrap_or(!self.wait_for_iframe_load)
+    }
+}
+
+impl TryFrom<WidgetStateEvent> for WidgetSettings {
+    type Error = WidgetSettingsError;
+
+    fn try_from(event: WidgetStateEvent) -> Result<Self, Self::Error> {
+        let init_after_content_load = event.init_after_content_load();
+        WidgetSettings::new(event.id, init_after_content_load, &event.url)
+    }
+}
+
+#[derive(Deserialize)]
+struct WidgetEventRepr {
+    state_key: Option<String>,
+    content: WidgetContentRepr,
+}
+
+#[derive(Deserialize)]
+struct WidgetContentRepr {
+    id: Option<String>,
+    url: Option<String>,
+    #[serde(default)]
+    data: JsonObject,
+    #[serde(rename = "waitForIframeLoad")]
+    wait_for_iframe_load: Option<bool>,
+}
+
+impl TryFrom<WidgetEventRepr> for WidgetStateEvent {
+    type Error = &'static str;
+
+    fn try_from(repr: WidgetEventRepr) -> Result<Self, Self::Error> {
+        let WidgetEventRepr { state_key, content } = repr;
+        let url = content.url.ok_or("the widget has no url")?;
+        let id =
+            content.id.or(state_key).filter(|id| !id.is_empty()).ok_or("the widget has no id")?;
+
+        Ok(Self {
+            id,
+            url,
+            data: content.data,
+            wait_for_iframe_load: content.wait_for_iframe_load.unwrap_or(true),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_value, json};
+
+    use super::WidgetStateEvent;
+    use crate::widget::WidgetSettings;
+
+    #[test]
+    fn test_widget_from_room_state_event() {
+        let event: WidgetStateEvent = from_value(json!({
+            "type": "im.vector.modular.widgets",
+            "state_key": "widget-id",
+            "sender": "@alice:example.org",
+            "content": {
+                "type": "m.custom",
+                "url": "https://foo.bar/widget?widgetId=$matrix_widget_id",
+                "data": { "title": "My widget" },
+            },
+        }))
+        .unwrap();
+
+        assert_eq!(event.id, "widget-id");
+        assert_eq!(event.data["title"], "My widget");
+        assert!(!event.init_after_content_load());
+
+        let settings = WidgetSettings::try_from(event).unwrap();
+        assert_eq!(settings.widget_id(), "widget-id");
+        assert_eq!(
+            settings.raw_url().as_str(),
+            "https://foo.bar/widget?widgetId=$matrix_widget_id"
+        );
+    }
+
+    #[test]
+    fn test_widget_from_legacy_account_data_entry() {
+        let event: WidgetStateEvent = from_value(json!({
+            "type": "m.widget",
+            "state_key": "legacy",
+            "id": "legacy",
+            "content": {
+                "id": "legacy",
+                "type": "m.stickerpicker",
+                "url": "https://stickers.example.org",
+                "waitForIframeLoad": false,
+            },
+        }))
+        .unwrap();
+
+        assert_eq!(event.id, "legacy");
+        assert!(event.init_after_content_load());
+    }
+
+    #[test]
+    fn test_init_after_content_load_flag_of_the_data_takes_precedence() {
+        let event: WidgetStateEvent = from_value(json!({
+            "state_key": "widget-id",
+            "content": {
+                "url": "https://foo.bar/widget",
+                "waitForIframeLoad": true,
+                "data": { "init_after_content_load": true },
+            },
+        }))
+        .unwrap();
+
+        assert!(event.init_after_content_load());
+    }
+
+    #[test]
+    fn test_widget_without_id_is_rejected() {
+        let result = from_value::<WidgetStateEvent>(json!({
+            "type": "m.widget",
+            "state_key": "",
+            "content": {
+                "type": "m.custom",
+                "url": "https://foo.bar/widget",
+            },
+        }));
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_removed_widget_is_rejected() {
+        let result = from_value::<WidgetStateEvent>(json!({
+            "type": "im.vector.modular.widgets",
+            "state_key": "widget-id",
+            "content": {},
+        }));
+
+        assert!(result.is_err());
+    }
+}
@@ -12,11 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::BTreeMap;
+
 use url::Url;
 use urlencoding::encode;
 
 pub const USER_ID: &str = "$matrix_user_id";
 pub const ROOM_ID: &str = "$matrix_room_id";
+pub const ROOM_TOPIC: &str = "$matrix_room_topic";
 pub const WIDGET_ID: &str = "$matrix_widget_id";
 pub const AVATAR_URL: &str = "$matrix_avatar_url";
 pub const DISPLAY_NAME: &str = "$matrix_display_name";
@@ -26,21 +29,70 @@ pub const CLIENT_ID: &str = "$org.matrix.msc2873.client_id";
 pub const DEVICE_ID: &str = "$org.matrix.msc2873.matrix_device_id";
 pub const HOMESERVER_URL: &str = "$org.matrix.msc4039.matrix_base_url";
 
+/// All the placeholders that can be replaced in a widget url.
+const ALL_PLACEHOLDERS: [&str; 11] = [
+    USER_ID,
+    ROOM_ID,
+    ROOM_TOPIC,
+    WIDGET_ID,
+    AVATAR_URL,
+    DISPLAY_NAME,
+    LANGUAGE,
+    CLIENT_THEME,
+    CLIENT_ID,
+    DEVICE_ID,
+    HOMESERVER_URL,
+];
+
+/// Get the known placeholders used in the given url, in the order of their
+/// first appearance.
+pub fn find_placeholders(url: &Url) -> Vec<&'static str> {
+    let mut placeholders = Vec::new();
+    for section in url.as_str().split('$').skip(1) {
+        let found = ALL_PLACEHOLDERS.into_iter().find(|placeholder| {
+            // It's safe to slice here since we know all placeholders start with `$`
+            section.starts_with(&placeholder[1..])
+        });
+        if let Some(placeholder) = found {
+            if !placeholders.contains(&placeholder) {
+                placeholders.push(placeholder);
+            }
+        }
+    }
+    placeholders
+}
+
+/// Whether the given placeholder, with or without its leading `$`, is one of
+/// the placeholders that the SDK replaces on its own.
+pub fn is_built_in_placeholder(placeholder: &str) -> bool {
+    let placeholder = placeholder.strip_prefix('$').unwrap_or(placeholder);
+    // It's safe to slice here since we know all placeholders start with `$`
+    ALL_PLACEHOLDERS.iter().any(|built_in| built_in[1..] == *placeholder)
+}
+
 pub struct QueryProperties {
     pub(crate) widget_id: String,
     pub(crate) avatar_url: String,
     pub(crate) display_name: String,
     pub(crate) user_id: String,
     pub(crate) room_id: String,
+    pub(crate) room_topic: String,
     pub(crate) language: String,
     pub(crate) client_theme: String,
     pub(crate) client_id: String,
     pub(crate) device_id: String,
     pub(crate) homeserver_url: String,
+    /// Extra placeholders provided by the client, mapped to their value.
+    pub(crate) extra: BTreeMap<String, String>,
 }
 
-pub fn replace_properties(url: &mut Url, props: QueryProperties) {
-    let replace_map: [(&str, String); 10] = [
+/// Replace the placeholders in the given url with the given properties.
+///
+/// The properties are percent-encoded, but the url may still not be valid
+/// anymore afterwards, e.g. if a placeholder is part of its host. In that case
+/// an error is returned and the url is left untouched.
+pub fn replace_properties(url: &mut Url, props: QueryProperties) -> Result<(), url::ParseError> {
+    let replace_map: [(&str, String); 11] = [
         (WIDGET_ID, encode(&props.widget_id).into()),
         (AVATAR_URL, encode(&props.avatar_url).into()),
         (DEVICE_ID, encode(&props.device_id).into()),
@@ -48,6 +100,7 @@ pub fn replace_properties(url: &mut Url, props: QueryProperties) {
         (HOMESERVER_URL, encode(&props.homeserver_url).into()),
         (USER_ID, encode(&props.user_id).into()),
         (ROOM_ID, encode(&props.room_id).into()),
+        (ROOM_TOPIC, encode(&props.room_topic).into()),
         (LANGUAGE, encode(&props.language).into()),
         (CLIENT_THEME, encode(&props.client_theme).into()),
         (CLIENT_ID, encode(&props.client_id).into()),
@@ -57,10 +110,23 @@ pub fn replace_properties(url: &mut Url, props: QueryProperties) {
         (to_replace.0.get(1..).unwrap(), to_replace.1)
     });
 
+    // The extra placeholders are only used if no built-in one matches, e.g. for
+    // `$matrix_user_id_hash`, the longest one first so that e.g. `$foo_bar` isn't
+    // replaced as `$foo`.
+    let mut extra_replace_map = props
+        .extra
+        .iter()
+        .map(|(placeholder, value)| {
+            (placeholder.strip_prefix('$').unwrap_or(placeholder), encode(value).into_owned())
+        })
+        .filter(|(placeholder, _)| !placeholder.is_empty())
+        .collect::<Vec<_>>();
+    extra_replace_map.sort_by_key(|(placeholder, _)| std::cmp::Reverse(placeholder.len()));
+
     let s = url.as_str();
     let Some(beginning) = s.split_once('$').map(|s| s.0) else {
         // There is no '$' in the string so we don't need to do anything
-        return;
+        return Ok(());
     };
     let mut result = String::from(beginning);
     for section in s.split('$').skip(1) {
@@ -77,17 +143,26 @@ pub fn replace_properties(url: &mut Url, props: QueryProperties) {
             }
         }
         if !section_added {
-            result.push_str(section);
+            let extra = extra_replace_map.iter().find(|(old, _)| section.starts_with(old));
+            if let Some((old, new)) = extra {
+                result.push_str(new);
+                result.push_str(&section[old.len()..]);
+            } else {
+                result.push_str(section);
+            }
         }
     }
-    *url = Url::parse(&result).unwrap();
+    *url = Url::parse(&result)?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+
     use url::Url;
 
-    use super::{replace_properties, QueryProperties};
+    use super::{find_placeholders, replace_properties, QueryProperties};
 
     const EXAMPLE_URL: &str = "\
         https://my.widget.org/custom/path/using/$matrix_display_name/in/it\
@@ -109,11 +184,13 @@ mod tests {
             display_name: "I_AM_THE_user".to_owned(),
             user_id: "!@/abc_user_id".to_owned(),
             room_id: "!@/abc_room_id".to_owned(),
+            room_topic: "!@/abc_room_topic".to_owned(),
             language: "!@/abc_language".to_owned(),
             client_theme: "light".to_owned(),
             client_id: "12345678".to_owned(),
             device_id: "!@/abc_device_id".to_owned(),
             homeserver_url: "https://abc_base_url/".to_owned(),
+            extra: BTreeMap::new(),
         }
     }
 
@@ -138,7 +215,114 @@ mod tests {
                 #andAHashWithAlightThemeAndTheClientId:12345678\
         ";
 
-        replace_properties(&mut url, get_example_props());
+        replace_properties(&mut url, get_example_props()).unwrap();
         assert_eq!(url.as_str(), CONVERTED_URL);
     }
+
+    #[test]
+    fn replace_room_topic() {
+        let mut url =
+            Url::parse("https://my.widget.org/?topic=$matrix_room_topic&room=$matrix_room_id")
+                .unwrap();
+        let props = QueryProperties {
+            room_topic: "Café ☕\nOpen 24/7 & more: 100%".to_owned(),
+            ..get_example_props()
+        };
+
+        replace_properties(&mut url, props).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://my.widget.org/\
+                ?topic=Caf%C3%A9%20%E2%98%95%0AOpen%2024%2F7%20%26%20more%3A%20100%25\
+                &room=%21%40%2Fabc_room_id"
+        );
+        assert_eq!(url.query_pairs().next().unwrap().1, "Café ☕\nOpen 24/7 & more: 100%");
+    }
+
+    #[test]
+    fn replace_absent_room_topic() {
+        let mut url = Url::parse("https://my.widget.org/?topic=$matrix_room_topic").unwrap();
+        let props = QueryProperties { room_topic: String::new(), ..get_example_props() };
+
+        replace_properties(&mut url, props).unwrap();
+        assert_eq!(url.as_str(), "https://my.widget.org/?topic=");
+    }
+
+    #[test]
+    fn replace_display_name_with_special_characters() {
+        let mut url = Url::parse("https://my.widget.org/?name=$matrix_display_name").unwrap();
+        let props =
+            QueryProperties { display_name: "#1 user 100% ok".to_owned(), ..get_example_props() };
+
+        replace_properties(&mut url, props).unwrap();
+        assert_eq!(url.query_pairs().next().unwrap().1, "#1 user 100% ok");
+    }
+
+    #[test]
+    fn replace_property_breaking_the_url() {
+        let raw_url = Url::parse("https://$matrix_display_name.widget.org/").unwrap();
+        let mut url = raw_url.clone();
+        let props = QueryProperties { display_name: "a user".to_owned(), ..get_example_props() };
+
+        replace_properties(&mut url, props).unwrap_err();
+        assert_eq!(url, raw_url);
+    }
+
+    #[test]
+    fn built_in_placeholders() {
+        assert!(is_built_in_placeholder("$matrix_user_id"));
+        assert!(is_built_in_placeholder("org.matrix.msc2873.client_theme"));
+        assert!(!is_built_in_placeholder("$matrix_user"));
+        assert!(!is_built_in_placeholder("$matrix_room_name"));
+    }
+
+    #[test]
+    fn replace_extra_properties() {
+        let mut url = Url::parse(
+            "https://my.widget.org/?room=$matrix_room_name\
+                &vendor=$com.example.vendor\
+                &vendorId=$com.example.vendor_id\
+                &unknown=$com.example.unknown\
+                &user=$matrix_user_id",
+        )
+        .unwrap();
+        let props = QueryProperties {
+            extra: BTreeMap::from([
+                ("$matrix_room_name".to_owned(), "Room #1 & co".to_owned()),
+                ("com.example.vendor".to_owned(), "%24matrix_user_id".to_owned()),
+                ("$com.example.vendor_id".to_owned(), "42".to_owned()),
+                // Built-in placeholders can't be overridden.
+                ("$matrix_user_id".to_owned(), "@evil:example.org".to_owned()),
+            ]),
+            ..get_example_props()
+        };
+
+        replace_properties(&mut url, props).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://my.widget.org/\
+                ?room=Room%20%231%20%26%20co\
+                &vendor=%2524matrix_user_id\
+                &vendorId=42\
+                &unknown=$com.example.unknown\
+                &user=%21%40%2Fabc_user_id"
+        );
+    }
+
+    #[test]
+    fn find_placeholders_in_url() {
+        let url = Url::parse(
+            "https://my.widget.org/$matrix_display_name\
+                ?widgetId=$matrix_widget_id\
+                &name=$matrix_display_name\
+                &theme=$org.matrix.msc2873.client_theme\
+                &price=$5",
+        )
+        .unwrap();
+
+        assert_eq!(
+            find_placeholders(&url),
+            ["$matrix_display_name", "$matrix_widget_id", "$org.matrix.msc2873.client_theme"]
+        );
+    }
 }
@@ -0,0 +1,179 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording of the steps of a widget session, to debug it.
+
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{value::RawValue as RawJsonValue, Map, Value};
+
+use super::{
+    machine::{Action, IncomingMessage},
+    redact_outgoing_message,
+};
+
+/// A step of a widget session, recorded by a [`SessionTrace`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum SessionTraceStep {
+    /// A raw message received from the widget, without its data.
+    FromWidget {
+        /// The message, with its `data` and `response` redacted.
+        message: String,
+    },
+
+    /// A raw message sent to the widget, with its secrets redacted.
+    ToWidget {
+        /// The message, as sent.
+        message: String,
+    },
+
+    /// A request sent to the client, e.g. to acquire capabilities.
+    DriverRequest {
+        /// The id of the request.
+        request_id: String,
+        /// The kind of the request, e.g. `AcquireCapabilities`.
+        kind: String,
+    },
+
+    /// The response of the client to a [`SessionTraceStep::DriverRequest`].
+    DriverResponse {
+        /// The id of the request.
+        request_id: String,
+        /// Whether the request failed.
+        is_error: bool,
+    },
+
+    /// An event of the room, or an ephemeral event, received by the widget
+    /// machine.
+    MatrixEvent {
+        /// The JSON of the event, with only its type and its event id.
+        event: String,
+    },
+
+    /// A notification of the client, e.g. `ThemeChanged` or `Pause`.
+    ClientNotification {
+        /// The kind of the notification.
+        kind: String,
+    },
+
+    /// Another action of the widget machine, e.g. `Subscribe`.
+    ClientAction {
+        /// The description of the action.
+        action: String,
+    },
+}
+
+/// The ordered steps of a widget session, recorded when it is enabled with
+/// [`WidgetSettings::with_session_trace`](super::WidgetSettings::with_session_trace).
+///
+/// The trace grows for the whole session, so it should only be enabled to debug
+/// a session, e.g. to attach its trace to a bug report.
+#[derive(Clone, Debug, Default)]
+pub struct SessionTrace {
+    steps: Arc<Mutex<Vec<SessionTraceStep>>>,
+}
+
+impl SessionTrace {
+    /// The steps recorded so far, in order.
+    pub fn steps(&self) -> Vec<SessionTraceStep> {
+        self.steps.lock().unwrap().clone()
+    }
+
+    /// Record a message received by the widget machine.
+    pub(crate) fn record_incoming(&self, message: &IncomingMessage) {
+        let step = match message {
+            IncomingMessage::WidgetMessage(message) => {
+                SessionTraceStep::FromWidget { message: redact_incoming_message(message) }
+            }
+            IncomingMessage::MatrixDriverResponse { request_id, response } => {
+                SessionTraceStep::DriverResponse {
+                    request_id: request_id.to_string(),
+                    is_error: response.is_err(),
+                }
+            }
+            IncomingMessage::MatrixEventReceived(event) => {
+                SessionTraceStep::MatrixEvent { event: redact_event(event.json()) }
+            }
+            IncomingMessage::MatrixEphemeralEventReceived(event) => {
+                SessionTraceStep::MatrixEvent { event: redact_event(event.json()) }
+            }
+            IncomingMessage::ThemeChanged(_) => notification("ThemeChanged"),
+            IncomingMessage::ClientPropertiesChanged { .. } => {
+                notification("ClientPropertiesChanged")
+            }
+            IncomingMessage::UpdateCapabilities(_) => notification("UpdateCapabilities"),
+            IncomingMessage::Pause => notification("Pause"),
+            IncomingMessage::Resume => notification("Resume"),
+            IncomingMessage::WidgetReconnected => notification("WidgetReconnected"),
+            IncomingMessage::ClientUnavailable => notification("ClientUnavailable"),
+            IncomingMessage::RoomUnavailable => notification("RoomUnavailable"),
+            IncomingMessage::ToWidgetTimeoutElapsed => notification("ToWidgetTimeoutElapsed"),
+        };
+
+        self.steps.lock().unwrap().push(step);
+    }
+
+    /// Record the actions of the widget machine.
+    pub(crate) fn record_actions(&self, actions: &[Action]) {
+        let mut steps = self.steps.lock().unwrap();
+        steps.extend(actions.iter().map(|action| match action {
+            Action::SendToWidget(message) => {
+                SessionTraceStep::ToWidget { message: redact_outgoing_message(message) }
+            }
+            Action::MatrixDriverRequest { request_id, data } => SessionTraceStep::DriverRequest {
+                request_id: request_id.to_string(),
+                kind: data.kind().to_owned(),
+            },
+            action => SessionTraceStep::ClientAction { action: format!("{action:?}") },
+        }));
+    }
+}
+
+fn notification(kind: &str) -> SessionTraceStep {
+    SessionTraceStep::ClientNotification { kind: kind.to_owned() }
+}
+
+/// Redact the data of a message received from the widget, since it can contain
+/// the content of the events sent by the widget.
+fn redact_incoming_message(msg: &str) -> String {
+    let Ok(mut message) = serde_json::from_str::<Value>(msg) else {
+        return "[REDACTED]".to_owned();
+    };
+
+    if let Some(message) = message.as_object_mut() {
+        for field in ["data", "response"] {
+            if let Some(value) = message.get_mut(field) {
+                *value = "[REDACTED]".into();
+            }
+        }
+    }
+
+    message.to_string()
+}
+
+/// Keep only the type and the event id of an event, to leave its content and
+/// its sender out of the trace.
+fn redact_event(event: &RawJsonValue) -> String {
+    let Ok(event) = serde_json::from_str::<Map<String, Value>>(event.get()) else {
+        return "[REDACTED]".to_owned();
+    };
+
+    let redacted: Map<_, _> = event
+        .into_iter()
+        .filter(|(field, _)| matches!(field.as_str(), "type" | "event_id"))
+        .collect();
+    Value::Object(redacted).to_string()
+}
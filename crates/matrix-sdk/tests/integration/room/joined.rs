@@ -10,7 +10,10 @@ use futures_util::{future::join_all, pin_mut};
 use matrix_sdk::{
     assert_next_with_timeout, assert_recv_with_timeout,
     config::SyncSettings,
-    room::{edit::EditedContent, Receipts, ReportedContentScore, RoomMemberRole},
+    room::{
+        edit::EditedContent, knock_requests::KnockRequestServerFilter, Receipts,
+        ReportedContentScore, RoomMemberRole,
+    },
     test_utils::mocks::MatrixMockServer,
 };
 use matrix_sdk_base::{EncryptionState, RoomMembersUpdate, RoomState};
@@ -34,7 +37,8 @@ use ruma::{
         },
         TimelineEventType,
     },
-    int, mxc_uri, owned_event_id, room_id, thirdparty, user_id, OwnedUserId, TransactionId,
+    int, mxc_uri, owned_event_id, owned_server_name, room_id, server_name, thirdparty, user_id,
+    OwnedUserId, TransactionId,
 };
 use serde_json::{from_value, json, Value};
 use stream_assert::assert_pending;
@@ -890,6 +894,263 @@ async fn test_subscribe_to_knock_requests() {
     handle.abort();
 }
 
+#[async_test]
+async fn test_mark_all_knock_requests_as_seen() {
+    let server = MatrixMockServer::new().await;
+    let client = server.client_builder().build().await;
+
+    server.mock_room_state_encryption().plain().mount().await;
+
+    let room_id = room_id!("!a:b.c");
+    let f = EventFactory::new().room(room_id);
+
+    let knock_events = [
+        (user_id!("@alice:b.c"), event_id!("$alice-knock:b.c")),
+        (user_id!("@bob:b.c"), event_id!("$bob-knock:b.c")),
+    ]
+    .into_iter()
+    .map(|(user_id, event_id)| {
+        f.member(user_id)
+            .membership(MembershipState::Knock)
+            .event_id(event_id)
+            .into_raw_timeline()
+            .cast()
+    })
+    .collect();
+
+    server.mock_get_members().ok(knock_events).mock_once().mount().await;
+
+    let room = server.sync_joined_room(&client, room_id).await;
+    let (stream, handle) = room.subscribe_to_knock_requests().await.unwrap();
+
+    pin_mut!(stream);
+
+    // We receive the initial knock requests, none of them has been seen.
+    let initial = assert_next_with_timeout!(stream, 100);
+    assert_eq!(initial.len(), 2);
+    assert!(initial.iter().all(|request| !request.is_seen));
+
+    // We then mark all of them as seen at once.
+    room.mark_all_knock_requests_as_seen().await.unwrap();
+
+    // They are all received again as seen, in a single update.
+    let seen = assert_next_with_timeout!(stream, 100);
+    assert_eq!(seen.len(), 2);
+    assert!(seen.iter().all(|request| request.is_seen));
+    assert_pending!(stream);
+
+    let count = room.knock_requests_count().await.unwrap();
+    assert_eq!(count.total, 2);
+    assert_eq!(count.unseen, 0);
+
+    handle.abort();
+}
+
+#[async_test]
+async fn test_knock_requests_count() {
+    let server = MatrixMockServer::new().await;
+    let client = server.client_builder().build().await;
+
+    server.mock_room_state_encryption().plain().mount().await;
+
+    let room_id = room_id!("!a:b.c");
+    let f = EventFactory::new().room(room_id);
+
+    let alice = user_id!("@alice:b.c");
+    let bob = user_id!("@bob:b.c");
+    let members = vec![
+        f.member(alice)
+            .membership(MembershipState::Knock)
+            .event_id(event_id!("$alice-knock:b.c"))
+            .into_raw_timeline()
+            .cast(),
+        f.member(bob)
+            .membership(MembershipState::Knock)
+            .event_id(event_id!("$bob-knock:b.c"))
+            .into_raw_timeline()
+            .cast(),
+        f.member(user_id!("@carol:b.c"))
+            .membership(MembershipState::Join)
+            .event_id(event_id!("$carol-join:b.c"))
+            .into_raw_timeline()
+            .cast(),
+    ];
+
+    // The members are only fetched once, then the cached ones are used.
+    server.mock_get_members().ok(members).mock_once().mount().await;
+
+    let room = server.sync_joined_room(&client, room_id).await;
+
+    let count = room.knock_requests_count().await.unwrap();
+    assert_eq!(count.total, 2);
+    assert_eq!(count.unseen, 2);
+
+    room.mark_knock_requests_as_seen(&[alice.to_owned()]).await.unwrap();
+
+    let count = room.knock_requests_count().await.unwrap();
+    assert_eq!(count.total, 2);
+    assert_eq!(count.unseen, 1);
+}
+
+#[async_test]
+async fn test_mark_knock_requests_as_seen_batch() {
+    let server = MatrixMockServer::new().await;
+    let client = server.client_builder().build().await;
+
+    server.mock_room_state_encryption().plain().mount().await;
+
+    let room_id = room_id!("!a:b.c");
+    let f = EventFactory::new().room(room_id);
+
+    let alice = user_id!("@alice:b.c");
+    let bob = user_id!("@bob:b.c");
+    let members = vec![
+        f.member(alice)
+            .membership(MembershipState::Knock)
+            .event_id(event_id!("$alice-knock:b.c"))
+            .into_raw_timeline()
+            .cast(),
+        f.member(bob)
+            .membership(MembershipState::Knock)
+            .event_id(event_id!("$bob-knock:b.c"))
+            .into_raw_timeline()
+            .cast(),
+    ];
+    server.mock_get_members().ok(members).mock_once().mount().await;
+
+    let room = server.sync_joined_room(&client, room_id).await;
+
+    let filter = KnockRequestServerFilter::Block(BTreeSet::new());
+    let (requests, _) = room.partition_knock_requests(&filter).await.unwrap();
+    assert_eq!(requests.len(), 2);
+
+    // Bob joins the room in the meantime, so his request is outdated.
+    server
+        .sync_room(
+            &client,
+            JoinedRoomBuilder::new(room_id).add_state_bulk(vec![f
+                .member(bob)
+                .membership(MembershipState::Join)
+                .event_id(event_id!("$bob-join:b.c"))
+                .into_raw_timeline()
+                .cast()]),
+        )
+        .await;
+
+    // Only the request of Alice is marked as seen.
+    let marked = room.mark_knock_requests_as_seen_batch(&requests).await.unwrap();
+    assert_eq!(marked, BTreeSet::from([owned_event_id!("$alice-knock:b.c")]));
+
+    let seen_ids = room.get_seen_knock_request_ids().await.unwrap();
+    assert_eq!(seen_ids.len(), 1);
+    assert!(seen_ids.contains_key(event_id!("$alice-knock:b.c")));
+
+    // The request of Alice was already seen, so it isn't marked again.
+    let marked = room.mark_knock_requests_as_seen_batch(&requests).await.unwrap();
+    assert!(marked.is_empty());
+}
+
+#[async_test]
+async fn test_subscribe_to_unseen_knock_requests() {
+    let server = MatrixMockServer::new().await;
+    let client = server.client_builder().build().await;
+
+    server.mock_room_state_encryption().plain().mount().await;
+
+    let room_id = room_id!("!a:b.c");
+    let f = EventFactory::new().room(room_id);
+
+    let alice = user_id!("@alice:b.c");
+    let bob = user_id!("@bob:b.c");
+    let members = vec![
+        f.member(alice)
+            .membership(MembershipState::Knock)
+            .event_id(event_id!("$alice-knock:b.c"))
+            .into_raw_timeline()
+            .cast(),
+        f.member(bob)
+            .membership(MembershipState::Knock)
+            .event_id(event_id!("$bob-knock:b.c"))
+            .into_raw_timeline()
+            .cast(),
+    ];
+    let knock_events: Vec<_> = members.iter().map(|member| member.clone().cast()).collect();
+    server.mock_get_members().ok(members).mock_once().mount().await;
+
+    // The knock requests are received with the room state, so that they can be
+    // marked as seen.
+    let room = server
+        .sync_room(&client, JoinedRoomBuilder::new(room_id).add_state_bulk(knock_events))
+        .await;
+
+    // The request of Alice was already seen.
+    room.mark_knock_requests_as_seen(&[alice.to_owned()]).await.unwrap();
+    let seen_ids = room.get_seen_knock_request_ids().await.unwrap();
+    assert!(seen_ids.contains_key(event_id!("$alice-knock:b.c")));
+
+    let (stream, handle) = room.subscribe_to_unseen_knock_requests().await.unwrap();
+    pin_mut!(stream);
+
+    // Only the request of Bob is emitted.
+    let initial = assert_next_with_timeout!(stream, 100);
+    assert_eq!(initial.len(), 1);
+    assert_eq!(initial[0].event_id, event_id!("$bob-knock:b.c"));
+    assert!(!initial[0].is_seen);
+
+    // Once it's seen too, there are no unseen requests left.
+    room.mark_knock_requests_as_seen(&[bob.to_owned()]).await.unwrap();
+    let updated = assert_next_with_timeout!(stream, 100);
+    assert!(updated.is_empty());
+
+    handle.abort();
+}
+
+#[async_test]
+async fn test_knock_requests_from_a_blocked_server_are_filtered_and_declined() {
+    let server = MatrixMockServer::new().await;
+    let client = server.client_builder().build().await;
+
+    server.mock_room_state_encryption().plain().mount().await;
+
+    let room_id = room_id!("!a:b.c");
+    let f = EventFactory::new().room(room_id);
+
+    let alice = user_id!("@alice:b.c");
+    let spammer = user_id!("@spammer:spam.org");
+    let members = vec![
+        f.member(alice)
+            .membership(MembershipState::Knock)
+            .event_id(event_id!("$alice-knock:b.c"))
+            .into_raw_timeline()
+            .cast(),
+        f.member(spammer)
+            .membership(MembershipState::Knock)
+            .event_id(event_id!("$spammer-knock:spam.org"))
+            .into_raw_timeline()
+            .cast(),
+    ];
+    server.mock_get_members().ok(members).mock_once().mount().await;
+
+    let room = server.sync_joined_room(&client, room_id).await;
+
+    // The requests from the blocked server are filtered out.
+    let filter = KnockRequestServerFilter::Block(BTreeSet::from([owned_server_name!("spam.org")]));
+    let (allowed, blocked) = room.partition_knock_requests(&filter).await.unwrap();
+    assert_eq!(allowed.len(), 1);
+    assert_eq!(allowed[0].member_info.user_id, alice);
+    assert_eq!(blocked.len(), 1);
+    assert_eq!(blocked[0].member_info.user_id, spammer);
+
+    // Only the user of the blocked server is kicked.
+    server.mock_kick_user().ok().mock_once().mount().await;
+
+    let declined = room
+        .decline_knock_requests_from_server(server_name!("spam.org"), Some("spam"))
+        .await
+        .unwrap();
+    assert_eq!(declined, vec![spammer.to_owned()]);
+}
+
 #[async_test]
 async fn test_subscribe_to_knock_requests_reloads_members_on_limited_sync() {
     let server = MatrixMockServer::new().await;
@@ -12,7 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{pin::pin, time::Duration};
+use std::{
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use assert_matches::assert_matches;
 use async_trait::async_trait;
@@ -20,25 +24,42 @@ use futures_util::FutureExt;
 use matrix_sdk::{
     test_utils::mocks::{MatrixMockServer, RoomMessagesResponseTemplate},
     widget::{
-        Capabilities, CapabilitiesProvider, WidgetDriver, WidgetDriverHandle, WidgetSettings,
+        Capabilities, CapabilitiesProvider, ClientProperties, GenerateWebviewUrlError,
+        NavigationHandler, ReconnectPolicy, SessionEnd, WidgetDriver, WidgetDriverHandle,
+        WidgetError, WidgetSettings,
     },
     Client,
 };
 use matrix_sdk_common::{executor::spawn, timeout::timeout};
-use matrix_sdk_test::{async_test, event_factory::EventFactory, JoinedRoomBuilder, ALICE, BOB};
+use matrix_sdk_test::{
+    async_test, event_factory::EventFactory, JoinedRoomBuilder, LeftRoomBuilder, ALICE, BOB,
+};
 use once_cell::sync::Lazy;
 use ruma::{
     event_id,
-    events::{room::member::MembershipState, MessageLikeEventType, StateEventType},
+    events::{
+        receipt::{ReceiptThread, ReceiptType},
+        room::{
+            encryption::RoomEncryptionEventContent, member::MembershipState,
+            message::RoomMessageEventContentWithoutRelation,
+        },
+        AnySyncStateEvent, MessageLikeEventType, StateEventType,
+    },
     owned_room_id,
-    serde::JsonObject,
-    user_id, OwnedRoomId,
+    serde::{JsonObject, Raw},
+    user_id, OwnedRoomId, UserId,
 };
 use serde::Serialize;
 use serde_json::{json, Value as JsonValue};
-use tracing::error;
+use tokio::time::sleep;
+use tracing::{error, level_filters::LevelFilter, Event, Level, Subscriber};
+use tracing_subscriber::{
+    layer::{Context, SubscriberExt},
+    util::SubscriberInitExt,
+    Layer,
+};
 use wiremock::{
-    matchers::{method, path_regex},
+    matchers::{body_json, body_partial_json, method, path_regex},
     Mock, ResponseTemplate,
 };
 
@@ -54,29 +75,39 @@ static ROOM_ID: Lazy<OwnedRoomId> = Lazy::new(|| owned_room_id!("!a98sd12bjh:exa
 async fn run_test_driver(
     init_on_content_load: bool,
 ) -> (Client, MatrixMockServer, WidgetDriverHandle) {
-    struct DummyCapabilitiesProvider;
+    run_test_driver_with_settings(
+        WidgetSettings::new(WIDGET_ID.to_owned(), init_on_content_load, "https://foo.bar/widget")
+            .unwrap(),
+    )
+    .await
+}
 
-    #[async_trait]
-    impl CapabilitiesProvider for DummyCapabilitiesProvider {
-        async fn acquire_capabilities(&self, capabilities: Capabilities) -> Capabilities {
-            // Grant all capabilities that the widget asks for
-            capabilities
-        }
+#[derive(Clone)]
+struct DummyCapabilitiesProvider;
+
+#[async_trait]
+impl CapabilitiesProvider for DummyCapabilitiesProvider {
+    async fn acquire_capabilities(&self, capabilities: Capabilities) -> Capabilities {
+        // Grant all capabilities that the widget asks for
+        capabilities
     }
+}
+
+async fn run_test_driver_with_settings(
+    settings: WidgetSettings,
+) -> (Client, MatrixMockServer, WidgetDriverHandle) {
     let mock_server = MatrixMockServer::new().await;
     let client = mock_server.client_builder().build().await;
 
     let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
     mock_server.mock_room_state_encryption().plain().mount().await;
 
-    let (driver, handle) = WidgetDriver::new(
-        WidgetSettings::new(WIDGET_ID.to_owned(), init_on_content_load, "https://foo.bar/widget")
-            .unwrap(),
-    );
+    let (driver, handle) = WidgetDriver::new(settings);
 
     spawn(async move {
-        if let Err(()) = driver.run(room, DummyCapabilitiesProvider).await {
-            error!("An error encountered in running the WidgetDriver (no details available yet)");
+        let session_end = driver.run(room, DummyCapabilitiesProvider).await;
+        if !matches!(session_end, SessionEnd::WidgetDisconnected | SessionEnd::Stopped) {
+            error!(?session_end, "The WidgetDriver stopped unexpectedly");
         }
     });
 
@@ -181,6 +212,138 @@ async fn test_negotiate_capabilities_immediately() {
     assert_matches!(driver_handle.recv().now_or_never(), None);
 }
 
+#[async_test]
+async fn test_requested_capabilities_are_observable_before_approval() {
+    /// A provider that never makes a decision, like a user who doesn't answer
+    /// the consent prompt.
+    struct UndecidedCapabilitiesProvider;
+
+    #[async_trait]
+    impl CapabilitiesProvider for UndecidedCapabilitiesProvider {
+        async fn acquire_capabilities(&self, _capabilities: Capabilities) -> Capabilities {
+            std::future::pending().await
+        }
+    }
+
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let (driver, driver_handle) = WidgetDriver::new(settings);
+    spawn(driver.run(room, UndecidedCapabilitiesProvider));
+
+    let mut requested = driver_handle.subscribe_to_requested_capabilities();
+    assert!(driver_handle.requested_capabilities().is_none());
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "capabilities");
+    let request_id = msg["requestId"].as_str().unwrap();
+    let caps = json!([
+        "org.matrix.msc2762.receive.event:m.room.message",
+        "org.matrix.msc2762.send.event:m.room.message",
+    ]);
+    let response = json!({ "capabilities": caps });
+    send_response(&driver_handle, request_id, "capabilities", &msg["data"], &response).await;
+
+    // The requested capabilities are known while the provider is still deciding.
+    let capabilities = timeout(requested.next(), Duration::from_secs(1)).await.unwrap().unwrap();
+    let capabilities = capabilities.unwrap();
+    assert_eq!(capabilities.read.len(), 1);
+    assert_eq!(capabilities.send.len(), 1);
+    assert!(driver_handle.requested_capabilities().is_some());
+
+    // Nothing was approved.
+    assert!(timeout(driver_handle.recv(), Duration::from_millis(100)).await.is_err());
+}
+
+#[async_test]
+async fn test_resumed_session_restores_the_capabilities() {
+    /// A provider that fails the test if the capabilities are negotiated again.
+    struct UnreachableCapabilitiesProvider;
+
+    #[async_trait]
+    impl CapabilitiesProvider for UnreachableCapabilitiesProvider {
+        async fn acquire_capabilities(&self, _capabilities: Capabilities) -> Capabilities {
+            panic!("The capabilities of a resumed session shouldn't be negotiated again");
+        }
+    }
+
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let (driver, driver_handle) = WidgetDriver::new(settings.clone());
+    let session = spawn(driver.run(room.clone(), DummyCapabilitiesProvider));
+
+    // Nothing was negotiated yet.
+    assert!(driver_handle.snapshot().approved_capabilities().is_none());
+
+    let caps = json!(["org.matrix.msc2762.receive.event:m.room.message"]);
+    negotiate_capabilities(&driver_handle, caps.clone()).await;
+
+    // The app is backgrounded.
+    let snapshot = driver_handle.snapshot();
+    driver_handle.stop();
+    assert_eq!(session.await.unwrap(), SessionEnd::Stopped);
+
+    let approved = snapshot.approved_capabilities().unwrap();
+    assert_eq!(approved.read.len(), 1);
+    assert!(approved.send.is_empty());
+
+    // The app is foregrounded again.
+    let (driver, driver_handle) = WidgetDriver::resume_from(settings, snapshot);
+    assert!(driver_handle.requested_capabilities().is_some());
+    spawn(driver.run(room, UnreachableCapabilitiesProvider));
+
+    // The widget is notified about the restored capabilities right away, without
+    // being asked for them.
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "notify_capabilities");
+    assert_eq!(msg["data"], json!({ "requested": caps, "approved": caps }));
+    let request_id = msg["requestId"].as_str().unwrap();
+    send_response(&driver_handle, request_id, "notify_capabilities", &caps, json!({})).await;
+
+    assert!(timeout(driver_handle.recv(), Duration::from_millis(100)).await.is_err());
+    assert!(driver_handle.snapshot().approved_capabilities().is_some());
+}
+
+#[async_test]
+async fn test_critical_messages_are_replayed_on_reconnect() {
+    let (_, _, driver_handle) = run_test_driver(false).await;
+
+    let caps = json!(["org.matrix.msc2762.receive.event:m.room.message"]);
+    negotiate_capabilities(&driver_handle, caps.clone()).await;
+
+    assert!(driver_handle.notify_theme_change("dark".to_owned()).await);
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "theme_change");
+    let request_id = msg["requestId"].as_str().unwrap();
+    send_response(&driver_handle, request_id, "theme_change", &msg["data"], json!({})).await;
+
+    // The webview was reloaded, the widget missed the previous messages.
+    assert!(driver_handle.replay_on_reconnect().await);
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "toWidget");
+    assert_eq!(msg["action"], "notify_capabilities");
+    assert_eq!(msg["data"], json!({ "requested": caps, "approved": caps }));
+    let request_id = msg["requestId"].as_str().unwrap();
+    send_response(&driver_handle, request_id, "notify_capabilities", &caps, json!({})).await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "theme_change");
+    assert_eq!(msg["data"], json!({ "name": "dark" }));
+    let request_id = msg["requestId"].as_str().unwrap();
+    send_response(&driver_handle, request_id, "theme_change", &msg["data"], json!({})).await;
+
+    // The events and the other messages aren't replayed.
+    assert_matches!(recv_message(&driver_handle).now_or_never(), None);
+}
+
 #[async_test]
 async fn test_read_messages() {
     let (_, mock_server, driver_handle) = run_test_driver(true).await;
@@ -371,476 +534,2887 @@ async fn test_read_room_members() {
 }
 
 #[async_test]
-async fn test_receive_live_events() {
+async fn test_read_own_membership_only() {
     let (client, mock_server, driver_handle) = run_test_driver(false).await;
 
-    negotiate_capabilities(
-        &driver_handle,
-        json!([
-            "org.matrix.msc2762.receive.event:m.room.member",
-            "org.matrix.msc2762.receive.event:m.room.message#m.text",
-            "org.matrix.msc2762.receive.state_event:m.room.name#",
-            "org.matrix.msc2762.receive.state_event:m.room.member#@example:localhost",
-        ]),
-    )
-    .await;
-
-    // No messages from the driver yet
-    assert_matches!(recv_message(&driver_handle).now_or_never(), None);
-
-    let f = EventFactory::new();
-
+    let own_user_id = client.user_id().unwrap().to_owned();
+    let member_event = |user_id: &UserId| {
+        Raw::new(&json!({
+            "type": "m.room.member",
+            "state_key": user_id,
+            "sender": user_id,
+            "event_id": format!("$member_{}", user_id.localpart()),
+            "origin_server_ts": 1_000,
+            "content": { "membership": "join" },
+        }))
+        .unwrap()
+        .cast()
+    };
     mock_server
         .mock_sync()
         .ok_and_run(&client, |sync_builder| {
             sync_builder.add_joined_room(
                 JoinedRoomBuilder::new(&ROOM_ID)
-                    // text message from alice - matches filter #2
-                    .add_timeline_event(f.text_msg("simple text message").sender(&ALICE))
-                    // emote from alice - doesn't match
-                    .add_timeline_event(f.emote("emote message").sender(&ALICE))
-                    // pointless member event - matches filter #4
-                    .add_timeline_event(
-                        f.member(user_id!("@example:localhost"))
-                            .membership(MembershipState::Join)
-                            .previous(MembershipState::Join),
-                    )
-                    // kick alice - doesn't match because the `#@example:localhost` bit
-                    // is about the state_key, not the sender
-                    .add_timeline_event(
-                        f.member(user_id!("@example:localhost"))
-                            .banned(&ALICE)
-                            .previous(MembershipState::Join),
-                    )
-                    // set room topic - doesn't match
-                    .add_timeline_event(f.room_topic("new room topic").sender(&BOB))
-                    // set room name - matches filter #3
-                    .add_timeline_event(f.room_name("New Room Name").sender(&BOB)),
+                    .add_state_bulk([member_event(&own_user_id), member_event(&ALICE)]),
             );
         })
         .await;
 
-    let msg = recv_message(&driver_handle).await;
-    assert_eq!(msg["api"], "toWidget");
-    assert_eq!(msg["action"], "send_event");
-    assert_eq!(msg["data"]["type"], "m.room.message");
-    assert_eq!(msg["data"]["room_id"], ROOM_ID.as_str());
-    assert_eq!(msg["data"]["content"]["msgtype"], "m.text");
-    assert_eq!(msg["data"]["content"]["body"], "simple text message");
+    // The widget may only read the membership of the user.
+    negotiate_capabilities(
+        &driver_handle,
+        json!([format!("org.matrix.msc2762.receive.state_event:m.room.member#{own_user_id}")]),
+    )
+    .await;
 
+    // Reading all the member events only returns the one of the user.
+    send_request(
+        &driver_handle,
+        "read-members",
+        "org.matrix.msc2876.read_events",
+        json!({ "type": "m.room.member", "state_key": true }),
+    )
+    .await;
     let msg = recv_message(&driver_handle).await;
-    assert_eq!(msg["api"], "toWidget");
-    assert_eq!(msg["action"], "send_event");
-    assert_eq!(msg["data"]["type"], "m.room.member");
-    assert_eq!(msg["data"]["room_id"], ROOM_ID.as_str());
-    assert_eq!(msg["data"]["state_key"], "@example:localhost");
-    assert_eq!(msg["data"]["content"]["membership"], "join");
-    assert_eq!(msg["data"]["unsigned"]["prev_content"]["membership"], "join");
+    let events = msg["response"]["events"].as_array().unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["state_key"], own_user_id.as_str());
 
+    // Reading the member event of someone else is denied.
+    send_request(
+        &driver_handle,
+        "read-alice",
+        "org.matrix.msc2876.read_events",
+        json!({ "type": "m.room.member", "state_key": ALICE.as_str() }),
+    )
+    .await;
     let msg = recv_message(&driver_handle).await;
-    assert_eq!(msg["api"], "toWidget");
-    assert_eq!(msg["action"], "send_event");
-    assert_eq!(msg["data"]["type"], "m.room.name");
-    assert_eq!(msg["data"]["sender"], BOB.as_str());
-    assert_eq!(msg["data"]["content"]["name"], "New Room Name");
-
-    // No more messages from the driver
-    assert_matches!(recv_message(&driver_handle).now_or_never(), None);
+    assert_eq!(msg["response"]["error"]["message"], "Not allowed to read state event");
 }
 
 #[async_test]
-async fn test_send_room_message() {
+async fn test_get_membership_events() {
     let (_, mock_server, driver_handle) = run_test_driver(false).await;
 
-    negotiate_capabilities(&driver_handle, json!(["org.matrix.msc2762.send.event:m.room.message"]))
-        .await;
+    negotiate_capabilities(
+        &driver_handle,
+        json!(["org.matrix.msc2762.receive.state_event:m.room.member"]),
+    )
+    .await;
 
+    // The room has a knock of Bob, accepted by Alice, and a join of Alice.
+    let f = EventFactory::new().room(&ROOM_ID);
+    let chunk = vec![
+        f.member(&BOB).membership(MembershipState::Join).into_raw_timeline(),
+        f.member(&ALICE).invited(&BOB).into_raw_timeline(),
+        f.member(&BOB).membership(MembershipState::Knock).into_raw_timeline(),
+        f.member(&ALICE).membership(MembershipState::Join).into_raw_timeline(),
+    ];
     mock_server
-        .mock_room_send()
-        .for_type("m.room.message".into())
-        .ok(event_id!("$foobar"))
+        .mock_room_messages()
+        .match_limit(10)
+        .match_from("prev_token")
+        .ok(RoomMessagesResponseTemplate::default().end_token("next_token").events(chunk))
         .mock_once()
         .mount()
         .await;
 
     send_request(
         &driver_handle,
-        "send-room-message",
-        "send_event",
-        json!({
-            "type": "m.room.message",
-            "content": {
-                "msgtype": "m.text",
-                "body": "Message from a widget!",
-            },
-        }),
+        "get-membership-events",
+        "get_membership_events",
+        json!({ "user_id": BOB.as_str(), "limit": 10, "from": "prev_token" }),
     )
     .await;
 
-    // Receive the response
     let msg = recv_message(&driver_handle).await;
     assert_eq!(msg["api"], "fromWidget");
-    assert_eq!(msg["action"], "send_event");
-    let event_id = msg["response"]["event_id"].as_str().unwrap();
-    assert_eq!(event_id, "$foobar");
+    assert_eq!(msg["action"], "get_membership_events");
+    assert_eq!(msg["response"]["next_token"], "next_token");
+
+    // Only the membership events of Bob are returned, from the newest to the
+    // oldest one.
+    let memberships: Vec<_> = msg["response"]["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| {
+            assert_eq!(event["state_key"], BOB.as_str());
+            event["content"]["membership"].as_str().unwrap().to_owned()
+        })
+        .collect();
+    assert_eq!(memberships, ["join", "invite", "knock"]);
 }
 
 #[async_test]
-async fn test_send_room_name() {
-    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+async fn test_try_get_membership_events_without_permission() {
+    let (_, _, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!([])).await;
+
+    send_request(&driver_handle, "get-membership-events", "get_membership_events", json!({})).await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "get_membership_events");
+    assert_eq!(msg["response"]["error"]["message"], "Not allowed to read m.room.member events");
+}
+
+#[cfg(feature = "e2e-encryption")]
+#[async_test]
+async fn test_send_file_in_encrypted_room() {
+    use std::sync::{Arc, Mutex};
+
+    use ruma::mxc_uri;
+
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+    mock_server.mock_room_state_encryption().encrypted().mount().await;
+    // Needed for the message to be sent in an encrypted room.
+    mock_server.mock_get_members().ok(Vec::new()).mock_once().mount().await;
+
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let (driver, driver_handle) = WidgetDriver::new(settings);
+    spawn(driver.run(room.clone(), DummyCapabilitiesProvider));
 
     negotiate_capabilities(
         &driver_handle,
-        json!(["org.matrix.msc2762.send.state_event:m.room.name#"]),
+        json!([
+            "org.matrix.msc4039.upload_file",
+            "org.matrix.msc2762.send.event:m.room.message#m.file",
+        ]),
     )
     .await;
 
+    // The file is encrypted before being uploaded.
     mock_server
-        .mock_room_send_state()
-        .for_type(StateEventType::RoomName)
-        .ok(event_id!("$foobar"))
+        .mock_upload()
+        .expect_mime_type("application/octet-stream")
+        .ok(mxc_uri!("mxc://example.org/file"))
         .mock_once()
         .mount()
         .await;
 
+    let sent_content = Arc::new(Mutex::new(None));
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/v3/rooms/.*/send/m.room.encrypted/.*"))
+        .and({
+            let sent_content = sent_content.clone();
+            move |request: &wiremock::Request| {
+                *sent_content.lock().unwrap() = Some(request.body_json::<JsonValue>().unwrap());
+                true
+            }
+        })
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "event_id": "$file" })))
+        .mock_once()
+        .mount(mock_server.server())
+        .await;
+
     send_request(
         &driver_handle,
-        "send-room-name",
-        "send_event",
+        "send-file",
+        "send_file",
         json!({
-            "type": "m.room.name",
-            "state_key": "",
-            "content": {
-                "name": "Room Name set by Widget",
-            },
+            "filename": "hello.txt",
+            "mimetype": "text/plain",
+            "data": "SGVsbG8gd29ybGQ",
         }),
     )
     .await;
 
-    // Receive the response
     let msg = recv_message(&driver_handle).await;
     assert_eq!(msg["api"], "fromWidget");
-    assert_eq!(msg["action"], "send_event");
-    let event_id = msg["response"]["event_id"].as_str().unwrap();
-    assert_eq!(event_id, "$foobar");
+    assert_eq!(msg["action"], "send_file");
+    assert_eq!(msg["response"]["event_id"], "$file");
+
+    // The sent event carries the metadata needed to decrypt the file.
+    let content = sent_content.lock().unwrap().take().unwrap();
+    let event = Raw::new(&json!({
+        "type": "m.room.encrypted",
+        "room_id": *ROOM_ID,
+        "event_id": "$file",
+        "origin_server_ts": 1_000,
+        "sender": client.user_id().unwrap(),
+        "content": content,
+    }))
+    .unwrap()
+    .cast();
+    let decrypted = room.decrypt_event(&event, None).await.unwrap();
+    let content = decrypted.raw().get_field::<JsonValue>("content").unwrap().unwrap();
+
+    assert_eq!(content["msgtype"], "m.file");
+    assert_eq!(content["body"], "hello.txt");
+    assert!(content.get("url").is_none());
+    assert_eq!(content["file"]["url"], "mxc://example.org/file");
+    assert!(content["file"]["key"].is_object());
+    assert!(content["file"]["iv"].is_string());
+    assert!(content["file"]["hashes"]["sha256"].is_string());
 }
 
 #[async_test]
-async fn test_send_delayed_message_event() {
-    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+async fn test_try_send_file_without_permission() {
+    let (_, _, driver_handle) = run_test_driver(false).await;
 
     negotiate_capabilities(
         &driver_handle,
-        json!([
-            "org.matrix.msc4157.send.delayed_event",
-            "org.matrix.msc2762.send.event:m.room.message"
-        ]),
+        json!(["org.matrix.msc2762.send.event:m.room.message#m.file"]),
     )
     .await;
-    mock_server
-        .mock_room_send()
-        .match_delayed_event(Duration::from_millis(1000))
-        .for_type(MessageLikeEventType::RoomMessage)
-        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
-            "delay_id": "1234",
-        })))
-        .mock_once()
-        .mount()
-        .await;
 
     send_request(
         &driver_handle,
-        "send-room-message",
-        "send_event",
-        json!({
-            "type": "m.room.message",
-            "content": {
-                "msgtype": "m.text",
-                "body": "Message from a widget!",
-            },
-            "delay":1000,
-        }),
+        "send-file",
+        "send_file",
+        json!({ "filename": "hello.txt", "data": "SGVsbG8gd29ybGQ" }),
     )
     .await;
 
-    // Receive the response
     let msg = recv_message(&driver_handle).await;
     assert_eq!(msg["api"], "fromWidget");
-    assert_eq!(msg["action"], "send_event");
-    let delay_id = msg["response"]["delay_id"].as_str().unwrap();
-    assert_eq!(delay_id, "1234");
+    assert_eq!(msg["action"], "send_file");
+    assert_eq!(
+        msg["response"]["error"]["message"],
+        "Not allowed: missing the org.matrix.msc4039.upload_file capability."
+    );
 }
 
-#[async_test]
-async fn test_send_delayed_state_event() {
-    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+fn call_member_event(user_id: &str, device_id: &str) -> Raw<AnySyncStateEvent> {
+    Raw::new(&json!({
+        "type": "org.matrix.msc3401.call.member",
+        "state_key": format!("_{user_id}_{device_id}"),
+        "sender": user_id,
+        "event_id": format!("$call-member-{device_id}"),
+        "origin_server_ts": 1_700_000_000_000u64,
+        "content": {
+            "application": "m.call",
+            "call_id": "",
+            "scope": "m.room",
+            "device_id": device_id,
+            "focus_active": {
+                "type": "livekit",
+                "focus_selection": "oldest_membership",
+            },
+            "foci_preferred": [{
+                "type": "livekit",
+                "livekit_service_url": "https://livekit.example.org",
+                "livekit_alias": ROOM_ID.as_str(),
+            }],
+        },
+    }))
+    .unwrap()
+    .cast()
+}
 
-    negotiate_capabilities(
-        &driver_handle,
-        json!([
-            "org.matrix.msc4157.send.delayed_event",
-            "org.matrix.msc2762.send.state_event:m.room.name#"
-        ]),
+async fn read_call_members(driver_handle: &WidgetDriverHandle) -> Vec<JsonValue> {
+    send_request(
+        driver_handle,
+        "read-call-members",
+        "org.matrix.msc2876.read_events",
+        json!({ "type": "org.matrix.msc3401.call.member", "state_key": true }),
     )
     .await;
 
-    mock_server
-        .mock_room_send_state()
-        .match_delayed_event(Duration::from_millis(1000))
-        .for_type(StateEventType::RoomName)
-        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
-            "delay_id": "1234",
-        })))
-        .mock_once()
-        .mount()
-        .await;
+    let msg = recv_message(driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "org.matrix.msc2876.read_events");
+    msg["response"]["events"].as_array().unwrap().clone()
+}
 
-    send_request(
+#[async_test]
+async fn test_read_call_state_without_active_call() {
+    let (_, _, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(
         &driver_handle,
-        "send-room-message",
-        "send_event",
-        json!({
-            "type": "m.room.name",
-            "state_key": "",
-            "content": {
-                "name": "Room Name set by Widget",
-            },
-            "delay":1000,
-        }),
+        json!(["org.matrix.msc2762.receive.state_event:org.matrix.msc3401.call.member"]),
     )
     .await;
 
-    // Receive the response
-    let msg = recv_message(&driver_handle).await;
-    assert_eq!(msg["api"], "fromWidget");
-    assert_eq!(msg["action"], "send_event");
-    let delay_id = msg["response"]["delay_id"].as_str().unwrap();
-    assert_eq!(delay_id, "1234");
+    // There is no call in the room, so there is no call member state.
+    assert!(read_call_members(&driver_handle).await.is_empty());
 }
 
 #[async_test]
-async fn test_fail_sending_delay_rate_limit() {
-    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+async fn test_read_call_state() {
+    let (client, mock_server, driver_handle) = run_test_driver(false).await;
+
+    // Alice and Bob are in a call.
+    mock_server
+        .mock_sync()
+        .ok_and_run(&client, |sync_builder| {
+            sync_builder.add_joined_room(JoinedRoomBuilder::new(&ROOM_ID).add_state_bulk([
+                call_member_event(ALICE.as_str(), "ALICEDEVICE"),
+                call_member_event(BOB.as_str(), "BOBDEVICE"),
+            ]));
+        })
+        .await;
 
     negotiate_capabilities(
         &driver_handle,
-        json!([
-            "org.matrix.msc4157.send.delayed_event",
-            "org.matrix.msc2762.send.event:m.room.message"
-        ]),
+        json!(["org.matrix.msc2762.receive.state_event:org.matrix.msc3401.call.member"]),
     )
     .await;
 
-    mock_server
-        .mock_room_send()
-        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
-            "errcode": "M_LIMIT_EXCEEDED",
-            "error": "Sending too many delay events"
-        })))
-        .mock_once()
-        .mount()
-        .await;
+    let events = read_call_members(&driver_handle).await;
+    assert_eq!(events.len(), 2);
 
-    send_request(
+    let mut senders: Vec<_> = events.iter().map(|ev| ev["sender"].as_str().unwrap()).collect();
+    senders.sort_unstable();
+    assert_eq!(senders, [ALICE.as_str(), BOB.as_str()]);
+
+    for event in &events {
+        assert_eq!(event["type"], "org.matrix.msc3401.call.member");
+        assert_eq!(event["room_id"], ROOM_ID.as_str());
+        assert_eq!(event["content"]["application"], "m.call");
+    }
+}
+
+#[async_test]
+async fn test_get_server_acl() {
+    let (client, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(
         &driver_handle,
-        "send-room-message",
-        "send_event",
-        json!({
-            "type": "m.room.message",
-            "content": {
-                "msgtype": "m.text",
-                "body": "Message from a widget!",
-            },
-            "delay":1000,
-        }),
+        json!(["org.matrix.msc2762.receive.state_event:m.room.server_acl#"]),
     )
     .await;
 
+    // Without an ACL in the room, none is returned.
+    send_request(&driver_handle, "get-server-acl", "get_server_acl", json!({})).await;
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "get_server_acl");
+    assert_eq!(msg["response"], json!({}));
+
+    mock_server
+        .mock_sync()
+        .ok_and_run(&client, |sync_builder| {
+            sync_builder.add_joined_room(
+                JoinedRoomBuilder::new(&ROOM_ID).add_state_bulk([Raw::new(&json!({
+                    "type": "m.room.server_acl",
+                    "state_key": "",
+                    "sender": ALICE.as_str(),
+                    "event_id": "$server_acl",
+                    "origin_server_ts": 1_000,
+                    "content": {
+                        "allow": ["*"],
+                        "deny": ["evil.org", "*.evil.org"],
+                        "allow_ip_literals": false,
+                    },
+                }))
+                .unwrap()
+                .cast()]),
+            );
+        })
+        .await;
+
+    // The new ACL is forwarded to the widget.
     let msg = recv_message(&driver_handle).await;
-    assert_eq!(msg["api"], "fromWidget");
     assert_eq!(msg["action"], "send_event");
-    // Receive the response in the correct widget error response format
+    assert_eq!(msg["data"]["type"], "m.room.server_acl");
+
+    send_request(&driver_handle, "get-server-acl", "get_server_acl", json!({})).await;
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "get_server_acl");
     assert_eq!(
-        msg["response"],
+        msg["response"]["acl"],
         json!({
-            "error": {
-              "matrix_api_error": {
-                "http_status": 400,
-                "response": {
-                  "errcode": "M_LIMIT_EXCEEDED",
-                  "error": "Sending too many delay events"
-                },
-              },
-              "message": "the server returned an error: [400 / M_LIMIT_EXCEEDED] Sending too many delay events"
-            }
+            "allow": ["*"],
+            "deny": ["evil.org", "*.evil.org"],
+            "allow_ip_literals": false,
         })
     );
 }
 
 #[async_test]
-async fn test_try_send_delayed_state_event_without_permission() {
-    let (_, _mock_server, driver_handle) = run_test_driver(false).await;
+async fn test_try_get_server_acl_without_permission() {
+    let (_, _, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!([])).await;
+
+    send_request(&driver_handle, "get-server-acl", "get_server_acl", json!({})).await;
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "get_server_acl");
+    assert_eq!(
+        msg["response"]["error"]["message"],
+        "Not allowed to read the m.room.server_acl state event"
+    );
+}
+
+#[async_test]
+async fn test_get_required_power_level() {
+    let (client, mock_server, driver_handle) = run_test_driver(false).await;
+
+    let own_user_id = client.user_id().unwrap();
+    mock_server
+        .mock_sync()
+        .ok_and_run(&client, |sync_builder| {
+            sync_builder.add_joined_room(
+                JoinedRoomBuilder::new(&ROOM_ID).add_state_bulk([Raw::new(&json!({
+                    "type": "m.room.power_levels",
+                    "state_key": "",
+                    "sender": ALICE.as_str(),
+                    "event_id": "$power_levels",
+                    "origin_server_ts": 1_000,
+                    "content": {
+                        "users": { ALICE.as_str(): 100, own_user_id.as_str(): 20 },
+                        "state_default": 50,
+                        "events_default": 0,
+                        "events": { "m.room.topic": 10 },
+                        "ban": 75,
+                    },
+                }))
+                .unwrap()
+                .cast()]),
+            );
+        })
+        .await;
 
     negotiate_capabilities(
         &driver_handle,
-        json!(["org.matrix.msc2762.send.state_event:m.room.name#"]),
+        json!(["org.matrix.msc2762.receive.state_event:m.room.power_levels#"]),
     )
     .await;
 
+    // A state event without a specific level needs the default level of state events.
     send_request(
         &driver_handle,
-        "send-room-message",
-        "send_event",
-        json!({
-            "type": "m.room.name",
-            "state_key": "",
-            "content": {
-                "name": "Room Name set by Widget",
-            },
-            "delay":1000,
-        }),
+        "get-required-power-level",
+        "get_required_power_level",
+        json!({ "event_type": "m.room.name", "state": true }),
     )
     .await;
-
-    // Receive the response
     let msg = recv_message(&driver_handle).await;
-    assert_eq!(msg["api"], "fromWidget");
-    assert_eq!(msg["action"], "send_event");
-    let error_message = msg["response"]["error"]["message"].as_str().unwrap();
+    assert_eq!(msg["action"], "get_required_power_level");
     assert_eq!(
-        error_message,
-        "Not allowed: missing the org.matrix.msc4157.send.delayed_event capability."
+        msg["response"],
+        json!({ "required_level": 50, "user_level": 20, "allowed": false })
     );
-}
-
-#[async_test]
-async fn test_update_delayed_event() {
-    let (_, mock_server, driver_handle) = run_test_driver(false).await;
-
-    negotiate_capabilities(&driver_handle, json!(["org.matrix.msc4157.update_delayed_event",]))
-        .await;
 
-    Mock::given(method("POST"))
-        .and(path_regex(r"^/_matrix/client/unstable/org.matrix.msc4140/delayed_events/1234"))
-        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
-        .expect(1)
-        .mount(mock_server.server())
-        .await;
+    send_request(
+        &driver_handle,
+        "get-required-power-level",
+        "get_required_power_level",
+        json!({ "event_type": "m.room.topic", "state": true }),
+    )
+    .await;
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["response"], json!({ "required_level": 10, "user_level": 20, "allowed": true }));
 
     send_request(
         &driver_handle,
-        "send-delay-update-id",
-        "org.matrix.msc4157.update_delayed_event",
-        json!({
-            "action":"refresh",
-            "delay_id": "1234",
-        }),
+        "get-required-power-level",
+        "get_required_power_level",
+        json!({ "action": "ban" }),
     )
     .await;
-    // Receive the response
-    let response = recv_message(&driver_handle).await;
-    print!("{:?}", response);
-    assert_eq!(response["api"], "fromWidget");
-    assert_eq!(response["action"], "org.matrix.msc4157.update_delayed_event");
-    let empty_response = response["response"].clone();
-    assert_eq!(empty_response, serde_json::from_str::<JsonValue>("{}").unwrap());
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(
+        msg["response"],
+        json!({ "required_level": 75, "user_level": 20, "allowed": false })
+    );
 }
 
 #[async_test]
-async fn test_try_update_delayed_event_without_permission() {
-    let (_, _mock_server, driver_handle) = run_test_driver(false).await;
+async fn test_try_get_required_power_level_without_permission() {
+    let (_, _, driver_handle) = run_test_driver(false).await;
 
     negotiate_capabilities(&driver_handle, json!([])).await;
 
     send_request(
         &driver_handle,
-        "send-delay-update-id",
-        "org.matrix.msc4157.update_delayed_event",
-        json!({
-            "action":"refresh",
-            "delay_id": "1234",
-        }),
+        "get-required-power-level",
+        "get_required_power_level",
+        json!({ "action": "kick" }),
     )
     .await;
-    // Receive the response
-    let response = recv_message(&driver_handle).await;
-    print!("{:?}", response);
-    assert_eq!(response["api"], "fromWidget");
-    assert_eq!(response["action"], "org.matrix.msc4157.update_delayed_event");
-    let error_response = response["response"]["error"]["message"].clone();
+    let msg = recv_message(&driver_handle).await;
     assert_eq!(
-        error_response.as_str().unwrap(),
-        "Not allowed: missing the org.matrix.msc4157.update_delayed_event capability."
+        msg["response"]["error"]["message"],
+        "Not allowed to read the m.room.power_levels state event"
     );
 }
 
 #[async_test]
-async fn test_try_update_delayed_event_without_permission_negotiate() {
-    let (_, _mock_server, driver_handle) = run_test_driver(false).await;
+async fn test_read_call_state_is_restricted_to_allowed_state_keys() {
+    let (client, mock_server, driver_handle) = run_test_driver(false).await;
 
-    send_request(
+    mock_server
+        .mock_sync()
+        .ok_and_run(&client, |sync_builder| {
+            sync_builder.add_joined_room(JoinedRoomBuilder::new(&ROOM_ID).add_state_bulk([
+                call_member_event(ALICE.as_str(), "ALICEDEVICE"),
+                call_member_event(BOB.as_str(), "BOBDEVICE"),
+            ]));
+        })
+        .await;
+
+    // The widget is only allowed to read Alice's call membership.
+    negotiate_capabilities(
         &driver_handle,
-        "send-delay-update-id",
-        "org.matrix.msc4157.update_delayed_event",
-        json!({
-            "action":"refresh",
-            "delay_id": "1234",
-        }),
+        json!([format!(
+            "org.matrix.msc2762.receive.state_event:org.matrix.msc3401.call.member#_{}_ALICEDEVICE",
+            *ALICE
+        )]),
     )
     .await;
-    // Wait for the corresponding response.
-    loop {
-        let response = recv_message(&driver_handle).await;
-        if response["api"] == "fromWidget"
-            && response["action"] == "org.matrix.msc4157.update_delayed_event"
-        {
-            let error_response = response["response"]["error"]["message"].clone();
-            assert_eq!(
-                error_response.as_str().unwrap(),
-                "Received send update delayed event request before capabilities were negotiated"
-            );
-            break;
-        }
-    }
+
+    let events = read_call_members(&driver_handle).await;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["sender"], ALICE.as_str());
 }
 
 #[async_test]
-async fn test_send_redaction() {
-    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+async fn test_receive_live_events() {
+    let (client, mock_server, driver_handle) = run_test_driver(false).await;
 
     negotiate_capabilities(
         &driver_handle,
         json!([
-            // "org.matrix.msc4157.send.delayed_event",
-            "org.matrix.msc2762.send.event:m.room.redaction"
+            "org.matrix.msc2762.receive.event:m.room.member",
+            "org.matrix.msc2762.receive.event:m.room.message#m.text",
+            "org.matrix.msc2762.receive.state_event:m.room.name#",
+            "org.matrix.msc2762.receive.state_event:m.room.member#@example:localhost",
         ]),
     )
     .await;
 
-    mock_server.mock_room_redact().ok(event_id!("$redact_event_id")).mock_once().mount().await;
+    // No messages from the driver yet
+    assert_matches!(recv_message(&driver_handle).now_or_never(), None);
 
-    send_request(
-        &driver_handle,
-        "send-redact-message",
-        "send_event",
-        json!({
-            "type": "m.room.redaction",
-            "content": {
-                "redacts": "$1234"
-            },
-        }),
-    )
-    .await;
+    let f = EventFactory::new();
 
-    // Receive the response
-    let msg = recv_message(&driver_handle).await;
-    assert_eq!(msg["api"], "fromWidget");
+    mock_server
+        .mock_sync()
+        .ok_and_run(&client, |sync_builder| {
+            sync_builder.add_joined_room(
+                JoinedRoomBuilder::new(&ROOM_ID)
+                    // text message from alice - matches filter #2
+                    .add_timeline_event(f.text_msg("simple text message").sender(&ALICE))
+                    // emote from alice - doesn't match
+                    .add_timeline_event(f.emote("emote message").sender(&ALICE))
+                    // pointless member event - matches filter #4
+                    .add_timeline_event(
+                        f.member(user_id!("@example:localhost"))
+                            .membership(MembershipState::Join)
+                            .previous(MembershipState::Join),
+                    )
+                    // kick alice - doesn't match because the `#@example:localhost` bit
+                    // is about the state_key, not the sender
+                    .add_timeline_event(
+                        f.member(user_id!("@example:localhost"))
+                            .banned(&ALICE)
+                            .previous(MembershipState::Join),
+                    )
+                    // set room topic - doesn't match
+                    .add_timeline_event(f.room_topic("new room topic").sender(&BOB))
+                    // set room name - matches filter #3
+                    .add_timeline_event(f.room_name("New Room Name").sender(&BOB)),
+            );
+        })
+        .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "toWidget");
+    assert_eq!(msg["action"], "send_event");
+    assert_eq!(msg["data"]["type"], "m.room.message");
+    assert_eq!(msg["data"]["room_id"], ROOM_ID.as_str());
+    assert_eq!(msg["data"]["content"]["msgtype"], "m.text");
+    assert_eq!(msg["data"]["content"]["body"], "simple text message");
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "toWidget");
+    assert_eq!(msg["action"], "send_event");
+    assert_eq!(msg["data"]["type"], "m.room.member");
+    assert_eq!(msg["data"]["room_id"], ROOM_ID.as_str());
+    assert_eq!(msg["data"]["state_key"], "@example:localhost");
+    assert_eq!(msg["data"]["content"]["membership"], "join");
+    assert_eq!(msg["data"]["unsigned"]["prev_content"]["membership"], "join");
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "toWidget");
+    assert_eq!(msg["action"], "send_event");
+    assert_eq!(msg["data"]["type"], "m.room.name");
+    assert_eq!(msg["data"]["sender"], BOB.as_str());
+    assert_eq!(msg["data"]["content"]["name"], "New Room Name");
+
+    // No more messages from the driver
+    assert_matches!(recv_message(&driver_handle).now_or_never(), None);
+}
+
+#[async_test]
+async fn test_watched_event_updates_are_forwarded() {
+    let (client, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(
+        &driver_handle,
+        json!([
+            "org.matrix.msc2762.receive.event:m.room.message",
+            "org.matrix.msc2762.receive.event:m.reaction",
+            "org.matrix.msc2762.receive.event:m.room.redaction",
+        ]),
+    )
+    .await;
+
+    let watched_event_id = event_id!("$poll");
+    send_request(&driver_handle, "1-watch", "watch_event", json!({ "event_id": watched_event_id }))
+        .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "watch_event");
+    assert!(msg["response"].as_object().unwrap().is_empty());
+
+    let f = EventFactory::new().room(&ROOM_ID).sender(&ALICE);
+
+    mock_server
+        .mock_sync()
+        .ok_and_run(&client, |sync_builder| {
+            sync_builder.add_joined_room(
+                JoinedRoomBuilder::new(&ROOM_ID)
+                    .add_timeline_event(f.text_msg("* edited").event_id(event_id!("$edit")).edit(
+                        watched_event_id,
+                        RoomMessageEventContentWithoutRelation::text_plain("edited"),
+                    ))
+                    .add_timeline_event(
+                        f.redaction(watched_event_id).event_id(event_id!("$redaction")),
+                    ),
+            );
+        })
+        .await;
+
+    for update_event_id in ["$edit", "$redaction"] {
+        let msg = recv_message(&driver_handle).await;
+        assert_eq!(msg["api"], "toWidget");
+        assert_eq!(msg["action"], "send_event");
+        assert_eq!(msg["data"]["event_id"], update_event_id);
+
+        let msg = recv_message(&driver_handle).await;
+        assert_eq!(msg["api"], "toWidget");
+        assert_eq!(msg["action"], "watched_event_update");
+        assert_eq!(msg["data"]["event_id"], watched_event_id.as_str());
+        assert_eq!(msg["data"]["event"]["event_id"], update_event_id);
+    }
+
+    send_request(
+        &driver_handle,
+        "2-unwatch",
+        "unwatch_event",
+        json!({ "event_id": watched_event_id }),
+    )
+    .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "unwatch_event");
+    assert!(msg["response"].as_object().unwrap().is_empty());
+
+    mock_server
+        .mock_sync()
+        .ok_and_run(&client, |sync_builder| {
+            sync_builder.add_joined_room(JoinedRoomBuilder::new(&ROOM_ID).add_timeline_event(
+                f.reaction(watched_event_id, "👍").event_id(event_id!("$reaction")),
+            ));
+        })
+        .await;
+
+    // The reaction is still forwarded as any other event, but not as an update of
+    // the event that isn't watched anymore.
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "send_event");
+    assert_eq!(msg["data"]["event_id"], "$reaction");
+    assert_matches!(recv_message(&driver_handle).now_or_never(), None);
+}
+
+#[async_test]
+async fn test_denied_event_types_are_not_forwarded() {
+    let (client, mock_server, driver_handle) = run_test_driver_with_settings(
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget")
+            .unwrap()
+            .with_denied_event_types(vec!["m.room.encryption".to_owned()]),
+    )
+    .await;
+
+    negotiate_capabilities(
+        &driver_handle,
+        json!([
+            "org.matrix.msc2762.receive.state_event:m.room.encryption",
+            "org.matrix.msc2762.receive.state_event:m.room.topic",
+        ]),
+    )
+    .await;
+
+    let f = EventFactory::new().sender(&BOB);
+
+    mock_server
+        .mock_sync()
+        .ok_and_run(&client, |sync_builder| {
+            sync_builder.add_joined_room(
+                JoinedRoomBuilder::new(&ROOM_ID)
+                    // enable encryption - denied, even though the capability was granted
+                    .add_timeline_event(
+                        f.event(RoomEncryptionEventContent::with_recommended_defaults())
+                            .state_key(""),
+                    )
+                    // set room topic - allowed
+                    .add_timeline_event(f.room_topic("new room topic")),
+            );
+        })
+        .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "toWidget");
+    assert_eq!(msg["action"], "send_event");
+    assert_eq!(msg["data"]["type"], "m.room.topic");
+    assert_eq!(msg["data"]["content"]["topic"], "new room topic");
+
+    // No more messages from the driver
+    assert_matches!(recv_message(&driver_handle).now_or_never(), None);
+}
+
+#[async_test]
+async fn test_receive_typing_notifications() {
+    let (client, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(
+        &driver_handle,
+        json!(["org.matrix.msc2762.receive.ephemeral_event:m.typing"]),
+    )
+    .await;
+
+    let f = EventFactory::new();
+
+    mock_server
+        .mock_sync()
+        .ok_and_run(&client, |sync_builder| {
+            sync_builder.add_joined_room(
+                JoinedRoomBuilder::new(&ROOM_ID)
+                    // Rapid typing updates - only the latest one is forwarded.
+                    .add_typing(f.typing(vec![&ALICE]))
+                    .add_typing(f.typing(vec![&ALICE, &BOB]))
+                    // read receipt - not allowed
+                    .add_receipt(
+                        f.read_receipts()
+                            .add(
+                                event_id!("$event"),
+                                &BOB,
+                                ReceiptType::Read,
+                                ReceiptThread::Unthreaded,
+                            )
+                            .into_event(),
+                    ),
+            );
+        })
+        .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "toWidget");
+    assert_eq!(msg["action"], "send_ephemeral_event");
+    assert_eq!(msg["data"]["type"], "m.typing");
+    assert_eq!(msg["data"]["room_id"], ROOM_ID.as_str());
+    assert_eq!(msg["data"]["content"]["user_ids"], json!([ALICE.as_str(), BOB.as_str()]));
+
+    // No more messages from the driver, even after the debounce delay.
+    assert!(timeout(driver_handle.recv(), Duration::from_millis(500)).await.is_err());
+}
+
+#[async_test]
+async fn test_typing_notifications_are_debounced_for_a_bounded_time() {
+    let (client, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(
+        &driver_handle,
+        json!(["org.matrix.msc2762.receive.ephemeral_event:m.typing"]),
+    )
+    .await;
+
+    // The typing notifications keep coming faster than the debounce delay for
+    // about 2 seconds.
+    let typing_updates = spawn(async move {
+        let f = EventFactory::new();
+        for _ in 0..14 {
+            mock_server
+                .sync_room(
+                    &client,
+                    JoinedRoomBuilder::new(&ROOM_ID).add_typing(f.typing(vec![&ALICE])),
+                )
+                .await;
+            sleep(Duration::from_millis(150)).await;
+        }
+    });
+
+    // One of them is forwarded once the maximum wait is over, before they stop.
+    let msg = timeout(driver_handle.recv(), Duration::from_millis(1500)).await.unwrap().unwrap();
+    let msg: JsonObject = serde_json::from_str(&msg).unwrap();
+    assert_eq!(msg["action"], "send_ephemeral_event");
+    assert_eq!(msg["data"]["type"], "m.typing");
+    assert!(!typing_updates.is_finished());
+}
+
+#[async_test]
+async fn test_notify_theme_change() {
+    let (_, _, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!([])).await;
+
+    // The client switches to the dark theme while the widget is running.
+    assert!(driver_handle.notify_theme_change("dark".to_owned()).await);
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "toWidget");
+    assert_eq!(msg["action"], "theme_change");
+    assert_eq!(msg["data"]["name"], "dark");
+    let request_id = msg["requestId"].as_str().unwrap();
+
+    send_response(&driver_handle, request_id, "theme_change", json!({ "name": "dark" }), json!({}))
+        .await;
+
+    // No more messages from the driver
+    assert_matches!(recv_message(&driver_handle).now_or_never(), None);
+}
+
+#[async_test]
+async fn test_update_client_properties() {
+    let (_, _, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!([])).await;
+
+    // The user changes the theme and the language of the system mid-call.
+    let props =
+        ClientProperties::from_locale("io.my_matrix.client", Some("dark".to_owned()), "de_DE");
+    assert!(driver_handle.update_client_properties(props).await);
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "toWidget");
+    assert_eq!(msg["action"], "theme_change");
+    assert_eq!(msg["data"]["name"], "dark");
+    let request_id = msg["requestId"].as_str().unwrap();
+    send_response(&driver_handle, request_id, "theme_change", json!({ "name": "dark" }), json!({}))
+        .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "toWidget");
+    assert_eq!(msg["action"], "language_change");
+    assert_eq!(msg["data"]["lang"], "de-DE");
+    let request_id = msg["requestId"].as_str().unwrap();
+    send_response(
+        &driver_handle,
+        request_id,
+        "language_change",
+        json!({ "lang": "de-DE" }),
+        json!({}),
+    )
+    .await;
+
+    // Only the language changes afterwards, so the theme isn't notified again.
+    let props =
+        ClientProperties::from_locale("io.my_matrix.client", Some("dark".to_owned()), "fr_FR");
+    assert!(driver_handle.update_client_properties(props).await);
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "language_change");
+    assert_eq!(msg["data"]["lang"], "fr-FR");
+    let request_id = msg["requestId"].as_str().unwrap();
+    send_response(
+        &driver_handle,
+        request_id,
+        "language_change",
+        json!({ "lang": "fr-FR" }),
+        json!({}),
+    )
+    .await;
+
+    // No more messages from the driver
+    assert_matches!(recv_message(&driver_handle).now_or_never(), None);
+}
+
+#[async_test]
+async fn test_session_is_restarted_after_a_disconnection() {
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let policy = ReconnectPolicy {
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(10),
+        max_attempts: Some(1),
+        reset_after: Duration::from_secs(60),
+    };
+
+    // The widget is gone before the first session could send it anything.
+    let (handle_tx, mut handle_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut sessions = 0;
+    let new_driver = move || {
+        sessions += 1;
+        let (driver, handle) = WidgetDriver::new(settings.clone());
+        if sessions > 1 {
+            handle_tx.send(handle).unwrap();
+        }
+        driver
+    };
+
+    spawn(WidgetDriver::run_with_reconnect(room, DummyCapabilitiesProvider, policy, new_driver));
+
+    // A new session is started, which asks the widget for its capabilities.
+    let driver_handle = timeout(handle_rx.recv(), Duration::from_secs(1)).await.unwrap().unwrap();
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "toWidget");
+    assert_eq!(msg["action"], "capabilities");
+}
+
+#[async_test]
+async fn test_generate_webview_url_reports_profile_errors() {
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+
+    let settings = WidgetSettings::new(
+        WIDGET_ID.to_owned(),
+        false,
+        "https://foo.bar/widget?name=$matrix_display_name",
+    )
+    .unwrap();
+    let props = ClientProperties::new("io.my_matrix.client", None, None);
+
+    // A user without a profile gets an empty display name.
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/v3/profile/"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+            "errcode": "M_NOT_FOUND",
+            "error": "Profile not found",
+        })))
+        .up_to_n_times(1)
+        .mount(mock_server.server())
+        .await;
+
+    let url = settings.generate_webview_url(&room, props.clone()).await.unwrap();
+    assert_eq!(url.as_str(), "https://foo.bar/widget?name=");
+
+    // A server error isn't hidden behind an empty display name.
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/v3/profile/"))
+        .respond_with(ResponseTemplate::new(500).set_body_json(json!({
+            "errcode": "M_UNKNOWN",
+            "error": "Internal server error",
+        })))
+        .mount(mock_server.server())
+        .await;
+
+    let error = settings.generate_webview_url(&room, props).await.unwrap_err();
+    assert_matches!(error, GenerateWebviewUrlError::ProfileUnavailable(_));
+}
+
+#[async_test]
+async fn test_stopped_session_is_not_restarted() {
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let policy = ReconnectPolicy {
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(10),
+        max_attempts: Some(3),
+        reset_after: Duration::from_secs(60),
+    };
+
+    // The session is stopped by the client before it could start.
+    let mut sessions = 0;
+    let new_driver = || {
+        sessions += 1;
+        let (driver, handle) = WidgetDriver::new(settings.clone());
+        handle.stop();
+        driver
+    };
+
+    let session_end = timeout(
+        WidgetDriver::run_with_reconnect(room, DummyCapabilitiesProvider, policy, new_driver),
+        Duration::from_secs(1),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(session_end, SessionEnd::Stopped);
+    assert_eq!(sessions, 1);
+}
+
+#[async_test]
+async fn test_transient_session_end_triggers_one_restart() {
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let policy = ReconnectPolicy {
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(10),
+        max_attempts: Some(1),
+        reset_after: Duration::from_secs(60),
+    };
+
+    // The widget is gone before each session could send it anything.
+    let mut sessions = 0;
+    let new_driver = || {
+        sessions += 1;
+        WidgetDriver::new(settings.clone()).0
+    };
+
+    let session_end = timeout(
+        WidgetDriver::run_with_reconnect(room, DummyCapabilitiesProvider, policy, new_driver),
+        Duration::from_secs(1),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(session_end, SessionEnd::WidgetDisconnected);
+    assert_eq!(session_end.into_result(), Err(WidgetError::ChannelClosed));
+    assert_eq!(sessions, 2);
+}
+
+#[async_test]
+async fn test_refused_widget_is_not_restarted() {
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let policy = ReconnectPolicy {
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(10),
+        max_attempts: Some(3),
+        reset_after: Duration::from_secs(60),
+    };
+
+    let mut sessions = 0;
+    let new_driver = || {
+        sessions += 1;
+        WidgetDriver::new(settings.clone()).0
+    };
+
+    let session_end = timeout(
+        WidgetDriver::run_with_reconnect(room, RefusingCapabilitiesProvider, policy, new_driver),
+        Duration::from_secs(1),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(session_end, SessionEnd::PermissionsDenied);
+    assert_eq!(session_end.into_result(), Err(WidgetError::PermissionsDenied));
+    assert_eq!(sessions, 1);
+}
+
+#[async_test]
+async fn test_session_in_a_left_room_ends_immediately() {
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+    let room = mock_server.sync_room(&client, LeftRoomBuilder::new(&ROOM_ID)).await;
+
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let (driver, driver_handle) = WidgetDriver::new(settings);
+
+    let session_end =
+        timeout(driver.run(room, DummyCapabilitiesProvider), Duration::from_secs(1)).await.unwrap();
+    assert_eq!(session_end, SessionEnd::RoomUnavailable);
+
+    // The widget doesn't even get asked for its capabilities.
+    assert_eq!(driver_handle.recv().await, None);
+}
+
+#[derive(Clone)]
+struct RefusingCapabilitiesProvider;
+
+#[async_trait]
+impl CapabilitiesProvider for RefusingCapabilitiesProvider {
+    async fn acquire_capabilities(&self, _capabilities: Capabilities) -> Capabilities {
+        panic!("capabilities shouldn't be requested for a widget that isn't allowed")
+    }
+
+    async fn allow_widget(&self, _settings: &WidgetSettings) -> bool {
+        false
+    }
+}
+
+#[async_test]
+async fn test_session_of_a_refused_widget_ends_immediately() {
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let (driver, driver_handle) = WidgetDriver::new(settings);
+
+    let session_end =
+        timeout(driver.run(room, RefusingCapabilitiesProvider), Duration::from_secs(1))
+            .await
+            .unwrap();
+    assert_eq!(session_end, SessionEnd::PermissionsDenied);
+    assert!(session_end.is_fatal());
+    assert_eq!(session_end.into_result(), Err(WidgetError::PermissionsDenied));
+
+    assert_eq!(driver_handle.recv().await, None);
+}
+
+#[async_test]
+async fn test_session_ends_when_the_room_is_left() {
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+    mock_server.mock_room_state_encryption().plain().mount().await;
+
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let (driver, driver_handle) = WidgetDriver::new(settings);
+    let session = spawn(driver.run(room, DummyCapabilitiesProvider));
+
+    negotiate_capabilities(&driver_handle, json!(["org.matrix.msc2762.send.event:m.room.message"]))
+        .await;
+
+    // The room is left in the middle of the session.
+    mock_server.sync_room(&client, LeftRoomBuilder::new(&ROOM_ID)).await;
+
+    send_request(
+        &driver_handle,
+        "send-room-message",
+        "send_event",
+        json!({
+            "type": "m.room.message",
+            "content": { "msgtype": "m.text", "body": "Message from a widget!" },
+        }),
+    )
+    .await;
+
+    // The widget is told about the failure first.
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "send_event");
+    assert!(msg["response"]["error"]["message"].is_string());
+
+    // Then the session ends.
+    let session_end = timeout(session, Duration::from_secs(1)).await.unwrap().unwrap();
+    assert_eq!(session_end, SessionEnd::RoomUnavailable);
+    assert!(session_end.is_fatal());
+}
+
+#[async_test]
+async fn test_room_widgets() {
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+
+    let widget_event = |event_type: &str, state_key: &str, content: JsonValue| {
+        Raw::new(&json!({
+            "type": event_type,
+            "state_key": state_key,
+            "sender": ALICE.as_str(),
+            "event_id": format!("${event_type}:{state_key}"),
+            "origin_server_ts": 1_000,
+            "content": content,
+        }))
+        .unwrap()
+        .cast()
+    };
+
+    let room = mock_server
+        .sync_room(
+            &client,
+            JoinedRoomBuilder::new(&ROOM_ID).add_state_bulk([
+                widget_event(
+                    "m.widget",
+                    "stable",
+                    json!({ "type": "m.custom", "url": "https://foo.bar/stable" }),
+                ),
+                widget_event(
+                    "im.vector.modular.widgets",
+                    "legacy",
+                    json!({
+                        "type": "m.custom",
+                        "url": "https://foo.bar/legacy",
+                        "data": { "init_after_content_load": true },
+                    }),
+                ),
+                // A removed widget.
+                widget_event("im.vector.modular.widgets", "removed", json!({})),
+                // A widget whose url can't be used.
+                widget_event(
+                    "im.vector.modular.widgets",
+                    "relative",
+                    json!({ "type": "m.custom", "url": "/widget" }),
+                ),
+                // A widget without an id.
+                widget_event(
+                    "m.widget",
+                    "",
+                    json!({ "type": "m.custom", "url": "https://foo.bar/anonymous" }),
+                ),
+            ]),
+        )
+        .await;
+
+    let mut widgets = room.widgets().await.unwrap();
+    widgets.sort_by(|a, b| a.widget_id().cmp(b.widget_id()));
+
+    assert_eq!(widgets.len(), 2);
+    assert_eq!(widgets[0].widget_id(), "legacy");
+    assert_eq!(widgets[0].raw_url().as_str(), "https://foo.bar/legacy");
+    assert!(widgets[0].should_init_after_content_load());
+    assert_eq!(widgets[1].widget_id(), "stable");
+    assert!(!widgets[1].should_init_after_content_load());
+}
+
+#[async_test]
+async fn test_events_are_held_back_while_paused() {
+    let (client, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(
+        &driver_handle,
+        json!(["org.matrix.msc2762.receive.event:m.room.message#m.text"]),
+    )
+    .await;
+
+    assert!(driver_handle.pause().await);
+
+    let f = EventFactory::new();
+    mock_server
+        .mock_sync()
+        .ok_and_run(&client, |sync_builder| {
+            sync_builder.add_joined_room(
+                JoinedRoomBuilder::new(&ROOM_ID)
+                    .add_timeline_event(f.text_msg("message while paused").sender(&ALICE)),
+            );
+        })
+        .await;
+
+    // The event isn't delivered while paused.
+    assert!(timeout(driver_handle.recv(), Duration::from_millis(100)).await.is_err());
+
+    assert!(driver_handle.resume().await);
+
+    // It is delivered once resumed.
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "toWidget");
+    assert_eq!(msg["action"], "send_event");
+    assert_eq!(msg["data"]["content"]["body"], "message while paused");
+
+    // No more messages from the driver
+    assert_matches!(recv_message(&driver_handle).now_or_never(), None);
+}
+
+#[async_test]
+async fn test_send_room_message() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!(["org.matrix.msc2762.send.event:m.room.message"]))
+        .await;
+
+    mock_server
+        .mock_room_send()
+        .for_type("m.room.message".into())
+        .ok(event_id!("$foobar"))
+        .mock_once()
+        .mount()
+        .await;
+
+    send_request(
+        &driver_handle,
+        "send-room-message",
+        "send_event",
+        json!({
+            "type": "m.room.message",
+            "content": {
+                "msgtype": "m.text",
+                "body": "Message from a widget!",
+            },
+        }),
+    )
+    .await;
+
+    // Receive the response
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "send_event");
+    let event_id = msg["response"]["event_id"].as_str().unwrap();
+    assert_eq!(event_id, "$foobar");
+}
+
+#[async_test]
+async fn test_send_room_name() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(
+        &driver_handle,
+        json!(["org.matrix.msc2762.send.state_event:m.room.name#"]),
+    )
+    .await;
+
+    mock_server
+        .mock_room_send_state()
+        .for_type(StateEventType::RoomName)
+        .ok(event_id!("$foobar"))
+        .mock_once()
+        .mount()
+        .await;
+
+    send_request(
+        &driver_handle,
+        "send-room-name",
+        "send_event",
+        json!({
+            "type": "m.room.name",
+            "state_key": "",
+            "content": {
+                "name": "Room Name set by Widget",
+            },
+        }),
+    )
+    .await;
+
+    // Receive the response
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "send_event");
+    let event_id = msg["response"]["event_id"].as_str().unwrap();
+    assert_eq!(event_id, "$foobar");
+}
+
+/// Syncs a widget-specific state event, and negotiates the capability to
+/// send it.
+async fn setup_widget_state(
+    client: &Client,
+    mock_server: &MatrixMockServer,
+    driver_handle: &WidgetDriverHandle,
+) {
+    mock_server
+        .mock_sync()
+        .ok_and_run(client, |sync_builder| {
+            sync_builder.add_joined_room(
+                JoinedRoomBuilder::new(&ROOM_ID).add_state_bulk([Raw::new(&json!({
+                    "type": "io.element.widget_state",
+                    "state_key": "widget-1",
+                    "sender": BOB.as_str(),
+                    "event_id": "$widget-state",
+                    "origin_server_ts": 1_700_000_000_000u64,
+                    "content": { "theme": "dark", "layout": "grid" },
+                }))
+                .unwrap()
+                .cast()]),
+            );
+        })
+        .await;
+
+    negotiate_capabilities(
+        driver_handle,
+        json!(["org.matrix.msc2762.send.state_event:io.element.widget_state#widget-1"]),
+    )
+    .await;
+}
+
+#[async_test]
+async fn test_send_state_event_replaces_content() {
+    let (client, mock_server, driver_handle) = run_test_driver(false).await;
+    setup_widget_state(&client, &mock_server, &driver_handle).await;
+
+    // By default, the new content replaces the current one.
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/v3/rooms/.*/state/io.element.widget_state/widget-1"))
+        .and(body_json(json!({ "layout": "spotlight" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "event_id": "$new" })))
+        .expect(1)
+        .mount(mock_server.server())
+        .await;
+
+    send_request(
+        &driver_handle,
+        "send-widget-state",
+        "send_event",
+        json!({
+            "type": "io.element.widget_state",
+            "state_key": "widget-1",
+            "content": { "layout": "spotlight" },
+        }),
+    )
+    .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "send_event");
+    assert_eq!(msg["response"]["event_id"], "$new");
+}
+
+#[async_test]
+async fn test_send_state_event_merges_content() {
+    let (client, mock_server, driver_handle) = run_test_driver(false).await;
+    setup_widget_state(&client, &mock_server, &driver_handle).await;
+
+    // The new fields are merged into the current content.
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/v3/rooms/.*/state/io.element.widget_state/widget-1"))
+        .and(body_json(json!({ "theme": "dark", "layout": "spotlight" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "event_id": "$new" })))
+        .expect(1)
+        .mount(mock_server.server())
+        .await;
+
+    send_request(
+        &driver_handle,
+        "send-widget-state",
+        "send_event",
+        json!({
+            "type": "io.element.widget_state",
+            "state_key": "widget-1",
+            "content": { "layout": "spotlight" },
+            "update_mode": "merge",
+        }),
+    )
+    .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "send_event");
+    assert_eq!(msg["response"]["event_id"], "$new");
+}
+
+#[async_test]
+async fn test_send_event_with_transaction_id_prefix() {
+    let (_, mock_server, driver_handle) = run_test_driver_with_settings(
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget")
+            .unwrap()
+            .with_transaction_id_prefix("widget_test_"),
+    )
+    .await;
+
+    negotiate_capabilities(&driver_handle, json!(["org.matrix.msc2762.send.event:m.room.message"]))
+        .await;
+
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/v3/rooms/.*/send/m.room.message/widget_test_.+"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "event_id": "$sent" })))
+        .expect(2)
+        .mount(mock_server.server())
+        .await;
+
+    for request_id in ["send-event-1", "send-event-2"] {
+        send_request(
+            &driver_handle,
+            request_id,
+            "send_event",
+            json!({
+                "type": "m.room.message",
+                "content": { "msgtype": "m.text", "body": "Hello from the widget" },
+            }),
+        )
+        .await;
+
+        let msg = recv_message(&driver_handle).await;
+        assert_eq!(msg["action"], "send_event");
+        assert_eq!(msg["response"]["event_id"], "$sent");
+    }
+
+    // Both events were sent with a prefixed transaction id, which is still unique.
+    let transaction_ids: Vec<_> = mock_server
+        .server()
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|request| request.method.as_str() == "PUT")
+        .filter_map(|request| {
+            request.url.path_segments().and_then(|segments| segments.last().map(ToOwned::to_owned))
+        })
+        .collect();
+    assert_eq!(transaction_ids.len(), 2);
+    assert!(transaction_ids.iter().all(|txn_id| txn_id.starts_with("widget_test_")));
+    assert_ne!(transaction_ids[0], transaction_ids[1]);
+}
+
+#[async_test]
+async fn test_send_delayed_message_event() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(
+        &driver_handle,
+        json!([
+            "org.matrix.msc4157.send.delayed_event",
+            "org.matrix.msc2762.send.event:m.room.message"
+        ]),
+    )
+    .await;
+    mock_server
+        .mock_room_send()
+        .match_delayed_event(Duration::from_millis(1000))
+        .for_type(MessageLikeEventType::RoomMessage)
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "delay_id": "1234",
+        })))
+        .mock_once()
+        .mount()
+        .await;
+
+    send_request(
+        &driver_handle,
+        "send-room-message",
+        "send_event",
+        json!({
+            "type": "m.room.message",
+            "content": {
+                "msgtype": "m.text",
+                "body": "Message from a widget!",
+            },
+            "delay":1000,
+        }),
+    )
+    .await;
+
+    // Receive the response
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "send_event");
+    let delay_id = msg["response"]["delay_id"].as_str().unwrap();
+    assert_eq!(delay_id, "1234");
+}
+
+#[async_test]
+async fn test_send_delayed_state_event() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(
+        &driver_handle,
+        json!([
+            "org.matrix.msc4157.send.delayed_event",
+            "org.matrix.msc2762.send.state_event:m.room.name#"
+        ]),
+    )
+    .await;
+
+    mock_server
+        .mock_room_send_state()
+        .match_delayed_event(Duration::from_millis(1000))
+        .for_type(StateEventType::RoomName)
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "delay_id": "1234",
+        })))
+        .mock_once()
+        .mount()
+        .await;
+
+    send_request(
+        &driver_handle,
+        "send-room-message",
+        "send_event",
+        json!({
+            "type": "m.room.name",
+            "state_key": "",
+            "content": {
+                "name": "Room Name set by Widget",
+            },
+            "delay":1000,
+        }),
+    )
+    .await;
+
+    // Receive the response
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "send_event");
+    let delay_id = msg["response"]["delay_id"].as_str().unwrap();
+    assert_eq!(delay_id, "1234");
+}
+
+#[async_test]
+async fn test_fail_sending_delay_rate_limit() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(
+        &driver_handle,
+        json!([
+            "org.matrix.msc4157.send.delayed_event",
+            "org.matrix.msc2762.send.event:m.room.message"
+        ]),
+    )
+    .await;
+
+    mock_server
+        .mock_room_send()
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "errcode": "M_LIMIT_EXCEEDED",
+            "error": "Sending too many delay events"
+        })))
+        .mock_once()
+        .mount()
+        .await;
+
+    send_request(
+        &driver_handle,
+        "send-room-message",
+        "send_event",
+        json!({
+            "type": "m.room.message",
+            "content": {
+                "msgtype": "m.text",
+                "body": "Message from a widget!",
+            },
+            "delay":1000,
+        }),
+    )
+    .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "send_event");
+    // Receive the response in the correct widget error response format
+    assert_eq!(
+        msg["response"],
+        json!({
+            "error": {
+              "matrix_api_error": {
+                "http_status": 400,
+                "response": {
+                  "errcode": "M_LIMIT_EXCEEDED",
+                  "error": "Sending too many delay events"
+                },
+              },
+              "message": "the server returned an error: [400 / M_LIMIT_EXCEEDED] Sending too many delay events"
+            }
+        })
+    );
+}
+
+#[async_test]
+async fn test_try_send_delayed_state_event_without_permission() {
+    let (_, _mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(
+        &driver_handle,
+        json!(["org.matrix.msc2762.send.state_event:m.room.name#"]),
+    )
+    .await;
+
+    send_request(
+        &driver_handle,
+        "send-room-message",
+        "send_event",
+        json!({
+            "type": "m.room.name",
+            "state_key": "",
+            "content": {
+                "name": "Room Name set by Widget",
+            },
+            "delay":1000,
+        }),
+    )
+    .await;
+
+    // Receive the response
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "send_event");
+    let error_message = msg["response"]["error"]["message"].as_str().unwrap();
+    assert_eq!(
+        error_message,
+        "Not allowed: missing the org.matrix.msc4157.send.delayed_event capability."
+    );
+}
+
+/// Send a delayed `m.room.message` event, to which the homeserver answers with
+/// the given delay id.
+async fn send_delayed_message(
+    mock_server: &MatrixMockServer,
+    driver_handle: &WidgetDriverHandle,
+    delay_id: &str,
+) {
+    mock_server
+        .mock_room_send()
+        .match_delayed_event(Duration::from_millis(1000))
+        .for_type(MessageLikeEventType::RoomMessage)
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "delay_id": delay_id,
+        })))
+        .mock_once()
+        .mount()
+        .await;
+
+    send_request(
+        driver_handle,
+        "send-delayed-message",
+        "send_event",
+        json!({
+            "type": "m.room.message",
+            "content": {
+                "msgtype": "m.text",
+                "body": "Message from a widget!",
+            },
+            "delay": 1000,
+        }),
+    )
+    .await;
+
+    let msg = recv_message(driver_handle).await;
+    assert_eq!(msg["action"], "send_event");
+    assert_eq!(msg["response"]["delay_id"], delay_id);
+}
+
+/// Ask to apply the given action to the delayed event with the given delay id,
+/// and return the error message of the response, if any.
+async fn update_delayed_event(
+    driver_handle: &WidgetDriverHandle,
+    delay_id: &str,
+    action: &str,
+) -> Option<String> {
+    send_request(
+        driver_handle,
+        "send-delay-update-id",
+        "org.matrix.msc4157.update_delayed_event",
+        json!({
+            "action": action,
+            "delay_id": delay_id,
+        }),
+    )
+    .await;
+
+    let response = recv_message(driver_handle).await;
+    assert_eq!(response["action"], "org.matrix.msc4157.update_delayed_event");
+    response["response"]["error"]["message"].as_str().map(ToOwned::to_owned)
+}
+
+/// The capabilities to send delayed `m.room.message` events and update them.
+fn delayed_message_capabilities() -> JsonValue {
+    json!([
+        "org.matrix.msc4157.send.delayed_event",
+        "org.matrix.msc4157.update_delayed_event",
+        "org.matrix.msc2762.send.event:m.room.message"
+    ])
+}
+
+#[async_test]
+async fn test_update_delayed_event() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, delayed_message_capabilities()).await;
+    send_delayed_message(&mock_server, &driver_handle, "1234").await;
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/unstable/org.matrix.msc4140/delayed_events/1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(mock_server.server())
+        .await;
+
+    send_request(
+        &driver_handle,
+        "send-delay-update-id",
+        "org.matrix.msc4157.update_delayed_event",
+        json!({
+            "action":"refresh",
+            "delay_id": "1234",
+        }),
+    )
+    .await;
+    // Receive the response
+    let response = recv_message(&driver_handle).await;
+    print!("{:?}", response);
+    assert_eq!(response["api"], "fromWidget");
+    assert_eq!(response["action"], "org.matrix.msc4157.update_delayed_event");
+    let empty_response = response["response"].clone();
+    assert_eq!(empty_response, serde_json::from_str::<JsonValue>("{}").unwrap());
+}
+
+#[async_test]
+async fn test_update_unknown_delayed_event_is_rejected() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, delayed_message_capabilities()).await;
+    send_delayed_message(&mock_server, &driver_handle, "1234").await;
+
+    // The homeserver is never asked to update a delayed event that the widget
+    // didn't send.
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/unstable/org.matrix.msc4140/delayed_events/"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(0)
+        .mount(mock_server.server())
+        .await;
+
+    let error_message = update_delayed_event(&driver_handle, "5678", "restart").await.unwrap();
+    assert!(error_message.starts_with("Unknown delay id"), "{error_message}");
+}
+
+#[async_test]
+async fn test_restarts_of_a_delayed_event_are_rate_limited() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, delayed_message_capabilities()).await;
+    send_delayed_message(&mock_server, &driver_handle, "1234").await;
+
+    // The first restart and the cancellation reach the homeserver.
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/unstable/org.matrix.msc4140/delayed_events/1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(2)
+        .mount(mock_server.server())
+        .await;
+
+    assert_eq!(update_delayed_event(&driver_handle, "1234", "restart").await, None);
+
+    // Restarting it again right away is rejected.
+    let error_message = update_delayed_event(&driver_handle, "1234", "restart").await.unwrap();
+    assert!(error_message.starts_with("Too many updates of the delayed event"), "{error_message}");
+
+    // But it can still be cancelled, after which it can't be updated anymore.
+    assert_eq!(update_delayed_event(&driver_handle, "1234", "cancel").await, None);
+    let error_message = update_delayed_event(&driver_handle, "1234", "restart").await.unwrap();
+    assert!(error_message.starts_with("Unknown delay id"), "{error_message}");
+}
+
+#[async_test]
+async fn test_failed_restart_of_a_delayed_event_is_not_rate_limited() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, delayed_message_capabilities()).await;
+    send_delayed_message(&mock_server, &driver_handle, "1234").await;
+
+    // The first restart fails, the second one succeeds.
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/unstable/org.matrix.msc4140/delayed_events/1234"))
+        .respond_with(ResponseTemplate::new(500).set_body_json(json!({
+            "errcode": "M_UNKNOWN",
+            "error": "Internal server error",
+        })))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(mock_server.server())
+        .await;
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/unstable/org.matrix.msc4140/delayed_events/1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(mock_server.server())
+        .await;
+
+    assert!(update_delayed_event(&driver_handle, "1234", "restart").await.is_some());
+
+    // The failed restart doesn't prevent restarting it again right away.
+    assert_eq!(update_delayed_event(&driver_handle, "1234", "restart").await, None);
+}
+
+#[async_test]
+async fn test_resumed_session_can_update_the_delayed_events() {
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+    mock_server.mock_room_state_encryption().plain().mount().await;
+
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let (driver, driver_handle) = WidgetDriver::new(settings.clone());
+    let session = spawn(driver.run(room.clone(), DummyCapabilitiesProvider));
+
+    let caps = delayed_message_capabilities();
+    negotiate_capabilities(&driver_handle, caps.clone()).await;
+    send_delayed_message(&mock_server, &driver_handle, "1234").await;
+
+    // The app is backgrounded.
+    let snapshot = driver_handle.snapshot();
+    driver_handle.stop();
+    assert_eq!(session.await.unwrap(), SessionEnd::Stopped);
+
+    // The app is foregrounded again.
+    let (driver, driver_handle) = WidgetDriver::resume_from(settings, snapshot);
+    spawn(driver.run(room, DummyCapabilitiesProvider));
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "notify_capabilities");
+    let request_id = msg["requestId"].as_str().unwrap();
+    send_response(&driver_handle, request_id, "notify_capabilities", &msg["data"], json!({})).await;
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/unstable/org.matrix.msc4140/delayed_events/1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(mock_server.server())
+        .await;
+
+    // The delayed event sent in the previous session can still be cancelled.
+    assert_eq!(update_delayed_event(&driver_handle, "1234", "cancel").await, None);
+}
+
+#[async_test]
+async fn test_try_update_delayed_event_without_permission() {
+    let (_, _mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!([])).await;
+
+    send_request(
+        &driver_handle,
+        "send-delay-update-id",
+        "org.matrix.msc4157.update_delayed_event",
+        json!({
+            "action":"refresh",
+            "delay_id": "1234",
+        }),
+    )
+    .await;
+    // Receive the response
+    let response = recv_message(&driver_handle).await;
+    print!("{:?}", response);
+    assert_eq!(response["api"], "fromWidget");
+    assert_eq!(response["action"], "org.matrix.msc4157.update_delayed_event");
+    let error_response = response["response"]["error"]["message"].clone();
+    assert_eq!(
+        error_response.as_str().unwrap(),
+        "Not allowed: missing the org.matrix.msc4157.update_delayed_event capability."
+    );
+}
+
+#[async_test]
+async fn test_try_update_delayed_event_without_permission_negotiate() {
+    let (_, _mock_server, driver_handle) = run_test_driver(false).await;
+
+    send_request(
+        &driver_handle,
+        "send-delay-update-id",
+        "org.matrix.msc4157.update_delayed_event",
+        json!({
+            "action":"refresh",
+            "delay_id": "1234",
+        }),
+    )
+    .await;
+    // Wait for the corresponding response.
+    loop {
+        let response = recv_message(&driver_handle).await;
+        if response["api"] == "fromWidget"
+            && response["action"] == "org.matrix.msc4157.update_delayed_event"
+        {
+            let error_response = response["response"]["error"]["message"].clone();
+            assert_eq!(
+                error_response.as_str().unwrap(),
+                "Received send update delayed event request before capabilities were negotiated"
+            );
+            break;
+        }
+    }
+}
+
+#[async_test]
+async fn test_send_redaction() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(
+        &driver_handle,
+        json!([
+            // "org.matrix.msc4157.send.delayed_event",
+            "org.matrix.msc2762.send.event:m.room.redaction"
+        ]),
+    )
+    .await;
+
+    mock_server.mock_room_redact().ok(event_id!("$redact_event_id")).mock_once().mount().await;
+
+    send_request(
+        &driver_handle,
+        "send-redact-message",
+        "send_event",
+        json!({
+            "type": "m.room.redaction",
+            "content": {
+                "redacts": "$1234"
+            },
+        }),
+    )
+    .await;
+
+    // Receive the response
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "send_event");
+    let redact_event_id = msg["response"]["event_id"].as_str().unwrap();
+    let redact_room_id = msg["response"]["room_id"].as_str().unwrap();
+
+    assert_eq!(redact_event_id, "$redact_event_id");
+    assert_eq!(redact_room_id, "!a98sd12bjh:example.org");
+}
+
+#[async_test]
+async fn test_report_event() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!(["io.element.report_event"])).await;
+
+    let event_id = event_id!("$to_report");
+    let f = EventFactory::new().room(&ROOM_ID).sender(&BOB);
+    mock_server
+        .mock_room_event()
+        .match_event_id()
+        .ok(f.text_msg("spam").event_id(event_id).into())
+        .mock_once()
+        .mount()
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/v3/rooms/.*/report/.*"))
+        .and(body_partial_json(json!({ "reason": "spam", "score": -100 })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(mock_server.server())
+        .await;
+
+    send_request(
+        &driver_handle,
+        "report-event-id",
+        "report_event",
+        json!({
+            "event_id": event_id,
+            "score": -100,
+            "reason": "spam",
+        }),
+    )
+    .await;
+
+    // Receive the response
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "report_event");
+    assert_eq!(msg["response"], json!({}));
+}
+
+#[async_test]
+async fn test_try_report_event_without_permission() {
+    let (_, _mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!([])).await;
+
+    send_request(
+        &driver_handle,
+        "report-event-id",
+        "report_event",
+        json!({
+            "event_id": "$to_report",
+        }),
+    )
+    .await;
+
+    // Receive the response
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "report_event");
+    assert_eq!(
+        msg["response"]["error"]["message"].as_str().unwrap(),
+        "Not allowed: missing the io.element.report_event capability."
+    );
+}
+
+#[async_test]
+async fn test_send_reaction() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!(["org.matrix.msc2762.send.event:m.reaction"]))
+        .await;
+
+    let event_id = event_id!("$to_react_to");
+    let f = EventFactory::new().room(&ROOM_ID).sender(&BOB);
+    mock_server
+        .mock_room_event()
+        .match_event_id()
+        .ok(f.text_msg("hello").event_id(event_id).into())
+        .mock_once()
+        .mount()
+        .await;
+
+    mock_server
+        .mock_room_send()
+        .for_type("m.reaction".into())
+        .body_matches_partial_json(json!({
+            "m.relates_to": {
+                "rel_type": "m.annotation",
+                "event_id": event_id,
+                "key": "👍",
+            },
+        }))
+        .ok(event_id!("$reaction"))
+        .mock_once()
+        .mount()
+        .await;
+
+    send_request(
+        &driver_handle,
+        "send-reaction-id",
+        "send_reaction",
+        json!({
+            "event_id": event_id,
+            "key": "👍",
+        }),
+    )
+    .await;
+
+    // Receive the response
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "send_reaction");
+    assert_eq!(msg["response"]["event_id"].as_str().unwrap(), "$reaction");
+    assert_eq!(msg["response"]["room_id"].as_str().unwrap(), ROOM_ID.as_str());
+}
+
+#[async_test]
+async fn test_try_send_reaction_without_permission() {
+    let (_, _mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!(["org.matrix.msc2762.send.event:m.room.message"]))
+        .await;
+
+    send_request(
+        &driver_handle,
+        "send-reaction-id",
+        "send_reaction",
+        json!({
+            "event_id": "$to_react_to",
+            "key": "👍",
+        }),
+    )
+    .await;
+
+    // Receive the response
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "send_reaction");
+    assert_eq!(
+        msg["response"]["error"]["message"].as_str().unwrap(),
+        "Not allowed to send m.reaction events"
+    );
+}
+
+/// Set up a widget which may redact events, in a room where the user has the
+/// given power level and an event of Bob can be redacted.
+async fn setup_redaction(power_level: i64) -> (MatrixMockServer, WidgetDriverHandle) {
+    let (client, mock_server, driver_handle) = run_test_driver(false).await;
+
+    let own_user_id = client.user_id().unwrap().to_owned();
+    mock_server
+        .mock_sync()
+        .ok_and_run(&client, |sync_builder| {
+            sync_builder.add_joined_room(
+                JoinedRoomBuilder::new(&ROOM_ID).add_state_bulk([Raw::new(&json!({
+                    "type": "m.room.power_levels",
+                    "state_key": "",
+                    "sender": ALICE.as_str(),
+                    "event_id": "$power_levels",
+                    "origin_server_ts": 1_000,
+                    "content": {
+                        "users": { ALICE.as_str(): 100, own_user_id.as_str(): power_level },
+                        "redact": 50,
+                    },
+                }))
+                .unwrap()
+                .cast()]),
+            );
+        })
+        .await;
+
+    negotiate_capabilities(
+        &driver_handle,
+        json!(["org.matrix.msc2762.send.event:m.room.redaction"]),
+    )
+    .await;
+
+    let f = EventFactory::new().room(&ROOM_ID).sender(&BOB);
+    mock_server
+        .mock_room_event()
+        .match_event_id()
+        .ok(f.text_msg("spam").event_id(event_id!("$spam")).into())
+        .mock_once()
+        .mount()
+        .await;
+
+    (mock_server, driver_handle)
+}
+
+#[async_test]
+async fn test_redact_event() {
+    let (mock_server, driver_handle) = setup_redaction(50).await;
+
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/v3/rooms/.*/redact/"))
+        .and(body_json(json!({ "reason": "Spam" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "event_id": "$redaction" })))
+        .expect(1)
+        .mount(mock_server.server())
+        .await;
+
+    send_request(
+        &driver_handle,
+        "redact-event",
+        "redact_event",
+        json!({ "event_id": "$spam", "reason": "Spam" }),
+    )
+    .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "redact_event");
+    assert_eq!(msg["response"]["event_id"], "$redaction");
+    assert_eq!(msg["response"]["room_id"], ROOM_ID.as_str());
+}
+
+#[async_test]
+async fn test_try_redact_event_without_power() {
+    let (mock_server, driver_handle) = setup_redaction(10).await;
+
+    mock_server.mock_room_redact().ok(event_id!("$redaction")).never().mount().await;
+
+    send_request(&driver_handle, "redact-event", "redact_event", json!({ "event_id": "$spam" }))
+        .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "redact_event");
+    assert_eq!(
+        msg["response"]["error"]["message"],
+        "The user doesn't have the power level to redact this event"
+    );
+}
+
+#[async_test]
+async fn test_try_redact_event_without_permission() {
+    let (_, _mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!(["org.matrix.msc2762.send.event:m.reaction"]))
+        .await;
+
+    send_request(&driver_handle, "redact-event", "redact_event", json!({ "event_id": "$spam" }))
+        .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "redact_event");
+    assert_eq!(msg["response"]["error"]["message"], "Not allowed to send m.room.redaction events");
+}
+
+#[async_test]
+async fn test_channels_are_closed_when_the_session_ends() {
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+    mock_server.mock_room_state_encryption().plain().mount().await;
+
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let (driver, driver_handle) = WidgetDriver::new(settings);
+    let session = spawn(driver.run(room, DummyCapabilitiesProvider));
+
+    negotiate_capabilities(&driver_handle, json!(["org.matrix.msc2762.send.event:m.room.message"]))
+        .await;
+
+    // The session ends because the client is logged out.
+    mock_server.mock_logout().ok().mock_once().mount().await;
+    client.logout().await.unwrap();
+
+    mock_server
+        .mock_room_send()
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+            "errcode": "M_UNKNOWN_TOKEN",
+            "error": "Invalid access token passed.",
+        })))
+        .mock_once()
+        .mount()
+        .await;
+
+    send_request(
+        &driver_handle,
+        "send-room-message",
+        "send_event",
+        json!({
+            "type": "m.room.message",
+            "content": { "msgtype": "m.text", "body": "Message from a widget!" },
+        }),
+    )
+    .await;
+
+    let session_end = timeout(session, Duration::from_secs(1)).await.unwrap().unwrap();
+    assert_eq!(session_end, SessionEnd::ClientUnavailable);
+
+    // The widget still receives the last message of the session, the error.
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "send_event");
+
+    // Then it observes that both channels are closed, although it still holds
+    // the handle.
+    assert!(driver_handle.recv().await.is_none());
+    assert!(!driver_handle.send(json_string!({ "api": "fromWidget" })).await);
+}
+
+#[async_test]
+async fn test_late_response_after_stop_is_dropped() {
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+    mock_server.mock_room_state_encryption().plain().mount().await;
+
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let (driver, driver_handle) = WidgetDriver::new(settings);
+    let session = spawn(driver.run(room, DummyCapabilitiesProvider));
+
+    // The widget driver asks for the capabilities of the widget.
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "capabilities");
+    let request_id = msg["requestId"].as_str().unwrap().to_owned();
+
+    // The session is stopped before the widget answers.
+    driver_handle.stop();
+
+    let session_end = timeout(session, Duration::from_secs(1)).await.unwrap().unwrap();
+    assert_eq!(session_end, SessionEnd::Stopped);
+
+    // The late response of the widget is dropped.
+    let sent = driver_handle
+        .send(json_string!({
+            "api": "toWidget",
+            "widgetId": WIDGET_ID,
+            "requestId": request_id,
+            "action": "capabilities",
+            "data": {},
+            "response": { "capabilities": [] },
+        }))
+        .await;
+    assert!(!sent);
+
+    // And the widget can observe that the session ended.
+    assert!(driver_handle.recv().await.is_none());
+}
+
+#[async_test]
+async fn test_session_ends_when_the_client_is_logged_out() {
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+    mock_server.mock_room_state_encryption().plain().mount().await;
+
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let (driver, driver_handle) = WidgetDriver::new(settings);
+    let session = spawn(driver.run(room, DummyCapabilitiesProvider));
+
+    negotiate_capabilities(&driver_handle, json!(["org.matrix.msc2762.send.event:m.room.message"]))
+        .await;
+
+    // The client gets logged out in the middle of the session, its access token
+    // isn't valid anymore.
+    mock_server.mock_logout().ok().mock_once().mount().await;
+    client.logout().await.unwrap();
+
+    mock_server
+        .mock_room_send()
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+            "errcode": "M_UNKNOWN_TOKEN",
+            "error": "Invalid access token passed.",
+        })))
+        .mock_once()
+        .mount()
+        .await;
+
+    send_request(
+        &driver_handle,
+        "send-room-message",
+        "send_event",
+        json!({
+            "type": "m.room.message",
+            "content": {
+                "msgtype": "m.text",
+                "body": "Message from a widget!",
+            },
+        }),
+    )
+    .await;
+
+    // The widget is told about the failure first.
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
     assert_eq!(msg["action"], "send_event");
-    let redact_event_id = msg["response"]["event_id"].as_str().unwrap();
-    let redact_room_id = msg["response"]["room_id"].as_str().unwrap();
+    assert_eq!(
+        msg["response"]["error"]["matrix_api_error"]["response"]["errcode"],
+        "M_UNKNOWN_TOKEN"
+    );
 
-    assert_eq!(redact_event_id, "$redact_event_id");
-    assert_eq!(redact_room_id, "!a98sd12bjh:example.org");
+    // Then the session ends.
+    let session_end = timeout(session, Duration::from_secs(1)).await.unwrap().unwrap();
+    assert_eq!(session_end, SessionEnd::ClientUnavailable);
+
+    // And the widget can observe it.
+    assert!(driver_handle.recv().await.is_none());
+}
+
+#[async_test]
+async fn test_openid_homeserver_error_is_blocked() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!([])).await;
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/v3/user/.*/openid/request_token"))
+        .respond_with(ResponseTemplate::new(403).set_body_json(json!({
+            "errcode": "M_FORBIDDEN",
+            "error": "OpenID is disabled on this homeserver",
+        })))
+        .expect(1)
+        .mount(mock_server.server())
+        .await;
+
+    send_request(&driver_handle, "openid-request-id", "get_openid", json!({})).await;
+
+    // The widget is told right away that the request is pending.
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "get_openid");
+    assert_eq!(msg["response"]["state"], "request");
+
+    // Then it is informed that the request has been blocked.
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "toWidget");
+    assert_eq!(msg["action"], "openid_credentials");
+    assert_eq!(msg["data"]["state"], "blocked");
+    assert_eq!(msg["data"]["original_request_id"], "openid-request-id");
+
+    // The session is still alive and keeps answering the widget's requests.
+    send_request(&driver_handle, "get-api-versions", "supported_api_versions", json!({})).await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "supported_api_versions");
+    assert!(msg["response"]["supported_versions"].is_array());
+}
+
+/// A navigation handler that accepts all the requests and records them.
+#[derive(Clone, Default)]
+struct RecordingNavigationHandler(Arc<Mutex<Vec<OwnedRoomId>>>);
+
+#[async_trait]
+impl NavigationHandler for RecordingNavigationHandler {
+    async fn navigate_to_room(&self, room_id: OwnedRoomId) -> bool {
+        self.0.lock().unwrap().push(room_id);
+        true
+    }
+}
+
+#[async_test]
+async fn test_navigate_to_room() {
+    let mock_server = MatrixMockServer::new().await;
+    let client = mock_server.client_builder().build().await;
+
+    let room = mock_server.sync_joined_room(&client, &ROOM_ID).await;
+    let other_room_id = owned_room_id!("!other:example.org");
+    mock_server.sync_joined_room(&client, &other_room_id).await;
+    mock_server.mock_room_state_encryption().plain().mount().await;
+
+    let navigation_handler = RecordingNavigationHandler::default();
+    let settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    let (driver, driver_handle) = WidgetDriver::new(settings);
+    let driver = driver.with_navigation_handler(navigation_handler.clone());
+    spawn(driver.run(room, DummyCapabilitiesProvider));
+
+    negotiate_capabilities(&driver_handle, json!(["org.matrix.msc2931.navigate"])).await;
+
+    // Navigating to a joined room is forwarded to the client.
+    send_request(
+        &driver_handle,
+        "navigate-joined",
+        "org.matrix.msc2931.navigate",
+        json!({ "uri": format!("https://matrix.to/#/{other_room_id}") }),
+    )
+    .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "org.matrix.msc2931.navigate");
+    assert_eq!(msg["response"], json!({}));
+    assert_eq!(*navigation_handler.0.lock().unwrap(), vec![other_room_id]);
+
+    // Navigating to a room that the user isn't a member of is rejected.
+    send_request(
+        &driver_handle,
+        "navigate-unknown",
+        "org.matrix.msc2931.navigate",
+        json!({ "uri": "https://matrix.to/#/!unknown:example.org" }),
+    )
+    .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "org.matrix.msc2931.navigate");
+    assert!(msg["response"]["error"]["message"].as_str().unwrap().contains("isn't a member"));
+
+    // Invalid room URIs are rejected too.
+    send_request(
+        &driver_handle,
+        "navigate-user",
+        "org.matrix.msc2931.navigate",
+        json!({ "uri": "https://matrix.to/#/@bob:example.org" }),
+    )
+    .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert!(msg["response"]["error"]["message"].as_str().unwrap().contains("Invalid room URI"));
+
+    assert_eq!(navigation_handler.0.lock().unwrap().len(), 1);
+}
+
+#[async_test]
+async fn test_get_user_profile() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!(["io.element.get_user_profile"])).await;
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/v3/profile/@bob:example.org"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "displayname": "Bob",
+            "avatar_url": "mxc://example.org/bob",
+        })))
+        .expect(1)
+        .mount(mock_server.server())
+        .await;
+
+    send_request(
+        &driver_handle,
+        "get-profile-id",
+        "get_user_profile",
+        json!({ "user_id": "@bob:example.org" }),
+    )
+    .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "get_user_profile");
+    assert_eq!(
+        msg["response"],
+        json!({
+            "displayname": "Bob",
+            "avatar_url": "mxc://example.org/bob",
+        })
+    );
+}
+
+#[async_test]
+async fn test_get_unknown_user_profile() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!(["io.element.get_user_profile"])).await;
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/v3/profile/@nobody:example.org"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+            "errcode": "M_NOT_FOUND",
+            "error": "Profile not found",
+        })))
+        .expect(1)
+        .mount(mock_server.server())
+        .await;
+
+    send_request(
+        &driver_handle,
+        "get-profile-id",
+        "get_user_profile",
+        json!({ "user_id": "@nobody:example.org" }),
+    )
+    .await;
+
+    // An unknown user results in an empty profile, not an error.
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "get_user_profile");
+    assert_eq!(msg["response"], json!({}));
+}
+
+#[async_test]
+async fn test_try_get_user_profile_without_permission() {
+    let (_, _mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!([])).await;
+
+    send_request(
+        &driver_handle,
+        "get-profile-id",
+        "get_user_profile",
+        json!({ "user_id": "@bob:example.org" }),
+    )
+    .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "get_user_profile");
+    assert_eq!(
+        msg["response"]["error"]["message"].as_str().unwrap(),
+        "Not allowed: missing the io.element.get_user_profile capability."
+    );
+}
+
+#[async_test]
+async fn test_get_server_capabilities() {
+    let (_, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!(["io.element.get_server_capabilities"])).await;
+
+    // The capabilities are only fetched once per session.
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/v3/capabilities"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "capabilities": {
+                "m.change_password": { "enabled": false },
+                "m.room_versions": {
+                    "default": "10",
+                    "available": { "10": "stable", "11": "stable" },
+                },
+            },
+        })))
+        .expect(1)
+        .mount(mock_server.server())
+        .await;
+
+    for request_id in ["get-capabilities-1", "get-capabilities-2"] {
+        send_request(&driver_handle, request_id, "get_server_capabilities", json!({})).await;
+
+        let msg = recv_message(&driver_handle).await;
+        assert_eq!(msg["api"], "fromWidget");
+        assert_eq!(msg["action"], "get_server_capabilities");
+        assert_eq!(msg["requestId"], request_id);
+
+        let capabilities = &msg["response"]["capabilities"];
+        assert_eq!(capabilities["m.change_password"], json!({ "enabled": false }));
+        assert_eq!(capabilities["m.room_versions"]["default"], "10");
+        assert_eq!(
+            capabilities["m.room_versions"]["available"],
+            json!({ "10": "stable", "11": "stable" })
+        );
+    }
+}
+
+#[async_test]
+async fn test_try_get_server_capabilities_without_permission() {
+    let (_, _mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!([])).await;
+
+    send_request(&driver_handle, "get-capabilities-id", "get_server_capabilities", json!({})).await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "get_server_capabilities");
+    assert_eq!(
+        msg["response"]["error"]["message"].as_str().unwrap(),
+        "Not allowed: missing the io.element.get_server_capabilities capability."
+    );
+}
+
+#[async_test]
+async fn test_get_knock_requests() {
+    let (client, mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!(["io.element.read_knock_requests"])).await;
+
+    let f = EventFactory::new().room(&ROOM_ID);
+    let knock_event = f
+        .member(&ALICE)
+        .membership(MembershipState::Knock)
+        .display_name("Alice")
+        .reason("Let me in!")
+        .event_id(event_id!("$alice-knock"))
+        .into_raw_timeline()
+        .cast();
+    mock_server.mock_get_members().ok(vec![knock_event]).mock_once().mount().await;
+
+    send_request(&driver_handle, "get-knock-requests", "get_knock_requests", json!({})).await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "get_knock_requests");
+
+    let requests = msg["response"]["requests"].as_array().unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0]["event_id"], "$alice-knock");
+    assert_eq!(requests[0]["user_id"], ALICE.as_str());
+    assert_eq!(requests[0]["displayname"], "Alice");
+    assert_eq!(requests[0]["reason"], "Let me in!");
+    assert_eq!(requests[0]["is_seen"], false);
+
+    // Once the request is marked as seen, the widget sees it too.
+    let room = client.get_room(&ROOM_ID).unwrap();
+    room.mark_knock_requests_as_seen(&[ALICE.to_owned()]).await.unwrap();
+
+    send_request(&driver_handle, "get-knock-requests-2", "get_knock_requests", json!({})).await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["response"]["requests"][0]["is_seen"], true);
+}
+
+#[async_test]
+async fn test_try_get_knock_requests_without_permission() {
+    let (_, _mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!([])).await;
+
+    send_request(&driver_handle, "get-knock-requests", "get_knock_requests", json!({})).await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "get_knock_requests");
+    assert_eq!(
+        msg["response"]["error"]["message"].as_str().unwrap(),
+        "Not allowed: missing the io.element.read_knock_requests capability."
+    );
+}
+
+#[async_test]
+async fn test_outgoing_messages_audit() {
+    let (_, mock_server, driver_handle) = run_test_driver(true).await;
+    let mut audit = driver_handle.subscribe_to_outgoing_messages();
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/v3/user/.*/openid/request_token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "access_token": "SECRET_TOKEN",
+            "expires_in": 3600,
+            "matrix_server_name": "example.org",
+            "token_type": "Bearer",
+        })))
+        .expect(1)
+        .mount(mock_server.server())
+        .await;
+
+    send_request(&driver_handle, "openid-request-id", "get_openid", json!({})).await;
+
+    // The pending response and the credentials are both sent to the widget…
+    let pending = recv_message(&driver_handle).await;
+    assert_eq!(pending["response"]["state"], "request");
+    let credentials = recv_message(&driver_handle).await;
+    assert_eq!(credentials["action"], "openid_credentials");
+    assert_eq!(credentials["data"]["access_token"], "SECRET_TOKEN");
+
+    // … and appear on the audit stream, without the access token.
+    let audited: JsonValue = serde_json::from_str(&audit.recv().await.unwrap()).unwrap();
+    assert_eq!(audited, JsonValue::Object(pending));
+
+    let audited: JsonValue = serde_json::from_str(&audit.recv().await.unwrap()).unwrap();
+    assert_eq!(audited["action"], "openid_credentials");
+    assert_eq!(audited["data"]["access_token"], "[REDACTED]");
+    assert_eq!(audited["data"]["matrix_server_name"], "example.org");
+}
+
+#[async_test]
+async fn test_join_action() {
+    let (_, _mock_server, driver_handle) = run_test_driver(true).await;
+    let mut joins = driver_handle.subscribe_to_joins();
+
+    send_request(&driver_handle, "join-request-id", "io.element.join", json!({})).await;
+
+    // The request is acknowledged…
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["api"], "fromWidget");
+    assert_eq!(msg["action"], "io.element.join");
+    assert_eq!(msg["response"], json!({}));
+
+    // … and forwarded to the client.
+    timeout(joins.recv(), Duration::from_secs(1)).await.unwrap().unwrap();
+}
+
+#[async_test]
+async fn test_set_always_on_screen() {
+    let (_, _mock_server, driver_handle) = run_test_driver(false).await;
+    let mut always_on_screen = driver_handle.subscribe_to_always_on_screen();
+    assert!(!driver_handle.is_always_on_screen());
+
+    negotiate_capabilities(&driver_handle, json!(["m.always_on_screen"])).await;
+
+    for value in [true, false] {
+        send_request(
+            &driver_handle,
+            "always-on-screen-id",
+            "set_always_on_screen",
+            json!({ "value": value }),
+        )
+        .await;
+
+        let msg = recv_message(&driver_handle).await;
+        assert_eq!(msg["api"], "fromWidget");
+        assert_eq!(msg["action"], "set_always_on_screen");
+        assert_eq!(msg["response"], json!({}));
+
+        let observed = timeout(always_on_screen.next(), Duration::from_secs(1)).await.unwrap();
+        assert_eq!(observed, Some(value));
+        assert_eq!(driver_handle.is_always_on_screen(), value);
+    }
+}
+
+#[async_test]
+async fn test_try_set_always_on_screen_without_permission() {
+    let (_, _mock_server, driver_handle) = run_test_driver(false).await;
+
+    negotiate_capabilities(&driver_handle, json!([])).await;
+
+    send_request(
+        &driver_handle,
+        "always-on-screen-id",
+        "set_always_on_screen",
+        json!({ "value": true }),
+    )
+    .await;
+
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "set_always_on_screen");
+    assert_eq!(
+        msg["response"]["error"]["message"].as_str().unwrap(),
+        "Not allowed: missing the m.always_on_screen capability."
+    );
+    assert!(!driver_handle.is_always_on_screen());
+}
+
+/// A tracing layer which counts the `DEBUG` events emitted by the widget
+/// driver.
+struct WidgetDebugEventsCounter(Arc<Mutex<usize>>);
+
+impl<S: Subscriber> Layer<S> for WidgetDebugEventsCounter {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() == Level::DEBUG && metadata.target() == "matrix_sdk::widget" {
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+}
+
+async fn count_widget_debug_events(log_level: Option<LevelFilter>) -> usize {
+    let counter = Arc::new(Mutex::new(0));
+    let _guard = tracing_subscriber::registry()
+        .with(WidgetDebugEventsCounter(counter.clone()))
+        .set_default();
+
+    let mut settings =
+        WidgetSettings::new(WIDGET_ID.to_owned(), false, "https://foo.bar/widget").unwrap();
+    if let Some(log_level) = log_level {
+        settings = settings.with_log_level(log_level);
+    }
+    let (_, _mock_server, driver_handle) = run_test_driver_with_settings(settings).await;
+
+    // The capabilities negotiation starts right away, so the driver sends a
+    // message to the widget.
+    let msg = recv_message(&driver_handle).await;
+    assert_eq!(msg["action"], "capabilities");
+
+    let count = *counter.lock().unwrap();
+    count
+}
+
+#[async_test]
+async fn test_debug_log_level_emits_driver_debug_events() {
+    assert!(count_widget_debug_events(Some(LevelFilter::DEBUG)).await > 0);
+}
+
+#[async_test]
+async fn test_default_log_level_hides_driver_debug_events() {
+    assert_eq!(count_widget_debug_events(None).await, 0);
 }
 
 async fn negotiate_capabilities(driver_handle: &WidgetDriverHandle, caps: JsonValue) {